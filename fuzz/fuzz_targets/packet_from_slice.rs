@@ -0,0 +1,22 @@
+//! `cargo +nightly fuzz run packet_from_slice`
+
+#![no_main]
+
+use ardl::{
+    protocol::{frag::WireFormat, packet::Packet},
+    utils::buf::{BufSlice, OwnedBufWtr},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for format in [WireFormat::V1, WireFormat::V2, WireFormat::V3] {
+        let mut slice = BufSlice::from_bytes(data.to_vec());
+        let packet = match Packet::from_slice_limited(&mut slice, format, 256) {
+            Ok(packet) => packet,
+            Err(_) => continue,
+        };
+        // whatever decodes encodes again into exactly `wire_len` bytes
+        let mut wtr = OwnedBufWtr::new(packet.wire_len(), 0);
+        packet.append_to(&mut wtr).unwrap();
+    }
+});
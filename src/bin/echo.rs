@@ -1,10 +1,11 @@
 use ardl::{
     layer::{Builder, Downloader, IObserver, SetUploadState, Uploader},
+    protocol::{frag::WireFormat, packet::Packet, packet_hdr},
     utils::buf::{BufSlice, BufWtr, OwnedBufWtr},
 };
 use std::{
     net::{SocketAddr, UdpSocket},
-    sync::{mpsc, Arc},
+    sync::{mpsc, Arc, Mutex},
     thread,
     time::{self, Duration, Instant, SystemTime},
 };
@@ -15,10 +16,24 @@ const STAT_INTERVAL_S: u64 = 1;
 const LISTEN_ADDR: &str = "0.0.0.0:19479";
 const LOCAL_RECV_BUF_LEN: usize = 1024;
 const RATIO_RTO_TO_ONE_RTT: f64 = 1.5;
-// const TO_SEND_QUEUE_LEN_CAP: usize = 1024 * 64;
-const TO_SEND_QUEUE_LEN_CAP: usize = 1024;
+const RTO_JITTER: f64 = 0.0;
+const RTO_JITTER_SEED: u64 = 0;
+const MIN_RTO: Option<Duration> = None;
+const MAX_RTO: Option<Duration> = None;
+const INITIAL_RTO: Option<Duration> = None;
+const COALESCE_SMALL_WRITES: bool = false;
+const TIMESTAMPS: bool = false;
+const MAX_FAST_RETRANSMIT_BURST: usize = usize::MAX;
+const STRICT: bool = false;
+const WINDOW_UPDATE_CHUNK: usize = 0;
+const TO_SEND_BYTES_CAP: usize = 1024 * 1024;
+const TO_SEND_QUEUE_LEN_CAP: Option<usize> = None;
+// wake `block_sending` once half of the queue is free
+const SEND_AVAILABLE_LOW_WATER_MARK: Option<usize> = Some(TO_SEND_BYTES_CAP / 2);
 const SWND_SIZE_CAP: usize = 1024;
 const ENABLE_PRINTING_DATA: bool = false;
+const MAX_RETRANSMISSIONS: Option<usize> = None;
+const MAX_NO_ACK_DURATION: Option<Duration> = Some(Duration::from_secs(10));
 static NACK_DUPLICATE_THRESHOLD_TO_ACTIVATE_FAST_RETRANSMIT: usize = SWND_SIZE_CAP * 1 / 2;
 
 fn main() {
@@ -32,19 +47,50 @@ fn main() {
     let uploading_messaging_tx = Arc::new(uploading_messaging_tx);
     let (downloading_messaging_tx, downloading_messaging_rx) = mpsc::sync_channel(0);
     let downloading_messaging_tx = Arc::new(downloading_messaging_tx);
-    let (processing_messaging_tx, processing_messaging_rx) = mpsc::sync_channel(0);
-    let processing_messaging_tx = Arc::new(processing_messaging_tx);
     let (on_send_available_tx, on_send_available_rx) = mpsc::sync_channel(1);
+    let (on_recv_available_tx, on_recv_available_rx) = mpsc::sync_channel(1);
 
     // layer
-    let (mut uploader, downloader) = Builder {
+    let (mut uploader, mut downloader) = Builder {
         local_recv_buf_len: LOCAL_RECV_BUF_LEN,
         nack_duplicate_threshold_to_activate_fast_retransmit:
             NACK_DUPLICATE_THRESHOLD_TO_ACTIVATE_FAST_RETRANSMIT,
+        max_fast_retransmit_burst: MAX_FAST_RETRANSMIT_BURST,
         ratio_rto_to_one_rtt: RATIO_RTO_TO_ONE_RTT,
+        rto_jitter: RTO_JITTER,
+        rto_jitter_seed: RTO_JITTER_SEED,
+        min_rto: MIN_RTO,
+        max_rto: MAX_RTO,
+        initial_rto: INITIAL_RTO,
+        coalesce_small_writes: COALESCE_SMALL_WRITES,
+        timestamps: TIMESTAMPS,
+        legacy_header: false,
+        strict: STRICT,
+        window_update_chunk: WINDOW_UPDATE_CHUNK,
+        ack_late_pushes: true,
+        to_send_bytes_cap: TO_SEND_BYTES_CAP,
         to_send_queue_len_cap: TO_SEND_QUEUE_LEN_CAP,
+        send_available_low_water_mark: SEND_AVAILABLE_LOW_WATER_MARK,
         swnd_size_cap: SWND_SIZE_CAP,
         mtu: MTU,
+        max_retransmissions: MAX_RETRANSMISSIONS,
+        max_no_ack_duration: MAX_NO_ACK_DURATION,
+        rst_transmissions: 3,
+        keepalive_interval: None,
+        sack: false,
+        packet_seqs: false,
+        conn_id: None,
+        handshake: None,
+        drop_duplicate_packets: true,
+        auth: None,
+        wire_format: WireFormat::V1,
+        pad_packets_to: None,
+        fec_group_size: None,
+        codec: None,
+        cumulative_ack_only: false,
+        ack_bitmap: false,
+        mtu_probing: None,
+        max_frags_per_packet: None,
     }
     .build()
     .unwrap();
@@ -57,29 +103,37 @@ fn main() {
     let weak_observer = Arc::downgrade(&observer);
     uploader.set_on_send_available(Some(weak_observer));
 
+    // on recv available
+    let recv_observer = OnRecvAvailable {
+        tx: on_recv_available_tx,
+    };
+    let recv_observer = Arc::new(recv_observer);
+    let weak_recv_observer = Arc::downgrade(&recv_observer);
+    downloader.set_on_recv_available(Some(weak_recv_observer));
+    let downloader = Arc::new(Mutex::new(downloader));
+
     // spawn threads
     let mut threads = Vec::new();
     {
+        let downloader1 = Arc::clone(&downloader);
         let uploading_messaging_tx1 = Arc::clone(&uploading_messaging_tx);
-        let processing_messaging_tx1 = Arc::clone(&processing_messaging_tx);
         let thread = thread::spawn(move || {
             downloading(
-                downloader,
+                downloader1,
                 downloading_messaging_rx,
                 uploading_messaging_tx1,
-                processing_messaging_tx1,
             )
         });
         threads.push(thread);
     }
     {
+        let downloader1 = Arc::clone(&downloader);
         let uploading_messaging_tx1 = Arc::clone(&uploading_messaging_tx);
-        let downloading_messaging_tx1 = Arc::clone(&downloading_messaging_tx);
         let thread = thread::spawn(move || {
             processing(
-                processing_messaging_rx,
+                downloader1,
                 uploading_messaging_tx1,
-                downloading_messaging_tx1,
+                on_recv_available_rx,
                 on_send_available_rx,
             )
         });
@@ -156,31 +210,63 @@ fn uploading(
 ) {
     let mut old_stat = None;
     let mut remote_addr_ = None;
+    let mut next_msg = None;
     loop {
-        let msg = messaging.recv().unwrap();
+        let msg = match next_msg.take() {
+            Some(msg) => msg,
+            None => messaging.recv().unwrap(),
+        };
         match msg {
             UploadingMessaging::SetUploadState(state) => {
-                uploader.set_state(state, &Instant::now()).unwrap();
+                // take the rest of the burst under the same emit
+                let mut states = vec![state];
+                while let Ok(msg) = messaging.try_recv() {
+                    match msg {
+                        UploadingMessaging::SetUploadState(state) => states.push(state),
+                        msg => {
+                            next_msg = Some(msg);
+                            break;
+                        }
+                    }
+                }
+                uploader.set_states(states, &Instant::now()).unwrap();
                 if let Some(remote_addr) = remote_addr_ {
-                    output(&mut uploader, &listener, &remote_addr);
+                    output(
+                        &mut uploader,
+                        &listener,
+                        &remote_addr,
+                        Uploader::emit_on_ack,
+                    );
                 }
             }
             UploadingMessaging::Flush => {
                 if let Some(remote_addr) = remote_addr_ {
-                    output(&mut uploader, &listener, &remote_addr);
+                    output(
+                        &mut uploader,
+                        &listener,
+                        &remote_addr,
+                        Uploader::emit_on_timer,
+                    );
                 }
             }
-            UploadingMessaging::ToSend(slice, responser) => match uploader.write(slice) {
-                Ok(()) => {
-                    responser.send(UploadingToSendResponse::Ok).unwrap();
-                    if let Some(remote_addr) = remote_addr_ {
-                        output(&mut uploader, &listener, &remote_addr);
+            UploadingMessaging::ToSend(slice, responser) => {
+                if uploader.is_broken(&Instant::now()) {
+                    responser.send(UploadingToSendResponse::Broken).unwrap();
+                } else {
+                    let (written, rest) = uploader.write_partial(slice);
+                    match rest {
+                        None => responser.send(UploadingToSendResponse::Ok).unwrap(),
+                        Some(rest) => responser.send(UploadingToSendResponse::Err(rest)).unwrap(),
+                    }
+                    if 0 < written {
+                        if let Some(remote_addr) = remote_addr_ {
+                            output(&mut uploader, &listener, &remote_addr, Uploader::emit);
+                        }
                     }
                 }
-                Err(e) => responser.send(UploadingToSendResponse::Err(e.0)).unwrap(),
-            },
+            }
             UploadingMessaging::PrintStat => {
-                let stat = uploader.stat();
+                let stat = uploader.stat_reset();
                 if let Some(old_stat) = old_stat {
                     if old_stat != stat {
                         let time = SystemTime::now()
@@ -193,26 +279,31 @@ fn uploading(
                 old_stat = Some(stat);
             }
             UploadingMessaging::SetRemoteAddr(remote_addr) => {
-                remote_addr_ = Some(remote_addr);
+                if !uploader.is_broken(&Instant::now()) {
+                    remote_addr_ = Some(remote_addr);
+                }
             }
         }
+        if remote_addr_.is_some() && uploader.is_broken(&Instant::now()) {
+            println!("uploading: link broken, dropping remote address");
+            remote_addr_ = None;
+        }
     }
 }
 
 fn downloading(
-    mut downloader: Downloader,
+    downloader: Arc<Mutex<Downloader>>,
     messaging: mpsc::Receiver<DownloadingMessaging>,
     uploading_messaging_tx: Arc<mpsc::SyncSender<UploadingMessaging>>,
-    processing_messaging_tx: Arc<mpsc::SyncSender<ProcessingMessaging>>,
 ) {
     let mut old_stat = None;
-    let mut is_processing_free = false;
     loop {
         let msg = messaging.recv().unwrap();
         match msg {
             DownloadingMessaging::ConnRecv(wtr) => {
                 let rdr = wtr.into_slice();
-                let set_upload_state = match downloader.write(rdr) {
+                let res = downloader.lock().unwrap().write(rdr);
+                let set_upload_state = match res {
                     Ok(x) => x,
                     Err(e) => {
                         println!("err: download.input ({:?})", e);
@@ -223,17 +314,9 @@ fn downloading(
                 uploading_messaging_tx
                     .send(UploadingMessaging::SetUploadState(set_upload_state))
                     .unwrap();
-                if is_processing_free {
-                    if let Some(slice) = downloader.emit() {
-                        processing_messaging_tx
-                            .send(ProcessingMessaging::Recv(slice))
-                            .unwrap();
-                        is_processing_free = false;
-                    }
-                }
             }
             DownloadingMessaging::PrintStat => {
-                let stat = downloader.stat();
+                let stat = downloader.lock().unwrap().stat_reset();
                 if let Some(old_stat) = old_stat {
                     if old_stat != stat {
                         let time = SystemTime::now()
@@ -245,43 +328,30 @@ fn downloading(
                 }
                 old_stat = Some(stat);
             }
-            DownloadingMessaging::ProcessingIsFree => {
-                is_processing_free = true;
-                if let Some(slice) = downloader.emit() {
-                    processing_messaging_tx
-                        .send(ProcessingMessaging::Recv(slice))
-                        .unwrap();
-                    is_processing_free = false;
-                }
-            }
         }
     }
 }
 
-// basically a channel of size one
 fn processing(
-    messaging: mpsc::Receiver<ProcessingMessaging>,
+    downloader: Arc<Mutex<Downloader>>,
     uploading_messaging_tx: Arc<mpsc::SyncSender<UploadingMessaging>>,
-    downloading_messaging_tx: Arc<mpsc::SyncSender<DownloadingMessaging>>,
+    on_recv_available_rx: mpsc::Receiver<()>,
     on_send_available_rx: mpsc::Receiver<()>,
 ) {
     loop {
-        downloading_messaging_tx
-            .send(DownloadingMessaging::ProcessingIsFree)
-            .unwrap();
-        let msg = messaging.recv().unwrap();
-        match msg {
-            ProcessingMessaging::Recv(slice) => {
-                if ENABLE_PRINTING_DATA {
-                    println!(
-                        "{}, {:X?}",
-                        String::from_utf8_lossy(&slice.data()),
-                        slice.data()
-                    );
-                }
-
-                block_sending(slice, &uploading_messaging_tx, &on_send_available_rx);
+        on_recv_available_rx.recv().unwrap();
+        // the observer is notified again once the ready bytes are all taken
+        let slices = downloader.lock().unwrap().drain();
+        for slice in slices {
+            if ENABLE_PRINTING_DATA {
+                println!(
+                    "{}, {:X?}",
+                    String::from_utf8_lossy(&slice.data()),
+                    slice.data()
+                );
             }
+
+            block_sending(slice, &uploading_messaging_tx, &on_send_available_rx);
         }
     }
 }
@@ -291,9 +361,21 @@ fn socket_receiving(
     uploading_messaging: Arc<mpsc::SyncSender<UploadingMessaging>>,
     downloading_messaging: Arc<mpsc::SyncSender<DownloadingMessaging>>,
 ) {
+    // the session follows its connection ID across source addresses
+    let mut session_conn_id = None;
     loop {
         let mut buf = vec![0; MTU];
         let (len, remote_addr) = listener.recv_from(&mut buf).unwrap();
+        // do not let stray datagrams take over the remote address
+        let hdr = match packet_hdr::peek(&buf[..len]) {
+            Ok(hdr) => hdr,
+            Err(_) => continue,
+        };
+        if let Some(conn_id) = hdr.conn_id() {
+            if *session_conn_id.get_or_insert(conn_id) != conn_id {
+                continue;
+            }
+        }
 
         let wtr = OwnedBufWtr::from_bytes(buf, 0, len);
 
@@ -317,16 +399,12 @@ enum UploadingMessaging {
 enum DownloadingMessaging {
     ConnRecv(OwnedBufWtr),
     PrintStat,
-    ProcessingIsFree,
-}
-
-enum ProcessingMessaging {
-    Recv(BufSlice),
 }
 
 enum UploadingToSendResponse {
     Ok,
     Err(BufSlice),
+    Broken,
 }
 
 fn block_sending(
@@ -347,6 +425,8 @@ fn block_sending(
             UploadingToSendResponse::Err(slice) => {
                 some_slice = Some(slice);
             }
+            // the peer is gone; drop the data
+            UploadingToSendResponse::Broken => break,
         }
         // println!("[main] got blocked");
         on_send_available_rx.recv().unwrap();
@@ -354,10 +434,15 @@ fn block_sending(
     }
 }
 
-fn output(uploader: &mut Uploader, listener: &Arc<UdpSocket>, remote_addr: &SocketAddr) {
+fn output(
+    uploader: &mut Uploader,
+    listener: &Arc<UdpSocket>,
+    remote_addr: &SocketAddr,
+    emit: fn(&mut Uploader, &Instant) -> Vec<Packet>,
+) {
     let mut wtr = OwnedBufWtr::new(MTU, 0);
     let wtr_data_len = wtr.data_len();
-    let packets = uploader.emit(&Instant::now());
+    let packets = emit(uploader, &Instant::now());
     for packet in packets {
         packet.append_to(&mut wtr).unwrap();
         match listener.send_to(wtr.data(), remote_addr) {
@@ -375,6 +460,16 @@ struct OnSendAvailable {
     tx: mpsc::SyncSender<()>,
 }
 
+struct OnRecvAvailable {
+    tx: mpsc::SyncSender<()>,
+}
+
+impl IObserver for OnRecvAvailable {
+    fn notify(&self) {
+        let _result = self.tx.try_send(());
+    }
+}
+
 impl IObserver for OnSendAvailable {
     fn notify(&self) {
         let _result = self.tx.try_send(());
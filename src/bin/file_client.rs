@@ -1,5 +1,6 @@
 use ardl::{
     layer::{Builder, Downloader, IObserver, SetUploadState, Uploader},
+    protocol::{frag::WireFormat, packet::Packet},
     utils::buf::{BufSlice, BufWtr, OwnedBufWtr},
 };
 use std::{
@@ -19,8 +20,20 @@ const STAT_INTERVAL_S: u64 = 1;
 const LISTEN_ADDR: &str = "0.0.0.0:19479";
 const LOCAL_RECV_BUF_LEN: usize = 1024;
 const RATIO_RTO_TO_ONE_RTT: f64 = 1.5;
-// const TO_SEND_QUEUE_LEN_CAP: usize = 1024 * 64;
-const TO_SEND_QUEUE_LEN_CAP: usize = 1024;
+const RTO_JITTER: f64 = 0.0;
+const RTO_JITTER_SEED: u64 = 0;
+const MIN_RTO: Option<Duration> = None;
+const MAX_RTO: Option<Duration> = None;
+const INITIAL_RTO: Option<Duration> = None;
+const COALESCE_SMALL_WRITES: bool = false;
+const TIMESTAMPS: bool = false;
+const MAX_FAST_RETRANSMIT_BURST: usize = usize::MAX;
+const STRICT: bool = false;
+const WINDOW_UPDATE_CHUNK: usize = LOCAL_RECV_BUF_LEN / 8;
+const TO_SEND_BYTES_CAP: usize = 1024 * 1024;
+const TO_SEND_QUEUE_LEN_CAP: Option<usize> = None;
+// wake `block_sending` once half of the queue is free
+const SEND_AVAILABLE_LOW_WATER_MARK: Option<usize> = Some(TO_SEND_BYTES_CAP / 2);
 const SWND_SIZE_CAP: usize = 1024;
 const SOURCE_FILE_NAME: &str = "Free_Test_Data_10MB_MP4.upload.mp4";
 const DESTINATION_FILE_NAME: &str = "Free_Test_Data_10MB_MP4.download.mp4";
@@ -56,16 +69,49 @@ fn main() {
     let processing_messaging_tx = Arc::new(processing_messaging_tx);
     let (on_send_available_tx, on_send_available_rx) = mpsc::sync_channel(1);
     let (on_destination_available_tx, on_destination_available_rx) = mpsc::sync_channel(1);
+    let (on_drained_tx, on_drained_rx) = mpsc::sync_channel(1);
 
     // layer
     let (mut uploader, downloader) = Builder {
         local_recv_buf_len: LOCAL_RECV_BUF_LEN,
         nack_duplicate_threshold_to_activate_fast_retransmit:
             NACK_DUPLICATE_THRESHOLD_TO_ACTIVATE_FAST_RETRANSMIT,
+        max_fast_retransmit_burst: MAX_FAST_RETRANSMIT_BURST,
         ratio_rto_to_one_rtt: RATIO_RTO_TO_ONE_RTT,
+        rto_jitter: RTO_JITTER,
+        rto_jitter_seed: RTO_JITTER_SEED,
+        min_rto: MIN_RTO,
+        max_rto: MAX_RTO,
+        initial_rto: INITIAL_RTO,
+        coalesce_small_writes: COALESCE_SMALL_WRITES,
+        timestamps: TIMESTAMPS,
+        legacy_header: false,
+        strict: STRICT,
+        window_update_chunk: WINDOW_UPDATE_CHUNK,
+        ack_late_pushes: true,
+        to_send_bytes_cap: TO_SEND_BYTES_CAP,
         to_send_queue_len_cap: TO_SEND_QUEUE_LEN_CAP,
+        send_available_low_water_mark: SEND_AVAILABLE_LOW_WATER_MARK,
         swnd_size_cap: SWND_SIZE_CAP,
         mtu: MTU,
+        max_retransmissions: None,
+        max_no_ack_duration: None,
+        rst_transmissions: 3,
+        keepalive_interval: None,
+        sack: false,
+        packet_seqs: false,
+        conn_id: None,
+        handshake: None,
+        drop_duplicate_packets: true,
+        auth: None,
+        wire_format: WireFormat::V1,
+        pad_packets_to: None,
+        fec_group_size: None,
+        codec: None,
+        cumulative_ack_only: false,
+        ack_bitmap: false,
+        mtu_probing: None,
+        max_frags_per_packet: None,
     }
     .build()
     .unwrap();
@@ -78,6 +124,12 @@ fn main() {
     let weak_observer = Arc::downgrade(&observer);
     uploader.set_on_send_available(Some(weak_observer));
 
+    // on drained
+    let on_drained = OnDrained { tx: on_drained_tx };
+    let on_drained = Arc::new(on_drained);
+    let weak_on_drained = Arc::downgrade(&on_drained);
+    uploader.set_on_drained(Some(weak_on_drained));
+
     // spawn threads
     let mut threads = Vec::new();
     {
@@ -155,8 +207,20 @@ fn main() {
     on_destination_available_rx.recv().unwrap();
     println!("main: done receiving file");
 
-    // flush all acks
-    thread::sleep(Duration::from_secs(1));
+    // wait for the peer to ack everything and for all acks to be flushed
+    loop {
+        // drop the signals from before
+        while on_drained_rx.try_recv().is_ok() {}
+        let (responser, receiver) = mpsc::sync_channel(1);
+        uploading_messaging_tx
+            .send(UploadingMessaging::IsDrained(responser))
+            .unwrap();
+        if receiver.recv().unwrap() {
+            break;
+        }
+        on_drained_rx.recv().unwrap();
+    }
+    println!("main: done sending file");
 
     // verify integrity
     // TODO
@@ -193,25 +257,50 @@ fn uploading(
     messaging: mpsc::Receiver<UploadingMessaging>,
 ) {
     let mut old_stat = None;
+    let mut next_msg = None;
     loop {
-        let msg = messaging.recv().unwrap();
+        let msg = match next_msg.take() {
+            Some(msg) => msg,
+            None => messaging.recv().unwrap(),
+        };
         match msg {
             UploadingMessaging::SetUploadState(x) => {
+                // take the rest of the burst under the same emit
+                let mut states = vec![x];
+                while let Ok(msg) = messaging.try_recv() {
+                    match msg {
+                        UploadingMessaging::SetUploadState(x) => states.push(x),
+                        msg => {
+                            next_msg = Some(msg);
+                            break;
+                        }
+                    }
+                }
+                uploader.set_states(states, &Instant::now()).unwrap();
+                output(&mut uploader, &connection, Uploader::emit_on_ack);
+            }
+            UploadingMessaging::WindowUpdate(x) => {
                 uploader.set_state(x, &Instant::now()).unwrap();
-                output(&mut uploader, &connection);
+                output(&mut uploader, &connection, Uploader::emit_window_update);
             }
             UploadingMessaging::Flush => {
-                output(&mut uploader, &connection);
+                output(&mut uploader, &connection, Uploader::emit_on_timer);
             }
-            UploadingMessaging::ToSend(slice, responser) => match uploader.write(slice) {
-                Ok(()) => {
-                    responser.send(UploadingToSendResponse::Ok).unwrap();
-                    output(&mut uploader, &connection);
+            UploadingMessaging::ToSend(slice, responser) => {
+                let (written, rest) = uploader.write_partial(slice);
+                match rest {
+                    None => responser.send(UploadingToSendResponse::Ok).unwrap(),
+                    Some(rest) => responser.send(UploadingToSendResponse::Err(rest)).unwrap(),
+                }
+                if 0 < written {
+                    output(&mut uploader, &connection, Uploader::emit);
                 }
-                Err(e) => responser.send(UploadingToSendResponse::Err(e.0)).unwrap(),
-            },
+            }
+            UploadingMessaging::IsDrained(responser) => {
+                responser.send(uploader.is_drained()).unwrap();
+            }
             UploadingMessaging::PrintStat => {
-                let stat = uploader.stat();
+                let stat = uploader.stat_reset();
                 if let Some(old_stat) = old_stat {
                     if old_stat != stat {
                         let time = SystemTime::now()
@@ -219,6 +308,7 @@ fn uploading(
                             .unwrap()
                             .as_millis();
                         println!("{}. Upload: {:?}", time, stat);
+                        println!("{}. Upload acked: {} B", time, uploader.bytes_acked());
                     }
                 }
                 old_stat = Some(stat);
@@ -261,7 +351,7 @@ fn downloading(
                 }
             }
             DownloadingMessaging::PrintStat => {
-                let stat = downloader.stat();
+                let stat = downloader.stat_reset();
                 if let Some(old_stat) = old_stat {
                     if old_stat != stat {
                         let time = SystemTime::now()
@@ -281,6 +371,13 @@ fn downloading(
                         .unwrap();
                     is_processing_free = false;
                 }
+                if downloader.should_advertise_window() {
+                    if let Some(state) = downloader.window_update() {
+                        uploading_messaging_tx
+                            .send(UploadingMessaging::WindowUpdate(state))
+                            .unwrap();
+                    }
+                }
             }
         }
     }
@@ -359,8 +456,10 @@ fn socket_receiving(
 
 enum UploadingMessaging {
     SetUploadState(SetUploadState),
+    WindowUpdate(SetUploadState),
     Flush,
     ToSend(BufSlice, mpsc::SyncSender<UploadingToSendResponse>),
+    IsDrained(mpsc::SyncSender<bool>),
     PrintStat,
 }
 
@@ -404,10 +503,14 @@ fn block_sending(
     }
 }
 
-fn output(uploader: &mut Uploader, connection: &Arc<UdpSocket>) {
+fn output(
+    uploader: &mut Uploader,
+    connection: &Arc<UdpSocket>,
+    emit: fn(&mut Uploader, &Instant) -> Vec<Packet>,
+) {
     let mut wtr = OwnedBufWtr::new(MTU, 0);
     let wtr_data_len = wtr.data_len();
-    let packets = uploader.emit(&Instant::now());
+    let packets = emit(uploader, &Instant::now());
     for packet in packets {
         packet.append_to(&mut wtr).unwrap();
         match connection.send(wtr.data()) {
@@ -430,3 +533,13 @@ impl IObserver for OnSendAvailable {
         let _ = self.tx.try_send(());
     }
 }
+
+struct OnDrained {
+    tx: mpsc::SyncSender<()>,
+}
+
+impl IObserver for OnDrained {
+    fn notify(&self) {
+        let _ = self.tx.try_send(());
+    }
+}
@@ -1,6 +1,10 @@
 use ardl::{
     layer::{Builder, Downloader, IObserver, SetUploadState, Uploader},
-    protocol::{frag::PUSH_HDR_LEN, packet_hdr::PACKET_HDR_LEN},
+    protocol::{
+        frag::{WireFormat, PUSH_HDR_LEN},
+        packet::Packet,
+        packet_hdr::PACKET_HDR_LEN,
+    },
     utils::buf::{BufSlice, BufWtr, OwnedBufWtr},
 };
 use std::{
@@ -19,8 +23,19 @@ const LISTEN_ADDR: &str = "0.0.0.0:19479";
 const LOCAL_RECV_BUF_LEN: usize = 2;
 const NACK_DUPLICATE_THRESHOLD_TO_ACTIVATE_FAST_RETRANSMIT: usize = 0;
 const RATIO_RTO_TO_ONE_RTT: f64 = 1.5;
-// const TO_SEND_QUEUE_LEN_CAP: usize = 1024 * 64;
-const TO_SEND_QUEUE_LEN_CAP: usize = 1;
+const RTO_JITTER: f64 = 0.0;
+const RTO_JITTER_SEED: u64 = 0;
+const MIN_RTO: Option<Duration> = None;
+const MAX_RTO: Option<Duration> = None;
+const INITIAL_RTO: Option<Duration> = None;
+const COALESCE_SMALL_WRITES: bool = false;
+const TIMESTAMPS: bool = false;
+const MAX_FAST_RETRANSMIT_BURST: usize = usize::MAX;
+const STRICT: bool = false;
+const WINDOW_UPDATE_CHUNK: usize = 0;
+const TO_SEND_BYTES_CAP: usize = 1024 * 64;
+const TO_SEND_QUEUE_LEN_CAP: Option<usize> = Some(1);
+const SEND_AVAILABLE_LOW_WATER_MARK: Option<usize> = None;
 const MAX_SWND_SIZE: usize = usize::MAX;
 
 fn main() {
@@ -42,10 +57,42 @@ fn main() {
         local_recv_buf_len: LOCAL_RECV_BUF_LEN,
         nack_duplicate_threshold_to_activate_fast_retransmit:
             NACK_DUPLICATE_THRESHOLD_TO_ACTIVATE_FAST_RETRANSMIT,
+        max_fast_retransmit_burst: MAX_FAST_RETRANSMIT_BURST,
         ratio_rto_to_one_rtt: RATIO_RTO_TO_ONE_RTT,
+        rto_jitter: RTO_JITTER,
+        rto_jitter_seed: RTO_JITTER_SEED,
+        min_rto: MIN_RTO,
+        max_rto: MAX_RTO,
+        initial_rto: INITIAL_RTO,
+        coalesce_small_writes: COALESCE_SMALL_WRITES,
+        timestamps: TIMESTAMPS,
+        legacy_header: false,
+        strict: STRICT,
+        window_update_chunk: WINDOW_UPDATE_CHUNK,
+        ack_late_pushes: true,
+        to_send_bytes_cap: TO_SEND_BYTES_CAP,
         to_send_queue_len_cap: TO_SEND_QUEUE_LEN_CAP,
+        send_available_low_water_mark: SEND_AVAILABLE_LOW_WATER_MARK,
         swnd_size_cap: MAX_SWND_SIZE,
         mtu: MTU,
+        max_retransmissions: None,
+        max_no_ack_duration: None,
+        rst_transmissions: 3,
+        keepalive_interval: None,
+        sack: false,
+        packet_seqs: false,
+        conn_id: None,
+        handshake: None,
+        drop_duplicate_packets: true,
+        auth: None,
+        wire_format: WireFormat::V1,
+        pad_packets_to: None,
+        fec_group_size: None,
+        codec: None,
+        cumulative_ack_only: false,
+        ack_bitmap: false,
+        mtu_probing: None,
+        max_frags_per_packet: None,
     }
     .build()
     .unwrap();
@@ -152,14 +199,17 @@ fn uploading(
                 uploader.set_state(x, &Instant::now()).unwrap();
             }
             UploadingMessaging::Flush => {
-                output(&mut uploader, &connection);
+                output(&mut uploader, &connection, Uploader::emit_on_timer);
+            }
+            UploadingMessaging::ToSend(slice, responser) => {
+                let (_, rest) = uploader.write_partial(slice);
+                match rest {
+                    None => responser.send(UploadingToSendResponse::Ok).unwrap(),
+                    Some(rest) => responser.send(UploadingToSendResponse::Err(rest)).unwrap(),
+                }
             }
-            UploadingMessaging::ToSend(slice, responser) => match uploader.write(slice) {
-                Ok(()) => responser.send(UploadingToSendResponse::Ok).unwrap(),
-                Err(e) => responser.send(UploadingToSendResponse::Err(e.0)).unwrap(),
-            },
             UploadingMessaging::PrintStat => {
-                let stat = uploader.stat();
+                let stat = uploader.stat_reset();
                 if let Some(old_stat) = old_stat {
                     if old_stat != stat {
                         let time = SystemTime::now()
@@ -199,7 +249,7 @@ fn downloading(
                     .unwrap();
 
                 let mut buf = Vec::new();
-                while let Some(slice) = downloader.emit() {
+                for slice in downloader.drain() {
                     assert!(slice.data().len() > 0);
 
                     buf.extend_from_slice(slice.data());
@@ -210,7 +260,7 @@ fn downloading(
                 }
             }
             DownloadingMessaging::PrintStat => {
-                let stat = downloader.stat();
+                let stat = downloader.stat_reset();
                 if let Some(old_stat) = old_stat {
                     if old_stat != stat {
                         let time = SystemTime::now()
@@ -284,10 +334,14 @@ fn block_sending(
     }
 }
 
-fn output(uploader: &mut Uploader, connection: &Arc<UdpSocket>) {
+fn output(
+    uploader: &mut Uploader,
+    connection: &Arc<UdpSocket>,
+    emit: fn(&mut Uploader, &Instant) -> Vec<Packet>,
+) {
     let mut wtr = OwnedBufWtr::new(MTU, 0);
     let wtr_data_len = wtr.data_len();
-    let packets = uploader.emit(&Instant::now());
+    let packets = emit(uploader, &Instant::now());
     for packet in packets {
         packet.append_to(&mut wtr).unwrap();
         match connection.send(wtr.data()) {
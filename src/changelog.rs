@@ -0,0 +1,131 @@
+//! Entries backing the changelog.
+//!
+//! Every change to the public API should come with an entry under the
+//! current crate version, which is unreleased until published. Entries are
+//! newest first. `tests/api_surface.rs` pins the public signatures and checks
+//! that the current version has entries.
+
+pub struct Entry {
+    pub version: &'static str,
+    pub changes: &'static [&'static str],
+}
+
+pub const ENTRIES: &[Entry] = &[
+    Entry {
+        version: "0.2.0",
+        changes: &[
+            "Add `changelog::ENTRIES` and the public API surface test",
+            "Add dead link detection: `Uploader::is_broken`, `max_retransmissions` and `max_no_ack_duration` on the builders",
+            "Breaking: `SendError` is now an enum distinguishing `QueueFull` from `Broken`",
+            "Add `Uploader::bytes_in_flight` and `Uploader::pending_bytes`, also reported in `Stat`",
+            "Add `Downloader::window_span` and `Downloader::buffered_count`",
+            "Add byte and packet counters to the uploader and downloader `Stat`",
+            "Add `Uploader::checkpoint`, `Downloader::checkpoint` and `resume_from` on their builders",
+            "Add `Uploader::emit_on_ack` and `Uploader::emit_on_timer` with per-trigger counters in `Stat`",
+            "Add `Uploader::stat_reset` and `Downloader::stat_reset`",
+            "Add `Uploader::write_vectored` which queues all slices or none",
+            "Add `Uploader::write_bytes` copying borrowed data into the send queue",
+            "Add `Uploader::lifetime_totals` and `Downloader::lifetime_totals`, unaffected by `stat_reset`",
+            "Add `Uploader::write_partial` returning the number of bytes taken and the rest",
+            "Add `Builder::validate` and `Builder::warnings`; `Builder::build` now rejects incoherent caps and thresholds",
+            "Breaking: the send queue is bounded by `to_send_bytes_cap` in bytes; `to_send_queue_len_cap` is now an optional secondary limit",
+            "Add `Uploader::pending_acks`, `swnd_len`, `swnd_end` and `Downloader::received_ready`, `ooo_buffered`",
+            "Add `rto_jitter` and `rto_jitter_seed` to the builders to desynchronize retransmissions",
+            "Add `Uploader::set_mtu`",
+            "Add `layer::shared::SharedUploader` and `Uploader::is_send_queue_full`; observers are now notified after the uploader state is consistent",
+            "Add `Uploader::set_swnd_size_cap`",
+            "Add `protocol::packet_hdr::peek` and `PacketHeader::len_hint`",
+            "Add `coalesce_small_writes` to the builders to hold back small pushes while data is unacked",
+            "Add `Uploader::write_urgent` to send data ahead of the send queue",
+            "Add `strict` to the builders to report empty writes, unknown acks and very late pushes",
+            "Add `Downloader::should_advertise_window`, `Downloader::window_update` and `Uploader::emit_window_update` for batched window updates",
+            "Add `Uploader::clear_pending` to drop data not yet sent",
+            "Add `Uploader::queue_delay_estimate` and `Stat::queue_delay_estimate`",
+            "Reject a negative `ratio_rto_to_one_rtt` and a `local_recv_buf_len` over `u16::MAX` instead of panicking",
+            "Count duplicate nacks per nack value in FastRetransmissionWnd::on_nack, replacing try_set_boundaries",
+            "Add `max_fast_retransmit_burst` to the builders to spread a large fast retransmission over several emits",
+            "`Swnd::remove_before` returns the removed keys along with the values",
+            "Replace the RTO priority queue of the uploader with a timing wheel",
+            "Add `IAckObserver` and `Uploader::set_on_ack` to report the pushes acked by each `set_state`",
+            "Add `IRtoObserver` and `Uploader::set_on_rto` to report retransmission timeouts per emit",
+            "Add `send_available_low_water_mark` to the builders to delay `on_send_available` until the send queue drains below it",
+            "Implement `Default` for `Builder`, `UploaderBuilder` and `DownloaderBuilder`; deprecate the inherent `default` functions",
+            "`UploaderBuilder::build` rejects zero caps, a zero fast retransmit burst and a ratio_rto_to_one_rtt that is not positive; `RatioRtoToOneRttNegative` is renamed to `InvalidRtoRatio`",
+            "Add chainable setters to `Builder`, `UploaderBuilder` and `DownloaderBuilder`",
+            "`Downloader::set_on_rwnd_reopened` notifies when an emit frees a slot of a receive window advertised as zero",
+            "Add `Uploader::swnd_cap`, `remote_rwnd`, `srtt` and `Downloader::rwnd_free`; `Uploader::swnd_len` counts the pushes in flight instead of the span of sequences",
+            "Add `min_rto`, `max_rto` and `initial_rto` to the builders; `rto` clamps against them instead of fixed bounds",
+            "Add `utils::Clock` with `StdClock` and the test `ManualClock`",
+            "Add `Uploader::write_unreliable` and the `Skip` frag telling the receiver to stop waiting for a push",
+            "Add `Uploader::write_with_deadline` and `Stat::expired_writes`",
+            "Add `Stat::delivery_rate_bps`",
+            "Add `utils::buf::BufPool` and `Uploader::emit_serialized`; frag and packet headers are encoded in place",
+            "Single-slice pushes are sent as `Body::Slice` without allocating a pasta",
+            "`Uploader::set_state` ignores the nack and rwnd of a state older than one already applied, keeping its explicit acks",
+            "`Uploader::emit` leaves room for a push in each packet while new data can be sent, instead of packing every pending ack first",
+            "Add `Uploader::emit_budgeted` and `Packet::wire_len`",
+            "Add `Uploader::is_drained` and `Uploader::set_on_drained`",
+            "Add `Uploader::acked_watermark` and `Uploader::bytes_acked`",
+            "Add `Stat::spurious_rtos` and `IRtoObserver::on_spurious_rto`; the RTT of the original send of a spurious timeout is sampled into srtt",
+            "Add `Stat::duplicate_acks_received`",
+            "Add opt-in packet timestamps whose echoes give RTT samples for retransmitted pushes (`Builder::timestamps`)",
+            "Add `Uploader::set_states` to apply a burst of `SetUploadState`s at once",
+            "`SetUploadState` seq lists are `SeqList`s, which keep up to 4 seqs inline instead of a `Vec`",
+            "Add `Downloader::drain` to take all the ready slices at once",
+            "Add `Downloader::read` to copy ready bytes into a buffer",
+            "Add `Downloader::peek` and `Downloader::peek_len` to inspect ready bytes without taking them",
+            "Add `Downloader::buffered_len` and `Downloader::reorder_len`",
+            "Add `Downloader::set_on_recv_available`",
+            "Downloader: `Stat::out_of_orders` counts each parked seq once; replacements and late arrivals count toward the new `Stat::duplicate_pushes`",
+            "Downloader: `ack_late_pushes` builder option to leave late pushes unacked, counted in `Stat::late_acks_suppressed`",
+            "Protocol: command bytes from `EXTENSION_CMD_START` on carry a length and are stepped over when unknown, counted in `Packet::unknown_frags` and `Stat::unknown_frags`",
+            "Downloader: deprecated `input_packet` and `recv` aliases for `write` and `emit`",
+            "Downloader: `missing_ranges` lists the seqs still missing below the highest parked push",
+            "Uploader: `close` ends the stream with a retransmitted `Fin` frag; `Downloader::is_finished` tells the end of the stream",
+            "Uploader: `is_closing` renamed to `is_send_closed`; closing one direction leaves the other open",
+            "Uploader: `abort` tears the stream down with `Rst` frags, sent `rst_transmissions` times; the downloader drops its buffers and `write` returns `Error::Reset`",
+            "Protocol: `Ping`/`Pong` frags; `Uploader::keepalive_due`, `send_ping` and `last_pong_received` with the `keepalive_interval` option; `SetUploadState` carries `pings_to_answer` and `pong`",
+            "Protocol: a `Sack` frag carrying the ranges the downloader has buffered, sent in place of their acks with the `sack` option; `SetUploadState` carries `remote_ranges_to_sack` and `sacked_local_ranges`",
+            "Protocol: an optional 64-bit connection ID in the packet header, set by the `conn_id` option and echoed by the peer; `SetUploadState::conn_id` and `packet_hdr::peek` expose it for routing datagrams to their session",
+            "Protocol: `Syn`/`SynAck` frags opening a session from a random initial seq with the `handshake` option; `Uploader`/`Downloader::handshake_state`; `SetUploadState` carries `syn_to_answer` and `is_established`; `Stat.pushes_before_established`",
+            "`initial_seq` option on `UploaderBuilder` and `DownloaderBuilder` to start the seq space at an arbitrary value",
+            "`packet_seqs` option numbering every packet; the downloader drops duplicated packets as `Error::DuplicatePacket` and counts them in `Stat::duplicate_packets`",
+            "`PacketAuth` tags every packet and the downloader drops packets without a valid tag as `Error::Auth`; `HmacSha256` over the `hmac` and `sha2` crates behind the `hmac-sha256` feature",
+            "`protocol::transform::PacketTransform` with `Uploader::emit_transformed` and `Downloader::write_transformed` to rewrite datagrams on the wire; `IdentityTransform` and `XorTransform` provided",
+            "`wire_format` option selecting `WireFormat::V2`, whose `CompactPush` frag carries a 2-byte length; `EncodingError::BodyTooLong` for a body the format cannot carry",
+            "`WireFormat::V3` with varint `seq`, relative to the nack of the packet, and `len`; `Frag::*_with` take the nack; `PacketHeader::from_slice_with`/`append_to_with` and `packet_hdr::peek_with`",
+            "Protocol: a `Padding` frag; the `pad_packets_to` option tops every packet up to a uniform size and the downloader counts the padding in `Stat::padding_bytes`",
+            "Layer: forward error correction; `fec_group_size` follows every group of new pushes with a `Parity` frag from which the downloader rebuilds one lost push, counted in `Stat::fec_recoveries`",
+            "Layer: an optional `BodyCodec` compresses the bodies of pushes it makes shorter, sent as `CompressedPush` frags; `Lz4Codec` over the `lz4_flex` crate behind the `lz4` feature and `IdentityCodec` ship with it",
+            "Layer: `cumulative_ack_only` leaves pushes received in order to the nack of the packet header instead of an `Ack` each and samples the RTT from the nack advance",
+            "Protocol: an `AckBitmap` frag acking up to `ACK_BITMAP_SPAN` seqs from its own; the `ack_bitmap` option coalesces queued acks into it where that takes fewer bytes",
+            "Layer: path MTU discovery; `mtu_probing` sends padded `Probe` frags above the MTU, raises it on each `ProbeAck` and lowers the search ceiling after unacked probes, counted in `Stat::probes_sent` and `Stat::probes_acked`",
+            "Layer: `mux::Mux` carries several streams over one path, each with its own `Uploader`, `Downloader` and seq space, so a loss on one stream holds back no other; the streams share packets and one packet seq space, each frag tagged with its stream ID behind `STREAM_FLAG` and the frags of a stream behind its `Window` frag; `Mux::close_stream` closes a stream, released once both sides are done; `Builder` is `Clone`",
+            "Protocol: `Frag::stream_id` and `Frag::with_stream_id` for the stream ID behind `STREAM_FLAG`; a `Window` frag with the nack and `rwnd` of a stream",
+            "Protocol: `Packet::frags_len` and `Packet::push_bytes` to size a packet without encoding it",
+            "Protocol: `Packet::into_frags`; `Packet`, `PacketHeader`, `Frag` and `FragCommand` are `Clone` and `Debug`, which shows body lengths instead of bytes",
+            "Protocol: `DecodingError::Decoding` carries the `offset` of the field in the datagram; every public error type implements `Display` and `std::error::Error`, and `SendError` and `PushError` are `Debug` without showing the rejected data",
+            "Protocol: a `len` past the end of the datagram is rejected as `DecodingError::LenPastEnd` before anything is consumed; `Packet::from_slice_limited` and the `max_frags_per_packet` option cap the frags of a packet as `DecodingError::TooManyFrags`; `Stat::oversized_lens` and `Stat::frag_limit_exceeded` count the two; a `cargo fuzz` target for `Packet::from_slice_limited`",
+            "Utils: `Seq64`, a `Seq` that wraps after 2^64 values for `Swnd` and `RecvBuf`; the wire format still carries 32-bit seqs",
+            "Utils: `Seq::dist` and `Seq::range_iter` across the wrap point; `Seq` requires `Ord` and `Hash`, whose `min` and `max` hold across the wrap point; `FastRetransmissionWnd::len`",
+            "Breaking: `SetStateError::InvalidState` is split into `LocalRwndTooLarge`, `AckedNack` and `SackedNack`; a rejected delta leaves the uploader as it was",
+            "Breaking: packet headers start with a version byte (`PROTOCOL_VERSION`); other versions are rejected as `DecodingError::UnsupportedVersion` and counted in `Stat::unsupported_versions`",
+            "`Downloader::peer_version`, and the `legacy_header` option with `PacketHeader::from_legacy_slice`, `Packet::from_legacy_slice` and `packet_hdr::peek_legacy_with` for peers predating the version byte",
+            "`Mux::set_max_streams` caps the streams, `DEFAULT_MAX_STREAMS` unless set, and the frags of a peer stream past it are dropped as `mux::Error::TooManyStreams`",
+            "Breaking: the high 4 bits of the version byte flag the header options instead of a marker in the `cmd` of the first frag; `PACKET_OPTIONS_LEN` is 8 and only a timestamp or an echo needs it; legacy headers keep the marker and the 10 bytes of `LEGACY_PACKET_OPTIONS_LEN`",
+            "Layer: the connection ID of the peer is adopted only once the grown header leaves room in the MTU for the pushes in flight, instead of overflowing the packets; `Stat::conn_ids_deferred` counts the wait",
+            "Layer: without `timestamps`, a timestamp of the peer is echoed only where the grown header leaves room in the MTU for the pushes in flight, counted otherwise in `Stat::echoes_skipped`; `set_mtu` counts a pending echo in the header",
+            "Layer: a fast or RTO retransmission of a push no longer fitting in a packet is put off instead of panicking, counted in `Stat::retransmits_too_large`",
+            "Layer: `SharedUploader` notifies `on_ack`, `on_rto` and `on_drained` after releasing the lock, so they may call back into it like `on_send_available`",
+        ],
+    },
+    Entry {
+        version: "0.1.0",
+        changes: &["Initial release"],
+    },
+];
+
+#[must_use]
+pub fn entry(version: &str) -> Option<&'static Entry> {
+    ENTRIES.iter().find(|entry| entry.version == version)
+}
@@ -1,42 +1,293 @@
-use super::SetUploadState;
+use std::{
+    fmt,
+    ops::Range,
+    sync::{Arc, Weak},
+};
+
+use super::{
+    fec::ParityCache, Handshake, HandshakeState, IObserver, Role, SeqList, SetUploadState,
+};
 use crate::{
     protocol::{
-        frag::{Body, Frag, FragCommand},
+        auth::{PacketAuth, MAX_TAG_LEN},
+        codec::BodyCodec,
+        frag::{Body, Frag, FragCommand, WireFormat, ACK_BITMAP_SPAN, MAX_SACK_RANGES},
         packet::Packet,
+        transform::PacketTransform,
+        DecodingError,
     },
     utils::{
         buf::{self, BufSlice},
-        RecvBuf, Seq32, SeqLocationToRwnd,
+        RecvBuf, Seq, Seq32, SeqLocationToRwnd,
     },
 };
 
+/// How far behind the largest packet seq a reordered packet can be and still be taken
+pub const PACKET_REORDER_WINDOW: usize = 64;
+
 pub struct Downloader {
     recv_buf: RecvBuf<Seq32, BufSlice>,
     leftover: Option<BufSlice>,
+    // the seq of the fin from the peer
+    fin_seq: Option<Seq32>,
+    // the peer sent an rst; nothing is taken any more
+    is_reset: bool,
+    handshake: Option<Handshake>,
+    handshake_state: HandshakeState,
+    // the nonce of the accepted syn
+    remote_nonce: Option<u64>,
+    drop_duplicate_packets: bool,
+    // the largest packet seq seen
+    max_packet_seq: Option<Seq32>,
+    // bit `i` is set if `max_packet_seq - i` has been seen
+    packet_seqs_seen: u64,
+    auth: Option<Arc<dyn PacketAuth>>,
+    wire_format: WireFormat,
+    fec_cache: Option<ParityCache>,
+    codec: Option<Arc<dyn BodyCodec>>,
+    max_frags_per_packet: usize,
+    legacy_header: bool,
+    // the version in the header of the last packet decoded
+    peer_version: Option<u8>,
     stat: LocalStat,
+    totals: DownloaderLifetimeTotals,
+    recv_buf_len: usize,
+    strict: bool,
+    ack_late_pushes: bool,
+
+    // window updates
+    window_update_chunk: usize,
+    advertised_rwnd: usize,
+    last_remote: Option<(u16, Seq32)>,
+    on_rwnd_reopened: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    on_recv_available: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
 }
 
 pub struct DownloaderBuilder {
     pub recv_buf_len: usize,
+    /// Reject packets carrying pushes older than a whole receive window
+    ///
+    /// Such pushes cannot have been in flight together with the expected one, so they
+    /// point at a mixed-up stream. Meant for development.
+    pub strict: bool,
+    /// `should_advertise_window` waits for more than this many slots to be freed
+    pub window_update_chunk: usize,
+    /// Ack pushes that arrive after their seq has been received
+    ///
+    /// The nack in every outgoing header already covers them, so turning this off only
+    /// stops replayed pushes from drawing ack traffic out of us.
+    pub ack_late_pushes: bool,
+    /// Drop pushes until the session is established; shared with the uploader
+    pub handshake: Option<Handshake>,
+    /// The seq of the first push from the peer; 0 if `None`
+    ///
+    /// Has to match the `initial_seq` of the uploader of the peer. The handshake overrides it.
+    pub initial_seq: Option<Seq32>,
+    /// Drop packets whose packet seq has been seen before
+    ///
+    /// Packets up to `PACKET_REORDER_WINDOW` behind the largest packet seq are still taken
+    /// if new. Packets without a packet seq are always taken.
+    pub drop_duplicate_packets: bool,
+    /// Drop packets without a valid tag before looking into them
+    pub auth: Option<Arc<dyn PacketAuth>>,
+    /// The layout of the pushes of the peer; pushes of the other format fail to decode
+    pub wire_format: WireFormat,
+    /// Rebuild a push lost from a group covered by a `Parity` of the peer
+    ///
+    /// Groups longer than this are passed over; at least the `fec_group_size` of the uploader
+    /// of the peer.
+    pub fec_group_size: Option<usize>,
+    /// Decompress the bodies of `CompressedPush` frags; they are dropped without one
+    pub codec: Option<Arc<dyn BodyCodec>>,
+    /// Drop packets of more frags than this, unknown ones included, before acting on any
+    pub max_frags_per_packet: Option<usize>,
+    /// Take packet headers without the version byte, from a peer predating it
+    pub legacy_header: bool,
+}
+
+impl Default for DownloaderBuilder {
+    fn default() -> Self {
+        DownloaderBuilder {
+            recv_buf_len: u16::MAX as usize,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+    }
+}
+
+impl DownloaderBuilder {
+    #[must_use]
+    pub fn recv_buf_len(mut self, recv_buf_len: usize) -> Self {
+        self.recv_buf_len = recv_buf_len;
+        self
+    }
+
+    #[must_use]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    #[must_use]
+    pub fn window_update_chunk(mut self, window_update_chunk: usize) -> Self {
+        self.window_update_chunk = window_update_chunk;
+        self
+    }
+
+    #[must_use]
+    pub fn ack_late_pushes(mut self, ack_late_pushes: bool) -> Self {
+        self.ack_late_pushes = ack_late_pushes;
+        self
+    }
+
+    #[must_use]
+    pub fn handshake(mut self, handshake: Option<Handshake>) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    #[must_use]
+    pub fn initial_seq(mut self, initial_seq: Option<Seq32>) -> Self {
+        self.initial_seq = initial_seq;
+        self
+    }
+
+    #[must_use]
+    pub fn drop_duplicate_packets(mut self, drop_duplicate_packets: bool) -> Self {
+        self.drop_duplicate_packets = drop_duplicate_packets;
+        self
+    }
+
+    #[must_use]
+    pub fn auth(mut self, auth: Option<Arc<dyn PacketAuth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    #[must_use]
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
+    #[must_use]
+    pub fn fec_group_size(mut self, fec_group_size: Option<usize>) -> Self {
+        self.fec_group_size = fec_group_size;
+        self
+    }
+
+    #[must_use]
+    pub fn codec(mut self, codec: Option<Arc<dyn BodyCodec>>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    #[must_use]
+    pub fn max_frags_per_packet(mut self, max_frags_per_packet: Option<usize>) -> Self {
+        self.max_frags_per_packet = max_frags_per_packet;
+        self
+    }
+
+    #[must_use]
+    pub fn legacy_header(mut self, legacy_header: bool) -> Self {
+        self.legacy_header = legacy_header;
+        self
+    }
 }
 
 impl DownloaderBuilder {
     pub fn build(self) -> Result<Downloader, BuildError> {
+        let checkpoint = DownloaderCheckpoint {
+            next_seq_to_receive: self.initial_seq.unwrap_or(Seq32::from_u32(0)),
+        };
+        self.resume_from(&checkpoint)
+    }
+
+    /// Build a downloader that continues the stream of a checkpointed one.
+    ///
+    /// Buffered data not yet delivered at the time of the checkpoint is lost.
+    pub fn resume_from(self, checkpoint: &DownloaderCheckpoint) -> Result<Downloader, BuildError> {
         if !(self.recv_buf_len <= u16::MAX as usize) {
             return Err(BuildError::RecvBufTooLarge);
         }
+        if let Some(auth) = &self.auth {
+            if MAX_TAG_LEN < auth.tag_len() {
+                return Err(BuildError::AuthTagTooLong);
+            }
+        }
+        if let Some(fec_group_size) = self.fec_group_size {
+            if !(1..=u8::MAX as usize).contains(&fec_group_size) {
+                return Err(BuildError::FecGroupSize);
+            }
+        }
         let this = Downloader {
-            recv_buf: RecvBuf::new(self.recv_buf_len),
+            recv_buf: RecvBuf::new_at_counted(
+                self.recv_buf_len,
+                checkpoint.next_seq_to_receive,
+                BufSlice::len,
+            ),
             leftover: None,
+            fin_seq: None,
+            is_reset: false,
+            handshake: self.handshake,
+            handshake_state: match self.handshake.map(|x| x.role) {
+                None => HandshakeState::Established,
+                Some(Role::Client) => HandshakeState::SynSent,
+                Some(Role::Server) => HandshakeState::Idle,
+            },
+            remote_nonce: None,
+            drop_duplicate_packets: self.drop_duplicate_packets,
+            max_packet_seq: None,
+            packet_seqs_seen: 0,
+            auth: self.auth,
+            wire_format: self.wire_format,
+            fec_cache: self.fec_group_size.map(ParityCache::new),
+            codec: self.codec,
+            max_frags_per_packet: self.max_frags_per_packet.unwrap_or(usize::MAX),
+            legacy_header: self.legacy_header,
+            peer_version: None,
             stat: LocalStat {
                 early_pushes: 0,
                 late_pushes: 0,
                 out_of_orders: 0,
+                duplicate_pushes: 0,
+                late_acks_suppressed: 0,
+                unknown_frags: 0,
+                pushes_before_established: 0,
+                duplicate_packets: 0,
+                auth_failures: 0,
+                padding_bytes: 0,
+                fec_recoveries: 0,
+                decompression_errors: 0,
                 decoding_errors: 0,
+                oversized_lens: 0,
+                frag_limit_exceeded: 0,
+                unsupported_versions: 0,
                 packets: 0,
                 acks: 0,
                 pushes: 0,
+                bytes_received: 0,
+                bytes_delivered: 0,
             },
+            totals: DownloaderLifetimeTotals::default(),
+            recv_buf_len: self.recv_buf_len,
+            strict: self.strict,
+            window_update_chunk: self.window_update_chunk,
+            ack_late_pushes: self.ack_late_pushes,
+            advertised_rwnd: self.recv_buf_len,
+            last_remote: None,
+            on_rwnd_reopened: None,
+            on_recv_available: None,
         };
         this.check_rep();
         Ok(this)
@@ -46,73 +297,465 @@ impl DownloaderBuilder {
 #[derive(Debug)]
 pub enum BuildError {
     RecvBufTooLarge,
+    /// The `PacketAuth` makes tags longer than `MAX_TAG_LEN`
+    AuthTagTooLong,
+    /// `fec_group_size` is not between 1 and `u8::MAX`
+    FecGroupSize,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::RecvBufTooLarge => write!(f, "`recv_buf_len` above `u16::MAX`"),
+            BuildError::AuthTagTooLong => write!(f, "auth tag longer than `MAX_TAG_LEN`"),
+            BuildError::FecGroupSize => write!(f, "`fec_group_size` not between 1 and 255"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Counters kept for the whole life of the downloader
+///
+/// `stat_reset` leaves them alone. They wrap on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DownloaderLifetimeTotals {
+    pub bytes_delivered: u64,
+    pub packets_received: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DownloaderCheckpoint {
+    pub next_seq_to_receive: Seq32,
 }
 
 #[derive(Debug)]
 pub enum Error {
     Decoding,
+    /// Strict mode only
+    LatePush {
+        seq: Seq32,
+    },
+    /// The peer aborted the stream; returned for the packet carrying the `Rst` and every one after
+    Reset,
+    /// The packet seq has been seen before or is too old to tell; nothing is written
+    DuplicatePacket {
+        packet_seq: Seq32,
+    },
+    /// The tag is missing or does not match; nothing is written
+    Auth,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decoding => write!(f, "undecodable packet"),
+            Error::LatePush { seq } => write!(f, "late push of seq {}", seq.to_u32()),
+            Error::Reset => write!(f, "stream reset by the peer"),
+            Error::DuplicatePacket { packet_seq } => {
+                write!(f, "duplicate packet of seq {}", packet_seq.to_u32())
+            }
+            Error::Auth => write!(f, "packet failed authentication"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl Downloader {
     #[inline]
     fn check_rep(&self) {
         assert!(self.recv_buf.rwnd_size() <= u16::MAX as usize);
     }
 
+    /// Same as `stat` but also zeroes the counters
+    pub fn stat_reset(&mut self) -> Stat {
+        let stat = self.stat();
+        self.stat = LocalStat::default();
+        stat
+    }
+
     #[must_use]
     pub fn stat(&self) -> Stat {
         Stat {
             early_pushes: self.stat.early_pushes,
             late_pushes: self.stat.late_pushes,
             out_of_orders: self.stat.out_of_orders,
+            duplicate_pushes: self.stat.duplicate_pushes,
+            late_acks_suppressed: self.stat.late_acks_suppressed,
+            unknown_frags: self.stat.unknown_frags,
+            pushes_before_established: self.stat.pushes_before_established,
+            duplicate_packets: self.stat.duplicate_packets,
+            auth_failures: self.stat.auth_failures,
+            padding_bytes: self.stat.padding_bytes,
+            fec_recoveries: self.stat.fec_recoveries,
+            decompression_errors: self.stat.decompression_errors,
             decoding_errors: self.stat.decoding_errors,
+            oversized_lens: self.stat.oversized_lens,
+            frag_limit_exceeded: self.stat.frag_limit_exceeded,
+            unsupported_versions: self.stat.unsupported_versions,
             next_seq_to_receive: self.recv_buf.next_seq_to_receive(),
             packets: self.stat.packets,
             pushes: self.stat.pushes,
             acks: self.stat.acks,
+            bytes_received: self.stat.bytes_received,
+            bytes_delivered: self.stat.bytes_delivered,
+        }
+    }
+
+    #[must_use]
+    pub fn lifetime_totals(&self) -> DownloaderLifetimeTotals {
+        self.totals
+    }
+
+    #[must_use]
+    pub fn checkpoint(&self) -> DownloaderCheckpoint {
+        DownloaderCheckpoint {
+            next_seq_to_receive: self.recv_buf.next_seq_to_receive(),
+        }
+    }
+
+    /// From the next expected sequence to the highest out-of-order sequence buffered
+    ///
+    /// `None` if nothing is buffered ahead of the next expected sequence.
+    #[must_use]
+    pub fn window_span(&self) -> Option<(Seq32, Seq32)> {
+        let last = self.recv_buf.last_buffered_seq()?;
+        Some((self.recv_buf.next_seq_to_receive(), last))
+    }
+
+    /// Number of out-of-order pushes buffered ahead of the next expected sequence
+    #[must_use]
+    pub fn buffered_count(&self) -> usize {
+        self.recv_buf.buffered_count()
+    }
+
+    /// Same as `buffered_count`
+    #[must_use]
+    pub fn ooo_buffered(&self) -> usize {
+        self.buffered_count()
+    }
+
+    /// Number of slices `emit` can return right away
+    ///
+    /// A slice partly taken by `emit_max` counts as one.
+    #[must_use]
+    pub fn received_ready(&self) -> usize {
+        let leftover = match self.leftover {
+            Some(_) => 1,
+            None => 0,
+        };
+        self.recv_buf.ready_count() + leftover
+    }
+
+    /// The bytes the next `emit_max` starts with, left in place
+    #[must_use]
+    pub fn peek(&self) -> Option<&[u8]> {
+        if let Some(slice) = &self.leftover {
+            return Some(slice.data());
         }
+        self.recv_buf
+            .ready()
+            .find(|slice| !slice.is_empty())
+            .map(|slice| slice.data())
+    }
+
+    /// Same as `buffered_len`
+    #[must_use]
+    pub fn peek_len(&self) -> usize {
+        self.buffered_len()
+    }
+
+    /// Whether the peer has closed the stream and everything before its fin has been taken
+    ///
+    /// `emit` returning `None` is the end of the stream once this holds.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        match self.fin_seq {
+            Some(seq) => seq < self.recv_buf.next_seq_to_receive() && self.buffered_len() == 0,
+            None => false,
+        }
+    }
+
+    /// Whether the peer has aborted the stream
+    ///
+    /// The buffered data has been dropped; `emit` returns `None` and `write` fails from now on.
+    #[must_use]
+    pub fn is_reset(&self) -> bool {
+        self.is_reset
+    }
+
+    /// Pushes are taken only once the session is established
+    #[must_use]
+    pub fn handshake_state(&self) -> HandshakeState {
+        self.handshake_state
+    }
+
+    #[inline]
+    fn is_established(&self) -> bool {
+        self.handshake_state == HandshakeState::Established
+    }
+
+    /// Bytes ready for the application, including what `emit_max` left over
+    #[must_use]
+    pub fn buffered_len(&self) -> usize {
+        let leftover = match &self.leftover {
+            Some(slice) => slice.len(),
+            None => 0,
+        };
+        self.recv_buf.ready_bytes() + leftover
+    }
+
+    /// Pushes parked out of order; same as `buffered_count`
+    #[must_use]
+    pub fn reorder_len(&self) -> usize {
+        self.buffered_count()
+    }
+
+    /// The protocol version in the header of the last packet taken; `LEGACY_VERSION` for a
+    /// peer predating the version byte
+    #[must_use]
+    pub fn peer_version(&self) -> Option<u8> {
+        self.peer_version
+    }
+
+    /// The seqs still missing below the highest parked push
+    #[must_use]
+    pub fn missing_ranges(&self) -> Vec<Range<Seq32>> {
+        self.recv_buf.missing_ranges()
+    }
+
+    /// Slots of the receive window not taken by pushes waiting for `emit`
+    #[must_use]
+    pub fn rwnd_free(&self) -> usize {
+        self.recv_buf.rwnd_size()
+    }
+
+    /// Whether enough of the receive window has been freed by `emit`s to tell the peer
+    ///
+    /// True once more than `window_update_chunk` slots are freed since the last
+    /// `SetUploadState`, or as soon as a slot is freed after a zero window.
+    #[must_use]
+    pub fn should_advertise_window(&self) -> bool {
+        let rwnd = self.recv_buf.rwnd_size();
+        let freed = rwnd.saturating_sub(self.advertised_rwnd);
+        match self.advertised_rwnd {
+            0 => 0 < freed,
+            _ => self.window_update_chunk < freed,
+        }
+    }
+
+    /// State carrying only the current receive window
+    ///
+    /// Pass it to `Uploader::set_state` and then call `Uploader::emit_window_update`.
+    /// `None` if no packet has been received yet.
+    #[must_use]
+    pub fn window_update(&mut self) -> Option<SetUploadState> {
+        let (remote_rwnd_size, remote_nack) = self.last_remote?;
+        let state = SetUploadState {
+            remote_rwnd_size,
+            remote_nack,
+            local_next_seq_to_receive: self.recv_buf.next_seq_to_receive(),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: SeqList::new(),
+            local_rwnd_size: self.recv_buf.rwnd_size(),
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: self.is_established(),
+        };
+        self.advertised_rwnd = state.local_rwnd_size;
+        Some(state)
+    }
+
+    /// Notified by an emit that frees a slot of a receive window advertised as zero
+    ///
+    /// The peer stops sending on a zero window and might have nothing left to be acked, so
+    /// nothing would carry the new window; answer with `window_update` and
+    /// `Uploader::emit_window_update`.
+    pub fn set_on_rwnd_reopened(
+        &mut self,
+        observer: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    ) {
+        self.on_rwnd_reopened = observer;
+    }
+
+    /// Notified by a `write` that makes bytes ready while none were, or that finishes the stream
+    ///
+    /// It is not notified again until the ready bytes are all taken.
+    pub fn set_on_recv_available(
+        &mut self,
+        observer: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    ) {
+        self.on_recv_available = observer;
+    }
+
+    fn pop_front(&mut self) -> Option<BufSlice> {
+        let was_closed = self.recv_buf.rwnd_size() == 0;
+        // skipped pushes leave empty slices behind
+        let received = loop {
+            match self.recv_buf.pop_front() {
+                Some(slice) if slice.is_empty() => continue,
+                received => break received,
+            }
+        };
+        if was_closed && self.advertised_rwnd == 0 && 0 < self.recv_buf.rwnd_size() {
+            if let Some(x) = self.on_rwnd_reopened.as_ref().and_then(|x| x.upgrade()) {
+                x.notify();
+            }
+        }
+        received
+    }
+
+    #[inline]
+    fn count_delivered(&mut self, slice: &BufSlice) {
+        self.stat.bytes_delivered += slice.len() as u64;
+        self.totals.bytes_delivered = self.totals.bytes_delivered.wrapping_add(slice.len() as u64);
     }
 
     #[must_use]
     pub fn emit(&mut self) -> Option<BufSlice> {
-        let received = self.recv_buf.pop_front();
+        let received = self.pop_front();
+        if let Some(slice) = &received {
+            self.count_delivered(slice);
+        }
         self.check_rep();
         received
     }
 
+    /// Take all the slices ready for the application
+    ///
+    /// What `emit_max` left over comes first.
+    #[must_use]
+    pub fn drain(&mut self) -> Vec<BufSlice> {
+        let mut slices = Vec::new();
+        if let Some(slice) = self.leftover.take() {
+            slices.push(slice);
+        }
+        while let Some(slice) = self.pop_front() {
+            slices.push(slice);
+        }
+        for slice in &slices {
+            self.count_delivered(slice);
+        }
+        self.check_rep();
+        slices
+    }
+
     #[must_use]
     pub fn emit_max(&mut self, max_len: usize) -> Option<BufSlice> {
         let leftover = self.leftover.take();
-        let slice = if let Some(slice) = leftover {
-            slice
-        } else {
-            if let Some(slice) = self.recv_buf.pop_front() {
-                slice
-            } else {
-                return None;
-            }
+        let slice = match leftover {
+            Some(slice) => slice,
+            None => self.pop_front()?,
         };
 
         let final_slice = if slice.len() > max_len {
             let (head, tail) = slice.split(max_len).unwrap();
             self.leftover = Some(tail);
-            Some(head)
+            head
         } else {
-            Some(slice)
+            slice
         };
+        self.count_delivered(&final_slice);
+
+        let final_slice = Some(final_slice);
 
         self.check_rep();
         final_slice
     }
 
+    /// Copy as many ready bytes as fit into `buf`
+    ///
+    /// A slice only partly copied is kept as the leftover of `emit_max`. Zero means nothing is
+    /// ready, not the end of the stream.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len() {
+            let slice = match self.emit_max(buf.len() - read) {
+                Some(slice) => slice,
+                None => break,
+            };
+            buf[read..read + slice.len()].copy_from_slice(slice.data());
+            read += slice.len();
+        }
+        read
+    }
+
     #[must_use]
     pub fn write(&mut self, mut slice: buf::BufSlice) -> Result<SetUploadState, Error> {
-        let packet = Packet::from_slice(&mut slice).map_err(|_| {
+        if self.is_reset {
+            return Err(Error::Reset);
+        }
+        if let Some(auth) = &self.auth {
+            let data_len = slice.len().checked_sub(auth.tag_len());
+            let is_valid = data_len.is_some_and(|data_len| {
+                let (data, tag) = slice.data().split_at(data_len);
+                auth.verify(data, tag)
+            });
+            if !is_valid {
+                self.stat.auth_failures += 1;
+                self.check_rep();
+                return Err(Error::Auth);
+            }
+            slice = slice.slice(0..data_len.unwrap()).unwrap();
+        }
+        let packet = match self.legacy_header {
+            true => {
+                Packet::from_legacy_slice(&mut slice, self.wire_format, self.max_frags_per_packet)
+            }
+            false => {
+                Packet::from_slice_limited(&mut slice, self.wire_format, self.max_frags_per_packet)
+            }
+        };
+        let packet = packet.map_err(|e| {
             self.stat.decoding_errors += 1;
+            match e {
+                DecodingError::Decoding { .. } => (),
+                DecodingError::LenPastEnd { .. } => self.stat.oversized_lens += 1,
+                DecodingError::TooManyFrags { .. } => self.stat.frag_limit_exceeded += 1,
+                DecodingError::UnsupportedVersion { .. } => self.stat.unsupported_versions += 1,
+            }
             self.check_rep();
             Error::Decoding
         })?;
+        self.write_decoded(packet)
+    }
+
+    /// Same as `write` for a packet already decoded and authenticated, as a `Mux` hands over
+    /// the frags of a stream
+    pub(crate) fn write_decoded(&mut self, packet: Packet) -> Result<SetUploadState, Error> {
+        if self.is_reset {
+            return Err(Error::Reset);
+        }
+        self.peer_version = Some(packet.hdr().version());
+        let packet_seq = packet.hdr().packet_seq();
+        if let Some(packet_seq) = packet_seq.filter(|_| self.drop_duplicate_packets) {
+            if self.is_duplicate_packet(packet_seq) {
+                self.stat.duplicate_packets += 1;
+                self.check_rep();
+                return Err(Error::DuplicatePacket { packet_seq });
+            }
+        }
+        if self.strict {
+            self.check_late_pushes(&packet)?;
+        }
+        if let Some(packet_seq) = packet_seq {
+            self.mark_packet_seq(packet_seq);
+        }
+        let was_empty = self.buffered_len() == 0;
+        let was_finished = self.is_finished();
         let packet_state = self.write_packet(packet);
+        if self.is_reset {
+            return Err(Error::Reset);
+        }
         let state = SetUploadState {
             remote_rwnd_size: packet_state.remote_rwnd,
             remote_nack: packet_state.remote_nack,
@@ -120,67 +763,381 @@ impl Downloader {
             remote_seqs_to_ack: packet_state.frags.remote_seqs_to_ack,
             acked_local_seqs: packet_state.frags.acked_local_seqs,
             local_rwnd_size: self.recv_buf.rwnd_size(),
+            remote_timestamp: packet_state.remote_timestamp,
+            timestamp_echo: packet_state.timestamp_echo,
+            conn_id: packet_state.conn_id,
+            pings_to_answer: packet_state.frags.pings_to_answer,
+            pong: packet_state.frags.pong,
+            probe_to_answer: packet_state.frags.probe_to_answer,
+            probe_ack: packet_state.frags.probe_ack,
+            remote_ranges_to_sack: packet_state.frags.remote_ranges_to_sack,
+            sacked_local_ranges: packet_state.frags.sacked_local_ranges,
+            syn_to_answer: packet_state.frags.syn_to_answer,
+            is_established: self.is_established(),
         };
+        self.last_remote = Some((state.remote_rwnd_size, state.remote_nack));
+        self.advertised_rwnd = state.local_rwnd_size;
         self.check_rep();
+        if (was_empty && self.buffered_len() != 0) || (!was_finished && self.is_finished()) {
+            if let Some(x) = self.on_recv_available.as_ref().and_then(|x| x.upgrade()) {
+                x.notify();
+            }
+        }
         Ok(state)
     }
 
+    /// Same as `write` for a datagram rewritten by the `transform` of the peer
+    ///
+    /// A datagram `transform` fails to decode counts as a decoding error.
+    pub fn write_transformed(
+        &mut self,
+        mut slice: buf::BufSlice,
+        transform: &dyn PacketTransform,
+    ) -> Result<SetUploadState, Error> {
+        transform.decode(&mut slice).map_err(|()| {
+            self.stat.decoding_errors += 1;
+            self.check_rep();
+            Error::Decoding
+        })?;
+        self.write(slice)
+    }
+
+    #[deprecated(note = "use `write`")]
+    pub fn input_packet(&mut self, slice: buf::BufSlice) -> Result<SetUploadState, Error> {
+        self.write(slice)
+    }
+
+    #[deprecated(note = "use `emit`")]
+    #[must_use]
+    pub fn recv(&mut self) -> Option<BufSlice> {
+        self.emit()
+    }
+
+    fn is_duplicate_packet(&self, packet_seq: Seq32) -> bool {
+        let max_packet_seq = match self.max_packet_seq {
+            Some(x) => x,
+            None => return false,
+        };
+        if max_packet_seq < packet_seq {
+            return false;
+        }
+        let behind = max_packet_seq.dist(&packet_seq);
+        if PACKET_REORDER_WINDOW <= behind {
+            return true;
+        }
+        self.packet_seqs_seen & (1 << behind) != 0
+    }
+
+    fn mark_packet_seq(&mut self, packet_seq: Seq32) {
+        match self.max_packet_seq {
+            Some(max_packet_seq) if packet_seq <= max_packet_seq => {
+                let behind = max_packet_seq.dist(&packet_seq);
+                if behind < PACKET_REORDER_WINDOW {
+                    self.packet_seqs_seen |= 1 << behind;
+                }
+            }
+            max_packet_seq => {
+                let ahead = max_packet_seq
+                    .map(|x| packet_seq.dist(&x))
+                    .unwrap_or(PACKET_REORDER_WINDOW);
+                let seen = match ahead < PACKET_REORDER_WINDOW {
+                    true => self.packet_seqs_seen << ahead,
+                    false => 0,
+                };
+                self.packet_seqs_seen = seen | 1;
+                self.max_packet_seq = Some(packet_seq);
+            }
+        }
+    }
+
+    /// Nothing is written if any push is more than a receive window behind
+    fn check_late_pushes(&self, packet: &Packet) -> Result<(), Error> {
+        // the pushes are dropped anyway
+        if !self.is_established() {
+            return Ok(());
+        }
+        let next_seq_to_receive = self.recv_buf.next_seq_to_receive();
+        for frag in packet.frags() {
+            if let FragCommand::Ack
+            | FragCommand::AckBitmap { .. }
+            | FragCommand::Rst
+            | FragCommand::Ping { .. }
+            | FragCommand::Pong { .. }
+            | FragCommand::Probe { .. }
+            | FragCommand::ProbeAck { .. }
+            | FragCommand::Sack { .. }
+            | FragCommand::Syn { .. }
+            | FragCommand::SynAck { .. }
+            | FragCommand::Padding { .. }
+            | FragCommand::Parity { .. }
+            | FragCommand::Window { .. } = frag.cmd()
+            {
+                continue;
+            }
+            let seq = frag.seq();
+            if seq < next_seq_to_receive && self.recv_buf_len < seq.dist(&next_seq_to_receive) {
+                return Err(Error::LatePush { seq });
+            }
+        }
+        Ok(())
+    }
+
     #[must_use]
     fn write_packet(&mut self, packet: Packet) -> PacketState {
+        self.stat.unknown_frags += packet.unknown_frags() as u64;
         let packet = packet.into_builder();
         let frags_state = self.write_frags(packet.frags);
         let state = PacketState {
             frags: frags_state,
             remote_rwnd: packet.hdr.rwnd(),
             remote_nack: packet.hdr.nack(),
+            remote_timestamp: packet.hdr.timestamp(),
+            timestamp_echo: packet.hdr.timestamp_echo(),
+            conn_id: packet.hdr.conn_id(),
         };
         self.stat.packets += 1;
+        self.totals.packets_received = self.totals.packets_received.wrapping_add(1);
         self.check_rep();
         state
     }
 
+    /// `None` without a codec or if the body does not decompress into a non-empty one
+    fn decompress(&self, body: &Body) -> Option<BufSlice> {
+        let codec = self.codec.as_ref()?;
+        let data = match body {
+            Body::Slice(x) => codec.decompress(x.data()),
+            // intentional: `Packet::from_slice` only decodes into slices
+            Body::Pasta(_) => unreachable!("decoded push carries a pasta body"),
+        };
+        match data {
+            Ok(data) if !data.is_empty() => Some(BufSlice::from_bytes(data)),
+            _ => None,
+        }
+    }
+
     #[must_use]
     fn write_frags(&mut self, frags: Vec<Frag>) -> FragsState {
-        let mut remote_seqs_to_ack = Vec::new();
-        let mut acked_local_seqs = Vec::new();
+        let mut remote_seqs_to_ack = SeqList::new();
+        let mut acked_local_seqs = SeqList::new();
+        let mut pings_to_answer = Vec::new();
+        let mut pong = None;
+        let mut probe_to_answer = None;
+        let mut probe_ack = None;
+        let mut sacked_local_ranges = Vec::new();
+        let mut syn_to_answer = None;
         for frag in frags {
-            let frag = frag.into_builder();
+            let mut frag = frag.into_builder();
+            // a push like any other from here on
+            if let FragCommand::CompressedPush { body } = frag.cmd {
+                match self.decompress(&body) {
+                    Some(body) => {
+                        frag.cmd = FragCommand::Push {
+                            body: Body::Slice(body),
+                        }
+                    }
+                    None => {
+                        self.stat.decompression_errors += 1;
+                        continue;
+                    }
+                }
+            }
+            if !self.is_established() {
+                match frag.cmd {
+                    // a padding proves nothing about the session
+                    FragCommand::Syn { .. }
+                    | FragCommand::SynAck { .. }
+                    | FragCommand::Padding { .. } => (),
+                    // only a client that got the syn ack sends anything else
+                    _ if self.handshake_state == HandshakeState::SynReceived => {
+                        self.handshake_state = HandshakeState::Established;
+                    }
+                    // possibly from an earlier session
+                    FragCommand::Push { .. } => {
+                        self.stat.pushes_before_established += 1;
+                        continue;
+                    }
+                    _ => continue,
+                }
+            }
             match frag.cmd {
                 FragCommand::Push { body } => {
                     let body = match body {
                         Body::Slice(x) => x,
-                        Body::Pasta(_) => panic!(),
+                        // intentional: `Packet::from_slice` only decodes into slices
+                        Body::Pasta(_) => unreachable!("decoded push carries a pasta body"),
                     };
+                    self.stat.bytes_received += body.len() as u64;
+                    let cached_body = self.fec_cache.as_ref().map(|_| BufSlice::clone(&body));
                     // if out of rwnd
-                    let location = self.recv_buf.insert(frag.seq, body);
-                    match location {
+                    let insertion = self.recv_buf.insert(frag.seq, body);
+                    match insertion.location {
                         SeqLocationToRwnd::InRecvWindow => {
                             // schedule uploader to ack this seq
                             remote_seqs_to_ack.push(frag.seq);
 
-                            self.stat.out_of_orders += 1;
+                            match insertion.is_replacement {
+                                true => self.stat.duplicate_pushes += 1,
+                                false => {
+                                    self.stat.out_of_orders += 1;
+                                    if let Some(cache) = &mut self.fec_cache {
+                                        cache.insert(frag.seq, cached_body.unwrap());
+                                    }
+                                }
+                            }
                         }
                         SeqLocationToRwnd::AtRecvWindowStart => {
                             // schedule uploader to ack this seq
                             remote_seqs_to_ack.push(frag.seq);
+                            if let Some(cache) = &mut self.fec_cache {
+                                cache.insert(frag.seq, cached_body.unwrap());
+                            }
                         }
                         SeqLocationToRwnd::TooLate => {
-                            // schedule uploader to ack this seq
-                            remote_seqs_to_ack.push(frag.seq);
+                            match self.ack_late_pushes {
+                                // schedule uploader to ack this seq
+                                true => remote_seqs_to_ack.push(frag.seq),
+                                false => self.stat.late_acks_suppressed += 1,
+                            }
 
                             self.stat.late_pushes += 1;
+                            self.stat.duplicate_pushes += 1;
                             // drop the fragment
                         }
-                        SeqLocationToRwnd::TooEarly => {
-                            self.stat.early_pushes += 1;
-                            // drop the fragment
+                        SeqLocationToRwnd::TooEarly => {
+                            self.stat.early_pushes += 1;
+                            // drop the fragment
+                        }
+                    }
+                    self.stat.pushes += 1;
+                }
+                FragCommand::CompressedPush { .. } => unreachable!("decompressed above"),
+                FragCommand::Ack => {
+                    acked_local_seqs.push(frag.seq);
+                    self.stat.acks += 1;
+                }
+                FragCommand::AckBitmap { bitmap } => {
+                    for i in (0..ACK_BITMAP_SPAN).filter(|i| bitmap & 1 << i != 0) {
+                        acked_local_seqs.push(frag.seq.add_usize(i));
+                        self.stat.acks += 1;
+                    }
+                }
+                FragCommand::Skip => {
+                    // the push might have made it after all
+                    if !self.recv_buf.contains(frag.seq) {
+                        // an empty slice stands in for the push and is never delivered
+                        let _ = self.recv_buf.insert(frag.seq, BufSlice::from_bytes(vec![]));
+                    }
+                }
+                FragCommand::Fin => {
+                    // an empty slice takes the seq of the fin like that of a skipped push
+                    let insertion = self.recv_buf.insert(frag.seq, BufSlice::from_bytes(vec![]));
+                    match insertion.location {
+                        SeqLocationToRwnd::InRecvWindow | SeqLocationToRwnd::AtRecvWindowStart => {
+                            remote_seqs_to_ack.push(frag.seq);
+                            self.fin_seq = Some(frag.seq);
+                        }
+                        SeqLocationToRwnd::TooLate => {
+                            remote_seqs_to_ack.push(frag.seq);
+                        }
+                        SeqLocationToRwnd::TooEarly => {
+                            self.stat.early_pushes += 1;
+                        }
+                    }
+                }
+                FragCommand::Ping { token } => {
+                    pings_to_answer.push(token);
+                }
+                FragCommand::Pong { token } => {
+                    pong = Some(token);
+                }
+                FragCommand::Probe { id } => {
+                    probe_to_answer = Some(id);
+                }
+                FragCommand::ProbeAck { id } => {
+                    probe_ack = Some(id);
+                }
+                FragCommand::Sack { ranges } => {
+                    sacked_local_ranges.extend(ranges);
+                }
+                FragCommand::Padding { len } => {
+                    self.stat.padding_bytes += len as u64;
+                }
+                FragCommand::Parity {
+                    count,
+                    len_xor,
+                    body,
+                } => {
+                    let recovered = match &self.fec_cache {
+                        Some(cache) => cache.recover(frag.seq, count, len_xor, &body),
+                        None => None,
+                    };
+                    let (seq, body) = match recovered {
+                        Some(x) => x,
+                        None => continue,
+                    };
+                    // already delivered or buffered
+                    if seq < self.recv_buf.next_seq_to_receive() || self.recv_buf.contains(seq) {
+                        continue;
+                    }
+                    let insertion = self.recv_buf.insert(seq, BufSlice::clone(&body));
+                    if let SeqLocationToRwnd::InRecvWindow | SeqLocationToRwnd::AtRecvWindowStart =
+                        insertion.location
+                    {
+                        remote_seqs_to_ack.push(seq);
+                        self.fec_cache.as_mut().unwrap().insert(seq, body);
+                        self.stat.fec_recoveries += 1;
+                    }
+                }
+                FragCommand::Syn { nonce } => {
+                    let is_server = self.handshake.map(|x| x.role) == Some(Role::Server);
+                    match self.handshake_state {
+                        HandshakeState::Idle if is_server => {
+                            self.recv_buf =
+                                RecvBuf::new_at_counted(self.recv_buf_len, frag.seq, BufSlice::len);
+                            self.remote_nonce = Some(nonce);
+                            self.handshake_state = HandshakeState::SynReceived;
+                            syn_to_answer = Some(nonce);
+                        }
+                        // the syn ack got lost
+                        HandshakeState::SynReceived if self.remote_nonce == Some(nonce) => {
+                            syn_to_answer = Some(nonce);
                         }
+                        _ => (),
                     }
-                    self.stat.pushes += 1;
                 }
-                FragCommand::Ack => {
-                    acked_local_seqs.push(frag.seq);
-                    self.stat.acks += 1;
+                FragCommand::SynAck { nonce } => {
+                    if self.handshake_state == HandshakeState::SynSent
+                        && self.handshake.map(|x| x.nonce) == Some(nonce)
+                    {
+                        self.recv_buf =
+                            RecvBuf::new_at_counted(self.recv_buf_len, frag.seq, BufSlice::len);
+                        self.handshake_state = HandshakeState::Established;
+                    }
+                }
+                FragCommand::Rst => {
+                    // drop everything buffered; the frags after it do not matter
+                    self.recv_buf = RecvBuf::new_at_counted(
+                        self.recv_buf_len,
+                        self.recv_buf.next_seq_to_receive(),
+                        BufSlice::len,
+                    );
+                    self.leftover = None;
+                    self.is_reset = true;
+                    break;
+                }
+                // a `Mux` turns it into the packet header before it gets here
+                FragCommand::Window { .. } => (),
+            }
+        }
+        // only new pushes are worth a sack
+        let mut remote_ranges_to_sack = Vec::new();
+        if !remote_seqs_to_ack.is_empty() && self.recv_buf.buffered_count() != 0 {
+            remote_ranges_to_sack = self.recv_buf.buffered_ranges();
+            remote_ranges_to_sack.truncate(MAX_SACK_RANGES);
+            for range in &mut remote_ranges_to_sack {
+                // the length field of a sack range is 16 bits wide
+                if (u16::MAX as usize) < range.end.sub(&range.start) {
+                    range.end = range.start.add_usize(u16::MAX as usize);
                 }
             }
         }
@@ -188,50 +1145,110 @@ impl Downloader {
         FragsState {
             remote_seqs_to_ack,
             acked_local_seqs,
+            pings_to_answer,
+            pong,
+            probe_to_answer,
+            probe_ack,
+            remote_ranges_to_sack,
+            sacked_local_ranges,
+            syn_to_answer,
         }
     }
 }
 
 struct FragsState {
-    remote_seqs_to_ack: Vec<Seq32>,
-    acked_local_seqs: Vec<Seq32>,
+    remote_seqs_to_ack: SeqList,
+    acked_local_seqs: SeqList,
+    pings_to_answer: Vec<u32>,
+    pong: Option<u32>,
+    probe_to_answer: Option<u32>,
+    probe_ack: Option<u32>,
+    remote_ranges_to_sack: Vec<Range<Seq32>>,
+    sacked_local_ranges: Vec<Range<Seq32>>,
+    syn_to_answer: Option<u64>,
 }
 
 struct PacketState {
     frags: FragsState,
     remote_rwnd: u16,
     remote_nack: Seq32,
+    remote_timestamp: Option<u32>,
+    timestamp_echo: Option<u32>,
+    conn_id: Option<u64>,
 }
 
+#[derive(Default)]
 struct LocalStat {
     late_pushes: u64,
     early_pushes: u64,
     out_of_orders: u64,
+    duplicate_pushes: u64,
+    late_acks_suppressed: u64,
+    unknown_frags: u64,
+    pushes_before_established: u64,
+    duplicate_packets: u64,
+    auth_failures: u64,
+    padding_bytes: u64,
+    fec_recoveries: u64,
+    decompression_errors: u64,
     decoding_errors: u64,
+    oversized_lens: u64,
+    frag_limit_exceeded: u64,
+    unsupported_versions: u64,
     packets: u64,
     acks: u64,
     pushes: u64,
+    bytes_received: u64,
+    bytes_delivered: u64,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct Stat {
     pub late_pushes: u64,
     pub early_pushes: u64,
+    /// Pushes parked ahead of the next seq to receive, each seq counted once
     pub out_of_orders: u64,
+    /// Pushes of a seq already waiting in or already past the receive window
+    pub duplicate_pushes: u64,
+    /// Late pushes left unacked as `ack_late_pushes` is off
+    pub late_acks_suppressed: u64,
+    /// Extension frags stepped over as not understood
+    pub unknown_frags: u64,
+    /// Pushes dropped as they came before the session was established
+    pub pushes_before_established: u64,
+    /// Packets dropped as their packet seq had been seen before
+    pub duplicate_packets: u64,
+    /// Packets dropped as their tag was missing or did not match
+    pub auth_failures: u64,
+    /// Bytes of `Padding` frags thrown away
+    pub padding_bytes: u64,
+    /// Lost pushes rebuilt from a `Parity`
+    pub fec_recoveries: u64,
+    /// Compressed pushes dropped as there was no codec or they did not decompress
+    pub decompression_errors: u64,
+    /// Packets dropped as undecodable, the three kinds below included
     pub decoding_errors: u64,
+    /// Packets dropped as a `len` in them covered more bytes than were left
+    pub oversized_lens: u64,
+    /// Packets dropped as they carried more than `max_frags_per_packet` frags
+    pub frag_limit_exceeded: u64,
+    /// Packets dropped as their header was of a protocol version other than `PROTOCOL_VERSION`
+    pub unsupported_versions: u64,
     pub next_seq_to_receive: Seq32,
     pub packets: u64,
     pub acks: u64,
     pub pushes: u64,
+    pub bytes_received: u64,
+    pub bytes_delivered: u64,
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
         protocol::{
-            frag::{Body, FragBuilder, FragCommand},
+            frag::{Body, FragBuilder, FragCommand, WireFormat, EXTENSION_CMD_START, PUSH_HDR_LEN},
             packet::PacketBuilder,
-            packet_hdr::PacketHeaderBuilder,
+            packet_hdr::{PacketHeaderBuilder, LEGACY_VERSION, PACKET_HDR_LEN, PROTOCOL_VERSION},
         },
         utils::{
             buf::{BufSlice, OwnedBufWtr},
@@ -239,11 +1256,34 @@ mod tests {
         },
     };
 
-    use super::DownloaderBuilder;
+    use super::{DownloaderBuilder, Error, PACKET_REORDER_WINDOW};
+
+    struct Counter(std::sync::atomic::AtomicUsize);
+    impl crate::layer::IObserver for Counter {
+        fn notify(&self) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
 
     #[test]
     fn test_empty() {
-        let mut download = DownloaderBuilder { recv_buf_len: 3 }.build().unwrap();
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 3,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         let origin1 = vec![];
         let slice = BufSlice::from_bytes(origin1);
@@ -253,12 +1293,32 @@ mod tests {
 
     #[test]
     fn test_few_1() {
-        let mut downloader = DownloaderBuilder { recv_buf_len: 3 }.build().unwrap();
+        let mut downloader = DownloaderBuilder {
+            recv_buf_len: 3,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         let packet = PacketBuilder {
             hdr: PacketHeaderBuilder {
                 rwnd: 2,
                 nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
             }
             .build()
             .unwrap(),
@@ -285,19 +1345,39 @@ mod tests {
         assert_eq!(state.remote_nack.to_u32(), 0);
         assert_eq!(state.remote_rwnd_size, 2);
         let tmp: Vec<Seq32> = vec![0].iter().map(|&x| Seq32::from_u32(x)).collect();
-        assert_eq!(state.remote_seqs_to_ack, tmp);
-        assert_eq!(state.acked_local_seqs, vec![]);
+        assert_eq!(state.remote_seqs_to_ack.as_slice(), tmp);
+        assert!(state.acked_local_seqs.is_empty());
         assert_eq!(downloader.emit().unwrap().data(), vec![4; 11]);
     }
 
     #[test]
     fn test_out_of_order() {
-        let mut downloader = DownloaderBuilder { recv_buf_len: 3 }.build().unwrap();
+        let mut downloader = DownloaderBuilder {
+            recv_buf_len: 3,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         let packet = PacketBuilder {
             hdr: PacketHeaderBuilder {
                 rwnd: 2,
                 nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
             }
             .build()
             .unwrap(),
@@ -324,19 +1404,39 @@ mod tests {
         assert_eq!(state.remote_nack.to_u32(), 0);
         assert_eq!(state.remote_rwnd_size, 2);
         let tmp: Vec<Seq32> = vec![1].iter().map(|&x| Seq32::from_u32(x)).collect();
-        assert_eq!(state.remote_seqs_to_ack, tmp);
-        assert_eq!(state.acked_local_seqs, vec![]);
+        assert_eq!(state.remote_seqs_to_ack.as_slice(), tmp);
+        assert!(state.acked_local_seqs.is_empty());
         assert!(downloader.emit().is_none());
     }
 
     #[test]
     fn test_out_of_window1() {
-        let mut downloader = DownloaderBuilder { recv_buf_len: 3 }.build().unwrap();
+        let mut downloader = DownloaderBuilder {
+            recv_buf_len: 3,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         let packet = PacketBuilder {
             hdr: PacketHeaderBuilder {
                 rwnd: 2,
                 nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
             }
             .build()
             .unwrap(),
@@ -360,19 +1460,39 @@ mod tests {
         assert_eq!(state.local_rwnd_size, 3);
         assert_eq!(state.remote_nack.to_u32(), 0);
         assert_eq!(state.remote_rwnd_size, 2);
-        assert_eq!(state.remote_seqs_to_ack, vec![]);
-        assert_eq!(state.acked_local_seqs, vec![]);
+        assert!(state.remote_seqs_to_ack.is_empty());
+        assert!(state.acked_local_seqs.is_empty());
         assert!(downloader.emit().is_none());
     }
 
     #[test]
     fn test_ack() {
-        let mut download = DownloaderBuilder { recv_buf_len: 3 }.build().unwrap();
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 3,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         let packet = PacketBuilder {
             hdr: PacketHeaderBuilder {
                 rwnd: 2,
                 nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
             }
             .build()
             .unwrap(),
@@ -410,21 +1530,41 @@ mod tests {
         assert_eq!(state.local_rwnd_size, 3);
         assert_eq!(state.remote_nack.to_u32(), 0);
         assert_eq!(state.remote_rwnd_size, 2);
-        assert_eq!(state.remote_seqs_to_ack, vec![]);
+        assert!(state.remote_seqs_to_ack.is_empty());
         let tmp: Vec<Seq32> = vec![1, 3].iter().map(|&x| Seq32::from_u32(x)).collect();
-        assert_eq!(state.acked_local_seqs, tmp);
+        assert_eq!(state.acked_local_seqs.as_slice(), tmp);
         assert!(download.emit().is_none());
     }
 
     #[test]
     fn test_rwnd_proceeding() {
-        let mut downloader = DownloaderBuilder { recv_buf_len: 2 }.build().unwrap();
+        let mut downloader = DownloaderBuilder {
+            recv_buf_len: 2,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         {
             let packet = PacketBuilder {
                 hdr: PacketHeaderBuilder {
                     rwnd: 2,
                     nack: Seq32::from_u32(0),
+                    timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    packet_seq: None,
                 }
                 .build()
                 .unwrap(),
@@ -461,15 +1601,24 @@ mod tests {
             assert_eq!(changes.remote_nack.to_u32(), 0);
             assert_eq!(changes.remote_rwnd_size, 2);
             let tmp: Vec<Seq32> = vec![1].iter().map(|&x| Seq32::from_u32(x)).collect();
-            assert_eq!(changes.remote_seqs_to_ack, tmp);
-            assert_eq!(changes.acked_local_seqs, vec![]);
+            assert_eq!(changes.remote_seqs_to_ack.as_slice(), tmp);
+            assert!(changes.acked_local_seqs.is_empty());
             assert!(downloader.emit().is_none());
+            // seq(2) is out of the window
+            assert_eq!(downloader.buffered_len(), 0);
+            assert_eq!(downloader.reorder_len(), 1);
+            // parked pushes are still inside the window
+            assert_eq!(downloader.rwnd_free(), 2);
         }
         {
             let packet = PacketBuilder {
                 hdr: PacketHeaderBuilder {
                     rwnd: 2,
                     nack: Seq32::from_u32(0),
+                    timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    packet_seq: None,
                 }
                 .build()
                 .unwrap(),
@@ -506,16 +1655,26 @@ mod tests {
             assert_eq!(state.remote_nack.to_u32(), 0);
             assert_eq!(state.remote_rwnd_size, 2);
             let tmp: Vec<Seq32> = vec![0].iter().map(|&x| Seq32::from_u32(x)).collect();
-            assert_eq!(state.remote_seqs_to_ack, tmp);
-            assert_eq!(state.acked_local_seqs, vec![]);
+            assert_eq!(state.remote_seqs_to_ack.as_slice(), tmp);
+            assert!(state.acked_local_seqs.is_empty());
+            assert_eq!(downloader.buffered_len(), 2);
+            assert_eq!(downloader.reorder_len(), 0);
+            assert_eq!(downloader.rwnd_free(), 0);
             assert_eq!(downloader.emit().unwrap().data(), vec![0; 1]);
+            assert_eq!(downloader.buffered_len(), 1);
             assert_eq!(downloader.emit().unwrap().data(), vec![1; 1]);
+            assert_eq!(downloader.buffered_len(), 0);
+            assert_eq!(downloader.rwnd_free(), 2);
         }
         {
             let packet = PacketBuilder {
                 hdr: PacketHeaderBuilder {
                     rwnd: 2,
                     nack: Seq32::from_u32(0),
+                    timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    packet_seq: None,
                 }
                 .build()
                 .unwrap(),
@@ -542,10 +1701,17 @@ mod tests {
             assert_eq!(changes.remote_nack.to_u32(), 0);
             assert_eq!(changes.remote_rwnd_size, 2);
             let tmp: Vec<Seq32> = vec![2].iter().map(|&x| Seq32::from_u32(x)).collect();
-            assert_eq!(changes.remote_seqs_to_ack, tmp);
-            assert_eq!(changes.acked_local_seqs, vec![]);
-            assert_eq!(downloader.emit().unwrap().data(), vec![2; 2]);
+            assert_eq!(changes.remote_seqs_to_ack.as_slice(), tmp);
+            assert!(changes.acked_local_seqs.is_empty());
+            assert_eq!(downloader.buffered_len(), 2);
+            assert_eq!(downloader.rwnd_free(), 1);
+            assert_eq!(downloader.emit_max(1).unwrap().data(), vec![2; 1]);
+            // the leftover no longer takes a slot
+            assert_eq!(downloader.buffered_len(), 1);
+            assert_eq!(downloader.rwnd_free(), 2);
+            assert_eq!(downloader.emit_max(1).unwrap().data(), vec![2; 1]);
             assert!(downloader.emit().is_none());
+            assert_eq!(downloader.buffered_len(), 0);
         }
         // test out of window2
         {
@@ -553,6 +1719,10 @@ mod tests {
                 hdr: PacketHeaderBuilder {
                     rwnd: 2,
                     nack: Seq32::from_u32(0),
+                    timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    packet_seq: None,
                 }
                 .build()
                 .unwrap(),
@@ -578,20 +1748,389 @@ mod tests {
             assert_eq!(changes.local_rwnd_size, 2);
             assert_eq!(changes.remote_nack.to_u32(), 0);
             assert_eq!(changes.remote_rwnd_size, 2);
-            assert_eq!(changes.remote_seqs_to_ack, vec![Seq32::from_u32(0)]);
-            assert_eq!(changes.acked_local_seqs, vec![]);
+            assert_eq!(
+                changes.remote_seqs_to_ack.as_slice(),
+                vec![Seq32::from_u32(0)]
+            );
+            assert!(changes.acked_local_seqs.is_empty());
             assert!(downloader.emit().is_none());
         }
     }
 
+    fn pushes(seqs: &[u32]) -> BufSlice {
+        numbered_pushes(None, seqs)
+    }
+
+    fn numbered_pushes(packet_seq: Option<u32>, seqs: &[u32]) -> BufSlice {
+        let packet = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 2,
+                nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: packet_seq.map(Seq32::from_u32),
+            }
+            .build()
+            .unwrap(),
+            frags: seqs
+                .iter()
+                .map(|&seq| {
+                    FragBuilder {
+                        seq: Seq32::from_u32(seq),
+                        cmd: FragCommand::Push {
+                            body: Body::Slice(BufSlice::from_bytes(vec![seq as u8])),
+                        },
+                    }
+                    .build()
+                    .unwrap()
+                })
+                .collect(),
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        packet.append_to(&mut wtr).unwrap();
+        wtr.into_slice()
+    }
+
+    #[test]
+    fn test_window_update() {
+        let updates = |window_update_chunk| {
+            let mut downloader = DownloaderBuilder {
+                recv_buf_len: 16,
+                strict: false,
+                window_update_chunk,
+                ack_late_pushes: true,
+                handshake: None,
+                initial_seq: None,
+                drop_duplicate_packets: true,
+                auth: None,
+                wire_format: WireFormat::V1,
+                fec_group_size: None,
+                codec: None,
+                max_frags_per_packet: None,
+                legacy_header: false,
+            }
+            .build()
+            .unwrap();
+            assert!(!downloader.should_advertise_window());
+            assert!(downloader.window_update().is_none());
+
+            let seqs: Vec<u32> = (0..16).collect();
+            let state = downloader.write(pushes(&seqs)).unwrap();
+            assert_eq!(state.local_rwnd_size, 0);
+
+            // drip-fed consumer
+            let mut updates = 0;
+            while downloader.emit().is_some() {
+                if downloader.should_advertise_window() {
+                    let state = downloader.window_update().unwrap();
+                    assert_eq!(state.local_next_seq_to_receive.to_u32(), 16);
+                    assert!(state.acked_local_seqs.is_empty());
+                    updates += 1;
+                }
+            }
+            updates
+        };
+        assert_eq!(updates(0), 16);
+        // out of the zero window, then every 5 slots
+        assert_eq!(updates(4), 4);
+    }
+
+    #[test]
+    fn test_strict_late_push() {
+        for strict in [false, true] {
+            let mut downloader = DownloaderBuilder {
+                recv_buf_len: 2,
+                strict,
+                window_update_chunk: 0,
+                ack_late_pushes: true,
+                handshake: None,
+                initial_seq: None,
+                drop_duplicate_packets: true,
+                auth: None,
+                wire_format: WireFormat::V1,
+                fec_group_size: None,
+                codec: None,
+                max_frags_per_packet: None,
+                legacy_header: false,
+            }
+            .build()
+            .unwrap();
+            for seqs in [[0, 1], [2, 3]] {
+                downloader.write(pushes(&seqs)).unwrap();
+                while downloader.emit().is_some() {}
+            }
+
+            // one behind
+            downloader.write(pushes(&[3])).unwrap();
+            assert_eq!(downloader.stat().late_pushes, 1);
+
+            // three behind, more than the receive window
+            let result = downloader.write(pushes(&[1]));
+            match strict {
+                true => {
+                    assert!(matches!(result, Err(Error::LatePush { seq }) if seq.to_u32() == 1));
+                    assert_eq!(downloader.stat().late_pushes, 1);
+                    assert_eq!(downloader.stat().packets, 3);
+                }
+                false => {
+                    assert!(result.is_ok());
+                    assert_eq!(downloader.stat().late_pushes, 2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_stat() {
+        let mut downloader = DownloaderBuilder::default().build().unwrap();
+
+        // the same seq parked twice
+        downloader.write(pushes(&[1])).unwrap();
+        downloader.write(pushes(&[1])).unwrap();
+        assert_eq!(downloader.stat().out_of_orders, 1);
+        assert_eq!(downloader.stat().duplicate_pushes, 1);
+
+        // the gap filled
+        downloader.write(pushes(&[0])).unwrap();
+        assert_eq!(downloader.stat().out_of_orders, 1);
+        while downloader.emit().is_some() {}
+
+        // a reordered pair
+        downloader.write(pushes(&[3])).unwrap();
+        downloader.write(pushes(&[2])).unwrap();
+        assert_eq!(downloader.stat().out_of_orders, 2);
+
+        // a retransmission arriving after delivery
+        downloader.write(pushes(&[0])).unwrap();
+        assert_eq!(downloader.stat().late_pushes, 1);
+        assert_eq!(downloader.stat().duplicate_pushes, 2);
+    }
+
+    #[test]
+    fn test_duplicate_packets() {
+        let mut downloader = DownloaderBuilder::default().build().unwrap();
+        downloader.write(numbered_pushes(Some(0), &[0])).unwrap();
+
+        // duplicated by the network
+        match downloader.write(numbered_pushes(Some(0), &[0])) {
+            Err(Error::DuplicatePacket { packet_seq }) => assert_eq!(packet_seq.to_u32(), 0),
+            _ => panic!(),
+        }
+        assert_eq!(downloader.stat().duplicate_packets, 1);
+        assert_eq!(downloader.stat().late_pushes, 0);
+
+        // reordered but new
+        downloader.write(numbered_pushes(Some(2), &[2])).unwrap();
+        downloader.write(numbered_pushes(Some(1), &[1])).unwrap();
+        assert_eq!(downloader.stat().duplicate_packets, 1);
+        assert_eq!(downloader.stat().next_seq_to_receive, Seq32::from_u32(3));
+        assert!(downloader.write(numbered_pushes(Some(1), &[1])).is_err());
+        assert!(downloader.write(numbered_pushes(Some(2), &[2])).is_err());
+        assert_eq!(downloader.stat().duplicate_packets, 3);
+
+        // too far behind to tell
+        let behind = 3 + PACKET_REORDER_WINDOW as u32;
+        downloader
+            .write(numbered_pushes(Some(behind), &[3]))
+            .unwrap();
+        assert!(downloader.write(numbered_pushes(Some(2), &[])).is_err());
+        // without a packet seq
+        downloader.write(pushes(&[3])).unwrap();
+        assert_eq!(downloader.stat().duplicate_packets, 4);
+
+        // turned off
+        let mut downloader = DownloaderBuilder::default()
+            .drop_duplicate_packets(false)
+            .build()
+            .unwrap();
+        downloader.write(numbered_pushes(Some(0), &[0])).unwrap();
+        downloader.write(numbered_pushes(Some(0), &[0])).unwrap();
+        assert_eq!(downloader.stat().duplicate_packets, 0);
+        assert_eq!(downloader.stat().late_pushes, 1);
+    }
+
+    #[test]
+    fn test_ack_late_pushes() {
+        for ack_late_pushes in [true, false] {
+            let mut downloader = DownloaderBuilder::default()
+                .ack_late_pushes(ack_late_pushes)
+                .build()
+                .unwrap();
+            downloader.write(pushes(&[0])).unwrap();
+            while downloader.emit().is_some() {}
+
+            // replayed
+            let state = downloader.write(pushes(&[0])).unwrap();
+            assert_eq!(downloader.stat().late_pushes, 1);
+            match ack_late_pushes {
+                true => {
+                    assert_eq!(state.remote_seqs_to_ack.as_slice(), [Seq32::from_u32(0)]);
+                    assert_eq!(downloader.stat().late_acks_suppressed, 0);
+                }
+                false => {
+                    assert!(state.remote_seqs_to_ack.is_empty());
+                    assert_eq!(downloader.stat().late_acks_suppressed, 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_frag() {
+        let mut downloader = DownloaderBuilder::default().build().unwrap();
+
+        // an extension frag between the two pushes
+        let slice = pushes(&[0, 1]);
+        let (front, back) = slice.data().split_at(PACKET_HDR_LEN + PUSH_HDR_LEN + 1);
+        let mut data = front.to_vec();
+        data.extend([0, 0, 0, 9, EXTENSION_CMD_START, 0, 0, 0, 3, 7, 7, 7]);
+        data.extend(back);
+        let state = downloader.write(BufSlice::from_bytes(data)).unwrap();
+        assert_eq!(state.remote_seqs_to_ack.len(), 2);
+        assert_eq!(downloader.stat().unknown_frags, 1);
+        assert_eq!(downloader.emit().unwrap().data(), [0]);
+        assert_eq!(downloader.emit().unwrap().data(), [1]);
+    }
+
+    #[test]
+    fn test_rejected_packets() {
+        let mut downloader = DownloaderBuilder::default()
+            .max_frags_per_packet(Some(4))
+            .build()
+            .unwrap();
+
+        assert!(downloader.write(pushes(&[0, 1, 2, 3])).is_ok());
+        assert!(matches!(
+            downloader.write(pushes(&[4, 5, 6, 7, 8])),
+            Err(Error::Decoding)
+        ));
+        // a push claiming far more bytes than follow
+        let slice = pushes(&[4]);
+        let mut data = slice.data().to_vec();
+        data[PACKET_HDR_LEN + 5..PACKET_HDR_LEN + 9].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            downloader.write(BufSlice::from_bytes(data)),
+            Err(Error::Decoding)
+        ));
+        // a header cut short
+        assert!(matches!(
+            downloader.write(BufSlice::from_bytes(vec![0, 0, 0])),
+            Err(Error::Decoding)
+        ));
+
+        let stat = downloader.stat();
+        assert_eq!(stat.frag_limit_exceeded, 1);
+        assert_eq!(stat.oversized_lens, 1);
+        assert_eq!(stat.decoding_errors, 3);
+        // nothing of the rejected packets was taken
+        assert_eq!(stat.pushes, 4);
+        assert_eq!(stat.next_seq_to_receive, Seq32::from_u32(4));
+    }
+
+    #[test]
+    fn test_window_span() {
+        let mut downloader = DownloaderBuilder {
+            recv_buf_len: 16,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
+        assert_eq!(downloader.window_span(), None);
+        assert_eq!(downloader.buffered_count(), 0);
+
+        let packet = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 2,
+                nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap(),
+            frags: vec![0, 1, 3, 5, 9]
+                .into_iter()
+                .map(|seq| {
+                    FragBuilder {
+                        seq: Seq32::from_u32(seq),
+                        cmd: FragCommand::Push {
+                            body: Body::Slice(BufSlice::from_bytes(vec![seq as u8])),
+                        },
+                    }
+                    .build()
+                    .unwrap()
+                })
+                .collect(),
+        }
+        .build()
+        .unwrap();
+
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        packet.append_to(&mut wtr).unwrap();
+        let slice = wtr.into_slice();
+        let state = downloader.write(slice).unwrap();
+        assert_eq!(state.local_next_seq_to_receive.to_u32(), 2);
+        assert_eq!(
+            downloader.window_span(),
+            Some((Seq32::from_u32(2), Seq32::from_u32(9)))
+        );
+        assert_eq!(downloader.buffered_count(), 3);
+        assert_eq!(downloader.ooo_buffered(), 3);
+        assert_eq!(downloader.received_ready(), 2);
+
+        let _ = downloader.emit().unwrap();
+        assert_eq!(downloader.received_ready(), 1);
+        let _ = downloader.stat_reset();
+        assert_eq!(downloader.stat().packets, 0);
+        assert_eq!(downloader.stat().bytes_delivered, 0);
+        let totals = downloader.lifetime_totals();
+        assert_eq!(totals.packets_received, 1);
+        assert_eq!(totals.bytes_delivered, 1);
+    }
+
     #[test]
     fn test_recv_max() {
-        let mut download = DownloaderBuilder { recv_buf_len: 3 }.build().unwrap();
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 3,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
 
         let packet = PacketBuilder {
             hdr: PacketHeaderBuilder {
                 rwnd: 2,
                 nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
             }
             .build()
             .unwrap(),
@@ -619,21 +2158,336 @@ mod tests {
             assert_eq!(changes.remote_nack.to_u32(), 0);
             assert_eq!(changes.remote_rwnd_size, 2);
             let tmp: Vec<Seq32> = vec![0].iter().map(|&x| Seq32::from_u32(x)).collect();
-            assert_eq!(changes.remote_seqs_to_ack, tmp);
-            assert_eq!(changes.acked_local_seqs, vec![]);
+            assert_eq!(changes.remote_seqs_to_ack.as_slice(), tmp);
+            assert!(changes.acked_local_seqs.is_empty());
             assert_eq!(download.emit_max(1).unwrap().data(), vec![0]);
             assert_eq!(download.emit_max(2).unwrap().data(), vec![1, 2]);
             assert_eq!(download.emit_max(10).unwrap().data(), vec![3]);
         }
     }
 
+    #[test]
+    fn test_drain() {
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 4,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
+
+        let pushes = |seqs: std::ops::Range<u32>| {
+            let packet = PacketBuilder {
+                hdr: PacketHeaderBuilder {
+                    rwnd: 4,
+                    nack: Seq32::from_u32(0),
+                    timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    packet_seq: None,
+                }
+                .build()
+                .unwrap(),
+                frags: seqs
+                    .map(|seq| {
+                        FragBuilder {
+                            seq: Seq32::from_u32(seq),
+                            cmd: FragCommand::Push {
+                                body: Body::Slice(BufSlice::from_bytes(vec![seq as u8; 2])),
+                            },
+                        }
+                        .build()
+                        .unwrap()
+                    })
+                    .collect(),
+            }
+            .build()
+            .unwrap();
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+
+        download.write(pushes(0..3)).unwrap();
+        let slices = download.drain();
+        let data: Vec<&[u8]> = slices.iter().map(|slice| slice.data()).collect();
+        assert_eq!(data, vec![[0, 0], [1, 1], [2, 2]]);
+        assert!(download.drain().is_empty());
+
+        // the leftover of `emit_max` comes first
+        download.write(pushes(3..5)).unwrap();
+        assert_eq!(download.emit_max(1).unwrap().data(), vec![3]);
+        let slices = download.drain();
+        let data: Vec<&[u8]> = slices.iter().map(|slice| slice.data()).collect();
+        assert_eq!(data, vec![&[3][..], &[4, 4]]);
+        assert_eq!(download.stat().bytes_delivered, 10);
+        assert_eq!(download.received_ready(), 0);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 4,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
+
+        let packet = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 4,
+                nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap(),
+            frags: (0..3)
+                .map(|seq| {
+                    FragBuilder {
+                        seq: Seq32::from_u32(seq),
+                        cmd: FragCommand::Push {
+                            body: Body::Slice(BufSlice::from_bytes(vec![seq as u8; 3])),
+                        },
+                    }
+                    .build()
+                    .unwrap()
+                })
+                .collect(),
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        packet.append_to(&mut wtr).unwrap();
+        download.write(wtr.into_slice()).unwrap();
+
+        let mut buf = [9; 4];
+        assert_eq!(download.read(&mut buf[..0]), 0);
+        // across slices, leaving the rest of the second one over
+        assert_eq!(download.read(&mut buf), 4);
+        assert_eq!(buf, [0, 0, 0, 1]);
+        assert_eq!(download.emit_max(1).unwrap().data(), vec![1]);
+        assert_eq!(download.read(&mut buf[..2]), 2);
+        assert_eq!(buf[..2], [1, 2]);
+        assert_eq!(download.read(&mut buf), 2);
+        assert_eq!(buf[..2], [2, 2]);
+        // nothing ready
+        assert_eq!(download.read(&mut buf), 0);
+        assert!(download.emit_max(1).is_none());
+        assert_eq!(download.stat().bytes_delivered, 9);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 4,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
+        assert!(download.peek().is_none());
+        assert_eq!(download.peek_len(), 0);
+
+        let packet = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 4,
+                nack: Seq32::from_u32(0),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap(),
+            frags: vec![
+                FragBuilder {
+                    seq: Seq32::from_u32(0),
+                    cmd: FragCommand::Skip,
+                }
+                .build()
+                .unwrap(),
+                FragBuilder {
+                    seq: Seq32::from_u32(1),
+                    cmd: FragCommand::Push {
+                        body: Body::Slice(BufSlice::from_bytes(vec![1, 2, 3])),
+                    },
+                }
+                .build()
+                .unwrap(),
+                FragBuilder {
+                    seq: Seq32::from_u32(2),
+                    cmd: FragCommand::Push {
+                        body: Body::Slice(BufSlice::from_bytes(vec![4])),
+                    },
+                }
+                .build()
+                .unwrap(),
+            ],
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        packet.append_to(&mut wtr).unwrap();
+        download.write(wtr.into_slice()).unwrap();
+
+        // past the skipped push
+        assert_eq!(download.peek(), Some(&[1, 2, 3][..]));
+        assert_eq!(download.peek_len(), 4);
+        assert_eq!(download.rwnd_free(), 1);
+        assert_eq!(download.emit_max(1).unwrap().data(), vec![1]);
+        assert_eq!(download.peek(), Some(&[2, 3][..]));
+        assert_eq!(download.peek_len(), 3);
+        let peeked = download.peek().unwrap().to_vec();
+        assert_eq!(download.emit_max(10).unwrap().data(), peeked);
+        assert_eq!(download.peek(), Some(&[4][..]));
+        assert_eq!(download.emit().unwrap().data(), vec![4]);
+        assert!(download.peek().is_none());
+        assert_eq!(download.peek_len(), 0);
+        assert_eq!(download.rwnd_free(), 4);
+    }
+
+    #[test]
+    fn test_on_recv_available() {
+        let mut download = DownloaderBuilder {
+            recv_buf_len: 4,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
+        let counter = std::sync::Arc::new(Counter(std::sync::atomic::AtomicUsize::new(0)));
+        let weak = std::sync::Arc::downgrade(&counter);
+        download.set_on_recv_available(Some(weak));
+        let available = || counter.0.load(std::sync::atomic::Ordering::SeqCst);
+
+        // out of order
+        download.write(pushes(&[1])).unwrap();
+        assert_eq!(available(), 0);
+        // a burst
+        download.write(pushes(&[0, 2])).unwrap();
+        assert_eq!(available(), 1);
+        download.write(pushes(&[3])).unwrap();
+        assert_eq!(available(), 1);
+
+        assert_eq!(download.emit().unwrap().data(), vec![0]);
+        download.write(pushes(&[4])).unwrap();
+        assert_eq!(available(), 1);
+        assert_eq!(download.drain().len(), 4);
+        assert_eq!(available(), 1);
+        download.write(pushes(&[5])).unwrap();
+        assert_eq!(available(), 2);
+    }
+
     #[test]
     fn test_large_rwnd() {
         let recv_buf_len = (u16::MAX as usize) + 1;
-        let result = DownloaderBuilder { recv_buf_len }.build();
+        let result = DownloaderBuilder {
+            recv_buf_len,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build();
         match result {
             Ok(_) => panic!(),
             Err(_) => (),
         }
     }
+
+    #[test]
+    fn test_peer_version() {
+        let mut downloader = DownloaderBuilder::default().build().unwrap();
+        assert_eq!(downloader.peer_version(), None);
+
+        // a peer from a future version
+        let mut data = pushes(&[0]).data().to_vec();
+        data[0] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            downloader.write(BufSlice::from_bytes(data)),
+            Err(Error::Decoding)
+        ));
+        // a peer predating the version byte
+        let mut data = pushes(&[0]).data().to_vec();
+        data.remove(0);
+        assert!(matches!(
+            downloader.write(BufSlice::from_bytes(data)),
+            Err(Error::Decoding)
+        ));
+        let stat = downloader.stat();
+        assert_eq!(stat.unsupported_versions, 2);
+        assert_eq!(stat.decoding_errors, 2);
+        assert_eq!(stat.pushes, 0);
+        assert_eq!(downloader.peer_version(), None);
+
+        downloader.write(pushes(&[0])).unwrap();
+        assert_eq!(downloader.peer_version(), Some(PROTOCOL_VERSION));
+        assert_eq!(downloader.emit().unwrap().data(), [0]);
+    }
+
+    #[test]
+    fn test_legacy_header() {
+        let mut downloader = DownloaderBuilder::default()
+            .legacy_header(true)
+            .build()
+            .unwrap();
+
+        let mut data = pushes(&[0]).data().to_vec();
+        data.remove(0);
+        downloader.write(BufSlice::from_bytes(data)).unwrap();
+        assert_eq!(downloader.peer_version(), Some(LEGACY_VERSION));
+        assert_eq!(downloader.emit().unwrap().data(), [0]);
+    }
 }
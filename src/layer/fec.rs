@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+
+use crate::{
+    protocol::frag::{Body, Frag, FragBuilder, FragCommand},
+    utils::{
+        buf::{BufSlice, BufWtr, OwnedBufWtr},
+        Seq, Seq32,
+    },
+};
+
+/// The consecutive pushes sent since the last parity
+pub struct ParityGroup {
+    start: Seq32,
+    count: usize,
+    len_xor: u32,
+    body: Vec<u8>,
+}
+
+impl ParityGroup {
+    #[must_use]
+    pub fn new(start: Seq32) -> Self {
+        ParityGroup {
+            start,
+            count: 0,
+            len_xor: 0,
+            body: Vec::new(),
+        }
+    }
+
+    /// Whether the push of `seq` continues the group
+    #[must_use]
+    pub fn is_next(&self, seq: Seq32) -> bool {
+        self.start.add_usize(self.count) == seq
+    }
+
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn add(&mut self, body: &Body) {
+        match body {
+            Body::Slice(x) => xor_into(&mut self.body, x.data()),
+            Body::Pasta(x) => {
+                let mut wtr = OwnedBufWtr::new(x.len(), 0);
+                x.append_to(&mut wtr).unwrap();
+                xor_into(&mut self.body, wtr.data());
+            }
+        }
+        self.len_xor ^= body.len() as u32;
+        self.count += 1;
+    }
+
+    /// # Panics
+    ///
+    /// If the group is empty or has more than `u8::MAX` pushes.
+    #[must_use]
+    pub fn into_frag(self) -> Frag {
+        FragBuilder {
+            seq: self.start,
+            cmd: FragCommand::Parity {
+                count: u8::try_from(self.count).unwrap(),
+                len_xor: self.len_xor,
+                body: BufSlice::from_bytes(self.body),
+            },
+        }
+        .build()
+        .unwrap()
+    }
+}
+
+/// The latest pushes from the peer, kept to rebuild the one lost from a parity group
+pub struct ParityCache {
+    pushes: VecDeque<(Seq32, BufSlice)>,
+    // pushes of the next group can come before the parity of this one
+    cap: usize,
+}
+
+impl ParityCache {
+    #[must_use]
+    pub fn new(group_size: usize) -> Self {
+        ParityCache {
+            pushes: VecDeque::new(),
+            cap: group_size * 2,
+        }
+    }
+
+    pub fn insert(&mut self, seq: Seq32, body: BufSlice) {
+        if self.pushes.len() == self.cap {
+            self.pushes.pop_front();
+        }
+        self.pushes.push_back((seq, body));
+    }
+
+    /// The push of the one seq of the group not in the cache
+    ///
+    /// `None` if not exactly one is missing or the lengths do not add up.
+    #[must_use]
+    pub fn recover(
+        &self,
+        start: Seq32,
+        count: u8,
+        len_xor: u32,
+        parity: &BufSlice,
+    ) -> Option<(Seq32, BufSlice)> {
+        let mut missing = None;
+        let mut body = parity.data().to_vec();
+        let mut len = len_xor as usize;
+        for i in 0..count as usize {
+            let seq = start.add_usize(i);
+            match self.pushes.iter().find(|(x, _)| *x == seq) {
+                Some((_, push)) => {
+                    xor_into(&mut body, push.data());
+                    len ^= push.len();
+                }
+                None if missing.is_none() => missing = Some(seq),
+                None => return None,
+            }
+        }
+        let seq = missing?;
+        if len == 0 || body.len() < len {
+            return None;
+        }
+        body.truncate(len);
+        Some((seq, BufSlice::from_bytes(body)))
+    }
+}
+
+/// XOR `data` into `acc`, growing it with zeros to fit
+fn xor_into(acc: &mut Vec<u8>, data: &[u8]) {
+    if acc.len() < data.len() {
+        acc.resize(data.len(), 0);
+    }
+    for (a, b) in acc.iter_mut().zip(data) {
+        *a ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recover() {
+        let bodies = [vec![1, 2, 3], vec![4], vec![5, 6, 7, 8]];
+        let mut group = ParityGroup::new(Seq32::from_u32(10));
+        for body in &bodies {
+            group.add(&Body::Slice(BufSlice::from_bytes(body.clone())));
+        }
+        assert!(group.is_next(Seq32::from_u32(13)));
+        let parity = group.into_frag();
+        let (count, len_xor, parity) = match parity.cmd() {
+            FragCommand::Parity {
+                count,
+                len_xor,
+                body,
+            } => (*count, *len_xor, BufSlice::clone(body)),
+            _ => panic!(),
+        };
+        assert_eq!(count, 3);
+
+        for lost in 0..bodies.len() {
+            let mut cache = ParityCache::new(3);
+            for (i, body) in bodies.iter().enumerate() {
+                if i != lost {
+                    cache.insert(
+                        Seq32::from_u32(10 + i as u32),
+                        BufSlice::from_bytes(body.clone()),
+                    );
+                }
+            }
+            let (seq, body) = cache
+                .recover(Seq32::from_u32(10), count, len_xor, &parity)
+                .unwrap();
+            assert_eq!(seq.to_u32(), 10 + lost as u32);
+            assert_eq!(body.data(), &bodies[lost][..]);
+        }
+
+        // two lost
+        let mut cache = ParityCache::new(3);
+        cache.insert(Seq32::from_u32(10), BufSlice::from_bytes(bodies[0].clone()));
+        assert!(cache
+            .recover(Seq32::from_u32(10), count, len_xor, &parity)
+            .is_none());
+        // none lost
+        cache.insert(Seq32::from_u32(11), BufSlice::from_bytes(bodies[1].clone()));
+        cache.insert(Seq32::from_u32(12), BufSlice::from_bytes(bodies[2].clone()));
+        assert!(cache
+            .recover(Seq32::from_u32(10), count, len_xor, &parity)
+            .is_none());
+    }
+}
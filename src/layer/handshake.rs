@@ -0,0 +1,49 @@
+use crate::utils::Seq32;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+/// The side of a session that opens it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Sends the `Syn` and waits for the `SynAck`
+    Client,
+    /// Waits for a `Syn` and answers it with a `SynAck`
+    Server,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    Idle,
+    SynSent,
+    SynReceived,
+    Established,
+}
+
+/// What the uploader and the downloader of one side share to establish a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handshake {
+    pub role: Role,
+    /// The seq of the first local push
+    pub initial_seq: Seq32,
+    /// Sent in the `Syn` of a client; a server answers with the nonce of the `Syn` instead
+    pub nonce: u64,
+}
+
+impl Handshake {
+    /// Draw the initial seq and the nonce at random
+    #[must_use]
+    pub fn new(role: Role) -> Self {
+        Handshake {
+            role,
+            initial_seq: Seq32::from_u32(random() as u32),
+            nonce: random(),
+        }
+    }
+}
+
+// every `RandomState` is keyed anew
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
@@ -1,76 +1,574 @@
 mod downloader;
+mod fec;
+mod handshake;
+pub mod mux;
 mod observer;
+pub mod shared;
+#[cfg(test)]
+mod sim;
 mod uploader;
 
-use crate::utils::Seq32;
+use crate::{
+    protocol::{auth::PacketAuth, codec::BodyCodec, frag::WireFormat},
+    utils::{InlineVec, Seq32},
+};
 pub use downloader::*;
+pub use handshake::*;
 pub use observer::*;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
 pub use uploader::*;
 
+#[derive(Clone)]
 pub struct Builder {
     pub local_recv_buf_len: usize,
     pub nack_duplicate_threshold_to_activate_fast_retransmit: usize,
+    pub max_fast_retransmit_burst: usize,
     pub ratio_rto_to_one_rtt: f64,
-    pub to_send_queue_len_cap: usize,
+    pub rto_jitter: f64,
+    pub rto_jitter_seed: u64,
+    pub min_rto: Option<Duration>,
+    pub max_rto: Option<Duration>,
+    pub initial_rto: Option<Duration>,
+    pub coalesce_small_writes: bool,
+    /// Stamp outgoing packets and echo the peer's stamps for unambiguous RTT samples
+    pub timestamps: bool,
+    /// Speak to a peer predating the version byte of the packet header
+    pub legacy_header: bool,
+    /// Report conditions that are otherwise tolerated; meant for development
+    pub strict: bool,
+    pub window_update_chunk: usize,
+    pub ack_late_pushes: bool,
+    /// Unit: byte
+    pub to_send_bytes_cap: usize,
+    pub to_send_queue_len_cap: Option<usize>,
+    pub send_available_low_water_mark: Option<usize>,
     pub swnd_size_cap: usize,
     pub mtu: usize,
+    pub max_retransmissions: Option<usize>,
+    pub max_no_ack_duration: Option<Duration>,
+    pub rst_transmissions: usize,
+    pub keepalive_interval: Option<Duration>,
+    pub sack: bool,
+    pub conn_id: Option<u64>,
+    /// Number the outgoing packets so that the peer can drop duplicated ones
+    pub packet_seqs: bool,
+    /// Drop incoming packets whose packet seq has been seen before
+    pub drop_duplicate_packets: bool,
+    /// Tag outgoing packets and drop incoming ones without a valid tag
+    pub auth: Option<Arc<dyn PacketAuth>>,
+    /// How the pushes are laid out in both directions; both peers need the same
+    pub wire_format: WireFormat,
+    /// Top every outgoing packet up to this many bytes with a `Padding` frag
+    pub pad_packets_to: Option<usize>,
+    /// Follow every this many new pushes with a `Parity` frag and rebuild a push lost from
+    /// the groups of the peer; both peers need it
+    pub fec_group_size: Option<usize>,
+    /// Compress the bodies of pushes that it makes shorter and decompress those of the peer;
+    /// both peers need the same
+    pub codec: Option<Arc<dyn BodyCodec>>,
+    /// Leave the pushes received in order to the nack instead of acking each
+    pub cumulative_ack_only: bool,
+    /// Coalesce acks queued close together into `AckBitmap` frags; the peer has to know them
+    pub ack_bitmap: bool,
+    /// Raise the MTU to what the path carries with padded probe packets; the peer has to know
+    /// the `Probe` frag
+    pub mtu_probing: Option<MtuProbing>,
+    /// Drop packets of more frags than this before acting on any
+    pub max_frags_per_packet: Option<usize>,
+    /// Open the session with a `Syn` from the client; `None` starts both sides at seq 0
+    pub handshake: Option<Role>,
 }
 
 impl Builder {
     pub fn build(self) -> Result<(Uploader, Downloader), BuildError> {
+        self.validate()?;
+        let handshake = self.handshake.map(Handshake::new);
         let uploader = UploaderBuilder {
             local_recv_buf_len: self.local_recv_buf_len,
             nack_duplicate_threshold_to_activate_fast_retransmit: self
                 .nack_duplicate_threshold_to_activate_fast_retransmit,
+            max_fast_retransmit_burst: self.max_fast_retransmit_burst,
             ratio_rto_to_one_rtt: self.ratio_rto_to_one_rtt,
+            rto_jitter: self.rto_jitter,
+            rto_jitter_seed: self.rto_jitter_seed,
+            min_rto: self.min_rto,
+            max_rto: self.max_rto,
+            initial_rto: self.initial_rto,
+            coalesce_small_writes: self.coalesce_small_writes,
+            timestamps: self.timestamps,
+            legacy_header: self.legacy_header,
+            strict: self.strict,
+            to_send_bytes_cap: self.to_send_bytes_cap,
             to_send_queue_len_cap: self.to_send_queue_len_cap,
+            send_available_low_water_mark: self.send_available_low_water_mark,
             swnd_size_cap: self.swnd_size_cap,
             mtu: self.mtu,
+            max_retransmissions: self.max_retransmissions,
+            max_no_ack_duration: self.max_no_ack_duration,
+            rst_transmissions: self.rst_transmissions,
+            keepalive_interval: self.keepalive_interval,
+            sack: self.sack,
+            conn_id: self.conn_id,
+            packet_seqs: self.packet_seqs,
+            handshake,
+            initial_seq: None,
+            auth: self.auth.clone(),
+            wire_format: self.wire_format,
+            pad_packets_to: self.pad_packets_to,
+            fec_group_size: self.fec_group_size,
+            codec: self.codec.clone(),
+            cumulative_ack_only: self.cumulative_ack_only,
+            ack_bitmap: self.ack_bitmap,
+            mtu_probing: self.mtu_probing,
         }
         .build()
         .map_err(|e| BuildError::Uploader(e))?;
         let downloader = DownloaderBuilder {
             recv_buf_len: self.local_recv_buf_len,
+            strict: self.strict,
+            window_update_chunk: self.window_update_chunk,
+            ack_late_pushes: self.ack_late_pushes,
+            handshake,
+            initial_seq: None,
+            drop_duplicate_packets: self.drop_duplicate_packets,
+            auth: self.auth,
+            wire_format: self.wire_format,
+            fec_group_size: self.fec_group_size,
+            codec: self.codec,
+            max_frags_per_packet: self.max_frags_per_packet,
+            legacy_header: self.legacy_header,
         }
         .build()
         .map_err(|e| BuildError::Downloader(e))?;
         Ok((uploader, downloader))
     }
 
+    /// Reject field combinations that can never work together
+    pub fn validate(&self) -> Result<(), BuildError> {
+        if self.to_send_bytes_cap == 0 {
+            return Err(BuildError::ToSendBytesCapZero);
+        }
+        if self.to_send_queue_len_cap == Some(0) {
+            return Err(BuildError::ToSendQueueLenCapZero);
+        }
+        if self.swnd_size_cap == 0 {
+            return Err(BuildError::SwndSizeCapZero);
+        }
+        if self.max_fast_retransmit_burst == 0 {
+            return Err(BuildError::FastRetransmitBurstZero);
+        }
+        // there cannot be more duplicate nacks than pushes in flight
+        if self.swnd_size_cap < self.nack_duplicate_threshold_to_activate_fast_retransmit {
+            return Err(BuildError::FastRetransmitThresholdUnreachable);
+        }
+        Ok(())
+    }
+
+    /// Field combinations that work but are likely mistakes
+    #[must_use]
+    pub fn warnings(&self) -> Vec<BuildWarning> {
+        let mut warnings = Vec::new();
+        if self.swnd_size_cap / 2 < self.nack_duplicate_threshold_to_activate_fast_retransmit {
+            warnings.push(BuildWarning::FastRetransmitThresholdHigh);
+        }
+        warnings
+    }
+
+    #[deprecated(note = "use `Default::default()`")]
     pub fn default() -> Self {
+        <Self as Default>::default()
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Self {
         Builder {
             local_recv_buf_len: 1024,
             nack_duplicate_threshold_to_activate_fast_retransmit: 1024 * 1 / 2,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: 1024,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: 1024 * 1024,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: 1024,
             mtu: 1300,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
         }
     }
 }
 
+/// Chainable setters, one per field, for use with `Default::default()`
+impl Builder {
+    #[must_use]
+    pub fn local_recv_buf_len(mut self, local_recv_buf_len: usize) -> Self {
+        self.local_recv_buf_len = local_recv_buf_len;
+        self
+    }
+
+    #[must_use]
+    pub fn nack_duplicate_threshold_to_activate_fast_retransmit(
+        mut self,
+        nack_duplicate_threshold_to_activate_fast_retransmit: usize,
+    ) -> Self {
+        self.nack_duplicate_threshold_to_activate_fast_retransmit =
+            nack_duplicate_threshold_to_activate_fast_retransmit;
+        self
+    }
+
+    #[must_use]
+    pub fn max_fast_retransmit_burst(mut self, max_fast_retransmit_burst: usize) -> Self {
+        self.max_fast_retransmit_burst = max_fast_retransmit_burst;
+        self
+    }
+
+    #[must_use]
+    pub fn ratio_rto_to_one_rtt(mut self, ratio_rto_to_one_rtt: f64) -> Self {
+        self.ratio_rto_to_one_rtt = ratio_rto_to_one_rtt;
+        self
+    }
+
+    #[must_use]
+    pub fn rto_jitter(mut self, rto_jitter: f64) -> Self {
+        self.rto_jitter = rto_jitter;
+        self
+    }
+
+    #[must_use]
+    pub fn rto_jitter_seed(mut self, rto_jitter_seed: u64) -> Self {
+        self.rto_jitter_seed = rto_jitter_seed;
+        self
+    }
+
+    #[must_use]
+    pub fn min_rto(mut self, min_rto: Option<Duration>) -> Self {
+        self.min_rto = min_rto;
+        self
+    }
+
+    #[must_use]
+    pub fn max_rto(mut self, max_rto: Option<Duration>) -> Self {
+        self.max_rto = max_rto;
+        self
+    }
+
+    #[must_use]
+    pub fn initial_rto(mut self, initial_rto: Option<Duration>) -> Self {
+        self.initial_rto = initial_rto;
+        self
+    }
+
+    #[must_use]
+    pub fn coalesce_small_writes(mut self, coalesce_small_writes: bool) -> Self {
+        self.coalesce_small_writes = coalesce_small_writes;
+        self
+    }
+
+    #[must_use]
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    #[must_use]
+    pub fn legacy_header(mut self, legacy_header: bool) -> Self {
+        self.legacy_header = legacy_header;
+        self
+    }
+
+    #[must_use]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    #[must_use]
+    pub fn window_update_chunk(mut self, window_update_chunk: usize) -> Self {
+        self.window_update_chunk = window_update_chunk;
+        self
+    }
+
+    #[must_use]
+    pub fn ack_late_pushes(mut self, ack_late_pushes: bool) -> Self {
+        self.ack_late_pushes = ack_late_pushes;
+        self
+    }
+
+    #[must_use]
+    pub fn to_send_bytes_cap(mut self, to_send_bytes_cap: usize) -> Self {
+        self.to_send_bytes_cap = to_send_bytes_cap;
+        self
+    }
+
+    #[must_use]
+    pub fn to_send_queue_len_cap(mut self, to_send_queue_len_cap: Option<usize>) -> Self {
+        self.to_send_queue_len_cap = to_send_queue_len_cap;
+        self
+    }
+
+    #[must_use]
+    pub fn send_available_low_water_mark(
+        mut self,
+        send_available_low_water_mark: Option<usize>,
+    ) -> Self {
+        self.send_available_low_water_mark = send_available_low_water_mark;
+        self
+    }
+
+    #[must_use]
+    pub fn swnd_size_cap(mut self, swnd_size_cap: usize) -> Self {
+        self.swnd_size_cap = swnd_size_cap;
+        self
+    }
+
+    #[must_use]
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    #[must_use]
+    pub fn max_retransmissions(mut self, max_retransmissions: Option<usize>) -> Self {
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    #[must_use]
+    pub fn max_no_ack_duration(mut self, max_no_ack_duration: Option<Duration>) -> Self {
+        self.max_no_ack_duration = max_no_ack_duration;
+        self
+    }
+
+    #[must_use]
+    pub fn rst_transmissions(mut self, rst_transmissions: usize) -> Self {
+        self.rst_transmissions = rst_transmissions;
+        self
+    }
+
+    #[must_use]
+    pub fn keepalive_interval(mut self, keepalive_interval: Option<Duration>) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
+
+    #[must_use]
+    pub fn sack(mut self, sack: bool) -> Self {
+        self.sack = sack;
+        self
+    }
+
+    #[must_use]
+    pub fn conn_id(mut self, conn_id: Option<u64>) -> Self {
+        self.conn_id = conn_id;
+        self
+    }
+
+    #[must_use]
+    pub fn packet_seqs(mut self, packet_seqs: bool) -> Self {
+        self.packet_seqs = packet_seqs;
+        self
+    }
+
+    #[must_use]
+    pub fn drop_duplicate_packets(mut self, drop_duplicate_packets: bool) -> Self {
+        self.drop_duplicate_packets = drop_duplicate_packets;
+        self
+    }
+
+    #[must_use]
+    pub fn handshake(mut self, handshake: Option<Role>) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    #[must_use]
+    pub fn auth(mut self, auth: Option<Arc<dyn PacketAuth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    #[must_use]
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
+    #[must_use]
+    pub fn pad_packets_to(mut self, pad_packets_to: Option<usize>) -> Self {
+        self.pad_packets_to = pad_packets_to;
+        self
+    }
+
+    #[must_use]
+    pub fn fec_group_size(mut self, fec_group_size: Option<usize>) -> Self {
+        self.fec_group_size = fec_group_size;
+        self
+    }
+
+    #[must_use]
+    pub fn codec(mut self, codec: Option<Arc<dyn BodyCodec>>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    #[must_use]
+    pub fn cumulative_ack_only(mut self, cumulative_ack_only: bool) -> Self {
+        self.cumulative_ack_only = cumulative_ack_only;
+        self
+    }
+
+    #[must_use]
+    pub fn ack_bitmap(mut self, ack_bitmap: bool) -> Self {
+        self.ack_bitmap = ack_bitmap;
+        self
+    }
+
+    #[must_use]
+    pub fn mtu_probing(mut self, mtu_probing: Option<MtuProbing>) -> Self {
+        self.mtu_probing = mtu_probing;
+        self
+    }
+
+    #[must_use]
+    pub fn max_frags_per_packet(mut self, max_frags_per_packet: Option<usize>) -> Self {
+        self.max_frags_per_packet = max_frags_per_packet;
+        self
+    }
+}
+
 #[derive(Debug)]
 pub enum BuildError {
     Downloader(downloader::BuildError),
     Uploader(uploader::BuildError),
+    ToSendBytesCapZero,
+    ToSendQueueLenCapZero,
+    SwndSizeCapZero,
+    FastRetransmitBurstZero,
+    FastRetransmitThresholdUnreachable,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Downloader(e) => write!(f, "downloader: {e}"),
+            BuildError::Uploader(e) => write!(f, "uploader: {e}"),
+            BuildError::ToSendBytesCapZero => write!(f, "`to_send_bytes_cap` zero"),
+            BuildError::ToSendQueueLenCapZero => write!(f, "`to_send_queue_len_cap` zero"),
+            BuildError::SwndSizeCapZero => write!(f, "`swnd_size_cap` zero"),
+            BuildError::FastRetransmitBurstZero => write!(f, "`max_fast_retransmit_burst` zero"),
+            BuildError::FastRetransmitThresholdUnreachable => write!(
+                f,
+                "fast retransmit threshold above `swnd_size_cap`, never reached"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Downloader(e) => Some(e),
+            BuildError::Uploader(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BuildWarning {
+    /// The threshold is above half of `swnd_size_cap`
+    FastRetransmitThresholdHigh,
 }
 
+/// Seqs carried by a single packet; a packet rarely holds more than a few frags
+pub type SeqList = InlineVec<Seq32, 4>;
+
 pub struct SetUploadState {
     pub remote_rwnd_size: u16,
     pub remote_nack: Seq32,
     pub local_next_seq_to_receive: Seq32,
-    pub remote_seqs_to_ack: Vec<Seq32>,
-    pub acked_local_seqs: Vec<Seq32>,
+    pub remote_seqs_to_ack: SeqList,
+    pub acked_local_seqs: SeqList,
     pub local_rwnd_size: usize,
+    /// The timestamp of the packet, to be echoed
+    pub remote_timestamp: Option<u32>,
+    /// The echo of a local timestamp
+    pub timestamp_echo: Option<u32>,
+    /// Tokens of the peer's pings, to be answered with pongs
+    pub pings_to_answer: Vec<u32>,
+    /// The token of the latest pong from the peer
+    pub pong: Option<u32>,
+    /// The ID of an MTU probe from the peer, to be answered with a `ProbeAck`
+    pub probe_to_answer: Option<u32>,
+    /// The ID of a local MTU probe the peer received
+    pub probe_ack: Option<u32>,
+    /// The reordered pushes buffered locally, to be sacked in place of their acks
+    pub remote_ranges_to_sack: Vec<Range<Seq32>>,
+    /// The local pushes the peer reported as buffered
+    pub sacked_local_ranges: Vec<Range<Seq32>>,
+    /// The connection ID in the packet header, for routing the packet to its session
+    pub conn_id: Option<u64>,
+    /// The nonce of a `Syn` from the peer, to be answered with a `SynAck`
+    pub syn_to_answer: Option<u64>,
+    /// The downloader takes pushes; the nack and the rwnd are meaningless before
+    pub is_established: bool,
 }
 
 #[cfg(test)]
 mod tests {
-    use std::time::Instant;
+    use std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    };
 
-    use crate::utils::buf::{BufSlice, BufWtr, OwnedBufWtr};
+    use crate::protocol::packet_hdr::peek;
+    use crate::utils::{
+        buf::{BufPool, BufSlice, BufWtr, OwnedBufWtr},
+        Clock, ManualClock,
+    };
 
-    use super::Builder;
+    use super::{
+        BuildError, BuildWarning, Builder, DownloaderBuilder, HandshakeState, Role, UploaderBuilder,
+    };
+    use crate::protocol::{
+        auth::{constant_time_eq, PacketAuth},
+        codec::{BodyCodec, IdentityCodec},
+        frag::{FragCommand, WireFormat, COMPACT_PUSH_HDR_LEN, PUSH_HDR_LEN},
+        packet_hdr::{LEGACY_PACKET_HDR_LEN, LEGACY_VERSION, PACKET_HDR_LEN},
+        transform::{IdentityTransform, PacketTransform, XorTransform},
+    };
+    use crate::utils::Seq32;
+    use std::{collections::hash_map::DefaultHasher, hash::Hasher, sync::Arc};
 
     const MTU: usize = 1024;
 
@@ -80,20 +578,84 @@ mod tests {
         let (mut upload1, mut download1) = Builder {
             local_recv_buf_len: 2,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
             mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
         }
         .build()
         .unwrap();
         let (mut upload2, mut download2) = Builder {
             local_recv_buf_len: 2,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
             mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
         }
         .build()
         .unwrap();
@@ -114,6 +676,7 @@ mod tests {
             assert_eq!(
                 inflight.data(),
                 vec![
+                    1, // version
                     0, 2, // rwnd
                     0, 0, 0, 0, // nack
                     0, 0, 0, 0, // seq
@@ -139,8 +702,8 @@ mod tests {
 
             packets[0].append_to(&mut inflight).unwrap();
 
-            //                               rwnd] [     nack] [      seq] [cmd
-            assert_eq!(inflight.data(), vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
+            //                               v][rwnd] [     nack] [      seq] [cmd
+            assert_eq!(inflight.data(), vec![1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
 
             let inflight = inflight.into_slice();
             let upload1_changes = download1.write(inflight).unwrap();
@@ -148,26 +711,1960 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_validate_to_send_queue_len_cap() {
+        let mut builder: Builder = Default::default();
+        builder.to_send_queue_len_cap = Some(0);
+        match builder.validate() {
+            Err(BuildError::ToSendQueueLenCapZero) => (),
+            _ => panic!(),
+        }
+
+        builder.to_send_queue_len_cap = None;
+        builder.to_send_bytes_cap = 0;
+        match builder.validate() {
+            Err(BuildError::ToSendBytesCapZero) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_validate_swnd_size_cap() {
+        let mut builder: Builder = Default::default();
+        builder.swnd_size_cap = 0;
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 0;
+        match builder.build() {
+            Err(BuildError::SwndSizeCapZero) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_validate_fast_retransmit_threshold() {
+        let mut builder: Builder = Default::default();
+        builder.swnd_size_cap = 4;
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 5;
+        match builder.validate() {
+            Err(BuildError::FastRetransmitThresholdUnreachable) => (),
+            _ => panic!(),
+        }
+
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 3;
+        assert!(builder.validate().is_ok());
+        assert_eq!(
+            builder.warnings(),
+            vec![BuildWarning::FastRetransmitThresholdHigh]
+        );
+
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 2;
+        assert!(builder.warnings().is_empty());
+        assert!(Builder::warnings(&Default::default()).is_empty());
+    }
+
+    #[test]
+    fn test_build_uploader_error() {
+        let builder = Builder {
+            ratio_rto_to_one_rtt: 0.0,
+            ..Default::default()
+        };
+        match builder.build() {
+            Err(BuildError::Uploader(super::uploader::BuildError::InvalidRtoRatio)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_setters() {
+        let builder = <Builder as Default>::default()
+            .mtu(1400)
+            .swnd_size_cap(4096);
+        assert_eq!(builder.mtu, 1400);
+        assert_eq!(builder.swnd_size_cap, 4096);
+        // the rest keeps the defaults
+        assert_eq!(builder.local_recv_buf_len, 1024);
+        let (uploader, _) = builder.build().unwrap();
+        assert_eq!(uploader.mtu(), 1400);
+
+        let uploader = <UploaderBuilder as Default>::default()
+            .mtu(512)
+            .strict(true)
+            .build()
+            .unwrap();
+        assert_eq!(uploader.mtu(), 512);
+
+        let builder = DownloaderBuilder {
+            strict: true,
+            ..Default::default()
+        }
+        .recv_buf_len(8);
+        assert!(builder.strict);
+        assert_eq!(builder.recv_buf_len, 8);
+        assert_eq!(builder.window_update_chunk, 0);
+        assert!(builder.ack_late_pushes);
+    }
+
+    #[test]
+    fn test_default_struct_update() {
+        let builder = Builder {
+            mtu: 512,
+            ..Default::default()
+        };
+        assert_eq!(builder.mtu, 512);
+        assert_eq!(builder.swnd_size_cap, 1024);
+        let (uploader, _) = builder.build().unwrap();
+        assert_eq!(uploader.mtu(), 512);
+
+        let builder = UploaderBuilder {
+            mtu: 512,
+            ..Default::default()
+        };
+        assert_eq!(builder.build().unwrap().mtu(), 512);
+
+        let builder = DownloaderBuilder {
+            recv_buf_len: 8,
+            ..Default::default()
+        };
+        assert!(!builder.strict);
+        assert!(builder.build().is_ok());
+    }
+
+    #[test]
+    fn test_write_bytes() {
+        let now = Instant::now();
+        let mut upload1 = UploaderBuilder::build(Default::default()).unwrap();
+        let mut download2 = DownloaderBuilder {
+            recv_buf_len: 2,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .build()
+        .unwrap();
+
+        upload1.write_bytes(&[0, 1, 2]).map_err(|_| ()).unwrap();
+        let packets = upload1.emit(&now);
+        assert_eq!(packets.len(), 1);
+        let mut inflight = OwnedBufWtr::new(1024, 0);
+        packets[0].append_to(&mut inflight).unwrap();
+        download2.write(inflight.into_slice()).unwrap();
+
+        let recv2 = download2.emit().unwrap();
+        assert_eq!(recv2.data(), vec![0, 1, 2]);
+    }
+
+    struct Counter(std::sync::atomic::AtomicUsize);
+    impl super::IObserver for Counter {
+        fn notify(&self) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_rwnd_reopened() {
+        let now = Instant::now();
+        let builder = Builder {
+            local_recv_buf_len: 2,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder.build().unwrap();
+        let builder = Builder {
+            local_recv_buf_len: 2,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+        let counter = std::sync::Arc::new(Counter(std::sync::atomic::AtomicUsize::new(0)));
+        let weak = std::sync::Arc::downgrade(&counter);
+        download2.set_on_rwnd_reopened(Some(weak));
+        let reopened = || counter.0.load(std::sync::atomic::Ordering::SeqCst);
+        let deliver = |packets: Vec<crate::protocol::packet::Packet>,
+                       downloader: &mut super::Downloader| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            downloader.write(inflight.into_slice()).unwrap()
+        };
+
+        // fill the receive buffer of 2
+        for i in 0..2 {
+            upload1.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            let state = deliver(upload1.emit(&now), &mut download2);
+            upload2.set_state(state, &now).unwrap();
+            let state = deliver(upload2.emit(&now), &mut download1);
+            assert_eq!(state.remote_rwnd_size, 1 - i as u16);
+            upload1.set_state(state, &now).unwrap();
+        }
+        assert_eq!(download2.rwnd_free(), 0);
+        assert_eq!(upload1.remote_rwnd(), 0);
+        assert_eq!(upload1.swnd_len(), 0);
+        assert!(upload1.srtt().is_some());
+        // nothing is left to be acked
+        assert!(upload1.emit(&now).is_empty());
+        assert!(upload2.emit(&now).is_empty());
+
+        // drain it
+        assert_eq!(download2.emit().unwrap().data(), vec![0]);
+        assert_eq!(reopened(), 1);
+        assert_eq!(download2.emit().unwrap().data(), vec![1]);
+        assert_eq!(reopened(), 1);
+        assert!(download2.should_advertise_window());
+
+        // a bare header carries the window
+        upload2
+            .set_state(download2.window_update().unwrap(), &now)
+            .unwrap();
+        let state = deliver(upload2.emit_window_update(&now), &mut download1);
+        assert_eq!(state.remote_rwnd_size, 2);
+        upload1.set_state(state, &now).unwrap();
+        assert_eq!(upload1.remote_rwnd(), 2);
+
+        // the peer resumes sending with the whole window instead of a single probing push
+        for i in 2..4 {
+            upload1.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            let state = deliver(upload1.emit(&now), &mut download2);
+            upload2.set_state(state, &now).unwrap();
+        }
+        // full again
+        assert_eq!(download2.emit().unwrap().data(), vec![2]);
+        assert_eq!(reopened(), 2);
+        assert_eq!(download2.emit().unwrap().data(), vec![3]);
+        assert_eq!(reopened(), 2);
+    }
+
+    #[test]
+    fn test_on_drained() {
+        let now = Instant::now();
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder.build().unwrap();
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+        let counter = std::sync::Arc::new(Counter(std::sync::atomic::AtomicUsize::new(0)));
+        let weak = std::sync::Arc::downgrade(&counter);
+        upload1.set_on_drained(Some(weak));
+        let drained = || counter.0.load(std::sync::atomic::Ordering::SeqCst);
+        let deliver = |packets: Vec<crate::protocol::packet::Packet>,
+                       downloader: &mut super::Downloader| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            downloader.write(inflight.into_slice()).unwrap()
+        };
+        assert!(upload1.is_drained());
+
+        // learn the rwnd
+        upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        assert!(!upload1.is_drained());
+        let state = deliver(upload1.emit(&now), &mut download2);
+        upload2.set_state(state, &now).unwrap();
+        let state = deliver(upload2.emit(&now), &mut download1);
+        upload1.set_state(state, &now).unwrap();
+        assert_eq!(download2.emit().unwrap().data(), vec![0]);
+        assert_eq!(drained(), 1);
+        assert!(upload1.is_drained());
+
+        // three pushes in flight
+        let mut acks = Vec::new();
+        for i in 1..4 {
+            upload1.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            let state = deliver(upload1.emit(&now), &mut download2);
+            upload2.set_state(state, &now).unwrap();
+            acks.push(upload2.emit(&now));
+        }
+        assert_eq!(upload1.swnd_len(), 3);
+        for (i, packets) in acks.into_iter().enumerate() {
+            assert_eq!(drained(), 1);
+            assert!(!upload1.is_drained());
+            let state = deliver(packets, &mut download1);
+            upload1.set_state(state, &now).unwrap();
+            assert_eq!(download2.emit().unwrap().data(), vec![i as u8 + 1]);
+        }
+        // the final ack
+        assert_eq!(drained(), 2);
+        assert!(upload1.is_drained());
+
+        // the peer still waits for the ack of its push
+        upload2.write_bytes(&[9]).map_err(|_| ()).unwrap();
+        let state = deliver(upload2.emit(&now), &mut download1);
+        upload1.set_state(state, &now).unwrap();
+        assert!(!upload1.is_drained());
+        assert!(!upload1.emit(&now).is_empty());
+        assert!(upload1.is_drained());
+        assert_eq!(drained(), 2);
+    }
+
+    #[test]
+    fn test_write_unreliable() {
+        let clock = ManualClock::new(Instant::now());
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder.build().unwrap();
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+        let deliver = |packets: Vec<crate::protocol::packet::Packet>,
+                       downloader: &mut super::Downloader| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            downloader.write(inflight.into_slice()).unwrap()
+        };
+
+        // learn the rwnd
+        upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        let state = deliver(upload1.emit(&clock.now()), &mut download2);
+        upload2.set_state(state, &clock.now()).unwrap();
+        let state = deliver(upload2.emit(&clock.now()), &mut download1);
+        upload1.set_state(state, &clock.now()).unwrap();
+        assert_eq!(download2.emit().unwrap().data(), vec![0]);
+
+        // dropped
+        upload1
+            .write_unreliable(BufSlice::from_bytes(vec![1]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = upload1.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        assert_eq!(upload1.swnd_len(), 0);
+
+        upload1.write_bytes(&[2]).map_err(|_| ()).unwrap();
+        let state = deliver(upload1.emit(&clock.now()), &mut download2);
+        upload2.set_state(state, &clock.now()).unwrap();
+        assert!(download2.emit().is_none());
+        let state = deliver(upload2.emit(&clock.now()), &mut download1);
+        upload1.set_state(state, &clock.now()).unwrap();
+        assert!(upload1.emit(&clock.now()).is_empty());
+
+        // the skip instead of a retransmission
+        clock.advance(upload1.rto());
+        let packets = upload1.emit(&clock.now());
+        assert_eq!(packets[0].frags().len(), 1);
+        assert_eq!(packets[0].frags()[0].seq().to_u32(), 1);
+        assert!(matches!(
+            packets[0].frags()[0].cmd(),
+            crate::protocol::frag::FragCommand::Skip
+        ));
+        let state = deliver(packets, &mut download2);
+        assert_eq!(state.local_next_seq_to_receive.to_u32(), 3);
+        upload2.set_state(state, &clock.now()).unwrap();
+        assert_eq!(download2.emit().unwrap().data(), vec![2]);
+        assert!(download2.emit().is_none());
+
+        // the nack passing the skipped seq stops the skips
+        upload1.write_bytes(&[3]).map_err(|_| ()).unwrap();
+        let state = deliver(upload1.emit(&clock.now()), &mut download2);
+        upload2.set_state(state, &clock.now()).unwrap();
+        let state = deliver(upload2.emit(&clock.now()), &mut download1);
+        upload1.set_state(state, &clock.now()).unwrap();
+        assert_eq!(download2.emit().unwrap().data(), vec![3]);
+        clock.advance(upload1.rto());
+        assert!(upload1.emit(&clock.now()).is_empty());
+    }
+
+    #[test]
+    fn test_close() {
+        let clock = ManualClock::new(Instant::now());
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder.build().unwrap();
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+        let deliver = |packets: Vec<crate::protocol::packet::Packet>,
+                       downloader: &mut super::Downloader| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            downloader.write(inflight.into_slice()).unwrap()
+        };
+
+        upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        let state = deliver(upload1.emit(&clock.now()), &mut download2);
+        upload2.set_state(state, &clock.now()).unwrap();
+        let state = deliver(upload2.emit(&clock.now()), &mut download1);
+        upload1.set_state(state, &clock.now()).unwrap();
+
+        // dropped
+        upload1.close();
+        assert!(matches!(
+            upload1.write_bytes(&[1]),
+            Err(super::SendError::Closed(()))
+        ));
+        let packets = upload1.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        assert!(matches!(
+            packets[0].frags()[0].cmd(),
+            crate::protocol::frag::FragCommand::Fin
+        ));
+        assert!(!upload1.is_drained());
+        assert_eq!(download2.emit().unwrap().data(), vec![0]);
+        assert!(!download2.is_finished());
+
+        // retransmitted on timeout
+        clock.advance(upload1.rto());
+        let state = deliver(upload1.emit(&clock.now()), &mut download2);
+        assert!(download2.is_finished());
+        assert!(download2.emit().is_none());
+        upload2.set_state(state, &clock.now()).unwrap();
+        let state = deliver(upload2.emit(&clock.now()), &mut download1);
+        upload1.set_state(state, &clock.now()).unwrap();
+        assert!(upload1.is_drained());
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let clock = ManualClock::new(Instant::now());
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            keepalive_interval: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder.build().unwrap();
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+        let encode = |packets: Vec<crate::protocol::packet::Packet>| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            inflight.into_slice()
+        };
+        assert!(!upload2.keepalive_due(&clock.now()));
+
+        upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        let state = download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+        upload2.set_state(state, &clock.now()).unwrap();
+        assert!(!upload1.keepalive_due(&clock.now()));
+
+        // idle
+        clock.advance(Duration::from_secs(10));
+        assert!(upload1.keepalive_due(&clock.now()));
+        let token = upload1.send_ping(&clock.now());
+        assert!(!upload1.keepalive_due(&clock.now()));
+        let state = download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+        assert_eq!(state.pings_to_answer, vec![token]);
+        upload2.set_state(state, &clock.now()).unwrap();
+
+        // echoed
+        clock.advance(Duration::from_millis(50));
+        let packets = upload2.emit(&clock.now());
+        assert!(packets[0]
+            .frags()
+            .iter()
+            .any(|x| matches!(x.cmd(), crate::protocol::frag::FragCommand::Pong { token: t } if *t == token)));
+        let state = download1.write(encode(packets)).unwrap();
+        assert_eq!(state.pong, Some(token));
+        assert!(upload1.last_pong_received().is_none());
+        upload1.set_state(state, &clock.now()).unwrap();
+        assert_eq!(upload1.last_pong_received(), Some(clock.now()));
+        assert!(upload2.is_drained());
+    }
+
+    #[test]
+    fn test_abort() {
+        let clock = ManualClock::new(Instant::now());
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            rst_transmissions: 2,
+            keepalive_interval: None,
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder.build().unwrap();
+        let builder = Builder {
+            local_recv_buf_len: 8,
+            mtu: MTU,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+        let encode = |packets: Vec<crate::protocol::packet::Packet>| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            inflight.into_slice()
+        };
+
+        // learn the rwnd
+        upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        let state = download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+        upload2.set_state(state, &clock.now()).unwrap();
+        let state = download1.write(encode(upload2.emit(&clock.now()))).unwrap();
+        upload1.set_state(state, &clock.now()).unwrap();
+
+        // one push buffered, one in flight and one pending
+        upload1.write_bytes(&[1]).map_err(|_| ()).unwrap();
+        download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+        upload1.write_bytes(&[2]).map_err(|_| ()).unwrap();
+        let _ = upload1.emit(&clock.now());
+        upload1.write_bytes(&[3]).map_err(|_| ()).unwrap();
+        assert_eq!(download2.buffered_len(), 2);
+
+        upload1.abort();
+        assert!(upload1.is_aborted());
+        assert_eq!(upload1.pending_bytes(), 0);
+        assert!(matches!(
+            upload1.write_bytes(&[4]),
+            Err(super::SendError::Closed(()))
+        ));
+        let packets = upload1.emit(&clock.now());
+        assert_eq!(packets[0].frags().len(), 1);
+        assert!(matches!(
+            packets[0].frags()[0].cmd(),
+            crate::protocol::frag::FragCommand::Rst
+        ));
+        let result = download2.write(encode(packets));
+        assert!(matches!(result, Err(super::Error::Reset)));
+        assert!(download2.is_reset());
+        assert_eq!(download2.buffered_len(), 0);
+        assert!(download2.emit().is_none());
+
+        // sent again an RTO later, and no more than configured
+        assert!(upload1.emit(&clock.now()).is_empty());
+        clock.advance(upload1.rto());
+        let result = download2.write(encode(upload1.emit(&clock.now())));
+        assert!(matches!(result, Err(super::Error::Reset)));
+        clock.advance(upload1.rto());
+        assert!(upload1.emit(&clock.now()).is_empty());
+    }
+
+    #[test]
+    fn test_sack() {
+        for sack in [false, true] {
+            let clock = ManualClock::new(Instant::now());
+            let builder = Builder {
+                local_recv_buf_len: 8,
+                mtu: MTU,
+                sack,
+                ..Default::default()
+            };
+            let (mut upload1, mut download1) = builder.build().unwrap();
+            let builder = Builder {
+                local_recv_buf_len: 8,
+                mtu: MTU,
+                sack,
+                ..Default::default()
+            };
+            let (mut upload2, mut download2) = builder.build().unwrap();
+            let encode = |packets: Vec<crate::protocol::packet::Packet>| {
+                assert_eq!(packets.len(), 1);
+                let mut inflight = OwnedBufWtr::new(1024, 0);
+                packets[0].append_to(&mut inflight).unwrap();
+                inflight.into_slice()
+            };
+
+            // learn the rwnd
+            upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+            let state = download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+            upload2.set_state(state, &clock.now()).unwrap();
+            let state = download1.write(encode(upload2.emit(&clock.now()))).unwrap();
+            upload1.set_state(state, &clock.now()).unwrap();
+
+            // 1 is lost
+            upload1.write_bytes(&[1]).map_err(|_| ()).unwrap();
+            let _ = upload1.emit(&clock.now());
+            // 2 arrives but its ack is lost
+            upload1.write_bytes(&[2]).map_err(|_| ()).unwrap();
+            let state = download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+            upload2.set_state(state, &clock.now()).unwrap();
+            let _ = upload2.emit(&clock.now());
+            // 3 arrives and its ack too
+            upload1.write_bytes(&[3]).map_err(|_| ()).unwrap();
+            let state = download2.write(encode(upload1.emit(&clock.now()))).unwrap();
+            upload2.set_state(state, &clock.now()).unwrap();
+            let packets = upload2.emit(&clock.now());
+            let is_sacked = packets[0].frags().iter().any(|frag| {
+                matches!(
+                    frag.cmd(),
+                    crate::protocol::frag::FragCommand::Sack { ranges }
+                        if ranges[..] == [crate::utils::Seq32::from_u32(2)..crate::utils::Seq32::from_u32(4)]
+                )
+            });
+            assert_eq!(is_sacked, sack);
+            let state = download1.write(encode(packets)).unwrap();
+            upload1.set_state(state, &clock.now()).unwrap();
+
+            // only what the peer lacks is retransmitted
+            clock.advance(upload1.rto());
+            let _ = upload1.emit(&clock.now());
+            let retransmissions = match sack {
+                true => 1,
+                false => 2,
+            };
+            assert_eq!(upload1.stat().retransmissions, retransmissions);
+        }
+    }
+
+    #[test]
+    fn test_ack_bitmap() {
+        let clock = ManualClock::new(Instant::now());
+        let (mut upload1, mut download1) = Builder {
+            mtu: MTU,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let (mut upload2, mut download2) = Builder {
+            mtu: MTU,
+            ack_bitmap: true,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut inflight = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut inflight).unwrap();
+            inflight.into_slice()
+        };
+
+        // learn the rwnd
+        upload1.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        for packet in upload1.emit(&clock.now()) {
+            let state = download2.write(encode(packet)).unwrap();
+            upload2.set_state(state, &clock.now()).unwrap();
+        }
+        for packet in upload2.emit(&clock.now()) {
+            let state = download1.write(encode(packet)).unwrap();
+            upload1.set_state(state, &clock.now()).unwrap();
+        }
+
+        // a burst of 64 pushes
+        for i in 1..=64 {
+            upload1.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            for packet in upload1.emit(&clock.now()) {
+                let state = download2.write(encode(packet)).unwrap();
+                upload2.set_state(state, &clock.now()).unwrap();
+            }
+        }
+        assert_eq!(upload2.pending_acks(), 64);
+        let packets = upload2.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        let frags: Vec<_> = packets[0]
+            .frags()
+            .iter()
+            .map(|frag| (frag.seq().to_u32(), frag.cmd()))
+            .collect();
+        assert!(matches!(
+            frags[..],
+            [(
+                1,
+                crate::protocol::frag::FragCommand::AckBitmap { bitmap: u64::MAX }
+            )]
+        ));
+        let state = download1
+            .write(encode(packets.into_iter().next().unwrap()))
+            .unwrap();
+        let acked: Vec<u32> = state
+            .acked_local_seqs
+            .as_slice()
+            .iter()
+            .map(|seq| seq.to_u32())
+            .collect();
+        assert_eq!(acked, (1..=64).collect::<Vec<_>>());
+        upload1.set_state(state, &clock.now()).unwrap();
+        assert_eq!(upload1.bytes_in_flight(), 0);
+    }
+
+    #[test]
+    fn test_conn_id_demux() {
+        let clock = ManualClock::new(Instant::now());
+        let mut clients = Vec::new();
+        for conn_id in [1, 2] {
+            let builder = Builder {
+                mtu: MTU,
+                conn_id: Some(conn_id),
+                ..Default::default()
+            };
+            clients.push(builder.build().unwrap());
+        }
+        let mut sessions: HashMap<u64, (super::Uploader, super::Downloader)> = HashMap::new();
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packet.append_to(&mut inflight).unwrap();
+            inflight.into_slice()
+        };
+
+        for i in 0..3 {
+            // the datagrams of both clients interleave on the one server socket
+            let mut socket = Vec::new();
+            for (upload, _) in &mut clients {
+                let conn_id = upload.conn_id().unwrap() as u8;
+                upload.write_bytes(&[conn_id, i]).map_err(|_| ()).unwrap();
+                socket.extend(upload.emit(&clock.now()).into_iter().map(encode));
+            }
+            for datagram in socket {
+                let hdr = peek(datagram.data()).unwrap();
+                let conn_id = hdr.conn_id().unwrap();
+                let (upload, download) = sessions.entry(conn_id).or_insert_with(|| {
+                    let builder = Builder {
+                        mtu: MTU,
+                        ..Default::default()
+                    };
+                    builder.build().unwrap()
+                });
+                let state = download.write(datagram).unwrap();
+                assert_eq!(state.conn_id, Some(conn_id));
+                upload.set_state(state, &clock.now()).unwrap();
+            }
+
+            // each session answers with the ID of its client
+            for (&conn_id, (upload, _)) in &mut sessions {
+                assert_eq!(upload.conn_id(), Some(conn_id));
+                for datagram in upload.emit(&clock.now()).into_iter().map(encode) {
+                    assert_eq!(peek(datagram.data()).unwrap().conn_id(), Some(conn_id));
+                    let (upload, download) = &mut clients[conn_id as usize - 1];
+                    let state = download.write(datagram).unwrap();
+                    upload.set_state(state, &clock.now()).unwrap();
+                }
+            }
+        }
+        assert_eq!(sessions.len(), 2);
+        for (&conn_id, (_, download)) in &mut sessions {
+            let data: Vec<u8> = download
+                .drain()
+                .iter()
+                .flat_map(|x| x.data().to_vec())
+                .collect();
+            let id = conn_id as u8;
+            assert_eq!(data, vec![id, 0, id, 1, id, 2]);
+        }
+    }
+
+    /// Keyed SipHash; good enough to tell keys and tampering apart in tests
+    pub(super) struct TestAuth(pub(super) u64);
+    impl PacketAuth for TestAuth {
+        fn tag_len(&self) -> usize {
+            8
+        }
+
+        fn sign(&self, data: &[u8], tag: &mut [u8]) {
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u64(self.0);
+            hasher.write(data);
+            tag.copy_from_slice(&hasher.finish().to_be_bytes());
+        }
+
+        fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+            let mut expected = [0; 8];
+            self.sign(data, &mut expected);
+            constant_time_eq(&expected, tag)
+        }
+    }
+
+    #[test]
+    fn test_auth() {
+        let clock = ManualClock::new(Instant::now());
+        let keyed = |key: u64| Builder {
+            mtu: MTU,
+            auth: Some(Arc::new(TestAuth(key))),
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = keyed(1).build().unwrap();
+        let (mut upload2, mut download2) = keyed(1).build().unwrap();
+        let (_, mut download3) = keyed(2).build().unwrap();
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let wire_len = packet.wire_len();
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packet.append_to(&mut inflight).unwrap();
+            assert_eq!(inflight.data_len(), wire_len);
+            inflight.into_slice()
+        };
+
+        upload1.write_bytes(&[1, 2, 3]).map_err(|_| ()).unwrap();
+        let mut packets = upload1.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        let datagram = encode(packets.pop().unwrap());
+
+        // tampered
+        let mut tampered = datagram.data().to_vec();
+        tampered[PACKET_HDR_LEN] ^= 1;
+        let res = download2.write(BufSlice::from_bytes(tampered));
+        assert!(matches!(res, Err(super::Error::Auth)));
+        // signed with another key
+        let res = download3.write(BufSlice::clone(&datagram));
+        assert!(matches!(res, Err(super::Error::Auth)));
+        // untagged
+        let (mut plain, _) = Builder {
+            mtu: MTU,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        plain.write_bytes(&[1, 2, 3]).map_err(|_| ()).unwrap();
+        let untagged = encode(plain.emit(&clock.now()).pop().unwrap());
+        let res = download2.write(untagged);
+        assert!(matches!(res, Err(super::Error::Auth)));
+        let res = download2.write(BufSlice::from_bytes(vec![0; 3]));
+        assert!(matches!(res, Err(super::Error::Auth)));
+        assert_eq!(download2.stat().auth_failures, 3);
+        assert_eq!(download2.stat().packets, 0);
+        assert!(download2.emit().is_none());
+
+        // matching keys
+        let state = download2.write(datagram).unwrap();
+        upload2.set_state(state, &clock.now()).unwrap();
+        assert_eq!(download2.emit().unwrap().data(), [1, 2, 3]);
+        for datagram in upload2.emit(&clock.now()).into_iter().map(encode) {
+            let state = download1.write(datagram).unwrap();
+            upload1.set_state(state, &clock.now()).unwrap();
+        }
+        assert!(upload1.is_drained());
+        assert_eq!(download1.stat().auth_failures, 0);
+    }
+
+    #[test]
+    fn test_transform() {
+        let now = Instant::now();
+        let key = vec![0x5a, 0xc3, 0x17, 0x88];
+        for is_corrupted in [false, true] {
+            let upload_transform = XorTransform::new(key.clone());
+            // every byte of the key differs
+            let download_transform = match is_corrupted {
+                true => XorTransform::new(key.iter().map(|x| !x).collect()),
+                false => XorTransform::new(key.clone()),
+            };
+            let (mut upload1, _) = Builder {
+                mtu: MTU,
+                ..Default::default()
+            }
+            .build()
+            .unwrap();
+            let (_, mut download2) = Builder {
+                mtu: MTU,
+                ..Default::default()
+            }
+            .build()
+            .unwrap();
+            let mut pool = BufPool::new();
+
+            let data: Vec<u8> = (0..MTU / 2).map(|x| x as u8).collect();
+            let mut failures = 0;
+            for chunk in data.chunks(MTU / 8) {
+                upload1.write_bytes(chunk).map_err(|_| ()).unwrap();
+            }
+            for wtr in upload1.emit_transformed(&now, &mut pool, &upload_transform) {
+                // obfuscated on the wire
+                assert!(peek(wtr.data()).map_or(true, |hdr| hdr.rwnd() != MTU as u16));
+                let res = download2.write_transformed(wtr.into_slice(), &download_transform);
+                if let Err(super::Error::Decoding) = res {
+                    failures += 1;
+                }
+            }
+            let delivered: Vec<u8> = download2
+                .drain()
+                .iter()
+                .flat_map(|x| x.data().to_vec())
+                .collect();
+            match is_corrupted {
+                false => {
+                    assert_eq!(failures, 0);
+                    assert_eq!(delivered, data);
+                }
+                true => {
+                    assert_eq!(failures, 1);
+                    assert_eq!(download2.stat().decoding_errors, 1);
+                    assert!(delivered.is_empty());
+                }
+            }
+        }
+
+        // a transform refusing the datagram
+        struct Reject;
+        impl PacketTransform for Reject {
+            fn encode(&self, _wtr: &mut OwnedBufWtr) {}
+
+            fn decode(&self, _slice: &mut BufSlice) -> Result<(), ()> {
+                Err(())
+            }
+        }
+        let (mut upload1, _) = <Builder as Default>::default().build().unwrap();
+        let (_, mut download2) = <Builder as Default>::default().build().unwrap();
+        upload1.write_bytes(&[1]).map_err(|_| ()).unwrap();
+        let mut pool = BufPool::new();
+        for wtr in upload1.emit_transformed(&now, &mut pool, &IdentityTransform) {
+            let res = download2.write_transformed(wtr.into_slice(), &Reject);
+            assert!(matches!(res, Err(super::Error::Decoding)));
+        }
+        assert_eq!(download2.stat().decoding_errors, 1);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let now = Instant::now();
+        let builder = |wire_format: WireFormat| {
+            Builder {
+                mtu: MTU,
+                ..Default::default()
+            }
+            .wire_format(wire_format)
+        };
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut wtr = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+
+        let formats = [WireFormat::V1, WireFormat::V2, WireFormat::V3];
+        let mut datagrams = Vec::new();
+        for wire_format in formats {
+            let (mut upload, _) = builder(wire_format).build().unwrap();
+            upload.write_bytes(&[1]).map_err(|_| ()).unwrap();
+            let mut packets = upload.emit(&now);
+            assert_eq!(packets.len(), 1);
+            assert_eq!(packets[0].format(), wire_format);
+            datagrams.push(encode(packets.pop().unwrap()));
+        }
+        assert_eq!(datagrams[0].len(), PACKET_HDR_LEN + PUSH_HDR_LEN + 1);
+        assert_eq!(
+            datagrams[1].len(),
+            PACKET_HDR_LEN + COMPACT_PUSH_HDR_LEN + 1
+        );
+        // cmd, seq and len of one byte each
+        assert_eq!(datagrams[2].len(), PACKET_HDR_LEN + 3 + 1);
+
+        // the matching downloader takes it and the others reject it
+        for (datagram, wire_format) in datagrams.into_iter().zip(formats) {
+            for other in formats.into_iter().filter(|x| *x != wire_format) {
+                let (_, mut download) = builder(other).build().unwrap();
+                let res = download.write(BufSlice::clone(&datagram));
+                assert!(matches!(res, Err(super::Error::Decoding)));
+            }
+            let (_, mut download) = builder(wire_format).build().unwrap();
+            download.write(datagram).unwrap();
+            assert_eq!(download.emit().unwrap().data(), [1]);
+        }
+
+        // a whole exchange in `V3`, the seqs drifting away from the nacks
+        let (mut upload1, mut download1) = builder(WireFormat::V3).build().unwrap();
+        let (mut upload2, mut download2) = builder(WireFormat::V3).build().unwrap();
+        let mut delivered = Vec::new();
+        for round in 0..50u8 {
+            upload1.write_bytes(&[round; 300]).map_err(|_| ()).unwrap();
+            if round % 5 == 0 {
+                upload2.write_bytes(&[round]).map_err(|_| ()).unwrap();
+            }
+            for packet in upload1.emit(&now) {
+                let state = download2.write(encode(packet)).unwrap();
+                upload2.set_state(state, &now).unwrap();
+            }
+            for packet in upload2.emit(&now) {
+                let state = download1.write(encode(packet)).unwrap();
+                upload1.set_state(state, &now).unwrap();
+            }
+            delivered.extend(download2.drain().iter().flat_map(|x| x.data().to_vec()));
+        }
+        assert_eq!(delivered.len(), 50 * 300);
+        assert_eq!(download1.stat().decoding_errors, 0);
+        assert_eq!(download2.stat().decoding_errors, 0);
+
+        // the MTU is checked against the push header of the format
+        let mtu = PACKET_HDR_LEN + COMPACT_PUSH_HDR_LEN + 1;
+        let builder = |wire_format: WireFormat| {
+            UploaderBuilder {
+                mtu,
+                ..Default::default()
+            }
+            .wire_format(wire_format)
+            .build()
+        };
+        assert!(builder(WireFormat::V2).is_ok());
+        let res = builder(WireFormat::V1);
+        assert!(matches!(res, Err(super::uploader::BuildError::MtuTooSmall)));
+    }
+
+    #[test]
+    fn test_padding() {
+        let now = Instant::now();
+        // the frags other than padding in every packet, and the data delivered
+        let run = |pad_packets_to: Option<usize>| {
+            let builder = || Builder {
+                mtu: MTU,
+                pad_packets_to,
+                ..Default::default()
+            };
+            let (mut upload1, mut download1) = builder().build().unwrap();
+            let (mut upload2, mut download2) = builder().build().unwrap();
+            let mut frags = Vec::new();
+            let mut check = |packet: &crate::protocol::packet::Packet| {
+                // exactly the target if it is the MTU
+                if let Some(pad_packets_to) = pad_packets_to {
+                    assert!(pad_packets_to <= packet.wire_len() && packet.wire_len() <= MTU);
+                }
+                let kinds: Vec<_> = packet
+                    .frags()
+                    .iter()
+                    .filter_map(|frag| match frag.cmd() {
+                        FragCommand::Padding { .. } => None,
+                        FragCommand::Push { body } => Some((frag.seq(), body.len())),
+                        _ => Some((frag.seq(), 0)),
+                    })
+                    .collect();
+                frags.push(kinds);
+            };
+            let encode = |packet: crate::protocol::packet::Packet| {
+                let mut wtr = OwnedBufWtr::new(MTU, 0);
+                packet.append_to(&mut wtr).unwrap();
+                wtr.into_slice()
+            };
+            let mut sent = Vec::new();
+            let mut delivered = Vec::new();
+            for round in 0..40u8 {
+                if round < 20 {
+                    let data: Vec<u8> = (0..round as usize * 97 + 1)
+                        .map(|x| x as u8 ^ round)
+                        .collect();
+                    upload1.write_bytes(&data).map_err(|_| ()).unwrap();
+                    sent.extend(data);
+                }
+                for packet in upload1.emit(&now) {
+                    check(&packet);
+                    let state = download2.write(encode(packet)).unwrap();
+                    upload2.set_state(state, &now).unwrap();
+                }
+                for packet in upload2.emit(&now) {
+                    check(&packet);
+                    let state = download1.write(encode(packet)).unwrap();
+                    upload1.set_state(state, &now).unwrap();
+                }
+                delivered.extend(download2.drain().iter().flat_map(|x| x.data().to_vec()));
+            }
+            assert_eq!(delivered, sent);
+            let padding_bytes = download1.stat().padding_bytes + download2.stat().padding_bytes;
+            (frags, padding_bytes)
+        };
+        let (plain, padding_bytes) = run(None);
+        assert_eq!(padding_bytes, 0);
+        // the padding goes into the room the packets leave anyway
+        let (padded, padding_bytes) = run(Some(MTU));
+        assert_eq!(padded, plain);
+        assert!(padding_bytes != 0);
+        let (padded, _) = run(Some(MTU / 2));
+        assert_eq!(padded.len(), plain.len());
+
+        // within the budget
+        let (mut upload, _) = Builder {
+            mtu: MTU,
+            pad_packets_to: Some(MTU),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        upload.write_bytes(&[1; 10]).map_err(|_| ()).unwrap();
+        let packets = upload.emit_budgeted(&now, MTU / 2);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].wire_len(), MTU / 2);
+    }
+
+    #[test]
+    fn test_fec() {
+        let now = Instant::now();
+        let builder = || Builder {
+            mtu: MTU,
+            fec_group_size: Some(4),
+            ..Default::default()
+        };
+        let (mut upload1, mut download1) = builder().build().unwrap();
+        let (mut upload2, mut download2) = builder().build().unwrap();
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut wtr = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+        let has_push = |packet: &crate::protocol::packet::Packet, seq: u32| {
+            packet.frags().iter().any(|frag| {
+                matches!(frag.cmd(), FragCommand::Push { .. }) && frag.seq().to_u32() == seq
+            })
+        };
+
+        // learn the rwnd of the peer
+        let mut sent = vec![0];
+        upload1.write_bytes(&sent).map_err(|_| ()).unwrap();
+        for packet in upload1.emit(&now) {
+            let state = download2.write(encode(packet)).unwrap();
+            upload2.set_state(state, &now).unwrap();
+        }
+        for packet in upload2.emit(&now) {
+            let state = download1.write(encode(packet)).unwrap();
+            upload1.set_state(state, &now).unwrap();
+        }
+
+        // seqs 1 to 3 complete the group of seq 0; one packet each
+        let data: Vec<u8> = (0..MTU * 3).map(|x| x as u8).collect();
+        upload1.write_bytes(&data).map_err(|_| ()).unwrap();
+        sent.extend(data);
+        let packets = upload1.emit(&now);
+        let parities = packets
+            .iter()
+            .flat_map(|packet| packet.frags())
+            .filter(|frag| matches!(frag.cmd(), FragCommand::Parity { count: 4, .. }))
+            .count();
+        assert_eq!(parities, 1);
+        let mut dropped = 0;
+        for packet in packets {
+            if has_push(&packet, 2) {
+                dropped += 1;
+                continue;
+            }
+            let state = download2.write(encode(packet)).unwrap();
+            upload2.set_state(state, &now).unwrap();
+        }
+        assert_eq!(dropped, 1);
+        assert_eq!(download2.stat().fec_recoveries, 1);
+        let delivered: Vec<u8> = download2
+            .drain()
+            .iter()
+            .flat_map(|x| x.data().to_vec())
+            .collect();
+        assert_eq!(delivered, sent);
+
+        // the rebuilt push is acked like the others
+        for packet in upload2.emit(&now) {
+            let state = download1.write(encode(packet)).unwrap();
+            upload1.set_state(state, &now).unwrap();
+        }
+        let later = now + Duration::from_secs(10);
+        assert!(upload1.emit(&later).is_empty());
+        assert_eq!(upload1.stat().retransmissions, 0);
+    }
+
+    /// Every run of a byte as its length and the byte
+    struct RunLengthCodec;
+    impl BodyCodec for RunLengthCodec {
+        fn compress(&self, data: &[u8]) -> Vec<u8> {
+            let mut out = Vec::new();
+            for run in data.chunk_by(|a, b| a == b) {
+                for chunk in run.chunks(u8::MAX as usize) {
+                    out.extend([chunk.len() as u8, chunk[0]]);
+                }
+            }
+            out
+        }
+
+        fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ()> {
+            if data.len() % 2 != 0 {
+                return Err(());
+            }
+            let mut out = Vec::new();
+            for pair in data.chunks(2) {
+                if pair[0] == 0 {
+                    return Err(());
+                }
+                out.extend(std::iter::repeat(pair[1]).take(pair[0] as usize));
+            }
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn test_codec() {
+        let now = Instant::now();
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut wtr = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+        // the packets taken to deliver `data` and the downloader stat
+        let run = |codecs: [Option<Arc<dyn BodyCodec>>; 2], data: &[u8]| {
+            let [codec1, codec2] = codecs;
+            let (mut upload1, mut download1) = Builder {
+                mtu: MTU,
+                codec: codec1,
+                ..Default::default()
+            }
+            .build()
+            .unwrap();
+            let (mut upload2, mut download2) = Builder {
+                mtu: MTU,
+                codec: codec2,
+                ..Default::default()
+            }
+            .build()
+            .unwrap();
+            upload1.write_bytes(data).map_err(|_| ()).unwrap();
+            let mut delivered = Vec::new();
+            for _ in 0..100 {
+                for packet in upload1.emit(&now) {
+                    let state = download2.write(encode(packet)).unwrap();
+                    upload2.set_state(state, &now).unwrap();
+                }
+                for packet in upload2.emit(&now) {
+                    let state = download1.write(encode(packet)).unwrap();
+                    upload1.set_state(state, &now).unwrap();
+                }
+                delivered.extend(download2.drain().iter().flat_map(|x| x.data().to_vec()));
+            }
+            (delivered, upload1.stat().packets_emitted, download2.stat())
+        };
+
+        let runs: Vec<u8> = (0..20000).map(|x| (x / 100) as u8).collect();
+        let (delivered, plain, _) = run([None, None], &runs);
+        assert_eq!(delivered, runs);
+        // the identity never shrinks a body
+        let identity = || Some(Arc::new(IdentityCodec) as Arc<dyn BodyCodec>);
+        let (delivered, packets, _) = run([identity(), identity()], &runs);
+        assert_eq!(delivered, runs);
+        assert_eq!(packets, plain);
+        let rle = || Some(Arc::new(RunLengthCodec) as Arc<dyn BodyCodec>);
+        let (delivered, packets, _) = run([rle(), rle()], &runs);
+        assert_eq!(delivered, runs);
+        assert!(packets * 4 < plain);
+        // data the codec would grow goes out as it is
+        let noise: Vec<u8> = (0..20000u32)
+            .map(|x| x.wrapping_mul(2654435761) as u8)
+            .collect();
+        let (delivered, packets, _) = run([rle(), rle()], &noise);
+        assert_eq!(delivered, noise);
+        let (_, plain, _) = run([None, None], &noise);
+        assert_eq!(packets, plain);
+
+        // a peer without the codec drops the compressed pushes
+        let (delivered, _, stat) = run([rle(), None], &runs);
+        assert!(delivered.is_empty());
+        assert!(stat.decompression_errors != 0);
+
+        #[cfg(feature = "lz4")]
+        {
+            use crate::protocol::codec::Lz4Codec;
+            let json = br#"{"level":"info","msg":"request served","status":200}"#.repeat(400);
+            let lz4 = || Some(Arc::new(Lz4Codec) as Arc<dyn BodyCodec>);
+            let (delivered, packets, _) = run([lz4(), lz4()], &json);
+            assert_eq!(delivered, json);
+            let (_, plain, _) = run([None, None], &json);
+            assert!(packets * 4 < plain);
+        }
+    }
+
+    #[test]
+    fn test_cumulative_ack_only() {
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut wtr = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+        // packets and bytes emitted by each side and the srtt of side 1; an RTT of 5 ms
+        let run = |cumulative_ack_only: bool, data1: &[u8], data2: &[u8]| {
+            let builder = || Builder {
+                mtu: MTU,
+                cumulative_ack_only,
+                ..Default::default()
+            };
+            let (mut upload1, mut download1) = builder().build().unwrap();
+            let (mut upload2, mut download2) = builder().build().unwrap();
+            upload1.write_bytes(data1).map_err(|_| ()).unwrap();
+            upload2.write_bytes(data2).map_err(|_| ()).unwrap();
+            let mut now = Instant::now();
+            let mut packets = [0, 0];
+            let mut bytes = [0, 0];
+            let mut delivered1 = Vec::new();
+            let mut delivered2 = Vec::new();
+            for _ in 0..200 {
+                for packet in upload1.emit(&now) {
+                    packets[0] += 1;
+                    bytes[0] += packet.wire_len();
+                    let state = download2.write(encode(packet)).unwrap();
+                    upload2.set_state(state, &now).unwrap();
+                }
+                now += Duration::from_millis(5);
+                for packet in upload2.emit(&now) {
+                    packets[1] += 1;
+                    bytes[1] += packet.wire_len();
+                    let state = download1.write(encode(packet)).unwrap();
+                    upload1.set_state(state, &now).unwrap();
+                }
+                now += Duration::from_millis(5);
+                delivered1.extend(download1.drain().iter().flat_map(|x| x.data().to_vec()));
+                delivered2.extend(download2.drain().iter().flat_map(|x| x.data().to_vec()));
+            }
+            assert_eq!(delivered2, data1);
+            assert_eq!(delivered1, data2);
+            assert_eq!(upload1.stat().retransmissions, 0);
+            assert_eq!(upload2.stat().retransmissions, 0);
+            (packets, bytes, upload1.srtt())
+        };
+        let data: Vec<u8> = (0..MTU * 50).map(|x| x as u8).collect();
+
+        // one way: the acks shrink to headers
+        let (packets, bytes, srtt) = run(false, &data, &[]);
+        let (cumulative_packets, cumulative_bytes, cumulative_srtt) = run(true, &data, &[]);
+        assert_eq!(cumulative_packets, packets);
+        assert!(cumulative_bytes[1] < bytes[1]);
+        assert_eq!(srtt, Some(Duration::from_millis(5)));
+        // sampled from the nack alone
+        let srtt = cumulative_srtt.unwrap();
+        assert!(Duration::from_millis(4) < srtt && srtt < Duration::from_millis(6));
+
+        // both ways: no acks take the room of the data
+        let data: Vec<u8> = (0..MTU * 500).map(|x| x as u8).collect();
+        let (packets, _, _) = run(false, &data, &data);
+        let (cumulative_packets, _, _) = run(true, &data, &data);
+        assert!(cumulative_packets[0] + cumulative_packets[1] < packets[0] + packets[1]);
+    }
+
+    #[test]
+    fn test_auth_mtu() {
+        let mtu = PACKET_HDR_LEN + PUSH_HDR_LEN + 1;
+        let builder = || UploaderBuilder {
+            mtu,
+            ..Default::default()
+        };
+        assert!(builder().build().is_ok());
+        let res = builder().auth(Some(Arc::new(TestAuth(1)))).build();
+        assert!(matches!(res, Err(super::uploader::BuildError::MtuTooSmall)));
+
+        // the tag fits into every packet
+        let mut upload = UploaderBuilder {
+            mtu: 64,
+            auth: Some(Arc::new(TestAuth(1))),
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        upload.write_bytes(&[7; 200]).map_err(|_| ()).unwrap();
+        let packets = upload.emit(&ManualClock::new(Instant::now()).now());
+        assert!(!packets.is_empty());
+        for packet in packets {
+            assert!(packet.wire_len() <= 64);
+            let mut wtr = OwnedBufWtr::new(64, 0);
+            packet.append_to(&mut wtr).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let clock = ManualClock::new(Instant::now());
+        let builder = Builder {
+            mtu: MTU,
+            handshake: Some(Role::Client),
+            ..Default::default()
+        };
+        let (mut client_up, mut client_down) = builder.build().unwrap();
+        let builder = Builder {
+            mtu: MTU,
+            handshake: Some(Role::Server),
+            ..Default::default()
+        };
+        let (mut server_up, mut server_down) = builder.build().unwrap();
+        let encode = |packets: Vec<crate::protocol::packet::Packet>| {
+            assert_eq!(packets.len(), 1);
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            inflight.into_slice()
+        };
+
+        // nothing goes out of a server before the syn
+        server_up.write_bytes(&[2]).map_err(|_| ()).unwrap();
+        assert!(server_up.emit(&clock.now()).is_empty());
+        assert_eq!(server_down.handshake_state(), HandshakeState::Idle);
+
+        // the data waits behind the syn
+        client_up.write_bytes(&[1]).map_err(|_| ()).unwrap();
+        let packets = client_up.emit(&clock.now());
+        assert_eq!(packets[0].frags().len(), 1);
+        assert!(matches!(
+            packets[0].frags()[0].cmd(),
+            FragCommand::Syn { .. }
+        ));
+        assert_eq!(client_up.handshake_state(), HandshakeState::SynSent);
+        let state = server_down.write(encode(packets)).unwrap();
+        server_up.set_state(state, &clock.now()).unwrap();
+        assert_eq!(server_up.handshake_state(), HandshakeState::SynReceived);
+
+        // the syn ack is lost along with the push behind it
+        let packets = server_up.emit(&clock.now());
+        assert!(matches!(
+            packets[0].frags()[0].cmd(),
+            FragCommand::SynAck { .. }
+        ));
+        assert!(client_up.emit(&clock.now()).is_empty());
+
+        // a push of an earlier session is not taken
+        let (mut stale_up, _) = <Builder as Default>::default().build().unwrap();
+        stale_up.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        let _ = client_down
+            .write(encode(stale_up.emit(&clock.now())))
+            .unwrap();
+        assert_eq!(client_down.stat().pushes_before_established, 1);
+
+        // the syn is sent again an RTO later and answered again
+        clock.advance(client_up.rto());
+        let state = server_down
+            .write(encode(client_up.emit(&clock.now())))
+            .unwrap();
+        server_up.set_state(state, &clock.now()).unwrap();
+        let state = client_down
+            .write(encode(server_up.emit(&clock.now())))
+            .unwrap();
+        client_up.set_state(state, &clock.now()).unwrap();
+        assert_eq!(client_down.handshake_state(), HandshakeState::Established);
+        assert_eq!(client_up.handshake_state(), HandshakeState::Established);
+
+        let state = server_down
+            .write(encode(client_up.emit(&clock.now())))
+            .unwrap();
+        server_up.set_state(state, &clock.now()).unwrap();
+        assert_eq!(server_down.handshake_state(), HandshakeState::Established);
+        assert_eq!(server_up.handshake_state(), HandshakeState::Established);
+        let state = client_down
+            .write(encode(server_up.emit(&clock.now())))
+            .unwrap();
+        client_up.set_state(state, &clock.now()).unwrap();
+        for (download, data) in [(&mut server_down, [1]), (&mut client_down, [2])] {
+            let received: Vec<u8> = download
+                .drain()
+                .iter()
+                .flat_map(|x| x.data().to_vec())
+                .collect();
+            assert_eq!(received, data);
+        }
+        assert!(client_up.is_drained());
+        assert!(server_up.is_drained());
+    }
+
+    #[test]
+    fn test_half_close() {
+        for (close1, close2) in [(false, false), (true, false), (false, true), (true, true)] {
+            let clock = ManualClock::new(Instant::now());
+            let builder = Builder {
+                local_recv_buf_len: 8,
+                mtu: MTU,
+                ..Default::default()
+            };
+            let (mut upload1, mut download1) = builder.build().unwrap();
+            let builder = Builder {
+                local_recv_buf_len: 8,
+                mtu: MTU,
+                ..Default::default()
+            };
+            let (mut upload2, mut download2) = builder.build().unwrap();
+            let one_way = |from: &mut super::Uploader,
+                           to: &mut super::Downloader,
+                           peer: &mut super::Uploader| {
+                for packet in from.emit(&clock.now()) {
+                    let mut inflight = OwnedBufWtr::new(1024, 0);
+                    packet.append_to(&mut inflight).unwrap();
+                    let state = to.write(inflight.into_slice()).unwrap();
+                    peer.set_state(state, &clock.now()).unwrap();
+                }
+            };
+            let exchange = |upload1: &mut super::Uploader,
+                            download1: &mut super::Downloader,
+                            upload2: &mut super::Uploader,
+                            download2: &mut super::Downloader| {
+                for _ in 0..4 {
+                    one_way(upload1, download2, upload2);
+                    one_way(upload2, download1, upload1);
+                }
+            };
+
+            upload1.write_bytes(&[1]).map_err(|_| ()).unwrap();
+            upload2.write_bytes(&[1]).map_err(|_| ()).unwrap();
+            if close1 {
+                upload1.close();
+            }
+            if close2 {
+                upload2.close();
+            }
+            exchange(&mut upload1, &mut download1, &mut upload2, &mut download2);
+            // not finished while the data before a fin waits to be taken
+            assert_eq!(download1.is_finished(), false);
+            assert_eq!(download2.is_finished(), false);
+
+            // the side still open keeps sending and the closed one keeps acking
+            for (upload, is_closed) in [(&mut upload1, close1), (&mut upload2, close2)] {
+                assert_eq!(upload.is_send_closed(), is_closed);
+                let result = upload.write_bytes(&[2]);
+                match is_closed {
+                    true => assert!(matches!(result, Err(super::SendError::Closed(())))),
+                    false => assert!(result.is_ok()),
+                }
+            }
+            exchange(&mut upload1, &mut download1, &mut upload2, &mut download2);
+            for (download, is_closed) in [(&mut download2, close1), (&mut download1, close2)] {
+                let data: Vec<u8> = download
+                    .drain()
+                    .iter()
+                    .flat_map(|x| x.data().to_vec())
+                    .collect();
+                match is_closed {
+                    true => assert_eq!(data, vec![1]),
+                    false => assert_eq!(data, vec![1, 2]),
+                }
+                assert_eq!(download.is_finished(), is_closed);
+            }
+            assert!(upload1.is_drained());
+            assert!(upload2.is_drained());
+        }
+    }
+
+    #[test]
+    fn test_stat_bytes() {
+        let now = Instant::now();
+        let (mut upload1, mut _download1) = Builder {
+            local_recv_buf_len: 2,
+            nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
+            ratio_rto_to_one_rtt: 1.5,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
+            swnd_size_cap: usize::MAX,
+            mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
+        }
+        .build()
+        .unwrap();
+        let (mut upload2, mut download2) = Builder {
+            local_recv_buf_len: 2,
+            nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
+            ratio_rto_to_one_rtt: 1.5,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
+            swnd_size_cap: usize::MAX,
+            mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
+        }
+        .build()
+        .unwrap();
+
+        // push: 1 -> 2
+        let slice = BufSlice::from_bytes(vec![0, 1, 2]);
+        upload1.write(slice).map_err(|_| ()).unwrap();
+        let packets = upload1.emit(&now);
+        let mut inflight = OwnedBufWtr::new(1024, 0);
+        packets[0].append_to(&mut inflight).unwrap();
+        let upload2_changes = download2.write(inflight.into_slice()).unwrap();
+        upload2.set_state(upload2_changes, &now).unwrap();
+
+        let stat = upload1.stat();
+        assert_eq!(stat.bytes_pushed, 3);
+        assert_eq!(stat.bytes_retransmitted, 0);
+        assert_eq!(stat.packets_emitted, 1);
+        let stat = download2.stat();
+        assert_eq!(stat.bytes_received, 3);
+        assert_eq!(stat.bytes_delivered, 0);
+
+        // retransmit: 1 -> 2
+        let now = now + upload1.rto();
+        let packets = upload1.emit(&now);
+        assert_eq!(packets.len(), 1);
+        let stat = upload1.stat();
+        assert_eq!(stat.bytes_pushed, 6);
+        assert_eq!(stat.bytes_retransmitted, 3);
+        assert_eq!(stat.packets_emitted, 2);
+
+        assert_eq!(download2.emit_max(2).unwrap().data(), vec![0, 1]);
+        assert_eq!(download2.stat().bytes_delivered, 2);
+        assert_eq!(download2.emit_max(2).unwrap().data(), vec![2]);
+        assert_eq!(download2.stat().bytes_delivered, 3);
+    }
+
+    #[test]
+    fn test_initial_seq_wraparound() {
+        let clock = ManualClock::new(Instant::now());
+        let initial_seq = Some(Seq32::from_u32(u32::MAX - 2));
+        let mut upload1 = UploaderBuilder {
+            mtu: MTU,
+            initial_seq,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let mut download2 = DownloaderBuilder {
+            initial_seq,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let (mut upload2, mut download1) = Builder {
+            mtu: MTU,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        let mut seqs = Vec::new();
+        let mut delivered = Vec::new();
+        for round in 0..20 {
+            if round < 10 {
+                upload1.write_bytes(&[round]).map_err(|_| ()).unwrap();
+            }
+            for packet in upload1.emit(&clock.now()) {
+                for frag in packet.frags() {
+                    seqs.push(frag.seq().to_u32());
+                }
+                // the first push past the wraparound is lost once
+                let is_wrapped_push = packet.frags().iter().any(|frag| frag.seq().to_u32() == 0);
+                if is_wrapped_push && seqs.iter().filter(|&&seq| seq == 0).count() == 1 {
+                    continue;
+                }
+                let mut inflight = OwnedBufWtr::new(1024, 0);
+                packet.append_to(&mut inflight).unwrap();
+                let state = download2.write(inflight.into_slice()).unwrap();
+                upload2.set_state(state, &clock.now()).unwrap();
+            }
+            for slice in download2.drain() {
+                delivered.extend_from_slice(slice.data());
+            }
+            for packet in upload2.emit(&clock.now()) {
+                let mut inflight = OwnedBufWtr::new(1024, 0);
+                packet.append_to(&mut inflight).unwrap();
+                let state = download1.write(inflight.into_slice()).unwrap();
+                upload1.set_state(state, &clock.now()).unwrap();
+            }
+            clock.advance(upload1.rto());
+        }
+        assert_eq!(seqs[0], u32::MAX - 2);
+        assert_eq!(seqs.iter().filter(|&&seq| seq == 0).count(), 2);
+        assert_eq!(*seqs.last().unwrap(), 6);
+        assert_eq!(delivered, (0..10).collect::<Vec<u8>>());
+        assert_eq!(download2.stat().next_seq_to_receive, Seq32::from_u32(7));
+        assert!(upload1.is_drained());
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let now = Instant::now();
+        let builder = || Builder {
+            local_recv_buf_len: 8,
+            nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
+            ratio_rto_to_one_rtt: 1.5,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
+            swnd_size_cap: usize::MAX,
+            mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
+        };
+        let (mut upload1, mut download1) = builder().build().unwrap();
+        let (mut upload2, mut download2) = builder().build().unwrap();
+        let journal: Vec<Vec<u8>> = (0..6).map(|i| vec![i; 3]).collect();
+        let mut delivered = Vec::new();
+
+        // push: 1 -> 2; one fragment per journal entry
+        for (i, entry) in journal[..3].iter().enumerate() {
+            upload1
+                .write(BufSlice::from_bytes(entry.clone()))
+                .map_err(|_| ())
+                .unwrap();
+            let packets = upload1.emit(&now);
+            assert_eq!(packets.len(), 1);
+            if i == 2 {
+                // dropped
+                continue;
+            }
+            let mut inflight = OwnedBufWtr::new(1024, 0);
+            packets[0].append_to(&mut inflight).unwrap();
+            let changes = download2.write(inflight.into_slice()).unwrap();
+            upload2.set_state(changes, &now).unwrap();
+            while let Some(slice) = download2.emit() {
+                delivered.extend_from_slice(slice.data());
+            }
+
+            // ack: 1 <- 2
+            for packet in upload2.emit(&now) {
+                let mut inflight = OwnedBufWtr::new(1024, 0);
+                packet.append_to(&mut inflight).unwrap();
+                let changes = download1.write(inflight.into_slice()).unwrap();
+                upload1.set_state(changes, &now).unwrap();
+            }
+        }
+
+        // crash
+        let upload1_checkpoint = upload1.checkpoint();
+        let download2_checkpoint = download2.checkpoint();
+        assert_eq!(upload1_checkpoint.next_seq.to_u32(), 3);
+        assert_eq!(upload1_checkpoint.acked_prefix.to_u32(), 2);
+        assert_eq!(download2_checkpoint.next_seq_to_receive.to_u32(), 2);
+
+        let b = builder();
+        let mut upload1 = UploaderBuilder {
+            local_recv_buf_len: b.local_recv_buf_len,
+            nack_duplicate_threshold_to_activate_fast_retransmit: b
+                .nack_duplicate_threshold_to_activate_fast_retransmit,
+            max_fast_retransmit_burst: b.max_fast_retransmit_burst,
+            ratio_rto_to_one_rtt: b.ratio_rto_to_one_rtt,
+            rto_jitter: b.rto_jitter,
+            rto_jitter_seed: b.rto_jitter_seed,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: b.coalesce_small_writes,
+            timestamps: b.timestamps,
+            legacy_header: false,
+            strict: b.strict,
+            to_send_bytes_cap: b.to_send_bytes_cap,
+            to_send_queue_len_cap: b.to_send_queue_len_cap,
+            send_available_low_water_mark: b.send_available_low_water_mark,
+            swnd_size_cap: b.swnd_size_cap,
+            mtu: b.mtu,
+            max_retransmissions: b.max_retransmissions,
+            max_no_ack_duration: b.max_no_ack_duration,
+            rst_transmissions: b.rst_transmissions,
+            keepalive_interval: b.keepalive_interval,
+            sack: b.sack,
+            conn_id: b.conn_id,
+            packet_seqs: b.packet_seqs,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+        }
+        .resume_from(&upload1_checkpoint)
+        .unwrap();
+        let mut download2 = DownloaderBuilder {
+            recv_buf_len: b.local_recv_buf_len,
+            strict: b.strict,
+            window_update_chunk: b.window_update_chunk,
+            ack_late_pushes: b.ack_late_pushes,
+            handshake: None,
+            initial_seq: None,
+            drop_duplicate_packets: b.drop_duplicate_packets,
+            auth: None,
+            wire_format: WireFormat::V1,
+            fec_group_size: None,
+            codec: None,
+            max_frags_per_packet: None,
+            legacy_header: false,
+        }
+        .resume_from(&download2_checkpoint)
+        .unwrap();
+
+        // re-send from the journal offset of the acked prefix
+        let offset = upload1_checkpoint.acked_prefix.to_u32() as usize;
+        for entry in &journal[offset..] {
+            upload1
+                .write(BufSlice::from_bytes(entry.clone()))
+                .map_err(|_| ())
+                .unwrap();
+            for packet in upload1.emit(&now) {
+                let mut inflight = OwnedBufWtr::new(1024, 0);
+                packet.append_to(&mut inflight).unwrap();
+                let changes = download2.write(inflight.into_slice()).unwrap();
+                upload2.set_state(changes, &now).unwrap();
+            }
+            while let Some(slice) = download2.emit() {
+                delivered.extend_from_slice(slice.data());
+            }
+            for packet in upload2.emit(&now) {
+                let mut inflight = OwnedBufWtr::new(1024, 0);
+                packet.append_to(&mut inflight).unwrap();
+                let changes = download1.write(inflight.into_slice()).unwrap();
+                upload1.set_state(changes, &now).unwrap();
+            }
+        }
+
+        assert_eq!(delivered, journal.concat());
+        assert_eq!(upload1.bytes_in_flight(), 0);
+    }
+
     #[test]
     fn test_rto() {
-        let mut now = Instant::now();
+        let clock = ManualClock::new(Instant::now());
         let (mut upload1, mut _download1) = Builder {
             local_recv_buf_len: 2,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
             mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
         }
         .build()
         .unwrap();
         let (mut upload2, mut download2) = Builder {
             local_recv_buf_len: 2,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            window_update_chunk: 0,
+            ack_late_pushes: true,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
             mtu: MTU,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            drop_duplicate_packets: true,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+            max_frags_per_packet: None,
         }
         .build()
         .unwrap();
@@ -179,7 +2676,7 @@ mod tests {
             upload1.write(slice).map_err(|_| ()).unwrap();
 
             let mut inflight = OwnedBufWtr::new(1024, 0);
-            let packets = upload1.emit(&now);
+            let packets = upload1.emit(&clock.now());
 
             assert_eq!(packets.len(), 1);
 
@@ -188,6 +2685,7 @@ mod tests {
             assert_eq!(
                 inflight.data(),
                 vec![
+                    1, // version
                     0, 2, // rwnd
                     0, 0, 0, 0, // nack
                     0, 0, 0, 0, // seq
@@ -197,32 +2695,38 @@ mod tests {
                 ]
             );
 
+            assert_eq!(upload1.swnd_len(), 1);
+            assert_eq!(upload1.swnd_cap(), usize::MAX);
+            assert_eq!(upload1.srtt(), None);
+
             let inflight = inflight.into_slice();
             let upload2_changes = download2.write(inflight).unwrap();
-            upload2.set_state(upload2_changes, &now).unwrap();
+            upload2.set_state(upload2_changes, &clock.now()).unwrap();
+            assert_eq!(download2.rwnd_free(), 1);
 
             let recv2 = download2.emit().unwrap();
             assert_eq!(recv2.data(), vec![0, 1, 2]);
+            assert_eq!(download2.rwnd_free(), 2);
         }
         // ack: 1 <- 2
         {
             let mut inflight = OwnedBufWtr::new(1024, 0);
-            let packets = upload2.emit(&now);
+            let packets = upload2.emit(&clock.now());
 
             assert_eq!(packets.len(), 1);
 
             packets[0].append_to(&mut inflight).unwrap();
 
-            //                               rwnd] [     nack] [      seq] [cmd
-            assert_eq!(inflight.data(), vec![0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
+            //                               v][rwnd] [     nack] [      seq] [cmd
+            assert_eq!(inflight.data(), vec![1, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 1]);
 
             // dropped
         }
-        now += upload1.rto();
+        clock.advance(upload1.rto());
         // retransmit: 1 -> 2
         {
             let mut inflight = OwnedBufWtr::new(1024, 0);
-            let packets = upload1.emit(&now);
+            let packets = upload1.emit(&clock.now());
 
             assert_eq!(packets.len(), 1);
 
@@ -231,6 +2735,7 @@ mod tests {
             assert_eq!(
                 inflight.data(),
                 vec![
+                    1, // version
                     0, 2, // rwnd
                     0, 0, 0, 0, // nack
                     0, 0, 0, 0, // seq
@@ -241,4 +2746,104 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_legacy_header() {
+        let now = Instant::now();
+        let builder = |legacy_header: bool| {
+            Builder {
+                mtu: MTU,
+                ..Default::default()
+            }
+            .legacy_header(legacy_header)
+        };
+        let encode = |packet: crate::protocol::packet::Packet| {
+            let mut wtr = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+
+        // the legacy header is the current one less the version byte
+        let (mut legacy, _) = builder(true).build().unwrap();
+        legacy.write_bytes(&[1]).map_err(|_| ()).unwrap();
+        let datagram = encode(legacy.emit(&now).pop().unwrap());
+        assert_eq!(datagram.len(), LEGACY_PACKET_HDR_LEN + PUSH_HDR_LEN + 1);
+
+        // a current peer rejects it
+        let (_, mut download) = builder(false).build().unwrap();
+        let res = download.write(BufSlice::clone(&datagram));
+        assert!(matches!(res, Err(super::Error::Decoding)));
+        assert_eq!(download.stat().unsupported_versions, 1);
+        assert_eq!(download.peer_version(), None);
+
+        // a whole exchange between two legacy peers
+        let (mut upload1, mut download1) = builder(true).build().unwrap();
+        let (mut upload2, mut download2) = builder(true).build().unwrap();
+        let mut delivered = Vec::new();
+        for round in 0..20u8 {
+            upload1.write_bytes(&[round; 300]).map_err(|_| ()).unwrap();
+            for packet in upload1.emit(&now) {
+                let state = download2.write(encode(packet)).unwrap();
+                upload2.set_state(state, &now).unwrap();
+            }
+            for packet in upload2.emit(&now) {
+                let state = download1.write(encode(packet)).unwrap();
+                upload1.set_state(state, &now).unwrap();
+            }
+            delivered.extend(download2.drain().iter().flat_map(|x| x.data().to_vec()));
+        }
+        assert_eq!(delivered.len(), 20 * 300);
+        assert_eq!(download1.peer_version(), Some(LEGACY_VERSION));
+        assert_eq!(download2.peer_version(), Some(LEGACY_VERSION));
+        assert_eq!(download2.stat().decoding_errors, 0);
+    }
+
+    #[test]
+    fn test_timestamp_echo() {
+        let clock = ManualClock::new(Instant::now());
+        let builder = Builder {
+            timestamps: true,
+            ..Default::default()
+        };
+        let (mut upload1, _) = builder.build().unwrap();
+        let builder = Builder {
+            timestamps: true,
+            ..Default::default()
+        };
+        let (mut upload2, mut download2) = builder.build().unwrap();
+
+        // push: 1 -> 2, dropped
+        upload1
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = upload1.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].hdr().timestamp().is_some());
+        assert_eq!(packets[0].hdr().timestamp_echo(), None);
+
+        // retransmit: 1 -> 2
+        clock.advance(upload1.rto());
+        let packets = upload1.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        let mut inflight = OwnedBufWtr::new(MTU, 0);
+        packets[0].append_to(&mut inflight).unwrap();
+        let changes = download2.write(inflight.into_slice()).unwrap();
+        upload2.set_state(changes, &clock.now()).unwrap();
+
+        // ack: 1 <- 2
+        clock.advance(std::time::Duration::from_millis(10));
+        let packets = upload2.emit(&clock.now());
+        assert_eq!(packets.len(), 1);
+        assert!(packets[0].hdr().timestamp_echo().is_some());
+        let mut inflight = OwnedBufWtr::new(MTU, 0);
+        packets[0].append_to(&mut inflight).unwrap();
+        let (_, mut download1) = <Builder as Default>::default().build().unwrap();
+        let changes = download1.write(inflight.into_slice()).unwrap();
+        upload1.set_state(changes, &clock.now()).unwrap();
+
+        // the ack of a retransmitted push still gives a sample
+        assert_eq!(upload1.srtt(), Some(std::time::Duration::from_millis(10)));
+        assert_eq!(upload1.swnd_len(), 0);
+    }
 }
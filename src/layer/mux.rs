@@ -0,0 +1,897 @@
+//! Several streams over one path
+//!
+//! Each stream has its own `Uploader` and `Downloader`, so a push lost on one stream holds back
+//! no other. Their frags share the packets, and with them a single packet seq space:
+//!
+//! ```text
+//! (Packet header with a packet seq)
+//! ((Window) (Fragment)*)*
+//! ```
+//!
+//! Every frag carries the ID of its stream behind `STREAM_FLAG`. The frags of a stream follow
+//! its `Window` frag, which holds the nack and the rwnd of the stream in place of those of the
+//! header, zero in a mux packet; a stream spanning several packets opens each with its window.
+//!
+//! A client opens the odd stream IDs and a server the even ones but 0. A stream is accepted on
+//! the first frags of an ID of the peer whose window has a nack of 0, nothing of the stream
+//! received yet, up to `max_streams` streams in all.
+//!
+//! A stream is released once both sides have called `close_stream`, every push including the
+//! `Fin`s has been acked and the data has been taken by `recv`. Its ID is handed out again
+//! only after every other ID of the side.
+
+use super::{BuildError, Builder, Downloader, Role, SendError, SetStateError, Uploader};
+use crate::{
+    protocol::{
+        auth::PacketAuth,
+        frag::{Frag, FragBuilder, FragCommand, WireFormat, STREAM_ID_LEN},
+        packet::{Packet, PacketBuilder},
+        packet_hdr::{PacketHeaderBuilder, PACKET_HDR_LEN, PACKET_SEQ_LEN},
+    },
+    utils::{
+        buf::{BufSlice, OwnedBufWtr},
+        Seq, Seq32,
+    },
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, mem,
+    sync::Arc,
+    time::Instant,
+};
+
+/// Streams a `Mux` holds unless told otherwise by `set_max_streams`
+pub const DEFAULT_MAX_STREAMS: usize = 256;
+
+/// A stream of a `Mux`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamHandle(u16);
+
+impl StreamHandle {
+    #[must_use]
+    pub fn id(&self) -> u16 {
+        self.0
+    }
+}
+
+struct Stream {
+    uploader: Uploader,
+    downloader: Downloader,
+}
+
+impl Stream {
+    /// Both sides are done with it
+    fn is_released(&self) -> bool {
+        self.uploader.is_send_closed()
+            && self.uploader.is_drained()
+            && self.downloader.is_finished()
+    }
+}
+
+pub struct Mux {
+    builder: Builder,
+    role: Role,
+    mtu: usize,
+    wire_format: WireFormat,
+    auth: Option<Arc<dyn PacketAuth>>,
+    max_frags_per_packet: usize,
+    streams: BTreeMap<u16, Stream>,
+    max_streams: usize,
+    next_stream_id: u16,
+    // streams opened by the peer and not yet taken by `accept`
+    accepted: VecDeque<StreamHandle>,
+    // the stream to go first on the next emit
+    cursor: u16,
+    next_packet_seq: Seq32,
+}
+
+impl Mux {
+    /// Every stream is built from `builder`, with its packets shrunk to make room for the
+    /// stream IDs and the window
+    ///
+    /// The streams neither shake hands, probe the MTU, stamp timestamps nor pad their packets;
+    /// the `auth` and the `wire_format` of `builder` apply to the mux packets. `role` only
+    /// splits the stream IDs.
+    pub fn new(builder: Builder, role: Role) -> Result<Self, BuildError> {
+        let mtu = builder.mtu;
+        let wire_format = builder.wire_format;
+        let auth = builder.auth.clone();
+        let tag_len = auth.as_ref().map_or(0, |x| x.tag_len());
+        // a frag of a stream packet alone in a mux packet behind the window
+        let overhead = PACKET_SEQ_LEN
+            + tag_len
+            + wire_format.window_len()
+            + STREAM_ID_LEN * 2
+            + wire_format.max_seq_growth();
+        let mut builder = builder;
+        builder.mtu = mtu.saturating_sub(overhead);
+        builder.handshake = None;
+        builder.mtu_probing = None;
+        // the mux packet has the only header
+        builder.timestamps = false;
+        builder.legacy_header = false;
+        builder.conn_id = None;
+        builder.packet_seqs = false;
+        builder.auth = None;
+        builder.pad_packets_to = None;
+        // fail early on a configuration no stream can be built from
+        let _ = builder.clone().build()?;
+        Ok(Mux {
+            max_frags_per_packet: builder.max_frags_per_packet.unwrap_or(usize::MAX),
+            builder,
+            role,
+            mtu,
+            wire_format,
+            auth,
+            streams: BTreeMap::new(),
+            max_streams: DEFAULT_MAX_STREAMS,
+            next_stream_id: match role {
+                Role::Client => 1,
+                Role::Server => 2,
+            },
+            accepted: VecDeque::new(),
+            cursor: 0,
+            next_packet_seq: Seq32::zero(),
+        })
+    }
+
+    /// Open a stream the peer accepts on its first packet
+    pub fn open_stream(&mut self) -> Result<StreamHandle, Error> {
+        let id = self.free_stream_id().ok_or(Error::StreamIdsExhausted)?;
+        if self.streams.len() >= self.max_streams {
+            return Err(Error::TooManyStreams { id });
+        }
+        self.next_stream_id = next_local_id(id);
+        self.insert_stream(id);
+        Ok(StreamHandle(id))
+    }
+
+    /// A stream the peer has opened since the last call
+    pub fn accept(&mut self) -> Option<StreamHandle> {
+        self.accepted.pop_front()
+    }
+
+    /// End the outgoing side of `stream` after the data written so far
+    ///
+    /// Writes fail from now on. The stream stays open until the peer closes it as well, then
+    /// is released on the `emit` after the last ack.
+    pub fn close_stream(&mut self, stream: StreamHandle) {
+        if let Some(x) = self.streams.get_mut(&stream.0) {
+            x.uploader.close();
+        }
+    }
+
+    /// Cap the streams open on either side; the frags of a new peer stream past it are
+    /// dropped
+    ///
+    /// Streams already open are kept.
+    pub fn set_max_streams(&mut self, max_streams: usize) {
+        self.max_streams = max_streams;
+    }
+
+    /// Streams open on either side
+    #[must_use]
+    pub fn streams(&self) -> Vec<StreamHandle> {
+        self.streams.keys().map(|&id| StreamHandle(id)).collect()
+    }
+
+    pub fn write(
+        &mut self,
+        stream: StreamHandle,
+        slice: BufSlice,
+    ) -> Result<(), SendError<BufSlice>> {
+        match self.streams.get_mut(&stream.0) {
+            Some(x) => x.uploader.write(slice),
+            None => Err(SendError::Closed(slice)),
+        }
+    }
+
+    /// The next slice received in order on `stream`
+    pub fn recv(&mut self, stream: StreamHandle) -> Option<BufSlice> {
+        self.streams.get_mut(&stream.0)?.downloader.emit()
+    }
+
+    /// The uploader of `stream`, for everything not wrapped here
+    #[must_use]
+    pub fn uploader(&self, stream: StreamHandle) -> Option<&Uploader> {
+        self.streams.get(&stream.0).map(|x| &x.uploader)
+    }
+
+    /// The downloader of `stream`, for everything not wrapped here
+    #[must_use]
+    pub fn downloader(&self, stream: StreamHandle) -> Option<&Downloader> {
+        self.streams.get(&stream.0).map(|x| &x.downloader)
+    }
+
+    /// Datagrams of at most `mtu` bytes carrying the frags of every stream
+    ///
+    /// The streams take turns packet by packet, starting from a different one on each call.
+    /// The streams released by it are gone afterwards.
+    #[must_use]
+    pub fn emit(&mut self, now: &Instant) -> Vec<OwnedBufWtr> {
+        let mut queues: Vec<(u16, VecDeque<Packet>)> = Vec::new();
+        let first = self
+            .streams
+            .range(self.cursor..)
+            .chain(self.streams.range(..self.cursor));
+        let ids: Vec<u16> = first.map(|(&id, _)| id).collect();
+        for id in ids {
+            let stream = self.streams.get_mut(&id).unwrap();
+            let packets: VecDeque<Packet> = stream.uploader.emit(now).into();
+            if !packets.is_empty() {
+                queues.push((id, packets));
+            }
+        }
+        if let Some(&(id, _)) = queues.first() {
+            self.cursor = id.wrapping_add(1);
+        }
+
+        let mut datagrams = Vec::new();
+        let mut frags = Vec::new();
+        let mut len = self.hdr_len();
+        while !queues.is_empty() {
+            for (id, packets) in &mut queues {
+                let (hdr, stream_frags) = packets.pop_front().unwrap().into_frags();
+                let window = FragBuilder {
+                    seq: hdr.nack(),
+                    cmd: FragCommand::Window { rwnd: hdr.rwnd() },
+                }
+                .build()
+                .unwrap()
+                .with_stream_id(Some(*id));
+                let window_len = window.len_with(self.wire_format, Seq32::zero());
+                // the window opens the frags of the stream in every packet they span
+                let mut is_window_in = false;
+                for frag in stream_frags {
+                    let frag = frag.with_stream_id(Some(*id));
+                    let mut frag_len = frag.len_with(self.wire_format, Seq32::zero());
+                    if !is_window_in {
+                        frag_len += window_len;
+                    }
+                    if self.mtu < len + frag_len && !frags.is_empty() {
+                        datagrams.push(self.encode(mem::take(&mut frags)));
+                        len = self.hdr_len();
+                        if is_window_in {
+                            frag_len += window_len;
+                            is_window_in = false;
+                        }
+                    }
+                    if !is_window_in {
+                        frags.push(window.clone());
+                        is_window_in = true;
+                    }
+                    frags.push(frag);
+                    len += frag_len;
+                }
+                // a window update
+                if !is_window_in {
+                    if self.mtu < len + window_len && !frags.is_empty() {
+                        datagrams.push(self.encode(mem::take(&mut frags)));
+                        len = self.hdr_len();
+                    }
+                    frags.push(window);
+                    len += window_len;
+                }
+            }
+            queues.retain(|(_, packets)| !packets.is_empty());
+        }
+        if !frags.is_empty() {
+            datagrams.push(self.encode(frags));
+        }
+        self.streams.retain(|_, x| !x.is_released());
+        datagrams
+    }
+
+    /// Hand the frags of `datagram` over to the streams they belong to
+    ///
+    /// Nothing is delivered from a datagram that does not decode. The frags after those a
+    /// stream rejects are still delivered; the first rejection is returned.
+    pub fn input(&mut self, mut datagram: BufSlice, now: &Instant) -> Result<(), Error> {
+        if let Some(auth) = &self.auth {
+            let data_len = datagram.len().checked_sub(auth.tag_len());
+            let is_valid = data_len.is_some_and(|data_len| {
+                let (data, tag) = datagram.data().split_at(data_len);
+                auth.verify(data, tag)
+            });
+            if !is_valid {
+                return Err(Error::Auth);
+            }
+            datagram = datagram.slice(0..data_len.unwrap()).unwrap();
+        }
+        let packet =
+            Packet::from_slice_limited(&mut datagram, self.wire_format, self.max_frags_per_packet)
+                .map_err(|_e| Error::Decoding)?;
+        let packet_seq = packet.hdr().packet_seq();
+        let (_, frags) = packet.into_frags();
+        let mut runs: Vec<(u16, PacketBuilder)> = Vec::new();
+        for frag in frags {
+            let id = frag.stream_id().ok_or(Error::Decoding)?;
+            match frag.cmd() {
+                FragCommand::Window { rwnd } => {
+                    let hdr = PacketHeaderBuilder {
+                        rwnd: *rwnd,
+                        nack: frag.seq(),
+                        timestamp: None,
+                        timestamp_echo: None,
+                        conn_id: None,
+                        packet_seq,
+                    }
+                    .build()
+                    .unwrap();
+                    let frags = Vec::new();
+                    runs.push((id, PacketBuilder { hdr, frags }));
+                }
+                _ => match runs.last_mut() {
+                    Some((run_id, packet)) if *run_id == id => {
+                        packet.frags.push(frag.with_stream_id(None));
+                    }
+                    // not behind the window of its stream
+                    _ => return Err(Error::Decoding),
+                },
+            }
+        }
+
+        let mut result = Ok(());
+        for (id, packet) in runs {
+            if !self.streams.contains_key(&id) {
+                // a released stream unless nothing of ours has been received on it
+                if id == 0 || self.is_local(id) || packet.hdr.nack() != Seq32::zero() {
+                    result = result.and(Err(Error::UnknownStream { id }));
+                    continue;
+                }
+                if self.streams.len() >= self.max_streams {
+                    result = result.and(Err(Error::TooManyStreams { id }));
+                    continue;
+                }
+                self.insert_stream(id);
+                self.accepted.push_back(StreamHandle(id));
+            }
+            let stream = self.streams.get_mut(&id).unwrap();
+            let state = match stream.downloader.write_decoded(packet.build().unwrap()) {
+                Ok(x) => x,
+                Err(e) => {
+                    result = result.and(Err(Error::Stream { id, error: e }));
+                    continue;
+                }
+            };
+            if let Err(e) = stream.uploader.set_state(state, now) {
+                result = result.and(Err(Error::SetState { id, error: e }));
+            }
+        }
+        result
+    }
+
+    /// Bytes of a mux packet without frags
+    #[inline]
+    fn hdr_len(&self) -> usize {
+        let tag_len = self.auth.as_ref().map_or(0, |x| x.tag_len());
+        PACKET_HDR_LEN + PACKET_SEQ_LEN + tag_len
+    }
+
+    fn encode(&mut self, frags: Vec<Frag>) -> OwnedBufWtr {
+        let hdr = PacketHeaderBuilder {
+            rwnd: 0,
+            nack: Seq32::zero(),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: Some(self.next_packet_seq),
+        }
+        .build()
+        .unwrap();
+        self.next_packet_seq.increment();
+        let packet = PacketBuilder { hdr, frags }
+            .build()
+            .unwrap()
+            .with_format(self.wire_format)
+            .with_auth(self.auth.clone());
+        let mut wtr = OwnedBufWtr::new(packet.wire_len(), 0);
+        packet.append_to(&mut wtr).unwrap();
+        wtr
+    }
+
+    #[inline]
+    fn is_local(&self, id: u16) -> bool {
+        let is_odd = id % 2 == 1;
+        is_odd == (self.role == Role::Client)
+    }
+
+    /// The first ID of ours from `next_stream_id` on that no open stream has
+    fn free_stream_id(&self) -> Option<u16> {
+        let mut id = self.next_stream_id;
+        // every ID of ours once
+        for _ in 0..=u16::MAX / 2 {
+            if !self.streams.contains_key(&id) {
+                return Some(id);
+            }
+            id = next_local_id(id);
+        }
+        None
+    }
+
+    fn insert_stream(&mut self, id: u16) {
+        // checked by `new`
+        let (uploader, downloader) = self.builder.clone().build().unwrap();
+        self.streams.insert(
+            id,
+            Stream {
+                uploader,
+                downloader,
+            },
+        );
+    }
+}
+
+/// The ID of the same side after `id`, from 65535 or 65534 back to 1 or 2
+#[inline]
+fn next_local_id(id: u16) -> u16 {
+    match id.wrapping_add(2) {
+        0 => 2,
+        x => x,
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The datagram is not a mux packet, or has a frag outside the frags of a stream
+    Decoding,
+    /// The tag of the datagram does not match
+    Auth,
+    /// Frags of a stream neither open nor being opened by the peer
+    UnknownStream {
+        id: u16,
+    },
+    Stream {
+        id: u16,
+        error: super::Error,
+    },
+    SetState {
+        id: u16,
+        error: SetStateError,
+    },
+    /// Every stream ID of this side is taken
+    StreamIdsExhausted,
+    /// A stream past `max_streams`
+    TooManyStreams {
+        id: u16,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decoding => write!(f, "invalid mux packet"),
+            Error::Auth => write!(f, "invalid tag"),
+            Error::UnknownStream { id } => write!(f, "stream {id} not open"),
+            Error::Stream { id, error } => write!(f, "stream {id}: {error}"),
+            Error::SetState { id, error } => write!(f, "stream {id}: {error}"),
+            Error::StreamIdsExhausted => write!(f, "stream IDs exhausted"),
+            Error::TooManyStreams { id } => write!(f, "stream {id} past the stream limit"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Stream { error, .. } => Some(error),
+            Error::SetState { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Mux, Role, StreamHandle};
+    use crate::{
+        layer::{tests::TestAuth, Builder},
+        protocol::{
+            frag::{FragCommand, WireFormat},
+            packet::Packet,
+        },
+        utils::{
+            buf::{BufSlice, BufWtr},
+            Seq32,
+        },
+    };
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    const MTU: usize = 512;
+
+    fn builder() -> Builder {
+        Builder {
+            mtu: MTU,
+            ..Default::default()
+        }
+    }
+
+    fn write(mux: &mut Mux, stream: StreamHandle, byte: u8) {
+        mux.write(stream, BufSlice::from_bytes(vec![byte]))
+            .map_err(|_| ())
+            .unwrap();
+    }
+
+    fn deliver(from: &mut Mux, to: &mut Mux, now: &Instant) {
+        for datagram in from.emit(now) {
+            to.input(datagram.into_slice(), now).unwrap();
+        }
+    }
+
+    /// Until neither side has anything left to send
+    fn exchange(client: &mut Mux, server: &mut Mux, now: &Instant) {
+        for _ in 0..8 {
+            deliver(client, server, now);
+            deliver(server, client, now);
+        }
+    }
+
+    #[test]
+    fn test_no_head_of_line_blocking() {
+        let now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        let a = client.open_stream().unwrap();
+        let b = client.open_stream().unwrap();
+        assert_eq!((a.id(), b.id()), (1, 3));
+
+        write(&mut client, a, 1);
+        write(&mut client, b, 2);
+        let datagrams = client.emit(&now);
+        // both streams share the datagram
+        assert_eq!(datagrams.len(), 1);
+        server
+            .input(datagrams.into_iter().next().unwrap().into_slice(), &now)
+            .unwrap();
+        let accepted: Vec<u16> = std::iter::from_fn(|| server.accept())
+            .map(|x| x.id())
+            .collect();
+        assert_eq!(accepted, [1, 3]);
+        assert_eq!(server.recv(a).unwrap().data(), [1]);
+        assert_eq!(server.recv(b).unwrap().data(), [2]);
+        deliver(&mut server, &mut client, &now);
+
+        // the next push of `a` is lost
+        write(&mut client, a, 3);
+        assert_eq!(client.emit(&now).len(), 1);
+        write(&mut client, a, 4);
+        write(&mut client, b, 5);
+        deliver(&mut client, &mut server, &now);
+
+        // `b` is delivered while `a` waits for its retransmission
+        assert!(server.recv(a).is_none());
+        assert_eq!(server.recv(b).unwrap().data(), [5]);
+    }
+
+    #[test]
+    fn test_lossy_link() {
+        let mut now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        let streams = [client.open_stream().unwrap(), client.open_stream().unwrap()];
+        let data: Vec<Vec<u8>> = (0..2)
+            .map(|i| (0..MTU * 40).map(|x| (x * (i + 1)) as u8).collect())
+            .collect();
+        let mut written = [0; 2];
+        let mut received = [Vec::new(), Vec::new()];
+        // xorshift64
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut is_lost = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % 5 == 0
+        };
+        for _ in 0..2000 {
+            for (i, &stream) in streams.iter().enumerate() {
+                if written[i] < data[i].len() {
+                    let end = usize::min(written[i] + MTU, data[i].len());
+                    let slice = BufSlice::from_bytes(data[i][written[i]..end].to_vec());
+                    if client.write(stream, slice).is_ok() {
+                        written[i] = end;
+                    }
+                }
+            }
+            for datagram in client.emit(&now) {
+                if !is_lost() {
+                    server.input(datagram.into_slice(), &now).unwrap();
+                }
+            }
+            for datagram in server.emit(&now) {
+                if !is_lost() {
+                    client.input(datagram.into_slice(), &now).unwrap();
+                }
+            }
+            for (i, &stream) in streams.iter().enumerate() {
+                while let Some(slice) = server.recv(stream) {
+                    received[i].extend_from_slice(slice.data());
+                }
+            }
+            if received == data[..] {
+                break;
+            }
+            now += Duration::from_millis(10);
+        }
+        assert_eq!(received, data[..]);
+        for stream in streams {
+            assert!(client.uploader(stream).unwrap().stat().retransmissions != 0);
+        }
+    }
+
+    #[test]
+    fn test_shared_packets() {
+        let now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let a = client.open_stream().unwrap();
+        let b = client.open_stream().unwrap();
+        write(&mut client, a, 1);
+        write(&mut client, b, 2);
+        let datagrams = client.emit(&now);
+        assert_eq!(datagrams.len(), 1);
+        let mut slice = datagrams.into_iter().next().unwrap().into_slice();
+        let packet = Packet::from_slice(&mut slice).unwrap();
+        assert_eq!(packet.hdr().packet_seq(), Some(Seq32::from_u32(0)));
+        let frags: Vec<(Option<u16>, char)> = packet
+            .frags()
+            .iter()
+            .map(|frag| {
+                let kind = match frag.cmd() {
+                    FragCommand::Window { .. } => 'w',
+                    FragCommand::Push { .. } => 'p',
+                    _ => panic!(),
+                };
+                (frag.stream_id(), kind)
+            })
+            .collect();
+        // every stream behind its window, each with a seq space of its own
+        assert_eq!(
+            frags,
+            [
+                (Some(1), 'w'),
+                (Some(1), 'p'),
+                (Some(3), 'w'),
+                (Some(3), 'p'),
+            ]
+        );
+        assert_eq!(packet.frags()[1].seq(), packet.frags()[3].seq());
+
+        // one packet seq space for all the streams
+        let c = client.open_stream().unwrap();
+        write(&mut client, c, 3);
+        let mut slice = client.emit(&now).into_iter().next().unwrap().into_slice();
+        let packet = Packet::from_slice(&mut slice).unwrap();
+        assert_eq!(packet.hdr().packet_seq(), Some(Seq32::from_u32(1)));
+    }
+
+    #[test]
+    fn test_full_packets() {
+        for wire_format in [WireFormat::V1, WireFormat::V2, WireFormat::V3] {
+            for auth in [false, true] {
+                let builder = Builder {
+                    mtu: MTU,
+                    wire_format,
+                    auth: match auth {
+                        true => Some(Arc::new(TestAuth(7))),
+                        false => None,
+                    },
+                    ..Default::default()
+                };
+                let now = Instant::now();
+                let mut client = Mux::new(builder.clone(), Role::Client).unwrap();
+                let mut server = Mux::new(builder, Role::Server).unwrap();
+                // the longest stream IDs and seqs far from the nack of the mux packet
+                client.next_stream_id = u16::MAX;
+                let stream = client.open_stream().unwrap();
+                let data: Vec<u8> = (0..MTU * 4).map(|x| x as u8).collect();
+                client
+                    .write(stream, BufSlice::from_bytes(data.clone()))
+                    .map_err(|_| ())
+                    .unwrap();
+                let mut received = Vec::new();
+                let mut max_len = 0;
+                for _ in 0..8 {
+                    let datagrams = client.emit(&now);
+                    for datagram in datagrams {
+                        max_len = usize::max(max_len, datagram.data_len());
+                        server.input(datagram.into_slice(), &now).unwrap();
+                    }
+                    deliver(&mut server, &mut client, &now);
+                    while let Some(slice) = server.recv(stream) {
+                        received.extend_from_slice(slice.data());
+                    }
+                }
+                assert!(received == data);
+                // filled up to the MTU but for the varints of `V3`
+                assert!(MTU - 16 <= max_len && max_len <= MTU);
+
+                // tampered with
+                write(&mut client, stream, 0);
+                let datagram = client.emit(&now).into_iter().next().unwrap();
+                let mut data = datagram.data().to_vec();
+                *data.last_mut().unwrap() ^= 1;
+                let result = server.input(BufSlice::from_bytes(data), &now);
+                match auth {
+                    true => assert!(matches!(result, Err(Error::Auth))),
+                    false => assert!(matches!(result, Err(Error::Decoding) | Ok(()))),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_frags_outside_streams() {
+        let now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        let stream = client.open_stream().unwrap();
+        write(&mut client, stream, 1);
+        let datagram = client.emit(&now).into_iter().next().unwrap();
+        let mut slice = datagram.into_slice();
+        let packet = Packet::from_slice(&mut slice).unwrap();
+        let (hdr, frags) = packet.into_frags();
+        let encode = |frags| {
+            let packet = crate::protocol::packet::PacketBuilder {
+                hdr: hdr.clone(),
+                frags,
+            }
+            .build()
+            .unwrap();
+            let mut wtr = crate::utils::buf::OwnedBufWtr::new(packet.wire_len(), 0);
+            packet.append_to(&mut wtr).unwrap();
+            wtr.into_slice()
+        };
+
+        // a push without its window
+        let push_only = vec![frags[1].clone()];
+        assert!(matches!(
+            server.input(encode(push_only), &now),
+            Err(Error::Decoding)
+        ));
+        // a push without a stream ID
+        let untagged = vec![frags[0].clone(), frags[1].clone().with_stream_id(None)];
+        assert!(matches!(
+            server.input(encode(untagged), &now),
+            Err(Error::Decoding)
+        ));
+        // behind the window of another stream
+        let other = vec![frags[0].clone(), frags[1].clone().with_stream_id(Some(3))];
+        assert!(matches!(
+            server.input(encode(other), &now),
+            Err(Error::Decoding)
+        ));
+        // nothing is delivered from them
+        assert!(server.accept().is_none());
+        server.input(encode(frags), &now).unwrap();
+        assert_eq!(server.recv(stream).unwrap().data(), [1]);
+    }
+
+    #[test]
+    fn test_close_stream() {
+        let now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        let a = client.open_stream().unwrap();
+        let b = client.open_stream().unwrap();
+        write(&mut client, a, 1);
+        write(&mut client, b, 2);
+        client.close_stream(a);
+        assert!(client.write(a, BufSlice::from_bytes(vec![3])).is_err());
+        exchange(&mut client, &mut server, &now);
+        assert_eq!(server.accept(), Some(a));
+        assert_eq!(server.recv(a).unwrap().data(), [1]);
+        assert!(server.downloader(a).unwrap().is_finished());
+
+        // open until both sides have closed it
+        exchange(&mut client, &mut server, &now);
+        assert_eq!(client.streams(), [a, b]);
+        assert_eq!(server.streams(), [a, b]);
+        server.close_stream(a);
+        exchange(&mut client, &mut server, &now);
+        assert_eq!(client.streams(), [b]);
+        assert_eq!(server.streams(), [b]);
+        assert!(client.recv(a).is_none());
+        assert!(matches!(
+            client.write(a, BufSlice::from_bytes(vec![3])),
+            Err(crate::layer::SendError::Closed(_))
+        ));
+
+        // the ID comes back only after the others
+        assert_eq!(client.open_stream().unwrap().id(), 5);
+    }
+
+    #[test]
+    fn test_released_stream() {
+        let mut now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        let stream = client.open_stream().unwrap();
+        write(&mut client, stream, 1);
+        exchange(&mut client, &mut server, &now);
+        server.accept().unwrap();
+        assert!(server.recv(stream).is_some());
+        server.close_stream(stream);
+        exchange(&mut client, &mut server, &now);
+        client.close_stream(stream);
+        deliver(&mut client, &mut server, &now);
+        // the ack for the fin of the client is lost
+        let _ = server.emit(&now);
+        assert!(server.streams().is_empty());
+        assert_eq!(client.streams(), [stream]);
+
+        // the fin retransmitted does not open the stream again
+        now += Duration::from_secs(10);
+        let datagrams = client.emit(&now);
+        assert!(!datagrams.is_empty());
+        for datagram in datagrams {
+            assert!(matches!(
+                server.input(datagram.into_slice(), &now),
+                Err(Error::UnknownStream { id: 1 })
+            ));
+        }
+        assert!(server.accept().is_none());
+        assert!(server.streams().is_empty());
+    }
+
+    #[test]
+    fn test_stream_ids() {
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        client.next_stream_id = u16::MAX - 2;
+        server.next_stream_id = u16::MAX - 1;
+        let ids: Vec<u16> = (0..3).map(|_| client.open_stream().unwrap().id()).collect();
+        assert_eq!(ids, [u16::MAX - 2, u16::MAX, 1]);
+        let ids: Vec<u16> = (0..2).map(|_| server.open_stream().unwrap().id()).collect();
+        assert_eq!(ids, [u16::MAX - 1, 2]);
+
+        // every ID of the side
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        client.set_max_streams(usize::MAX);
+        for _ in 0..=u16::MAX / 2 {
+            client.open_stream().unwrap();
+        }
+        assert!(matches!(
+            client.open_stream(),
+            Err(Error::StreamIdsExhausted)
+        ));
+        // one taken again past the others
+        client.streams.remove(&7);
+        assert_eq!(client.open_stream().unwrap().id(), 7);
+    }
+
+    #[test]
+    fn test_max_streams() {
+        let now = Instant::now();
+        let mut client = Mux::new(builder(), Role::Client).unwrap();
+        let mut server = Mux::new(builder(), Role::Server).unwrap();
+        server.set_max_streams(2);
+        let streams: Vec<StreamHandle> = (0..3).map(|_| client.open_stream().unwrap()).collect();
+        for (i, &stream) in streams.iter().enumerate() {
+            write(&mut client, stream, i as u8);
+        }
+
+        // the frags of the third stream are dropped
+        let mut result = Ok(());
+        for datagram in client.emit(&now) {
+            result = result.and(server.input(datagram.into_slice(), &now));
+        }
+        assert!(matches!(result, Err(Error::TooManyStreams { id: 5 })));
+        let accepted: Vec<u16> = std::iter::from_fn(|| server.accept())
+            .map(|x| x.id())
+            .collect();
+        assert_eq!(accepted, [1, 3]);
+        assert_eq!(server.recv(streams[0]).unwrap().data(), [0]);
+        assert_eq!(server.recv(streams[1]).unwrap().data(), [1]);
+        assert!(server.recv(streams[2]).is_none());
+
+        // nor can a stream of our own be opened past it
+        assert!(matches!(
+            server.open_stream(),
+            Err(Error::TooManyStreams { id: 2 })
+        ));
+        server.set_max_streams(3);
+        assert_eq!(server.open_stream().unwrap().id(), 2);
+    }
+}
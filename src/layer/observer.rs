@@ -1,3 +1,23 @@
+use crate::utils::Seq32;
+
 pub trait IObserver {
     fn notify(&self);
 }
+
+pub trait IAckObserver {
+    /// `seqs` are the local pushes acked for the first time, explicitly or by the nack
+    fn on_acked(&self, seqs: &[Seq32]);
+}
+
+pub trait IRtoObserver {
+    /// `retransmissions` is the number of pushes retransmitted on timeout by one emit
+    fn on_rto(&self, retransmissions: usize);
+
+    /// `count` is the number of pushes acked by one `set_state` that turned out to be
+    /// retransmitted on a spurious timeout
+    ///
+    /// A congestion controller should undo its reaction to those timeouts.
+    fn on_spurious_rto(&self, count: usize) {
+        let _ = count;
+    }
+}
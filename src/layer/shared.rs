@@ -0,0 +1,400 @@
+//! Share an `Uploader` between the receiving and the sending thread
+//!
+//! The receiving thread decodes packets with its own `Downloader` and passes the result
+//! straight to `SharedUploader::set_state`; the sending thread calls `write` and `emit`.
+//! No channel sits between the `Downloader` and the `Uploader`, and each call holds the lock
+//! only for the duration of the corresponding `Uploader` method.
+//!
+//! ```text
+//! recv thread:  socket -> Downloader::write -> SharedUploader::set_state
+//! send thread:  app -> SharedUploader::write; SharedUploader::emit -> socket
+//! ```
+//!
+//! Observers are notified after the lock is released, so they may call back into the
+//! `SharedUploader`.
+
+use super::{uploader::Stat, IObserver, SendError, SetStateError, SetUploadState, Uploader};
+use crate::{protocol::packet::Packet, utils::buf::BufSlice};
+use std::{
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::Instant,
+};
+
+#[derive(Clone)]
+pub struct SharedUploader {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    uploader: Mutex<Uploader>,
+    on_send_available: Mutex<Option<Weak<dyn IObserver + Send + Sync + 'static>>>,
+}
+
+impl SharedUploader {
+    /// Any `on_send_available` already set on `uploader` is dropped; use `set_on_send_available`
+    /// instead. The other observers are kept.
+    #[must_use]
+    pub fn new(mut uploader: Uploader) -> Self {
+        uploader.set_on_send_available(None);
+        uploader.defer_notifications();
+        SharedUploader {
+            inner: Arc::new(Inner {
+                uploader: Mutex::new(uploader),
+                on_send_available: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Notified after an `emit` makes room in the send queue
+    pub fn set_on_send_available(
+        &self,
+        observer: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    ) {
+        *self.inner.on_send_available.lock().unwrap() = observer;
+    }
+
+    /// For everything not wrapped here
+    ///
+    /// Keep the guard short-lived; the other thread is blocked meanwhile.
+    ///
+    /// # Panics
+    ///
+    /// If another thread panicked while holding the lock.
+    pub fn lock(&self) -> MutexGuard<'_, Uploader> {
+        self.inner.uploader.lock().unwrap()
+    }
+
+    pub fn write(&self, slice: BufSlice) -> Result<(), SendError<BufSlice>> {
+        self.lock().write(slice)
+    }
+
+    pub fn write_partial(&self, slice: BufSlice) -> (usize, Option<BufSlice>) {
+        self.lock().write_partial(slice)
+    }
+
+    pub fn set_state(&self, state: SetUploadState, now: &Instant) -> Result<(), SetStateError> {
+        self.with_notify(|uploader| uploader.set_state(state, now))
+    }
+
+    pub fn set_states(
+        &self,
+        states: impl IntoIterator<Item = SetUploadState>,
+        now: &Instant,
+    ) -> Result<(), SetStateError> {
+        self.with_notify(|uploader| uploader.set_states(states, now))
+    }
+
+    #[must_use]
+    pub fn emit(&self, now: &Instant) -> Vec<Packet> {
+        self.emit_with(now, Uploader::emit)
+    }
+
+    #[must_use]
+    pub fn emit_on_ack(&self, now: &Instant) -> Vec<Packet> {
+        self.emit_with(now, Uploader::emit_on_ack)
+    }
+
+    #[must_use]
+    pub fn emit_on_timer(&self, now: &Instant) -> Vec<Packet> {
+        self.emit_with(now, Uploader::emit_on_timer)
+    }
+
+    pub fn clear_pending(&self) -> usize {
+        self.with_notify(Uploader::clear_pending)
+    }
+
+    #[must_use]
+    pub fn stat(&self) -> Stat {
+        self.lock().stat()
+    }
+
+    #[must_use]
+    pub fn is_broken(&self, now: &Instant) -> bool {
+        self.lock().is_broken(now)
+    }
+
+    fn emit_with(
+        &self,
+        now: &Instant,
+        emit: fn(&mut Uploader, &Instant) -> Vec<Packet>,
+    ) -> Vec<Packet> {
+        self.with_notify(|uploader| emit(uploader, now))
+    }
+
+    /// Run `f` under the lock and notify the observers afterwards of what `f` did
+    fn with_notify<T>(&self, f: impl FnOnce(&mut Uploader) -> T) -> T {
+        let (ret, is_available, notifications) = {
+            let mut uploader = self.lock();
+            let ret = f(&mut uploader);
+            (
+                ret,
+                uploader.take_send_available(),
+                uploader.take_notifications(),
+            )
+        };
+        if is_available {
+            let observer = self.inner.on_send_available.lock().unwrap().clone();
+            if let Some(observer) = observer.and_then(|x| x.upgrade()) {
+                observer.notify();
+            }
+        }
+        notifications.deliver();
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedUploader;
+    use crate::{
+        layer::{Builder, IAckObserver, IObserver, SeqList, SetUploadState, Uploader},
+        utils::{
+            buf::{BufSlice, BufWtr, OwnedBufWtr},
+            Seq32,
+        },
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    const MTU: usize = 512;
+
+    fn builder() -> Builder {
+        let mut builder: Builder = Default::default();
+        builder.mtu = MTU;
+        builder.local_recv_buf_len = 64;
+        builder.swnd_size_cap = 64;
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 8;
+        builder
+    }
+
+    fn encode(packets: Vec<crate::protocol::packet::Packet>) -> Vec<Vec<u8>> {
+        packets
+            .into_iter()
+            .map(|packet| {
+                let mut wtr = OwnedBufWtr::new(MTU, 0);
+                packet.append_to(&mut wtr).unwrap();
+                wtr.data().to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_transfer_two_threads() {
+        let data: Vec<u8> = (0..1024 * 64).map(|i| i as u8).collect();
+
+        let (uploader1, mut downloader1) = builder().build().unwrap();
+        let (mut uploader2, mut downloader2) = builder().build().unwrap();
+        let uploader1 = SharedUploader::new(uploader1);
+        let (wire_tx_12, wire_rx_12) = mpsc::channel::<Vec<u8>>();
+        let (wire_tx_21, wire_rx_21) = mpsc::channel::<Vec<u8>>();
+
+        // sending thread of 1
+        let sending = {
+            let uploader1 = uploader1.clone();
+            let data = data.clone();
+            thread::spawn(move || {
+                let mut written = 0;
+                loop {
+                    if written < data.len() {
+                        let slice = BufSlice::from_bytes(data[written..].to_vec());
+                        let (n, _) = uploader1.write_partial(slice);
+                        written += n;
+                    }
+                    for packet in encode(uploader1.emit(&Instant::now())) {
+                        wire_tx_12.send(packet).unwrap();
+                    }
+                    let is_done = {
+                        let uploader1 = uploader1.lock();
+                        uploader1.pending_bytes() == 0 && uploader1.bytes_in_flight() == 0
+                    };
+                    if written == data.len() && is_done {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        // receiving thread of 1
+        let receiving = {
+            let uploader1 = uploader1.clone();
+            thread::spawn(move || {
+                while let Ok(packet) = wire_rx_21.recv() {
+                    let state = downloader1.write(BufSlice::from_bytes(packet)).unwrap();
+                    uploader1.set_state(state, &Instant::now()).unwrap();
+                }
+            })
+        };
+
+        // 2 runs on this thread
+        let mut received = Vec::new();
+        while !sending.is_finished() {
+            if let Ok(packet) = wire_rx_12.recv_timeout(Duration::from_millis(1)) {
+                let state = downloader2.write(BufSlice::from_bytes(packet)).unwrap();
+                uploader2.set_state(state, &Instant::now()).unwrap();
+            }
+            while let Some(slice) = downloader2.emit() {
+                received.extend_from_slice(slice.data());
+            }
+            for packet in encode(uploader2.emit(&Instant::now())) {
+                wire_tx_21.send(packet).unwrap();
+            }
+        }
+        sending.join().unwrap();
+        drop(wire_tx_21);
+        receiving.join().unwrap();
+
+        assert_eq!(received, data);
+        assert_eq!(uploader1.stat().bytes_in_flight, 0);
+    }
+
+    struct ReentrantObserver {
+        uploader: SharedUploader,
+        notified: AtomicBool,
+        acked: AtomicBool,
+    }
+
+    impl IObserver for ReentrantObserver {
+        fn notify(&self) {
+            // would deadlock if called with the lock held
+            let _ = self.uploader.stat();
+            self.notified.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl IAckObserver for ReentrantObserver {
+        fn on_acked(&self, _seqs: &[Seq32]) {
+            let _ = self.uploader.stat();
+            self.acked.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_observer_outside_lock() {
+        let mut builder = builder();
+        builder.to_send_queue_len_cap = Some(1);
+        let (uploader, _) = builder.build().unwrap();
+        let uploader = SharedUploader::new(uploader);
+        let observer = Arc::new(ReentrantObserver {
+            uploader: uploader.clone(),
+            notified: AtomicBool::new(false),
+            acked: AtomicBool::new(false),
+        });
+        let weak = Arc::downgrade(&observer);
+        uploader.set_on_send_available(Some(weak));
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let _ = uploader.emit(&Instant::now());
+        assert!(observer.notified.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_ack_observers_outside_lock() {
+        let (uploader, _) = builder().build().unwrap();
+        let uploader = SharedUploader::new(uploader);
+        let observer = Arc::new(ReentrantObserver {
+            uploader: uploader.clone(),
+            notified: AtomicBool::new(false),
+            acked: AtomicBool::new(false),
+        });
+        {
+            let mut locked = uploader.lock();
+            locked.set_on_ack(Some(Arc::downgrade(&observer) as _));
+            locked.set_on_drained(Some(Arc::downgrade(&observer) as _));
+        }
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(uploader.emit(&Instant::now()).len(), 1);
+        let state = SetUploadState {
+            remote_rwnd_size: 64,
+            remote_nack: Seq32::from_u32(1),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: SeqList::new(),
+            local_rwnd_size: 64,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &Instant::now()).unwrap();
+        assert!(observer.acked.load(Ordering::SeqCst));
+        // drained
+        assert!(observer.notified.load(Ordering::SeqCst));
+    }
+
+    /// `cargo test --release -- --ignored --nocapture bench_set_state`
+    #[test]
+    #[ignore]
+    fn bench_set_state() {
+        const ROUNDS: u32 = 100_000;
+        let state = || SetUploadState {
+            remote_rwnd_size: 64,
+            remote_nack: crate::utils::Seq32::from_u32(0),
+            local_next_seq_to_receive: crate::utils::Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: SeqList::new(),
+            local_rwnd_size: 64,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+
+        // the bins: a rendezvous channel into the thread owning the uploader
+        let (uploader, _) = builder().build().unwrap();
+        let (tx, rx) = mpsc::sync_channel::<SetUploadState>(0);
+        let owner = thread::spawn(move || {
+            let mut uploader: Uploader = uploader;
+            while let Ok(state) = rx.recv() {
+                uploader.set_state(state, &Instant::now()).unwrap();
+            }
+        });
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            tx.send(state()).unwrap();
+        }
+        let channel = start.elapsed();
+        drop(tx);
+        owner.join().unwrap();
+
+        let (uploader, _) = builder().build().unwrap();
+        let uploader = SharedUploader::new(uploader);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            uploader.set_state(state(), &Instant::now()).unwrap();
+        }
+        let shared = start.elapsed();
+
+        println!(
+            "channel: {:?}/op, shared: {:?}/op",
+            channel / ROUNDS,
+            shared / ROUNDS
+        );
+    }
+}
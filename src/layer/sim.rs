@@ -0,0 +1,377 @@
+//! Deterministic model of a lossy link between two layers, one path per direction
+
+use super::{Builder, Downloader, Uploader};
+use crate::utils::buf::{BufSlice, BufWtr, OwnedBufWtr};
+use std::time::{Duration, Instant};
+
+const MTU: usize = 1300;
+const STEP: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Copy)]
+pub struct PathParams {
+    pub delay: Duration,
+    pub jitter: Duration,
+    /// Probability of dropping a packet
+    pub loss: f64,
+    /// Probability of holding a packet back for another `delay`
+    pub reorder: f64,
+    /// Serialization limit; `None` for unlimited
+    pub bytes_per_sec: Option<u64>,
+    /// Drop packets that would wait longer than this to be serialized
+    pub max_queue_delay: Option<Duration>,
+    /// Drop packets longer than this, like a hop of a smaller MTU
+    pub max_len: Option<usize>,
+}
+
+impl PathParams {
+    pub fn ideal(delay: Duration) -> Self {
+        PathParams {
+            delay,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            reorder: 0.0,
+            bytes_per_sec: None,
+            max_queue_delay: None,
+            max_len: None,
+        }
+    }
+}
+
+struct Rng(u64);
+
+impl Rng {
+    // xorshift64
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct Path {
+    params: PathParams,
+    rng: Rng,
+    busy_until: Instant,
+    in_flight: Vec<(Instant, usize, Vec<u8>)>,
+}
+
+impl Path {
+    fn new(params: PathParams, seed: u64, now: Instant) -> Self {
+        Path {
+            params,
+            rng: Rng(seed),
+            busy_until: now,
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, flow: usize, data: Vec<u8>, now: Instant) {
+        if let Some(max_len) = self.params.max_len {
+            if max_len < data.len() {
+                return;
+            }
+        }
+        let start = Instant::max(now, self.busy_until);
+        if let Some(max_queue_delay) = self.params.max_queue_delay {
+            if max_queue_delay < start.duration_since(now) {
+                return;
+            }
+        }
+        // the packet occupies the path even if it is lost later on
+        if let Some(bytes_per_sec) = self.params.bytes_per_sec {
+            self.busy_until =
+                start + Duration::from_secs_f64(data.len() as f64 / bytes_per_sec as f64);
+        } else {
+            self.busy_until = start;
+        }
+        if self.rng.next_f64() < self.params.loss {
+            return;
+        }
+        let mut arrival =
+            self.busy_until + self.params.delay + self.params.jitter.mul_f64(self.rng.next_f64());
+        if self.rng.next_f64() < self.params.reorder {
+            arrival += self.params.delay;
+        }
+        self.in_flight.push((arrival, flow, data));
+    }
+
+    fn recv(&mut self, now: Instant) -> Vec<(usize, Vec<u8>)> {
+        self.in_flight.sort_by_key(|(arrival, _, _)| *arrival);
+        let arrived = self
+            .in_flight
+            .iter()
+            .take_while(|(arrival, _, _)| *arrival <= now)
+            .count();
+        self.in_flight
+            .drain(..arrived)
+            .map(|(_, flow, data)| (flow, data))
+            .collect()
+    }
+}
+
+struct Endpoint {
+    uploader: Uploader,
+    downloader: Downloader,
+}
+
+impl Endpoint {
+    fn new(rto_jitter: f64, rto_jitter_seed: u64, configure: &dyn Fn(&mut Builder)) -> Self {
+        let mut builder: Builder = Default::default();
+        builder.mtu = MTU;
+        builder.rto_jitter = rto_jitter;
+        builder.rto_jitter_seed = rto_jitter_seed;
+        builder.local_recv_buf_len = 64;
+        builder.swnd_size_cap = 64;
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 8;
+        configure(&mut builder);
+        let (uploader, downloader) = builder.build().unwrap();
+        Endpoint {
+            uploader,
+            downloader,
+        }
+    }
+
+    fn output(&mut self, flow: usize, path: &mut Path, now: Instant) {
+        for packet in self.uploader.emit(&now) {
+            // a probe outgrows the MTU
+            let mut wtr = OwnedBufWtr::new(packet.wire_len(), 0);
+            packet.append_to(&mut wtr).unwrap();
+            path.send(flow, wtr.data().to_vec(), now);
+        }
+    }
+
+    fn input(&mut self, data: Vec<u8>, now: Instant) {
+        let state = match self.downloader.write(BufSlice::from_bytes(data)) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        self.uploader.set_state(state, &now).unwrap();
+    }
+}
+
+pub struct Outcome {
+    pub received: Vec<u8>,
+    pub elapsed: Duration,
+    /// Sender side uploader
+    pub uploader: Uploader,
+}
+
+/// Send `data` over `forward` and get acks back over `backward`
+///
+/// Gives up after `timeout` of simulated time.
+pub fn transfer(
+    data: &[u8],
+    forward: PathParams,
+    backward: PathParams,
+    timeout: Duration,
+) -> Outcome {
+    transfer_with(data, &|_| (), forward, backward, timeout)
+}
+
+/// Same as `transfer` but both ends are built with `configure` applied to the builder
+pub fn transfer_with(
+    data: &[u8],
+    configure: &dyn Fn(&mut Builder),
+    forward: PathParams,
+    backward: PathParams,
+    timeout: Duration,
+) -> Outcome {
+    transfer_competing_with(data, 1, 0.0, configure, forward, backward, timeout)
+        .pop()
+        .unwrap()
+}
+
+/// Send `data` on each of `flows` flows sharing both paths
+pub fn transfer_competing(
+    data: &[u8],
+    flows: usize,
+    rto_jitter: f64,
+    forward: PathParams,
+    backward: PathParams,
+    timeout: Duration,
+) -> Vec<Outcome> {
+    transfer_competing_with(data, flows, rto_jitter, &|_| (), forward, backward, timeout)
+}
+
+fn transfer_competing_with(
+    data: &[u8],
+    flows: usize,
+    rto_jitter: f64,
+    configure: &dyn Fn(&mut Builder),
+    forward: PathParams,
+    backward: PathParams,
+    timeout: Duration,
+) -> Vec<Outcome> {
+    let start = Instant::now();
+    let mut now = start;
+    let mut forward = Path::new(forward, 0x9e37_79b9_7f4a_7c15, now);
+    let mut backward = Path::new(backward, 0x2545_f491_4f6c_dd1d, now);
+    let mut senders: Vec<Endpoint> = (0..flows)
+        .map(|flow| Endpoint::new(rto_jitter, flow as u64, configure))
+        .collect();
+    let mut receivers: Vec<Endpoint> = (0..flows)
+        .map(|flow| Endpoint::new(rto_jitter, flow as u64, configure))
+        .collect();
+
+    let mut written = vec![0; flows];
+    let mut received = vec![Vec::new(); flows];
+    let mut elapsed = vec![None; flows];
+    while now.duration_since(start) < timeout {
+        for flow in 0..flows {
+            if written[flow] < data.len() {
+                let slice = BufSlice::from_bytes(data[written[flow]..].to_vec());
+                let (n, _) = senders[flow].uploader.write_partial(slice);
+                written[flow] += n;
+            }
+            senders[flow].output(flow, &mut forward, now);
+        }
+        for (flow, data) in forward.recv(now) {
+            receivers[flow].input(data, now);
+        }
+        for flow in 0..flows {
+            while let Some(slice) = receivers[flow].downloader.emit() {
+                received[flow].extend_from_slice(slice.data());
+            }
+            receivers[flow].output(flow, &mut backward, now);
+        }
+        for (flow, data) in backward.recv(now) {
+            senders[flow].input(data, now);
+        }
+
+        for flow in 0..flows {
+            if elapsed[flow].is_none()
+                && received[flow].len() == data.len()
+                && senders[flow].uploader.bytes_in_flight() == 0
+            {
+                elapsed[flow] = Some(now.duration_since(start));
+            }
+        }
+        if elapsed.iter().all(|x| x.is_some()) {
+            break;
+        }
+        now += STEP;
+    }
+    senders
+        .into_iter()
+        .zip(received)
+        .zip(elapsed)
+        .map(|((sender, received), elapsed)| Outcome {
+            received,
+            elapsed: elapsed.unwrap_or_else(|| now.duration_since(start)),
+            uploader: sender.uploader,
+        })
+        .collect()
+}
+
+mod tests {
+    use super::{transfer, transfer_competing, transfer_with, PathParams};
+    use crate::layer::MtuProbing;
+    use std::time::Duration;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_ideal() {
+        let data = data(1024 * 64);
+        let path = PathParams::ideal(Duration::from_millis(20));
+        let outcome = transfer(&data, path, path, Duration::from_secs(10));
+        assert_eq!(outcome.received, data);
+        // no faster than a round trip, and no retransmission timeout on the way
+        assert!(Duration::from_millis(40) <= outcome.elapsed);
+        assert!(outcome.elapsed < Duration::from_millis(200));
+        assert_eq!(outcome.uploader.stat().retransmissions, 0);
+    }
+
+    #[test]
+    fn test_ack_path_loss() {
+        let data = data(1024 * 64);
+        let forward = PathParams::ideal(Duration::from_millis(20));
+        let mut backward = PathParams::ideal(Duration::from_millis(20));
+        backward.jitter = Duration::from_millis(5);
+        backward.loss = 0.3;
+        backward.reorder = 0.1;
+        let outcome = transfer(&data, forward, backward, Duration::from_secs(60));
+        assert_eq!(outcome.received, data);
+        assert_eq!(outcome.uploader.bytes_in_flight(), 0);
+        // the later acks cover for the lost ones
+        assert!(outcome.elapsed < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_narrow_ack_path() {
+        let data = data(1024 * 64);
+        let mut forward = PathParams::ideal(Duration::from_millis(20));
+        forward.bytes_per_sec = Some(1024 * 1024);
+        let mut backward = PathParams::ideal(Duration::from_millis(20));
+        // acks queue up behind each other
+        backward.bytes_per_sec = Some(1024 * 4);
+        let outcome = transfer(&data, forward, backward, Duration::from_secs(60));
+        assert_eq!(outcome.received, data);
+
+        let srtt = outcome.uploader.stat().srtt.unwrap();
+        assert!(Duration::from_millis(40) <= srtt);
+        assert!(srtt < Duration::from_secs(2));
+        assert!(outcome.elapsed < Duration::from_secs(2));
+    }
+
+    fn competing_retransmissions(rto_jitter: f64) -> u64 {
+        let data = data(1024 * 64);
+        let mut forward = PathParams::ideal(Duration::from_millis(20));
+        forward.bytes_per_sec = Some(1024 * 256);
+        forward.max_queue_delay = Some(Duration::from_millis(20));
+        let backward = PathParams::ideal(Duration::from_millis(20));
+        let outcomes = transfer_competing(
+            &data,
+            2,
+            rto_jitter,
+            forward,
+            backward,
+            Duration::from_secs(60),
+        );
+        let mut retransmissions = 0;
+        for outcome in outcomes {
+            assert_eq!(outcome.received, data);
+            retransmissions += outcome.uploader.stat().retransmissions;
+        }
+        retransmissions
+    }
+
+    #[test]
+    fn test_competing_rto_jitter() {
+        let plain = competing_retransmissions(0.0);
+        let jittered = competing_retransmissions(0.5);
+        assert!(jittered < plain);
+    }
+
+    #[test]
+    fn test_mtu_probing() {
+        let data = data(1024 * 1024 * 2);
+        let mut forward = PathParams::ideal(Duration::from_millis(20));
+        forward.bytes_per_sec = Some(1024 * 1024);
+        // a hop on the way carries no more than 1400 bytes
+        forward.max_len = Some(1400);
+        let backward = PathParams::ideal(Duration::from_millis(20));
+        let configure = |builder: &mut super::Builder| {
+            builder.mtu_probing = Some(MtuProbing {
+                max_mtu: 1500,
+                interval: Duration::from_secs(600),
+            });
+        };
+        let outcome = transfer_with(
+            &data,
+            &configure,
+            forward,
+            backward,
+            Duration::from_secs(60),
+        );
+        assert_eq!(outcome.received, data);
+        let mtu = outcome.uploader.mtu();
+        assert!(super::MTU < mtu && mtu <= 1400);
+        let stat = outcome.uploader.stat();
+        assert!(stat.probes_acked != 0);
+        assert!(stat.probes_acked < stat.probes_sent);
+    }
+}
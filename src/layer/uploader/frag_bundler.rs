@@ -1,9 +1,17 @@
-use crate::protocol::frag::Frag;
+use crate::{
+    protocol::frag::{Frag, WireFormat},
+    utils::Seq32,
+};
 use std::mem;
 
 pub struct FragBundler {
     each_bundle_space: usize,
+    // the layout the frags are measured in, with the nack of their packets
+    format: WireFormat,
+    nack: Seq32,
     bundles: Vec<Vec<Frag>>,
+    // sum of the frag lengths in `bundles`
+    bundled_len: usize,
 
     loading_bundle: Vec<Frag>,
     loading_len: usize,
@@ -11,26 +19,32 @@ pub struct FragBundler {
 
 impl FragBundler {
     pub fn check_rep(&self) {
+        let mut bundled_len = 0;
         for bundle in &self.bundles {
             let mut len = 0;
             for frag in bundle {
-                len += frag.len();
+                len += frag.len_with(self.format, self.nack);
             }
             assert!(len <= self.each_bundle_space);
+            bundled_len += len;
         }
+        assert_eq!(bundled_len, self.bundled_len);
         let mut len = 0;
         for frag in &self.loading_bundle {
-            len += frag.len();
+            len += frag.len_with(self.format, self.nack);
         }
         assert_eq!(len, self.loading_len);
         assert!(self.loading_len <= self.each_bundle_space);
     }
 
     #[must_use]
-    pub fn new(each_bundle_space: usize) -> Self {
+    pub fn new(each_bundle_space: usize, format: WireFormat, nack: Seq32) -> Self {
         let this = FragBundler {
             each_bundle_space,
+            format,
+            nack,
             bundles: Vec::new(),
+            bundled_len: 0,
             loading_bundle: Vec::new(),
             loading_len: 0,
         };
@@ -39,17 +53,19 @@ impl FragBundler {
     }
 
     pub fn pack(&mut self, frag: Frag) -> Result<(), PackError> {
-        if !(frag.len() <= self.each_bundle_space) {
+        let frag_len = frag.len_with(self.format, self.nack);
+        if !(frag_len <= self.each_bundle_space) {
             return Err(PackError::FragTooLarge);
         }
 
-        if !(frag.len() + self.loading_len <= self.each_bundle_space) {
+        if !(frag_len + self.loading_len <= self.each_bundle_space) {
             let loading_bundle = mem::replace(&mut self.loading_bundle, Vec::new());
             self.bundles.push(loading_bundle);
+            self.bundled_len += self.loading_len;
             self.loading_bundle = Vec::new();
             self.loading_len = 0;
         }
-        self.loading_len += frag.len();
+        self.loading_len += frag_len;
         self.loading_bundle.push(frag);
 
         self.check_rep();
@@ -61,6 +77,29 @@ impl FragBundler {
         self.each_bundle_space - self.loading_len
     }
 
+    /// The largest body of a frag with a `frag_hdr_len` header that `pack` accepts
+    ///
+    /// The frag goes to the loading bundle if a body of one byte fits there and opens a new
+    /// bundle otherwise, so the limit never exceeds one bundle.
+    #[must_use]
+    pub fn body_limit(&self, frag_hdr_len: usize) -> usize {
+        match frag_hdr_len < self.loading_space() {
+            true => self.loading_space() - frag_hdr_len,
+            false => self.each_bundle_space.saturating_sub(frag_hdr_len),
+        }
+    }
+
+    /// Bytes of all the bundles, each with a header of `bundle_hdr_len`, if a frag of `frag_len` were packed
+    #[must_use]
+    pub fn wire_len_after(&self, frag_len: usize, bundle_hdr_len: usize) -> usize {
+        let mut bundles = self.bundles.len() + 1;
+        if self.loading_len != 0 && self.each_bundle_space < frag_len + self.loading_len {
+            // the frag starts a new bundle
+            bundles += 1;
+        }
+        bundles * bundle_hdr_len + self.bundled_len + self.loading_len + frag_len
+    }
+
     #[must_use]
     pub fn into_bundles(mut self) -> Vec<Vec<Frag>> {
         if self.loading_len > 0 {
@@ -78,7 +117,7 @@ pub enum PackError {
 #[cfg(test)]
 mod tests {
     use crate::{
-        protocol::frag::{Body, FragBuilder, FragCommand, ACK_HDR_LEN, PUSH_HDR_LEN},
+        protocol::frag::{Body, FragBuilder, FragCommand, WireFormat, ACK_HDR_LEN, PUSH_HDR_LEN},
         utils::{buf::BufSlice, Seq32},
     };
 
@@ -109,7 +148,11 @@ mod tests {
         .build()
         .unwrap();
 
-        let mut bundler = FragBundler::new(ACK_HDR_LEN + PUSH_HDR_LEN + 1);
+        let mut bundler = FragBundler::new(
+            ACK_HDR_LEN + PUSH_HDR_LEN + 1,
+            WireFormat::V1,
+            Seq32::from_u32(0),
+        );
         bundler.pack(frag1).unwrap();
         assert_eq!(bundler.bundles.len(), 0);
         bundler.pack(frag2).unwrap();
@@ -122,4 +165,59 @@ mod tests {
         assert_eq!(bundles[0].len(), 2);
         assert_eq!(bundles[1].len(), 1);
     }
+
+    #[test]
+    fn test_wire_len_after() {
+        let ack = || {
+            FragBuilder {
+                seq: Seq32::from_u32(1),
+                cmd: FragCommand::Ack,
+            }
+            .build()
+            .unwrap()
+        };
+
+        let mut bundler = FragBundler::new(ACK_HDR_LEN * 2, WireFormat::V1, Seq32::from_u32(0));
+        assert_eq!(bundler.wire_len_after(ACK_HDR_LEN, 1), 1 + ACK_HDR_LEN);
+        bundler.pack(ack()).unwrap();
+        assert_eq!(bundler.wire_len_after(ACK_HDR_LEN, 1), 1 + ACK_HDR_LEN * 2);
+        bundler.pack(ack()).unwrap();
+        assert_eq!(bundler.wire_len_after(ACK_HDR_LEN, 1), 2 + ACK_HDR_LEN * 3);
+        bundler.pack(ack()).unwrap();
+        assert_eq!(bundler.wire_len_after(ACK_HDR_LEN, 1), 2 + ACK_HDR_LEN * 4);
+    }
+
+    #[test]
+    fn test_body_limit() {
+        let ack = || {
+            FragBuilder {
+                seq: Seq32::from_u32(1),
+                cmd: FragCommand::Ack,
+            }
+            .build()
+            .unwrap()
+        };
+
+        let mut bundler = FragBundler::new(
+            ACK_HDR_LEN + PUSH_HDR_LEN + 1,
+            WireFormat::V1,
+            Seq32::from_u32(0),
+        );
+        assert_eq!(bundler.body_limit(PUSH_HDR_LEN), ACK_HDR_LEN + 1);
+        bundler.pack(ack()).unwrap();
+        assert_eq!(bundler.body_limit(PUSH_HDR_LEN), 1);
+        bundler.pack(ack()).unwrap();
+        // the loading bundle is left with less than a push header
+        assert_eq!(bundler.body_limit(PUSH_HDR_LEN), ACK_HDR_LEN + 1);
+        bundler.pack(ack()).unwrap();
+        // full
+        assert_eq!(bundler.loading_space(), 0);
+        assert_eq!(bundler.body_limit(PUSH_HDR_LEN), ACK_HDR_LEN + 1);
+
+        assert_eq!(
+            FragBundler::new(PUSH_HDR_LEN, WireFormat::V1, Seq32::from_u32(0))
+                .body_limit(PUSH_HDR_LEN),
+            0
+        );
+    }
 }
@@ -1,6 +1,9 @@
 mod frag_bundler;
+mod mtu_prober;
+mod rto_wheel;
 mod sending_push;
 mod uploader;
 
+pub use mtu_prober::MtuProbing;
 use sending_push::*;
 pub use uploader::*;
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+
+/// Unacked probes of one size before the size is taken as too large for the path
+const MAX_PROBES: usize = 3;
+
+/// Raises `mtu` to what the path carries by sending padded `Probe` frags above it
+#[derive(Debug, Clone, Copy)]
+pub struct MtuProbing {
+    /// The largest MTU to try
+    pub max_mtu: usize,
+    /// How long to wait after a search before searching again from `max_mtu`
+    pub interval: Duration,
+}
+
+/// Binary search of the path MTU between the current `mtu` and a ceiling
+pub struct MtuProber {
+    probing: MtuProbing,
+    // the smallest size known not to make it through, or one past `max_mtu`
+    ceiling: usize,
+    next_id: u32,
+    in_flight: Option<Probe>,
+    // probes of the size in flight that went unacked
+    failures: usize,
+    search_done_at: Option<Instant>,
+}
+
+struct Probe {
+    id: u32,
+    size: usize,
+    sent_at: Instant,
+}
+
+impl MtuProber {
+    #[must_use]
+    pub fn new(probing: MtuProbing) -> Self {
+        MtuProber {
+            probing,
+            ceiling: probing.max_mtu + 1,
+            next_id: 0,
+            in_flight: None,
+            failures: 0,
+            search_done_at: None,
+        }
+    }
+
+    /// The ID and the size of a probe to send now, given the current `mtu`
+    ///
+    /// A probe unacked for `timeout` is lost; after `MAX_PROBES` of them the ceiling drops to
+    /// their size.
+    pub fn poll(&mut self, mtu: usize, now: &Instant, timeout: Duration) -> Option<(u32, usize)> {
+        if let Some(probe) = &self.in_flight {
+            if now.duration_since(probe.sent_at) < timeout {
+                return None;
+            }
+            let size = probe.size;
+            self.in_flight = None;
+            self.failures += 1;
+            if MAX_PROBES <= self.failures {
+                self.ceiling = size;
+                self.failures = 0;
+            }
+        }
+        if self.ceiling <= mtu + 1 {
+            // searched through; start over from the top once in a while
+            let done_at = *self.search_done_at.get_or_insert(*now);
+            if now.duration_since(done_at) < self.probing.interval {
+                return None;
+            }
+            self.ceiling = self.probing.max_mtu + 1;
+            self.search_done_at = None;
+            if self.ceiling <= mtu + 1 {
+                return None;
+            }
+        }
+        // the size of a lost probe again until the ceiling drops
+        let size = mtu + (self.ceiling - mtu) / 2;
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.in_flight = Some(Probe {
+            id,
+            size,
+            sent_at: *now,
+        });
+        Some((id, size))
+    }
+
+    /// The size the probe of `id` proved to get through, if it is the one in flight
+    pub fn on_ack(&mut self, id: u32) -> Option<usize> {
+        match &self.in_flight {
+            Some(probe) if probe.id == id => {
+                let size = probe.size;
+                self.in_flight = None;
+                self.failures = 0;
+                Some(size)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MtuProber, MtuProbing, MAX_PROBES};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_search() {
+        let mut now = Instant::now();
+        let timeout = Duration::from_millis(100);
+        let mut prober = MtuProber::new(MtuProbing {
+            max_mtu: 2000,
+            interval: Duration::from_secs(10),
+        });
+        let mut mtu = 1000;
+        let mut sent = 0;
+        // the path carries 1400 bytes
+        while let Some((id, size)) = prober.poll(mtu, &now, timeout) {
+            sent += 1;
+            match size <= 1400 {
+                true => mtu = prober.on_ack(id).unwrap(),
+                false => now += timeout,
+            }
+        }
+        assert_eq!(mtu, 1400);
+        assert!(sent < 64);
+
+        // searched again after the interval
+        assert!(prober.poll(mtu, &now, timeout).is_none());
+        now += Duration::from_secs(10);
+        let (_, size) = prober.poll(mtu, &now, timeout).unwrap();
+        assert!(1400 < size && size <= 2000);
+    }
+
+    #[test]
+    fn test_lost_probe_retried() {
+        let now = Instant::now();
+        let timeout = Duration::from_millis(100);
+        let mut prober = MtuProber::new(MtuProbing {
+            max_mtu: 2000,
+            interval: Duration::from_secs(10),
+        });
+        let (_, size) = prober.poll(1000, &now, timeout).unwrap();
+        assert!(prober.poll(1000, &now, timeout).is_none());
+        for i in 1..MAX_PROBES {
+            let (id, retry) = prober
+                .poll(1000, &(now + timeout * i as u32), timeout)
+                .unwrap();
+            assert_eq!(retry, size);
+            // a stale ack does not count
+            assert!(prober.on_ack(id.wrapping_sub(1)).is_none());
+        }
+        // too large after all
+        let (_, smaller) = prober
+            .poll(1000, &(now + timeout * MAX_PROBES as u32), timeout)
+            .unwrap();
+        assert!(smaller < size);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::utils::Seq32;
+
+const BUCKETS: usize = 64;
+static TICK: Duration = Duration::from_millis(10);
+
+/// Hashed timing wheel of the pushes in flight ordered by the time they were sent
+///
+/// Deadlines follow the current RTO, so entries are keyed by the send time and the deadline is
+/// checked on the way out. Entries are never removed in place: an entry goes stale once its push
+/// is acked or sent again, and is discarded when it reaches the front.
+pub struct RtoWheel {
+    // entries of the ticks `tick % BUCKETS`, increasing in tick
+    buckets: Vec<VecDeque<Entry>>,
+    origin: Option<Instant>,
+    // no entry is at a tick before it
+    cursor: u64,
+    // the latest tick an entry has been put at
+    last_tick: u64,
+    len: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    tick: u64,
+    seq: Seq32,
+    // tells the entry of the latest send of `seq` from the stale ones
+    generation: usize,
+}
+
+impl RtoWheel {
+    fn check_rep(&self) {
+        assert!(self.cursor <= self.last_tick || self.len == 0);
+        assert_eq!(
+            self.buckets
+                .iter()
+                .map(|bucket| bucket.len())
+                .sum::<usize>(),
+            self.len
+        );
+    }
+
+    #[must_use]
+    pub fn new() -> Self {
+        let this = RtoWheel {
+            buckets: vec![VecDeque::new(); BUCKETS],
+            origin: None,
+            cursor: 0,
+            last_tick: 0,
+            len: 0,
+        };
+        this.check_rep();
+        this
+    }
+
+    /// Entries including the stale ones
+    #[cfg(test)]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Record that the `generation`-th send of `seq` happened at `now`
+    ///
+    /// Entries are ordered as they are put if `now` goes backwards.
+    pub fn push(&mut self, seq: Seq32, generation: usize, now: Instant) {
+        let origin = *self.origin.get_or_insert(now);
+        let tick = (now.saturating_duration_since(origin).as_nanos() / TICK.as_nanos()) as u64;
+        let tick = u64::max(tick, self.last_tick);
+        if self.len == 0 {
+            self.cursor = tick;
+        }
+        self.last_tick = tick;
+        self.buckets[(tick % BUCKETS as u64) as usize].push_back(Entry {
+            tick,
+            seq,
+            generation,
+        });
+        self.len += 1;
+        self.check_rep();
+    }
+
+    /// The earliest entry for which `is_live` holds
+    ///
+    /// The entries before it are discarded.
+    pub fn peek(&mut self, is_live: impl Fn(Seq32, usize) -> bool) -> Option<Seq32> {
+        loop {
+            let bucket = self.front_bucket()?;
+            let entry = *self.buckets[bucket].front().unwrap();
+            if is_live(entry.seq, entry.generation) {
+                self.check_rep();
+                return Some(entry.seq);
+            }
+            self.buckets[bucket].pop_front();
+            self.len -= 1;
+        }
+    }
+
+    /// Remove the entry returned by the last `peek`
+    pub fn pop(&mut self) {
+        if let Some(bucket) = self.front_bucket() {
+            self.buckets[bucket].pop_front();
+            self.len -= 1;
+        }
+        self.check_rep();
+    }
+
+    fn front_bucket(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        loop {
+            for _ in 0..BUCKETS {
+                let bucket = (self.cursor % BUCKETS as u64) as usize;
+                if let Some(entry) = self.buckets[bucket].front() {
+                    if entry.tick == self.cursor {
+                        return Some(bucket);
+                    }
+                }
+                self.cursor += 1;
+            }
+            // a whole round of empty ticks; jump to the earliest entry
+            self.cursor = self
+                .buckets
+                .iter()
+                .filter_map(|bucket| bucket.front().map(|entry| entry.tick))
+                .min()
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RtoWheel, TICK};
+    use crate::utils::Seq32;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_order_and_discard() {
+        let start = Instant::now();
+        let mut wheel = RtoWheel::new();
+        let seq = Seq32::from_u32;
+        wheel.push(seq(0), 0, start);
+        wheel.push(seq(1), 0, start);
+        // far enough to wrap around the buckets
+        wheel.push(seq(2), 0, start + TICK * 100);
+        wheel.push(seq(0), 1, start + TICK * 200);
+        assert_eq!(wheel.len(), 4);
+
+        // the first entry of seq(0) is stale
+        let is_live = |s: Seq32, generation: usize| s != seq(0) || generation == 1;
+        assert_eq!(wheel.peek(is_live), Some(seq(1)));
+        assert_eq!(wheel.len(), 3);
+        wheel.pop();
+        assert_eq!(wheel.peek(is_live), Some(seq(2)));
+        wheel.pop();
+        assert_eq!(wheel.peek(is_live), Some(seq(0)));
+        wheel.pop();
+        assert_eq!(wheel.peek(is_live), None);
+        assert!(wheel.is_empty());
+
+        // time going backwards keeps the order of the pushes
+        wheel.push(seq(3), 0, start + Duration::from_secs(3600));
+        wheel.push(seq(4), 0, start);
+        assert_eq!(wheel.peek(|_, _| true), Some(seq(3)));
+        wheel.pop();
+        assert_eq!(wheel.peek(|_, _| true), Some(seq(4)));
+    }
+}
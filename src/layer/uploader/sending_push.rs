@@ -1,35 +1,86 @@
-use std::{
-    sync::Arc,
-    time::{self, Instant},
-};
+use std::time::{self, Instant};
 
-use crate::utils::buf::BufPasta;
+use crate::{
+    protocol::frag::{Body, FragCommand},
+    utils::buf::BufSlice,
+};
 
 /// Represents a PUSH message that has not been acknowledged by the peer yet.
 pub struct SendingPush {
-    body: Arc<BufPasta>,
+    body: Body,
+    first_sent: time::Instant,
     last_sent: time::Instant,
-    is_retransmitted: bool,
+    retransmissions: usize,
+    // the last retransmission was on timeout
+    is_rto_retransmitted: bool,
+    // bytes acked by the time of the first send
+    delivered_at_send: u64,
+    // a fin in place of a push; the body is empty
+    is_fin: bool,
+    // the body went through the codec
+    is_compressed: bool,
 }
 
 impl SendingPush {
     #[must_use]
-    pub fn new(body: Arc<BufPasta>, now: Instant) -> Self {
+    pub fn new(body: Body, now: Instant, delivered: u64) -> Self {
         SendingPush {
             body,
+            first_sent: now,
             last_sent: now,
-            is_retransmitted: false,
+            retransmissions: 0,
+            is_rto_retransmitted: false,
+            delivered_at_send: delivered,
+            is_fin: false,
+            is_compressed: false,
+        }
+    }
+
+    /// A push whose body is the compressed form of the data
+    #[must_use]
+    pub fn new_compressed(body: Body, now: Instant, delivered: u64) -> Self {
+        SendingPush {
+            is_compressed: true,
+            ..Self::new(body, now, delivered)
+        }
+    }
+
+    #[must_use]
+    pub fn new_fin(now: Instant, delivered: u64) -> Self {
+        SendingPush {
+            is_fin: true,
+            ..Self::new(Body::Slice(BufSlice::from_bytes(vec![])), now, delivered)
+        }
+    }
+
+    /// The command to (re)transmit this seq with
+    #[must_use]
+    pub fn frag_cmd(&self) -> FragCommand {
+        match (self.is_fin, self.is_compressed) {
+            (true, _) => FragCommand::Fin,
+            (false, true) => FragCommand::CompressedPush {
+                body: self.body.clone(),
+            },
+            (false, false) => FragCommand::Push {
+                body: self.body.clone(),
+            },
         }
     }
 
     #[must_use]
-    pub fn body(&self) -> &Arc<BufPasta> {
+    pub fn body(&self) -> &Body {
         &self.body
     }
 
     pub fn to_retransmit(&mut self, now: Instant) {
         self.last_sent = now;
-        self.is_retransmitted = true;
+        self.retransmissions += 1;
+        self.is_rto_retransmitted = false;
+    }
+
+    pub fn rto_retransmit(&mut self, now: Instant) {
+        self.to_retransmit(now);
+        self.is_rto_retransmitted = true;
     }
 
     // #[must_use]
@@ -37,6 +88,16 @@ impl SendingPush {
     //     *timeout <= self.last_sent.elapsed()
     // }
 
+    #[must_use]
+    pub fn first_sent(&self) -> Instant {
+        self.first_sent
+    }
+
+    #[must_use]
+    pub fn is_rto_retransmitted(&self) -> bool {
+        self.is_rto_retransmitted
+    }
+
     #[must_use]
     pub fn last_sent(&self) -> Instant {
         self.last_sent
@@ -44,7 +105,17 @@ impl SendingPush {
 
     #[must_use]
     pub fn is_retransmitted(&self) -> bool {
-        self.is_retransmitted
+        self.retransmissions > 0
+    }
+
+    #[must_use]
+    pub fn retransmissions(&self) -> usize {
+        self.retransmissions
+    }
+
+    #[must_use]
+    pub fn delivered_at_send(&self) -> u64 {
+        self.delivered_at_send
     }
 
     #[must_use]
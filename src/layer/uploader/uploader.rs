@@ -1,23 +1,38 @@
 use super::{
-    super::{IObserver, SetUploadState},
-    frag_bundler::FragBundler,
+    super::{
+        fec::ParityGroup, Handshake, HandshakeState, IAckObserver, IObserver, IRtoObserver, Role,
+        SetUploadState,
+    },
+    frag_bundler::{FragBundler, PackError},
+    mtu_prober::{MtuProber, MtuProbing},
+    rto_wheel::RtoWheel,
     SendingPush,
 };
 use crate::{
     protocol::{
-        frag::{Body, Frag, FragBuilder, FragCommand, ACK_HDR_LEN, PUSH_HDR_LEN},
+        auth::{PacketAuth, MAX_TAG_LEN},
+        codec::BodyCodec,
+        frag::{
+            Body, Frag, FragBuilder, FragCommand, WireFormat, ACK_BITMAP_SPAN, ACK_HDR_LEN,
+            PING_LEN, SACK_HDR_LEN, SACK_RANGE_LEN, SYN_LEN,
+        },
         packet::{Packet, PacketBuilder},
-        packet_hdr::{PacketHeaderBuilder, PACKET_HDR_LEN},
+        packet_hdr::{
+            PacketHeader, PacketHeaderBuilder, CONN_ID_LEN, LEGACY_PACKET_HDR_LEN,
+            LEGACY_PACKET_OPTIONS_LEN, LEGACY_VERSION, PACKET_HDR_LEN, PACKET_OPTIONS_LEN,
+            PACKET_SEQ_LEN, PROTOCOL_VERSION,
+        },
+        transform::PacketTransform,
     },
     utils::{
-        buf::{self, BufPasta, BufSlicerQue},
-        FastRetransmissionWnd, Seq32, Swnd,
+        buf::{self, BufPasta, BufPool, BufSlice, BufSlicerQue, BufWtr, OwnedBufWtr},
+        FastRetransmissionWnd, Seq, Seq32, Swnd, WindowedMin,
     },
 };
-use keyed_priority_queue::KeyedPriorityQueue;
 use std::{
-    cmp,
     collections::VecDeque,
+    fmt,
+    ops::Range,
     sync::{Arc, Weak},
     time::{self, Duration, Instant},
 };
@@ -29,765 +44,5105 @@ const MIN_RTO_MS: u64 = 100;
 static MAX_RTO: time::Duration = Duration::from_millis(MAX_RTO_MS);
 static DEFAULT_RTO: time::Duration = Duration::from_millis(DEFAULT_RTO_MS);
 static MIN_RTO: time::Duration = Duration::from_millis(MIN_RTO_MS);
+// long enough to see past a busy period, short enough to follow a route change
+static RTT_MIN_WINDOW: time::Duration = Duration::from_secs(30);
 
 pub struct Uploader {
     // modified by `append_frags_to`
     to_send_queue: buf::BufSlicerQue,
+    urgent_queue: buf::BufSlicerQue,
+    unreliable_queue: buf::BufSlicerQue,
     swnd: Swnd<Seq32, SendingPush>,
+    // unreliable pushes not known to have arrived, with the time they or their skip were last sent
+    skips: VecDeque<(Seq32, Instant)>,
     to_ack_queue: VecDeque<Seq32>,
-    last_sent_heap: KeyedPriorityQueue<Seq32, cmp::Reverse<Instant>>,
+    rto_wheel: RtoWheel,
+    bytes_in_flight: usize,
+    // bytes of the pushes acked so far
+    delivered: u64,
 
     // modified by setters
     local_rwnd_size: usize,
     local_next_seq_to_receive: Seq32,
     fast_retransmission_wnd: FastRetransmissionWnd<Seq32>,
+    // the largest nack from the peer; older ones come from reordered packets
+    max_remote_nack: Seq32,
+    // the latest timestamp from the peer, to be echoed by the next emit
+    timestamp_to_echo: Option<u32>,
+    // where the local timestamps count from
+    timestamp_epoch: Option<Instant>,
+    // set by the builder or taken from the first packet of the peer carrying one
+    conn_id: Option<u64>,
+    // the packet seq of the next packet; `None` if `packet_seqs` is off
+    next_packet_seq: Option<Seq32>,
+    auth: Option<Arc<dyn PacketAuth>>,
+    wire_format: WireFormat,
+    pad_packets_to: Option<usize>,
+    fec_group_size: Option<usize>,
+    // the fresh pushes not yet covered by a parity
+    parity_group: Option<ParityGroup>,
+    parities_to_send: VecDeque<Frag>,
+    codec: Option<Arc<dyn BodyCodec>>,
+
+    // `close` was called; nothing more is written
+    is_send_closed: bool,
+    // the seq taken by the fin once sent
+    fin_seq: Option<Seq32>,
+    // handshake
+    handshake: Option<Handshake>,
+    handshake_state: HandshakeState,
+    syn_last_sent: Option<Instant>,
+    // the nonce of the syn from the peer
+    syn_ack_to_send: Option<u64>,
+
+    // `abort` was called; only rsts go out
+    is_aborted: bool,
+    rsts_sent: usize,
+    rst_last_sent: Option<Instant>,
+
+    // keepalive
+    // when the last packet was emitted or a ping was asked for
+    last_activity: Option<Instant>,
+    next_ping_token: u32,
+    ping_to_send: Option<u32>,
+    last_ping_token: Option<u32>,
+    last_pong_received: Option<Instant>,
+    pongs_to_send: VecDeque<u32>,
+
+    // path MTU discovery
+    mtu_prober: Option<MtuProber>,
+    probe_ack_to_send: Option<u32>,
+
+    // the latest ranges the peer has buffered, sent in place of their acks
+    sack_to_send: Option<Vec<Range<Seq32>>>,
+
+    // dead link detection
+    is_broken: bool,
+    no_ack_since: Option<Instant>,
 
     // stat
     stat: LocalStat,
+    totals: LifetimeTotals,
+    rtt_min: WindowedMin,
 
     // const
     ratio_rto_to_one_rtt: f64,
+    rto_jitter: f64,
+    rto_jitter_seed: u64,
+    min_rto: Duration,
+    max_rto: Duration,
+    initial_rto: Duration,
+    coalesce_small_writes: bool,
+    timestamps: bool,
+    legacy_header: bool,
+    strict: bool,
+    max_fast_retransmit_burst: usize,
+    send_available_low_water_mark: Option<usize>,
     mtu: usize,
+    max_retransmissions: Option<usize>,
+    max_no_ack_duration: Option<Duration>,
+    rst_transmissions: usize,
+    keepalive_interval: Option<Duration>,
+    sack: bool,
+    cumulative_ack_only: bool,
+    ack_bitmap: bool,
+    // acks were dropped for the nack to cover them; a packet has to carry it
+    is_nack_pending: bool,
 
     // observer
     on_send_available: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    // the send queue has been full since the last notification
+    is_send_available_armed: bool,
+    on_ack: Option<Weak<dyn IAckObserver + Send + Sync + 'static>>,
+    on_rto: Option<Weak<dyn IRtoObserver + Send + Sync + 'static>>,
+    on_drained: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    // held back for `SharedUploader` instead of notified right away
+    deferred_notifications: Option<Notifications>,
 }
 
 pub struct UploaderBuilder {
     pub local_recv_buf_len: usize,
     pub nack_duplicate_threshold_to_activate_fast_retransmit: usize,
+    /// Fast retransmissions sent per `emit` at most; the rest of the gap goes out on later calls
+    pub max_fast_retransmit_burst: usize,
     pub ratio_rto_to_one_rtt: f64,
+    /// Each push waits up to `rto * (1 + rto_jitter)` before retransmission
+    ///
+    /// The factor is derived from the sequence and `rto_jitter_seed`, so it is stable per push.
+    /// It keeps flows sharing a bottleneck from retransmitting in lockstep.
+    pub rto_jitter: f64,
+    pub rto_jitter_seed: u64,
+    /// Lower bound of `rto`; 100 ms if `None`
+    pub min_rto: Option<Duration>,
+    /// Upper bound of `rto`; 60 s if `None`
+    pub max_rto: Option<Duration>,
+    /// `rto` before any RTT is measured; 3 s if `None`
+    ///
+    /// Must lie within the bounds.
+    pub initial_rto: Option<Duration>,
+    /// Hold back a push smaller than the frag body limit while older pushes are unacked
+    ///
+    /// Trades latency for fewer, fuller frags; leave it off for interactive traffic.
+    pub coalesce_small_writes: bool,
+    /// Put a timestamp in every packet header and sample the RTT from its echo
+    ///
+    /// Unlike acks, echoes give samples for retransmitted pushes too. The header grows by
+    /// `PACKET_OPTIONS_LEN`; a peer of a version without the option cannot decode it. Off, the
+    /// timestamps of the peer are still echoed where the pushes in flight leave room for it.
+    pub timestamps: bool,
+    /// Leave the version byte out of the packet headers, for a peer predating it
+    pub legacy_header: bool,
+    /// Return errors for conditions that are otherwise tolerated
+    ///
+    /// `write` rejects empty slices and `set_state` rejects acks for seqs never sent.
+    /// Meant for development; a misbehaving peer can trigger the errors too.
+    pub strict: bool,
     pub mtu: usize,
-    pub to_send_queue_len_cap: usize,
+    /// Unit: byte
+    pub to_send_bytes_cap: usize,
+    /// Optional secondary limit on the number of queued writes
+    pub to_send_queue_len_cap: Option<usize>,
+    /// `on_send_available` waits until the send queue holds fewer bytes than this
+    ///
+    /// `None` notifies as soon as the queue is not full.
+    pub send_available_low_water_mark: Option<usize>,
     pub swnd_size_cap: usize,
+    /// The link is considered broken once a push has to be retransmitted more than this many times
+    pub max_retransmissions: Option<usize>,
+    /// The link is considered broken once data has been in flight without any ack for this long
+    pub max_no_ack_duration: Option<Duration>,
+    /// Times `abort` sends its `Rst`, an RTO apart
+    pub rst_transmissions: usize,
+    /// `keepalive_due` holds once nothing has been emitted for this long
+    pub keepalive_interval: Option<Duration>,
+    /// Acknowledge reordered pushes with one `Sack` frag instead of an `Ack` each
+    ///
+    /// The sender stops retransmitting the sacked pushes. A peer of a version without the
+    /// `Sack` frag cannot decode it.
+    pub sack: bool,
+    /// Put this connection ID in every packet header
+    ///
+    /// Without one, the uploader echoes the ID of the peer once `set_state` has seen it and the
+    /// grown header leaves room in the MTU for the pushes in flight.
+    /// The header grows by `CONN_ID_LEN`, or `LEGACY_PACKET_OPTIONS_LEN + CONN_ID_LEN` at most
+    /// with `legacy_header`.
+    pub conn_id: Option<u64>,
+    /// Number every packet so that the downloader of the peer can drop duplicated ones
+    ///
+    /// The header grows by `PACKET_SEQ_LEN`, or `LEGACY_PACKET_OPTIONS_LEN + PACKET_SEQ_LEN` at
+    /// most with `legacy_header`.
+    pub packet_seqs: bool,
+    /// Establish the session before sending any push; shared with the downloader
+    pub handshake: Option<Handshake>,
+    /// The seq of the first push; the one drawn for the handshake or 0 if `None`
+    ///
+    /// Without the handshake, the downloader of the peer has to be built with the same value.
+    pub initial_seq: Option<Seq32>,
+    /// Append a tag to every packet; the tag counts against the MTU
+    pub auth: Option<Arc<dyn PacketAuth>>,
+    /// How the pushes are laid out; the downloader of the peer has to be built with the same
+    ///
+    /// `WireFormat::V2` saves 2 bytes per push and cuts the bodies at `u16::MAX` bytes.
+    pub wire_format: WireFormat,
+    /// Top every packet up to this many bytes with a `Padding` frag
+    ///
+    /// Capped by the MTU. A packet short of it by less than a padding frag stays as it is,
+    /// and so do packets of `emit_budgeted` once the budget is spent.
+    pub pad_packets_to: Option<usize>,
+    /// Follow every this many consecutive new pushes with a `Parity` frag
+    ///
+    /// The peer rebuilds one push lost from each group without waiting for a retransmission.
+    /// Pushes are cut short enough for the parity to fit in a packet of its own.
+    pub fec_group_size: Option<usize>,
+    /// Compress the body of every new push that it makes shorter
+    ///
+    /// The downloader of the peer needs the same codec.
+    pub codec: Option<Arc<dyn BodyCodec>>,
+    /// Leave the pushes received in order to the nack of the packet header instead of an
+    /// `Ack` each, and take RTT samples from the advance of the nack of the peer
+    ///
+    /// Pushes received out of order are still acked one by one. A packet of just the header
+    /// goes out if nothing else would carry the nack.
+    pub cumulative_ack_only: bool,
+    /// Coalesce acks queued close together into `AckBitmap` frags where that takes fewer bytes
+    ///
+    /// A peer of a version without the `AckBitmap` frag cannot decode it.
+    pub ack_bitmap: bool,
+    /// Search for the largest MTU the path carries with padded probe packets and raise `mtu`
+    /// to it
+    ///
+    /// A probe goes out an RTO after the previous one until the search ends, then once per
+    /// `MtuProbing::interval`. A peer of a version without the `Probe` frag cannot decode it.
+    pub mtu_probing: Option<MtuProbing>,
 }
 
 impl UploaderBuilder {
     #[must_use]
-    pub fn build(self) -> Result<Uploader, BuildError> {
-        if !(PACKET_HDR_LEN + ACK_HDR_LEN <= self.mtu)
-            || !(PACKET_HDR_LEN + PUSH_HDR_LEN + 1 <= self.mtu)
-        {
-            return Err(BuildError::MtuTooSmall);
+    pub fn build(mut self) -> Result<Uploader, BuildError> {
+        let initial_seq = match (self.initial_seq, &self.handshake) {
+            (Some(initial_seq), _) => initial_seq,
+            (None, Some(handshake)) => handshake.initial_seq,
+            (None, None) => Seq32::from_u32(0),
+        };
+        // the syn tells the peer
+        if let Some(handshake) = &mut self.handshake {
+            handshake.initial_seq = initial_seq;
+        }
+        let checkpoint = Checkpoint {
+            next_seq: initial_seq,
+            acked_prefix: initial_seq,
+            local_next_seq_to_receive: Seq32::from_u32(0),
+        };
+        self.resume_from(&checkpoint)
+    }
+
+    /// Build an uploader that continues the stream of a checkpointed one.
+    ///
+    /// The data in flight at the time of the checkpoint is lost; the first push is assigned
+    /// `checkpoint.acked_prefix`, so the application should write again everything it wrote
+    /// after the acked prefix, cut into the same fragments.
+    pub fn resume_from(self, checkpoint: &Checkpoint) -> Result<Uploader, BuildError> {
+        let tag_len = self.auth.as_ref().map_or(0, |auth| auth.tag_len());
+        if MAX_TAG_LEN < tag_len {
+            return Err(BuildError::AuthTagTooLong);
+        }
+        let hdr_len = packet_hdr_len(
+            self.legacy_header,
+            self.timestamps,
+            self.conn_id,
+            self.packet_seqs,
+        );
+        if let Some(fec_group_size) = self.fec_group_size {
+            if !(1..=u8::MAX as usize).contains(&fec_group_size) {
+                return Err(BuildError::FecGroupSize);
+            }
+        }
+        check_mtu(
+            self.mtu,
+            hdr_len + tag_len,
+            self.wire_format,
+            self.fec_group_size.is_some(),
+        )?;
+        if self.rto_jitter < 0.0 || self.rto_jitter.is_nan() {
+            return Err(BuildError::RtoJitterNegative);
+        }
+        if self.ratio_rto_to_one_rtt <= 0.0 || self.ratio_rto_to_one_rtt.is_nan() {
+            return Err(BuildError::InvalidRtoRatio);
+        }
+        let min_rto = self.min_rto.unwrap_or(MIN_RTO);
+        let max_rto = self.max_rto.unwrap_or(MAX_RTO);
+        let initial_rto = self.initial_rto.unwrap_or(DEFAULT_RTO);
+        if initial_rto < min_rto || max_rto < initial_rto {
+            return Err(BuildError::InvalidRtoBounds);
+        }
+        // the rwnd field of the packet header is 16 bits wide
+        if self.local_recv_buf_len > u16::MAX as usize {
+            return Err(BuildError::LocalRecvBufTooLarge);
+        }
+        if self.to_send_bytes_cap == 0 {
+            return Err(BuildError::ToSendBytesCapZero);
+        }
+        if self.to_send_queue_len_cap == Some(0) {
+            return Err(BuildError::ToSendQueueLenCapZero);
+        }
+        if self.swnd_size_cap == 0 {
+            return Err(BuildError::SwndSizeCapZero);
+        }
+        if self.max_fast_retransmit_burst == 0 {
+            return Err(BuildError::FastRetransmitBurstZero);
+        }
+        if self.send_available_low_water_mark == Some(0) {
+            return Err(BuildError::LowWaterMarkZero);
         }
         let this = Uploader {
-            to_send_queue: BufSlicerQue::new(self.to_send_queue_len_cap),
-            swnd: Swnd::new(self.swnd_size_cap),
+            to_send_queue: BufSlicerQue::new(self.to_send_bytes_cap, self.to_send_queue_len_cap),
+            urgent_queue: BufSlicerQue::new(usize::MAX, None),
+            unreliable_queue: BufSlicerQue::new(self.to_send_bytes_cap, self.to_send_queue_len_cap),
+            swnd: Swnd::new_at(self.swnd_size_cap, checkpoint.acked_prefix),
+            skips: VecDeque::new(),
             to_ack_queue: VecDeque::new(),
+            bytes_in_flight: 0,
+            delivered: 0,
             local_rwnd_size: self.local_recv_buf_len,
-            local_next_seq_to_receive: Seq32::from_u32(0),
+            local_next_seq_to_receive: checkpoint.local_next_seq_to_receive,
+            max_remote_nack: checkpoint.acked_prefix,
+            timestamp_to_echo: None,
+            timestamp_epoch: None,
             stat: LocalStat {
                 srtt: None,
+                delivery_rate: None,
                 retransmissions: 0,
                 rto_hits: 0,
+                spurious_rtos: 0,
+                duplicate_acks_received: 0,
                 fast_retransmissions: 0,
                 pushes: 0,
                 acks: 0,
+                bytes_pushed: 0,
+                bytes_retransmitted: 0,
+                packets_emitted: 0,
+                packets_on_ack: 0,
+                bytes_on_ack: 0,
+                packets_on_timer: 0,
+                bytes_on_timer: 0,
+                expired_writes: 0,
+                probes_sent: 0,
+                probes_acked: 0,
+                conn_ids_deferred: 0,
+                echoes_skipped: 0,
+                retransmits_too_large: 0,
             },
+            totals: LifetimeTotals::default(),
+            rtt_min: WindowedMin::new(RTT_MIN_WINDOW),
             fast_retransmission_wnd: FastRetransmissionWnd::new(
                 self.nack_duplicate_threshold_to_activate_fast_retransmit,
             ),
+            is_send_closed: false,
+            fin_seq: None,
+            handshake: self.handshake,
+            handshake_state: match self.handshake {
+                Some(_) => HandshakeState::Idle,
+                None => HandshakeState::Established,
+            },
+            syn_last_sent: None,
+            syn_ack_to_send: None,
+            next_packet_seq: match self.packet_seqs {
+                true => Some(Seq32::from_u32(0)),
+                false => None,
+            },
+            auth: self.auth,
+            wire_format: self.wire_format,
+            pad_packets_to: self.pad_packets_to,
+            fec_group_size: self.fec_group_size,
+            parity_group: None,
+            parities_to_send: VecDeque::new(),
+            codec: self.codec,
+            is_aborted: false,
+            rsts_sent: 0,
+            rst_last_sent: None,
+            last_activity: None,
+            next_ping_token: 0,
+            ping_to_send: None,
+            last_ping_token: None,
+            last_pong_received: None,
+            pongs_to_send: VecDeque::new(),
+            mtu_prober: self.mtu_probing.map(MtuProber::new),
+            probe_ack_to_send: None,
+            sack_to_send: None,
+            is_broken: false,
+            no_ack_since: None,
             ratio_rto_to_one_rtt: self.ratio_rto_to_one_rtt,
+            rto_jitter: self.rto_jitter,
+            rto_jitter_seed: self.rto_jitter_seed,
+            min_rto,
+            max_rto,
+            initial_rto,
+            coalesce_small_writes: self.coalesce_small_writes,
+            timestamps: self.timestamps,
+            legacy_header: self.legacy_header,
+            strict: self.strict,
+            max_fast_retransmit_burst: self.max_fast_retransmit_burst,
+            send_available_low_water_mark: self.send_available_low_water_mark,
             mtu: self.mtu,
+            max_retransmissions: self.max_retransmissions,
+            max_no_ack_duration: self.max_no_ack_duration,
+            rst_transmissions: self.rst_transmissions,
+            keepalive_interval: self.keepalive_interval,
+            sack: self.sack,
+            cumulative_ack_only: self.cumulative_ack_only,
+            ack_bitmap: self.ack_bitmap,
+            is_nack_pending: false,
+            conn_id: self.conn_id,
             on_send_available: None,
-            last_sent_heap: KeyedPriorityQueue::new(),
+            is_send_available_armed: false,
+            on_ack: None,
+            on_rto: None,
+            on_drained: None,
+            deferred_notifications: None,
+            rto_wheel: RtoWheel::new(),
         };
         this.check_rep();
         Ok(this)
     }
 
-    #[must_use]
-    pub fn default() -> UploaderBuilder {
+    #[deprecated(note = "use `Default::default()`")]
+    pub fn default() -> Self {
+        <Self as Default>::default()
+    }
+}
+
+impl Default for UploaderBuilder {
+    fn default() -> Self {
         let builder = Self {
             local_recv_buf_len: u16::MAX as usize,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
             mtu: 1300,
-            to_send_queue_len_cap: 1024 * 64,
+            to_send_bytes_cap: 1024 * 1024 * 4,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: u16::MAX as usize,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         };
         builder
     }
 }
 
-#[derive(Debug)]
-pub enum SetStateError {
-    InvalidState,
-}
+impl UploaderBuilder {
+    #[must_use]
+    pub fn local_recv_buf_len(mut self, local_recv_buf_len: usize) -> Self {
+        self.local_recv_buf_len = local_recv_buf_len;
+        self
+    }
 
-#[derive(Debug)]
-pub enum OutputError {
-    NothingToOutput,
-    BufferTooSmall,
-}
+    #[must_use]
+    pub fn nack_duplicate_threshold_to_activate_fast_retransmit(
+        mut self,
+        nack_duplicate_threshold_to_activate_fast_retransmit: usize,
+    ) -> Self {
+        self.nack_duplicate_threshold_to_activate_fast_retransmit =
+            nack_duplicate_threshold_to_activate_fast_retransmit;
+        self
+    }
 
-#[derive(Debug)]
-pub enum BuildError {
-    MtuTooSmall,
-}
+    #[must_use]
+    pub fn max_fast_retransmit_burst(mut self, max_fast_retransmit_burst: usize) -> Self {
+        self.max_fast_retransmit_burst = max_fast_retransmit_burst;
+        self
+    }
 
-pub struct SendError<T>(pub T);
+    #[must_use]
+    pub fn ratio_rto_to_one_rtt(mut self, ratio_rto_to_one_rtt: f64) -> Self {
+        self.ratio_rto_to_one_rtt = ratio_rto_to_one_rtt;
+        self
+    }
 
-impl Uploader {
-    #[inline]
-    fn check_rep(&self) {
-        assert!(self.local_rwnd_size <= u16::MAX as usize);
+    #[must_use]
+    pub fn rto_jitter(mut self, rto_jitter: f64) -> Self {
+        self.rto_jitter = rto_jitter;
+        self
     }
 
     #[must_use]
-    pub fn stat(&self) -> Stat {
-        Stat {
-            srtt: self.stat.srtt,
-            retransmissions: self.stat.retransmissions,
-            rto_hits: self.stat.rto_hits,
-            fast_retransmissions: self.stat.fast_retransmissions,
-            pushes: self.stat.pushes,
-            acks: self.stat.acks,
-            next_seq_to_send: self.swnd.end(),
-        }
+    pub fn rto_jitter_seed(mut self, rto_jitter_seed: u64) -> Self {
+        self.rto_jitter_seed = rto_jitter_seed;
+        self
     }
 
-    pub fn set_on_send_available(
-        &mut self,
-        observer: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
-    ) {
-        self.on_send_available = observer;
+    #[must_use]
+    pub fn min_rto(mut self, min_rto: Option<Duration>) -> Self {
+        self.min_rto = min_rto;
+        self
     }
 
-    pub fn write(&mut self, slice: buf::BufSlice) -> Result<(), SendError<buf::BufSlice>> {
-        let result = match self.to_send_queue.push_back(slice) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(SendError(e.0)),
-        };
-        result
+    #[must_use]
+    pub fn max_rto(mut self, max_rto: Option<Duration>) -> Self {
+        self.max_rto = max_rto;
+        self
     }
 
     #[must_use]
-    pub fn emit(&mut self, now: &Instant) -> Vec<Packet> {
-        let is_then_full = self.to_send_queue.is_full();
-        let packets = self.emit_packets(self.mtu, now).unwrap();
-
-        // callback when `to_send` is not full
-        if let Some(x) = &self.on_send_available {
-            let is_now_full = self.to_send_queue.is_full();
-            match (is_then_full, is_now_full) {
-                (true, false) => match x.upgrade() {
-                    Some(x) => x.notify(),
-                    None => (),
-                },
-                _ => (),
-            }
-        }
+    pub fn initial_rto(mut self, initial_rto: Option<Duration>) -> Self {
+        self.initial_rto = initial_rto;
+        self
+    }
 
-        self.check_rep();
-        packets
+    #[must_use]
+    pub fn coalesce_small_writes(mut self, coalesce_small_writes: bool) -> Self {
+        self.coalesce_small_writes = coalesce_small_writes;
+        self
     }
 
-    fn emit_packets(
-        &mut self,
-        packet_space: usize,
-        now: &Instant,
-    ) -> Result<Vec<Packet>, OutputError> {
-        if !(PACKET_HDR_LEN + ACK_HDR_LEN <= packet_space) {
-            self.check_rep();
-            return Err(OutputError::BufferTooSmall);
-        }
-        if !(PACKET_HDR_LEN + PUSH_HDR_LEN + 1 <= packet_space) {
-            self.check_rep();
-            return Err(OutputError::BufferTooSmall);
-        }
+    #[must_use]
+    pub fn timestamps(mut self, timestamps: bool) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
 
-        let bundles = self.emit_frags(packet_space - PACKET_HDR_LEN, now);
-        let mut packets = Vec::new();
+    #[must_use]
+    pub fn legacy_header(mut self, legacy_header: bool) -> Self {
+        self.legacy_header = legacy_header;
+        self
+    }
 
-        for frags in bundles {
-            // packet header
-            let hdr = PacketHeaderBuilder {
-                rwnd: self.local_rwnd_size as u16,
-                nack: self.local_next_seq_to_receive,
-            }
-            .build()
-            .unwrap();
-            let packet = PacketBuilder { hdr, frags }.build().unwrap();
-            packets.push(packet);
-        }
-        self.check_rep();
-        Ok(packets)
+    #[must_use]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
-    #[inline]
     #[must_use]
-    fn emit_frags(&mut self, space: usize, now: &Instant) -> Vec<Vec<Frag>> {
-        let mut bundler = FragBundler::new(space);
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
 
-        // piggyback ack
-        loop {
-            let ack = match self.to_ack_queue.pop_front() {
-                Some(ack) => ack,
-                None => break,
-            };
-            let frag = FragBuilder {
-                seq: ack,
-                cmd: FragCommand::Ack,
-            }
-            .build()
-            .unwrap();
-            bundler.pack(frag).unwrap();
-            self.stat.acks += 1;
-        }
+    #[must_use]
+    pub fn to_send_bytes_cap(mut self, to_send_bytes_cap: usize) -> Self {
+        self.to_send_bytes_cap = to_send_bytes_cap;
+        self
+    }
 
-        // retransmission
-        // write pushes from sending
-        if !self.fast_retransmission_wnd.is_empty() {
-            for (&seq, push) in self.swnd.range_mut(
-                self.fast_retransmission_wnd.start(),
-                self.fast_retransmission_wnd.end(),
-            ) {
-                {
-                    // add push to collection
-                    let frag = FragBuilder {
-                        seq,
-                        cmd: FragCommand::Push {
-                            body: Body::Pasta(Arc::clone(push.body())),
-                        },
-                    }
-                    .build()
-                    .unwrap();
-                    bundler.pack(frag).unwrap();
-                    push.to_retransmit(*now); // test case: `test_rto_once`
-                    self.last_sent_heap
-                        .set_priority(&seq, cmp::Reverse(push.last_sent()))
-                        .unwrap();
-                }
-                self.fast_retransmission_wnd.retransmitted(seq);
-                self.stat.fast_retransmissions += 1;
-                self.stat.retransmissions += 1;
-                self.stat.pushes += 1;
-            }
-        }
-        // min heap for rto
-        let rto = self.rto();
-        for _ in 0..self.last_sent_heap.len() {
-            if let Some((&seq, last_sent)) = self.last_sent_heap.peek() {
-                let last_sent = last_sent.0;
-                if now.duration_since(last_sent) < rto {
-                    break;
-                }
-                // write
-                if let Some(push) = self.swnd.value_mut(&seq) {
-                    {
-                        // add push to collection
-                        let frag = FragBuilder {
-                            seq,
-                            cmd: FragCommand::Push {
-                                body: Body::Pasta(Arc::clone(push.body())),
-                            },
-                        }
-                        .build()
-                        .unwrap();
-                        bundler.pack(frag).unwrap();
-                        push.to_retransmit(*now);
-                        self.last_sent_heap
-                            .set_priority(&seq, cmp::Reverse(push.last_sent()))
-                            .unwrap();
-                    }
-                    self.stat.rto_hits += 1;
-                    self.stat.retransmissions += 1;
-                    self.stat.pushes += 1;
-                } else {
-                    self.last_sent_heap.pop().unwrap();
-                }
-            } else {
-                break;
-            }
-        }
+    #[must_use]
+    pub fn to_send_queue_len_cap(mut self, to_send_queue_len_cap: Option<usize>) -> Self {
+        self.to_send_queue_len_cap = to_send_queue_len_cap;
+        self
+    }
 
-        // move data from to_send queue to sending queue and output those data
-        while !self.to_send_queue.is_empty() && !self.swnd.is_full() {
-            // get as many bytes from to_send_queue to body
-            let frag_body_limit = match PUSH_HDR_LEN + 1 <= bundler.loading_space() {
-                true => bundler.loading_space() - PUSH_HDR_LEN,
-                false => space - PUSH_HDR_LEN, // TODO: test when all body limit is used
-            };
-            assert!(frag_body_limit != 0);
-            let mut body = BufPasta::new();
-            while !self.to_send_queue.is_empty() {
-                let free_space = frag_body_limit - body.len();
-                if free_space == 0 {
-                    break;
-                }
-                let buf = self.to_send_queue.slice_front(free_space).unwrap();
-                body.append(buf);
-            }
-            assert!(body.len() <= frag_body_limit);
-            assert!(body.len() > 0);
+    #[must_use]
+    pub fn send_available_low_water_mark(
+        mut self,
+        send_available_low_water_mark: Option<usize>,
+    ) -> Self {
+        self.send_available_low_water_mark = send_available_low_water_mark;
+        self
+    }
 
-            let push = SendingPush::new(Arc::new(body), *now);
+    #[must_use]
+    pub fn swnd_size_cap(mut self, swnd_size_cap: usize) -> Self {
+        self.swnd_size_cap = swnd_size_cap;
+        self
+    }
 
-            // write the frag, including its hdr and body, to output buffer
-            let seq = self.swnd.end();
-            let frag = FragBuilder {
-                seq,
-                cmd: FragCommand::Push {
-                    body: Body::Pasta(Arc::clone(push.body())),
-                },
-            }
-            .build()
-            .unwrap();
-            bundler.pack(frag).unwrap();
+    #[must_use]
+    pub fn max_retransmissions(mut self, max_retransmissions: Option<usize>) -> Self {
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
 
-            // register seq to the rto lookup
-            self.last_sent_heap
-                .push(seq, cmp::Reverse(push.last_sent()));
+    #[must_use]
+    pub fn max_no_ack_duration(mut self, max_no_ack_duration: Option<Duration>) -> Self {
+        self.max_no_ack_duration = max_no_ack_duration;
+        self
+    }
 
-            // register the body to swnd
-            self.swnd.push_back(push);
+    #[must_use]
+    pub fn rst_transmissions(mut self, rst_transmissions: usize) -> Self {
+        self.rst_transmissions = rst_transmissions;
+        self
+    }
 
-            self.stat.pushes += 1;
-        }
+    #[must_use]
+    pub fn keepalive_interval(mut self, keepalive_interval: Option<Duration>) -> Self {
+        self.keepalive_interval = keepalive_interval;
+        self
+    }
 
-        self.check_rep();
-        return bundler.into_bundles();
+    #[must_use]
+    pub fn sack(mut self, sack: bool) -> Self {
+        self.sack = sack;
+        self
     }
 
     #[must_use]
-    #[inline]
-    pub fn rto(&self) -> time::Duration {
-        match self.stat.srtt {
-            Some(srtt) => {
-                let rto = srtt.mul_f64(self.ratio_rto_to_one_rtt);
-                let rto = Duration::min(rto, MAX_RTO);
-                let rto = Duration::max(rto, MIN_RTO);
-                rto
-            }
-            None => DEFAULT_RTO,
-        }
+    pub fn conn_id(mut self, conn_id: Option<u64>) -> Self {
+        self.conn_id = conn_id;
+        self
     }
 
     #[must_use]
-    pub fn mtu(&self) -> usize {
-        self.mtu
+    pub fn packet_seqs(mut self, packet_seqs: bool) -> Self {
+        self.packet_seqs = packet_seqs;
+        self
     }
 
-    #[inline]
-    fn set_remote_rwnd_size(&mut self, wnd: u16) {
-        self.swnd.set_remote_rwnd_size(wnd as usize);
-        self.check_rep();
+    #[must_use]
+    pub fn handshake(mut self, handshake: Option<Handshake>) -> Self {
+        self.handshake = handshake;
+        self
     }
 
-    #[inline]
-    fn set_local_next_seq_to_receive(&mut self, local_next_seq_to_receive: Seq32) {
-        self.local_next_seq_to_receive = local_next_seq_to_receive;
-        self.check_rep();
+    #[must_use]
+    pub fn auth(mut self, auth: Option<Arc<dyn PacketAuth>>) -> Self {
+        self.auth = auth;
+        self
     }
 
-    #[inline]
-    fn add_remote_seq_to_ack(&mut self, remote_seq_to_ack: Seq32) {
-        self.to_ack_queue.push_back(remote_seq_to_ack);
-        self.check_rep();
+    #[must_use]
+    pub fn wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
     }
 
-    #[inline]
-    fn set_acked_local_seq(&mut self, acked_local_seq: Seq32, now: &Instant) {
-        // remove the selected sequence
-        if let Some(frag) = self.swnd.remove(&acked_local_seq) {
-            if !frag.is_retransmitted() {
-                // set smooth RTT
-                let frag_rtt = frag.since_last_sent(now);
-                match self.stat.srtt {
-                    Some(srtt) => {
-                        let new_srtt = srtt.mul_f64(1.0 - ALPHA) + frag_rtt.mul_f64(ALPHA);
-                        self.stat.srtt = Some(new_srtt);
-                    }
-                    None => self.stat.srtt = Some(frag_rtt),
-                }
-            }
-            // else, `last_seen` might just been modified, letting `srtt` become smaller
-        }
-        self.check_rep();
+    #[must_use]
+    pub fn pad_packets_to(mut self, pad_packets_to: Option<usize>) -> Self {
+        self.pad_packets_to = pad_packets_to;
+        self
     }
 
-    #[inline]
-    fn remove_sending_before(&mut self, remote_nack: Seq32) {
-        self.swnd.remove_before(remote_nack);
-        self.check_rep();
+    #[must_use]
+    pub fn fec_group_size(mut self, fec_group_size: Option<usize>) -> Self {
+        self.fec_group_size = fec_group_size;
+        self
     }
 
-    #[inline]
-    fn set_local_rwnd_size(&mut self, local_rwnd_size: usize) {
-        self.local_rwnd_size = local_rwnd_size;
-        self.check_rep();
+    #[must_use]
+    pub fn codec(mut self, codec: Option<Arc<dyn BodyCodec>>) -> Self {
+        self.codec = codec;
+        self
     }
 
-    #[inline]
-    pub fn set_state(&mut self, delta: SetUploadState, now: &Instant) -> Result<(), SetStateError> {
-        for &acked_local_seq in &delta.acked_local_seqs {
-            if acked_local_seq == delta.remote_nack {
-                return Err(SetStateError::InvalidState);
-            }
-        }
+    #[must_use]
+    pub fn cumulative_ack_only(mut self, cumulative_ack_only: bool) -> Self {
+        self.cumulative_ack_only = cumulative_ack_only;
+        self
+    }
+
+    #[must_use]
+    pub fn ack_bitmap(mut self, ack_bitmap: bool) -> Self {
+        self.ack_bitmap = ack_bitmap;
+        self
+    }
+
+    #[must_use]
+    pub fn mtu_probing(mut self, mtu_probing: Option<MtuProbing>) -> Self {
+        self.mtu_probing = mtu_probing;
+        self
+    }
+
+    #[must_use]
+    pub fn initial_seq(mut self, initial_seq: Option<Seq32>) -> Self {
+        self.initial_seq = initial_seq;
+        self
+    }
+}
+
+/// Just enough of the sending state to resume the stream after a restart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkpoint {
+    /// The sequence the next new push would have been assigned
+    pub next_seq: Seq32,
+    /// Every sequence before this one has been acked
+    pub acked_prefix: Seq32,
+    pub local_next_seq_to_receive: Seq32,
+}
+
+#[derive(Debug)]
+pub enum SetStateError {
+    /// `local_rwnd_size` does not fit in the packet header
+    LocalRwndTooLarge { size: usize },
+    /// A seq is acked while the nack says it is not received yet
+    AckedNack { seq: Seq32 },
+    /// A sacked range covers the nack
+    SackedNack { range: Range<Seq32> },
+    /// Strict mode only
+    UnknownAck { seq: Seq32 },
+}
+
+impl fmt::Display for SetStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetStateError::LocalRwndTooLarge { size } => {
+                write!(f, "local rwnd size {} over {}", size, u16::MAX)
+            }
+            SetStateError::AckedNack { seq } => {
+                write!(f, "seq {} both acked and nacked", seq.to_u32())
+            }
+            SetStateError::SackedNack { range } => write!(
+                f,
+                "sacked range {}..{} covers the nack",
+                range.start.to_u32(),
+                range.end.to_u32()
+            ),
+            SetStateError::UnknownAck { seq } => {
+                write!(f, "ack of seq {} never sent", seq.to_u32())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SetStateError {}
+
+#[derive(Debug)]
+pub enum OutputError {
+    NothingToOutput,
+    BufferTooSmall,
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputError::NothingToOutput => write!(f, "nothing to output"),
+            OutputError::BufferTooSmall => write!(f, "buffer too small for a packet"),
+        }
+    }
+}
+
+impl std::error::Error for OutputError {}
+
+#[derive(Debug)]
+pub enum BuildError {
+    MtuTooSmall,
+    RtoJitterNegative,
+    /// `ratio_rto_to_one_rtt` is not positive
+    InvalidRtoRatio,
+    /// `min_rto <= initial_rto <= max_rto` does not hold
+    InvalidRtoBounds,
+    LocalRecvBufTooLarge,
+    ToSendBytesCapZero,
+    ToSendQueueLenCapZero,
+    SwndSizeCapZero,
+    FastRetransmitBurstZero,
+    /// The send queue never holds fewer than zero bytes
+    LowWaterMarkZero,
+    /// A push already sent would not fit in a packet anymore
+    MtuBelowInFlightPush,
+    /// The `PacketAuth` makes tags longer than `MAX_TAG_LEN`
+    AuthTagTooLong,
+    /// `fec_group_size` is not between 1 and `u8::MAX`
+    FecGroupSize,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::MtuTooSmall => write!(f, "`mtu` too small for a push"),
+            BuildError::RtoJitterNegative => write!(f, "`rto_jitter` negative"),
+            BuildError::InvalidRtoRatio => write!(f, "`ratio_rto_to_one_rtt` not positive"),
+            BuildError::InvalidRtoBounds => {
+                write!(f, "`min_rto <= initial_rto <= max_rto` does not hold")
+            }
+            BuildError::LocalRecvBufTooLarge => write!(f, "`local_recv_buf_len` above `u16::MAX`"),
+            BuildError::ToSendBytesCapZero => write!(f, "`to_send_bytes_cap` zero"),
+            BuildError::ToSendQueueLenCapZero => write!(f, "`to_send_queue_len_cap` zero"),
+            BuildError::SwndSizeCapZero => write!(f, "`swnd_size_cap` zero"),
+            BuildError::FastRetransmitBurstZero => write!(f, "`max_fast_retransmit_burst` zero"),
+            BuildError::LowWaterMarkZero => write!(f, "`send_available_low_water_mark` zero"),
+            BuildError::MtuBelowInFlightPush => write!(f, "`mtu` below a push in flight"),
+            BuildError::AuthTagTooLong => write!(f, "auth tag longer than `MAX_TAG_LEN`"),
+            BuildError::FecGroupSize => write!(f, "`fec_group_size` not between 1 and 255"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// `Duration::mul_f64` panics on overflow
+///
+/// `rhs` must not be negative.
+#[inline]
+fn mul_f64_saturating(lhs: Duration, rhs: f64) -> Duration {
+    Duration::try_from_secs_f64(lhs.as_secs_f64() * rhs).unwrap_or(Duration::MAX)
+}
+
+#[derive(Clone, Copy)]
+struct Budget {
+    max_bytes: usize,
+    // bytes of each packet header
+    hdr_len: usize,
+}
+
+/// The largest local seq acked or sacked by `delta`
+fn max_acked_local_seq(delta: &SetUploadState) -> Option<Seq32> {
+    let sacked = delta
+        .sacked_local_ranges
+        .iter()
+        .map(|range| Seq32::from_u32(range.end.to_u32().wrapping_sub(1)));
+    delta.acked_local_seqs.iter().copied().chain(sacked).max()
+}
+
+/// Whether the packets stay within `budget` with a frag of `frag_len` packed
+#[inline]
+fn fits_budget(bundler: &FragBundler, budget: Option<Budget>, frag_len: usize) -> bool {
+    match budget {
+        Some(budget) => bundler.wire_len_after(frag_len, budget.hdr_len) <= budget.max_bytes,
+        None => true,
+    }
+}
+
+/// Bytes of a packet header with or without the version byte, the timestamp options, a
+/// connection ID and a packet seq
+fn packet_hdr_len(
+    legacy_header: bool,
+    has_timestamps: bool,
+    conn_id: Option<u64>,
+    has_packet_seq: bool,
+) -> usize {
+    let mut len = match legacy_header {
+        true => LEGACY_PACKET_HDR_LEN,
+        false => PACKET_HDR_LEN,
+    };
+    // a legacy header needs the options to flag the connection ID and the packet seq
+    match legacy_header {
+        true if has_timestamps || conn_id.is_some() || has_packet_seq => {
+            len += LEGACY_PACKET_OPTIONS_LEN
+        }
+        false if has_timestamps => len += PACKET_OPTIONS_LEN,
+        _ => (),
+    }
+    if conn_id.is_some() {
+        len += CONN_ID_LEN;
+    }
+    if has_packet_seq {
+        len += PACKET_SEQ_LEN;
+    }
+    len
+}
+
+/// `hdr_len` is the longest packet header to be emitted
+fn check_mtu(
+    mtu: usize,
+    hdr_len: usize,
+    format: WireFormat,
+    has_fec: bool,
+) -> Result<(), BuildError> {
+    if mtu < hdr_len + ACK_HDR_LEN || mtu <= hdr_len + push_hdr_len(format, has_fec) {
+        return Err(BuildError::MtuTooSmall);
+    }
+    Ok(())
+}
+
+/// The longest header of a push or of the parity covering it
+#[inline]
+fn push_hdr_len(format: WireFormat, has_fec: bool) -> usize {
+    match has_fec {
+        true => format.parity_hdr_len(),
+        false => format.push_hdr_len(),
+    }
+}
+
+/// Counters kept for the whole life of the uploader
+///
+/// `stat_reset` leaves them alone. They wrap on overflow.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LifetimeTotals {
+    /// Bytes accepted by the write methods
+    pub bytes_written: u64,
+    pub packets_emitted: u64,
+}
+
+enum EmitTrigger {
+    Ack,
+    Timer,
+}
+
+pub enum SendError<T> {
+    QueueFull(T),
+    Broken(T),
+    /// Strict mode only
+    Empty(T),
+    /// `close` has been called
+    Closed(T),
+}
+
+/// Leaves out the rejected data
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SendError::QueueFull(_) => "QueueFull",
+            SendError::Broken(_) => "Broken",
+            SendError::Empty(_) => "Empty",
+            SendError::Closed(_) => "Closed",
+        };
+        f.debug_tuple(name).finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::QueueFull(_) => write!(f, "send queue full"),
+            SendError::Broken(_) => write!(f, "connection broken"),
+            SendError::Empty(_) => write!(f, "nothing to send"),
+            SendError::Closed(_) => write!(f, "sending side closed"),
+        }
+    }
+}
+
+impl<T> std::error::Error for SendError<T> {}
+
+impl<T> SendError<T> {
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::QueueFull(x) => x,
+            SendError::Broken(x) => x,
+            SendError::Empty(x) => x,
+            SendError::Closed(x) => x,
+        }
+    }
+}
+
+impl Uploader {
+    #[inline]
+    fn check_rep(&self) {
+        assert!(self.local_rwnd_size <= u16::MAX as usize);
+    }
+
+    #[must_use]
+    pub fn stat(&self) -> Stat {
+        Stat {
+            srtt: self.stat.srtt,
+            retransmissions: self.stat.retransmissions,
+            rto_hits: self.stat.rto_hits,
+            spurious_rtos: self.stat.spurious_rtos,
+            duplicate_acks_received: self.stat.duplicate_acks_received,
+            fast_retransmissions: self.stat.fast_retransmissions,
+            pushes: self.stat.pushes,
+            acks: self.stat.acks,
+            bytes_pushed: self.stat.bytes_pushed,
+            bytes_retransmitted: self.stat.bytes_retransmitted,
+            packets_emitted: self.stat.packets_emitted,
+            packets_on_ack: self.stat.packets_on_ack,
+            bytes_on_ack: self.stat.bytes_on_ack,
+            packets_on_timer: self.stat.packets_on_timer,
+            bytes_on_timer: self.stat.bytes_on_timer,
+            expired_writes: self.stat.expired_writes,
+            probes_sent: self.stat.probes_sent,
+            probes_acked: self.stat.probes_acked,
+            conn_ids_deferred: self.stat.conn_ids_deferred,
+            echoes_skipped: self.stat.echoes_skipped,
+            retransmits_too_large: self.stat.retransmits_too_large,
+            next_seq_to_send: self.swnd.end(),
+            bytes_in_flight: self.bytes_in_flight,
+            pending_bytes: self.pending_bytes(),
+            queue_delay_estimate: self.queue_delay_estimate(),
+            delivery_rate_bps: self.stat.delivery_rate,
+        }
+    }
+
+    /// How much longer the smoothed RTT is than the lowest RTT seen recently
+    ///
+    /// A growing value means packets are waiting in a queue along the path.
+    #[must_use]
+    pub fn queue_delay_estimate(&self) -> Option<Duration> {
+        let srtt = self.stat.srtt?;
+        let rtt_min = self.rtt_min.get()?;
+        Some(srtt.saturating_sub(rtt_min))
+    }
+
+    /// Same as `stat` but also zeroes the counters
+    ///
+    /// `srtt` and `delivery_rate_bps` are kept.
+    pub fn stat_reset(&mut self) -> Stat {
+        let stat = self.stat();
+        self.stat = LocalStat {
+            srtt: self.stat.srtt,
+            delivery_rate: self.stat.delivery_rate,
+            ..Default::default()
+        };
+        stat
+    }
+
+    /// Number of remote sequences waiting to be acked
+    #[must_use]
+    pub fn pending_acks(&self) -> usize {
+        self.to_ack_queue.len()
+    }
+
+    /// Pushes sent but not yet acked
+    ///
+    /// Less than the span from the oldest of them to `swnd_end` once pushes are acked out of
+    /// order; that span is what `swnd_cap` and `remote_rwnd` limit.
+    #[must_use]
+    pub fn swnd_len(&self) -> usize {
+        self.swnd.len()
+    }
+
+    /// Maximum number of pushes in flight, as set by `set_swnd_size_cap`
+    #[must_use]
+    pub fn swnd_cap(&self) -> usize {
+        self.swnd.wnd_size_cap()
+    }
+
+    /// Receive window of the peer as last seen by `set_state`
+    #[must_use]
+    pub fn remote_rwnd(&self) -> usize {
+        self.swnd.remote_rwnd_size()
+    }
+
+    /// The largest nack received; every push before it has reached the peer
+    #[must_use]
+    pub fn acked_watermark(&self) -> Seq32 {
+        self.max_remote_nack
+    }
+
+    /// Body bytes of the pushes acked so far, explicitly or by the nack
+    #[must_use]
+    pub fn bytes_acked(&self) -> u64 {
+        self.delivered
+    }
+
+    /// Smoothed RTT; `None` until a push is acked
+    #[must_use]
+    pub fn srtt(&self) -> Option<Duration> {
+        self.stat.srtt
+    }
+
+    /// The sequence the next new push will be assigned
+    #[must_use]
+    pub fn swnd_end(&self) -> Seq32 {
+        self.swnd.end()
+    }
+
+    /// Bytes of pushes sent but not yet acknowledged
+    #[must_use]
+    pub fn bytes_in_flight(&self) -> usize {
+        self.bytes_in_flight
+    }
+
+    /// Whether `write` would fail with `SendError::QueueFull` for any non-empty slice
+    #[must_use]
+    pub fn is_send_queue_full(&self) -> bool {
+        self.to_send_queue.is_full()
+    }
+
+    /// Bytes written but not yet sent
+    #[must_use]
+    pub fn pending_bytes(&self) -> usize {
+        self.urgent_queue.data_len()
+            + self.unreliable_queue.data_len()
+            + self.to_send_queue.data_len()
+    }
+
+    #[must_use]
+    pub fn lifetime_totals(&self) -> LifetimeTotals {
+        self.totals
+    }
+
+    #[must_use]
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            next_seq: self.swnd.end(),
+            acked_prefix: self.swnd.start(),
+            local_next_seq_to_receive: self.local_next_seq_to_receive,
+        }
+    }
+
+    pub fn set_on_send_available(
+        &mut self,
+        observer: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    ) {
+        self.on_send_available = observer;
+    }
+
+    /// Notified at the end of a `set_state` that acked any push
+    ///
+    /// Under a `SharedUploader` it runs after the lock is released.
+    pub fn set_on_ack(&mut self, observer: Option<Weak<dyn IAckObserver + Send + Sync + 'static>>) {
+        self.on_ack = observer;
+    }
+
+    /// Notified at the end of an emit that retransmitted any push on timeout,
+    /// and at the end of a `set_state` that found any of those timeouts spurious
+    ///
+    /// Under a `SharedUploader` it runs after the lock is released.
+    pub fn set_on_rto(&mut self, observer: Option<Weak<dyn IRtoObserver + Send + Sync + 'static>>) {
+        self.on_rto = observer;
+    }
+
+    /// Notified at the end of a `set_state` that acked the last push in flight with nothing left to send
+    ///
+    /// Under a `SharedUploader` it runs after the lock is released.
+    pub fn set_on_drained(
+        &mut self,
+        observer: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+    ) {
+        self.on_drained = observer;
+    }
+
+    /// Every write has been acked and every ack has been emitted
+    #[must_use]
+    pub fn is_drained(&self) -> bool {
+        self.pending_bytes() == 0
+            && self.swnd.is_empty()
+            && self.to_ack_queue.is_empty()
+            && !(self.is_send_closed && self.fin_seq.is_none())
+            && self.ping_to_send.is_none()
+            && self.pongs_to_send.is_empty()
+            && self.probe_ack_to_send.is_none()
+            && self.sack_to_send.is_none()
+            && self.syn_ack_to_send.is_none()
+            && !(self.is_client() && self.handshake_state != HandshakeState::Established)
+    }
+
+    /// Pushes go out once the session is established, or right after the `SynAck` of a server
+    #[must_use]
+    pub fn handshake_state(&self) -> HandshakeState {
+        self.handshake_state
+    }
+
+    #[inline]
+    fn is_client(&self) -> bool {
+        self.handshake.map(|x| x.role) == Some(Role::Client)
+    }
+
+    /// Whether nothing has been emitted for `keepalive_interval`
+    ///
+    /// Always false without a `keepalive_interval`.
+    #[must_use]
+    pub fn keepalive_due(&self, now: &Instant) -> bool {
+        match (self.keepalive_interval, self.last_activity) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(interval), Some(last_activity)) => interval <= now.duration_since(last_activity),
+        }
+    }
+
+    /// Queue a `Ping` for the next emit and return its token
+    ///
+    /// The peer answers with a `Pong`; `last_pong_received` tells when the latest ping was
+    /// answered.
+    pub fn send_ping(&mut self, now: &Instant) -> u32 {
+        let token = self.next_ping_token;
+        self.next_ping_token = self.next_ping_token.wrapping_add(1);
+        self.ping_to_send = Some(token);
+        self.last_ping_token = Some(token);
+        self.last_activity = Some(*now);
+        self.check_rep();
+        token
+    }
+
+    /// The ID put in the packet headers, if any
+    #[must_use]
+    pub fn conn_id(&self) -> Option<u64> {
+        self.conn_id
+    }
+
+    /// When the pong to the latest ping arrived
+    #[must_use]
+    pub fn last_pong_received(&self) -> Option<Instant> {
+        self.last_pong_received
+    }
+
+    /// End the outgoing stream after the data written so far
+    ///
+    /// A `Fin` frag takes the seq after the last push and is retransmitted until acked. Writes
+    /// fail with `SendError::Closed` from now on. Only this direction closes: the acks for the
+    /// peer's pushes keep being emitted.
+    pub fn close(&mut self) {
+        self.is_send_closed = true;
+        self.check_rep();
+    }
+
+    /// Whether `close` has been called; the stream from the peer may still be open
+    #[must_use]
+    pub fn is_send_closed(&self) -> bool {
+        self.is_send_closed
+    }
+
+    /// Tear the stream down without waiting for the data in flight
+    ///
+    /// The pending data is dropped and emits carry nothing but an `Rst` frag, sent
+    /// `rst_transmissions` times an RTO apart. The peer discards what it has buffered.
+    pub fn abort(&mut self) {
+        self.clear_pending();
+        self.is_send_closed = true;
+        self.is_aborted = true;
+        self.check_rep();
+    }
+
+    #[must_use]
+    pub fn is_aborted(&self) -> bool {
+        self.is_aborted
+    }
+
+    pub fn write(&mut self, slice: buf::BufSlice) -> Result<(), SendError<buf::BufSlice>> {
+        if self.is_broken {
+            return Err(SendError::Broken(slice));
+        }
+        if self.is_send_closed {
+            return Err(SendError::Closed(slice));
+        }
+        if self.strict && slice.is_empty() {
+            return Err(SendError::Empty(slice));
+        }
+        let len = slice.len();
+        let result = match self.to_send_queue.push_back(slice) {
+            Ok(_) => {
+                self.totals.bytes_written = self.totals.bytes_written.wrapping_add(len as u64);
+                Ok(())
+            }
+            Err(e) => Err(SendError::QueueFull(e.0)),
+        };
+        self.arm_send_available();
+        result
+    }
+
+    /// Same as `write` but `slice` is dropped if it is still queued once `deadline` has passed
+    ///
+    /// Nothing is dropped once any of it has been assigned a seq, so the stream stays consistent.
+    pub fn write_with_deadline(
+        &mut self,
+        slice: buf::BufSlice,
+        deadline: Instant,
+    ) -> Result<(), SendError<buf::BufSlice>> {
+        if self.is_broken {
+            return Err(SendError::Broken(slice));
+        }
+        if self.is_send_closed {
+            return Err(SendError::Closed(slice));
+        }
+        if self.strict && slice.is_empty() {
+            return Err(SendError::Empty(slice));
+        }
+        let len = slice.len();
+        let result = match self.to_send_queue.push_back_with_deadline(slice, deadline) {
+            Ok(_) => {
+                self.totals.bytes_written = self.totals.bytes_written.wrapping_add(len as u64);
+                Ok(())
+            }
+            Err(e) => Err(SendError::QueueFull(e.0)),
+        };
+        self.arm_send_available();
+        result
+    }
+
+    /// Queue `slice` ahead of everything in the send queue
+    ///
+    /// Urgent writes are sent in order before any bulk data that has not been assigned a seq yet.
+    /// They are not bounded by the send queue caps.
+    pub fn write_urgent(&mut self, slice: buf::BufSlice) -> Result<(), SendError<buf::BufSlice>> {
+        if self.is_broken {
+            return Err(SendError::Broken(slice));
+        }
+        if self.is_send_closed {
+            return Err(SendError::Closed(slice));
+        }
+        let len = slice.len();
+        self.urgent_queue.push_back(slice).map_err(|_| ()).unwrap();
+        self.totals.bytes_written = self.totals.bytes_written.wrapping_add(len as u64);
+        Ok(())
+    }
+
+    /// Queue `slice` to be sent once and never retransmitted
+    ///
+    /// Its pushes take seqs of the stream as usual but leave the swnd once sent, so they do not
+    /// hold up the window. If one is not acked within an RTO, a `Skip` frag tells the peer to stop
+    /// waiting for it. Unreliable writes go out after the urgent ones and before any bulk data
+    /// that has not been assigned a seq yet. They are bounded by the send queue caps.
+    pub fn write_unreliable(
+        &mut self,
+        slice: buf::BufSlice,
+    ) -> Result<(), SendError<buf::BufSlice>> {
+        if self.is_broken {
+            return Err(SendError::Broken(slice));
+        }
+        if self.is_send_closed {
+            return Err(SendError::Closed(slice));
+        }
+        if self.strict && slice.is_empty() {
+            return Err(SendError::Empty(slice));
+        }
+        let len = slice.len();
+        self.unreliable_queue
+            .push_back(slice)
+            .map_err(|e| SendError::QueueFull(e.0))?;
+        self.totals.bytes_written = self.totals.bytes_written.wrapping_add(len as u64);
+        Ok(())
+    }
+
+    /// Queue as much of `slice` as the send queue takes
+    ///
+    /// Returns the number of bytes taken and the rest of `slice`.
+    pub fn write_partial(&mut self, slice: buf::BufSlice) -> (usize, Option<buf::BufSlice>) {
+        if slice.is_empty() {
+            return (0, None);
+        }
+        if self.is_broken || self.is_send_closed {
+            return (0, Some(slice));
+        }
+        let free_bytes = self.free_bytes();
+        if free_bytes == 0 {
+            return (0, Some(slice));
+        }
+        let (slice, rest) = match free_bytes < slice.len() {
+            true => {
+                let (head, tail) = slice.split(free_bytes).unwrap();
+                (head, Some(tail))
+            }
+            false => (slice, None),
+        };
+        let len = slice.len();
+        self.write(slice).map_err(|_| ()).unwrap();
+        (len, rest)
+    }
+
+    /// Drop the data written but not yet sent
+    ///
+    /// Pushes already in flight are kept and retransmitted as usual.
+    /// Returns the number of bytes dropped.
+    pub fn clear_pending(&mut self) -> usize {
+        self.arm_send_available();
+        let cleared =
+            self.urgent_queue.clear() + self.unreliable_queue.clear() + self.to_send_queue.clear();
+        self.check_rep();
+        self.notify_send_available();
+        cleared
+    }
+
+    #[inline]
+    fn notify_send_available(&mut self) {
+        // leave the notification to `SharedUploader` if it took the observer
+        if let Some(x) = self.on_send_available.as_ref().and_then(|x| x.upgrade()) {
+            if self.take_send_available() {
+                x.notify();
+            }
+        }
+    }
+
+    /// Bytes the send queue can still take
+    #[inline]
+    fn free_bytes(&self) -> usize {
+        match self.to_send_queue.is_full() {
+            true => 0,
+            false => self.to_send_queue.free_bytes(),
+        }
+    }
+
+    /// Copy `data` into the send queue
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<(), SendError<()>> {
+        if !self.strict && data.is_empty() {
+            return Ok(());
+        }
+        match self.write(buf::BufSlice::from_bytes(data.to_vec())) {
+            Ok(()) => Ok(()),
+            Err(SendError::QueueFull(_)) => Err(SendError::QueueFull(())),
+            Err(SendError::Broken(_)) => Err(SendError::Broken(())),
+            Err(SendError::Empty(_)) => Err(SendError::Empty(())),
+            Err(SendError::Closed(_)) => Err(SendError::Closed(())),
+        }
+    }
+
+    /// Queue all the slices or none of them
+    pub fn write_vectored(
+        &mut self,
+        slices: Vec<buf::BufSlice>,
+    ) -> Result<(), SendError<Vec<buf::BufSlice>>> {
+        if self.is_broken {
+            return Err(SendError::Broken(slices));
+        }
+        if self.is_send_closed {
+            return Err(SendError::Closed(slices));
+        }
+        let non_empty = slices.iter().filter(|slice| !slice.is_empty()).count();
+        let len: usize = slices.iter().map(|slice| slice.len()).sum();
+        if self.to_send_queue.free_len() < non_empty || self.to_send_queue.free_bytes() < len {
+            return Err(SendError::QueueFull(slices));
+        }
+        for slice in slices {
+            if slice.is_empty() {
+                continue;
+            }
+            self.totals.bytes_written = self.totals.bytes_written.wrapping_add(slice.len() as u64);
+            self.to_send_queue.push_back(slice).map_err(|_| ()).unwrap();
+        }
+        self.arm_send_available();
+        Ok(())
+    }
+
+    #[inline]
+    fn arm_send_available(&mut self) {
+        if self.to_send_queue.is_full() {
+            self.is_send_available_armed = true;
+        }
+    }
+
+    /// Whether the send queue has drained to `send_available_low_water_mark` since it was full
+    ///
+    /// Disarms the notification until the queue is full again.
+    pub(crate) fn take_send_available(&mut self) -> bool {
+        self.arm_send_available();
+        if !self.is_send_available_armed || self.to_send_queue.is_full() {
+            return false;
+        }
+        let is_available = match self.send_available_low_water_mark {
+            Some(mark) => self.to_send_queue.data_len() < mark,
+            None => true,
+        };
+        if is_available {
+            self.is_send_available_armed = false;
+        }
+        is_available
+    }
+
+    /// Whether the peer is considered gone.
+    ///
+    /// Once broken, `emit` only outputs acks and `write` returns `SendError::Broken`.
+    #[must_use]
+    pub fn is_broken(&self, now: &Instant) -> bool {
+        if self.is_broken {
+            return true;
+        }
+        match (self.max_no_ack_duration, self.no_ack_since) {
+            (Some(max), Some(since)) => max <= now.duration_since(since),
+            _ => false,
+        }
+    }
+
+    #[must_use]
+    pub fn emit(&mut self, now: &Instant) -> Vec<Packet> {
+        self.emit_by(now, None, None)
+    }
+
+    /// Same as `emit` but counted as ack-clocked in `Stat`
+    ///
+    /// Call it right after `set_state`.
+    #[must_use]
+    pub fn emit_on_ack(&mut self, now: &Instant) -> Vec<Packet> {
+        self.emit_by(now, Some(EmitTrigger::Ack), None)
+    }
+
+    /// Same as `emit` but counted as timer-driven in `Stat`
+    #[must_use]
+    pub fn emit_on_timer(&mut self, now: &Instant) -> Vec<Packet> {
+        self.emit_by(now, Some(EmitTrigger::Timer), None)
+    }
+
+    /// Same as `emit` but stops before the packets add up to more than `max_bytes`
+    ///
+    /// What does not fit stays queued for later emits. Retransmissions take the budget before new data.
+    #[must_use]
+    pub fn emit_budgeted(&mut self, now: &Instant, max_bytes: usize) -> Vec<Packet> {
+        self.emit_by(now, None, Some(max_bytes))
+    }
+
+    /// Same as `emit` but outputs the packets encoded into writers taken from `pool`
+    ///
+    /// Each writer holds one datagram; put it back into `pool` once sent.
+    #[must_use]
+    pub fn emit_serialized(&mut self, now: &Instant, pool: &mut BufPool) -> Vec<OwnedBufWtr> {
+        let packets = self.emit(now);
+        packets
+            .into_iter()
+            .map(|packet| {
+                // a probe outgrows the MTU
+                let mut wtr = pool.take(usize::max(self.mtu, packet.wire_len()));
+                packet.append_to(&mut wtr).unwrap();
+                wtr
+            })
+            .collect()
+    }
+
+    /// Same as `emit_serialized` but each datagram is rewritten by `transform`
+    ///
+    /// The datagrams outgrow `mtu` by whatever `transform` adds.
+    #[must_use]
+    pub fn emit_transformed(
+        &mut self,
+        now: &Instant,
+        pool: &mut BufPool,
+        transform: &dyn PacketTransform,
+    ) -> Vec<OwnedBufWtr> {
+        let mut datagrams = self.emit_serialized(now, pool);
+        for wtr in &mut datagrams {
+            transform.encode(wtr);
+        }
+        datagrams
+    }
+
+    /// Same as `emit` but outputs a packet with just the header if there is nothing else to send
+    ///
+    /// Lets the peer learn about a receive window freed by the application.
+    #[must_use]
+    pub fn emit_window_update(&mut self, now: &Instant) -> Vec<Packet> {
+        let mut packets = self.emit(now);
+        if packets.is_empty() {
+            let (timestamp, timestamp_echo) = self.packet_hdr_options(self.mtu, now);
+            self.timestamp_to_echo = None;
+            self.is_nack_pending = false;
+            let hdr = PacketHeaderBuilder {
+                rwnd: self.local_rwnd_size as u16,
+                nack: self.local_next_seq_to_receive,
+                timestamp,
+                timestamp_echo,
+                conn_id: self.conn_id,
+                packet_seq: self.take_packet_seq(),
+            }
+            .build()
+            .unwrap()
+            .with_version(self.header_version());
+            let mut frags = vec![];
+            self.pad(&hdr, &mut frags, self.mtu, None);
+            let packet = PacketBuilder { hdr, frags }
+                .build()
+                .unwrap()
+                .with_auth(self.auth.clone())
+                .with_format(self.wire_format);
+            packets.push(packet);
+            self.stat.packets_emitted += 1;
+            self.totals.packets_emitted = self.totals.packets_emitted.wrapping_add(1);
+        }
+        self.check_rep();
+        packets
+    }
+
+    /// A packet padded to the size of the MTU probe due, if any
+    fn emit_probe(&mut self, now: &Instant) -> Option<Packet> {
+        if self.is_broken || self.is_aborted || self.handshake_state != HandshakeState::Established
+        {
+            return None;
+        }
+        let timeout = self.rto();
+        let (id, size) = self.mtu_prober.as_mut()?.poll(self.mtu, now, timeout)?;
+        let hdr = PacketHeaderBuilder {
+            rwnd: self.local_rwnd_size as u16,
+            nack: self.local_next_seq_to_receive,
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: self.conn_id,
+            packet_seq: self.take_packet_seq(),
+        }
+        .build()
+        .unwrap();
+        let mut frags = vec![FragBuilder {
+            seq: self.swnd.end(),
+            cmd: FragCommand::Probe { id },
+        }
+        .build()
+        .unwrap()];
+        self.pad_to(&hdr, &mut frags, size, None);
+        let packet = PacketBuilder { hdr, frags }
+            .build()
+            .unwrap()
+            .with_auth(self.auth.clone())
+            .with_format(self.wire_format);
+        self.stat.probes_sent += 1;
+        self.stat.packets_emitted += 1;
+        self.totals.packets_emitted = self.totals.packets_emitted.wrapping_add(1);
+        Some(packet)
+    }
+
+    #[must_use]
+    fn emit_by(
+        &mut self,
+        now: &Instant,
+        trigger: Option<EmitTrigger>,
+        budget: Option<usize>,
+    ) -> Vec<Packet> {
+        if self.is_broken(now) {
+            self.is_broken = true;
+        }
+        self.arm_send_available();
+        let rto_hits_then = self.stat.rto_hits;
+        let mut packets = self.emit_packets(self.mtu, now, budget).unwrap();
+        if budget.is_none() {
+            packets.extend(self.emit_probe(now));
+        }
+
+        if let Some(trigger) = trigger {
+            let mut bytes = 0;
+            for packet in &packets {
+                bytes += packet.wire_len();
+            }
+            match trigger {
+                EmitTrigger::Ack => {
+                    self.stat.packets_on_ack += packets.len() as u64;
+                    self.stat.bytes_on_ack += bytes as u64;
+                }
+                EmitTrigger::Timer => {
+                    self.stat.packets_on_timer += packets.len() as u64;
+                    self.stat.bytes_on_timer += bytes as u64;
+                }
+            }
+        }
+
+        self.check_rep();
+
+        // callback when `to_send` has drained
+        // after `check_rep` so that the observer sees a consistent state
+        self.notify_send_available();
+        let rto_hits = (self.stat.rto_hits - rto_hits_then) as usize;
+        if rto_hits != 0 {
+            self.notify(Notifications {
+                rto_hits,
+                ..Default::default()
+            });
+        }
+
+        packets
+    }
+
+    fn emit_packets(
+        &mut self,
+        packet_space: usize,
+        now: &Instant,
+        budget: Option<usize>,
+    ) -> Result<Vec<Packet>, OutputError> {
+        let (timestamp, timestamp_echo) = self.packet_hdr_options(packet_space, now);
+        let has_timestamps = timestamp.is_some() || timestamp_echo.is_some();
+        // the tag is overhead like the header
+        let hdr_len = packet_hdr_len(
+            self.legacy_header,
+            has_timestamps,
+            self.conn_id,
+            self.next_packet_seq.is_some(),
+        ) + self.tag_len();
+        if !(hdr_len + ACK_HDR_LEN <= packet_space) {
+            self.check_rep();
+            return Err(OutputError::BufferTooSmall);
+        }
+        if packet_space <= hdr_len + push_hdr_len(self.wire_format, self.fec_group_size.is_some()) {
+            self.check_rep();
+            return Err(OutputError::BufferTooSmall);
+        }
+
+        let budget = budget.map(|max_bytes| Budget { max_bytes, hdr_len });
+        let bundles = self.emit_frags(packet_space - hdr_len, now, budget);
+        // the padding takes only what the data leaves of the budget
+        let mut padding_budget = budget.map(|budget| {
+            let mut len = 0;
+            for frags in &bundles {
+                len += hdr_len + self.frags_len(frags);
+            }
+            budget.max_bytes.saturating_sub(len)
+        });
+        let mut packets = Vec::new();
+        if !bundles.is_empty() {
+            // echoed once
+            self.timestamp_to_echo = None;
+            self.is_nack_pending = false;
+            self.last_activity = Some(*now);
+        }
+
+        for frags in bundles {
+            // packet header
+            let hdr = PacketHeaderBuilder {
+                rwnd: self.local_rwnd_size as u16,
+                nack: self.local_next_seq_to_receive,
+                timestamp,
+                timestamp_echo,
+                conn_id: self.conn_id,
+                packet_seq: self.take_packet_seq(),
+            }
+            .build()
+            .unwrap()
+            .with_version(self.header_version());
+            let mut frags = frags;
+            let padding_len = self.pad(&hdr, &mut frags, packet_space, padding_budget);
+            if let Some(padding_budget) = &mut padding_budget {
+                *padding_budget -= padding_len;
+            }
+            let packet = PacketBuilder { hdr, frags }
+                .build()
+                .unwrap()
+                .with_auth(self.auth.clone())
+                .with_format(self.wire_format);
+            packets.push(packet);
+            self.stat.packets_emitted += 1;
+            self.totals.packets_emitted = self.totals.packets_emitted.wrapping_add(1);
+        }
+        self.check_rep();
+        Ok(packets)
+    }
+
+    #[inline]
+    #[must_use]
+    fn emit_frags(
+        &mut self,
+        space: usize,
+        now: &Instant,
+        budget: Option<Budget>,
+    ) -> Vec<Vec<Frag>> {
+        let mut bundler = FragBundler::new(space, self.wire_format, self.local_next_seq_to_receive);
+
+        if self.is_aborted {
+            let is_due = match self.rst_last_sent {
+                Some(last_sent) => self.rto() <= now.duration_since(last_sent),
+                None => true,
+            };
+            if is_due
+                && self.rsts_sent < self.rst_transmissions
+                && fits_budget(&bundler, budget, ACK_HDR_LEN)
+            {
+                let frag = FragBuilder {
+                    seq: self.swnd.end(),
+                    cmd: FragCommand::Rst,
+                }
+                .build()
+                .unwrap();
+                bundler.pack(frag).unwrap();
+                self.rsts_sent += 1;
+                self.rst_last_sent = Some(*now);
+            }
+            self.check_rep();
+            return bundler.into_bundles();
+        }
+
+        // handshake
+        if let Some(handshake) = self.handshake {
+            match (handshake.role, self.handshake_state) {
+                (_, HandshakeState::Established) | (Role::Server, HandshakeState::SynReceived) => {}
+                // sent an RTO apart until the syn ack comes
+                (Role::Client, _) => {
+                    let is_due = match self.syn_last_sent {
+                        Some(last_sent) => self.rto() <= now.duration_since(last_sent),
+                        None => true,
+                    };
+                    if is_due && fits_budget(&bundler, budget, SYN_LEN) {
+                        let frag = FragBuilder {
+                            seq: handshake.initial_seq,
+                            cmd: FragCommand::Syn {
+                                nonce: handshake.nonce,
+                            },
+                        }
+                        .build()
+                        .unwrap();
+                        bundler.pack(frag).unwrap();
+                        self.syn_last_sent = Some(*now);
+                        self.handshake_state = HandshakeState::SynSent;
+                    }
+                    self.check_rep();
+                    return bundler.into_bundles();
+                }
+                // no peer yet
+                (Role::Server, _) => {
+                    self.check_rep();
+                    return bundler.into_bundles();
+                }
+            }
+            // answered every time the syn comes
+            if let Some(nonce) = self.syn_ack_to_send {
+                if fits_budget(&bundler, budget, SYN_LEN) {
+                    let frag = FragBuilder {
+                        seq: handshake.initial_seq,
+                        cmd: FragCommand::SynAck { nonce },
+                    }
+                    .build()
+                    .unwrap();
+                    bundler.pack(frag).unwrap();
+                    self.syn_ack_to_send = None;
+                }
+            }
+        }
+
+        // piggyback ack
+        // leave room for a push in every packet while new data can be sent
+        let push_reserve = match self.pending_bytes() != 0 && !self.swnd.is_full() {
+            true => self.wire_format.push_hdr_len() + 1,
+            false => 0,
+        };
+        if self.is_broken {
+            self.pack_acks(&mut bundler, space, 0, budget);
+            self.check_rep();
+            return bundler.into_bundles();
+        }
+        self.pack_acks(&mut bundler, space, push_reserve, budget);
+
+        // selective ack
+        if let Some(mut ranges) = self.sack_to_send.take() {
+            ranges.truncate(space.saturating_sub(SACK_HDR_LEN) / SACK_RANGE_LEN);
+            let len = SACK_HDR_LEN + SACK_RANGE_LEN * ranges.len();
+            match !ranges.is_empty() && fits_budget(&bundler, budget, len) {
+                true => {
+                    let frag = FragBuilder {
+                        seq: self.local_next_seq_to_receive,
+                        cmd: FragCommand::Sack { ranges },
+                    }
+                    .build()
+                    .unwrap();
+                    bundler.pack(frag).unwrap();
+                }
+                false => self.sack_to_send = Some(ranges),
+            }
+        }
+
+        // keepalive
+        while let Some(&token) = self.pongs_to_send.front() {
+            if !fits_budget(&bundler, budget, PING_LEN) {
+                break;
+            }
+            let frag = FragBuilder {
+                seq: self.swnd.end(),
+                cmd: FragCommand::Pong { token },
+            }
+            .build()
+            .unwrap();
+            bundler.pack(frag).unwrap();
+            self.pongs_to_send.pop_front();
+        }
+        if let Some(id) = self.probe_ack_to_send {
+            if fits_budget(&bundler, budget, PING_LEN) {
+                let frag = FragBuilder {
+                    seq: self.swnd.end(),
+                    cmd: FragCommand::ProbeAck { id },
+                }
+                .build()
+                .unwrap();
+                bundler.pack(frag).unwrap();
+                self.probe_ack_to_send = None;
+            }
+        }
+        if let Some(token) = self.ping_to_send {
+            if fits_budget(&bundler, budget, PING_LEN) {
+                let frag = FragBuilder {
+                    seq: self.swnd.end(),
+                    cmd: FragCommand::Ping { token },
+                }
+                .build()
+                .unwrap();
+                bundler.pack(frag).unwrap();
+                self.ping_to_send = None;
+            }
+        }
+
+        // retransmission
+        // write pushes from sending
+        let mut is_burst_cut = false;
+        if !self.fast_retransmission_wnd.is_empty() {
+            for (burst, (&seq, push)) in self
+                .swnd
+                .range_mut(
+                    self.fast_retransmission_wnd.start(),
+                    self.fast_retransmission_wnd.end(),
+                )
+                .enumerate()
+            {
+                // the rest goes out on later calls
+                if self.max_fast_retransmit_burst <= burst
+                    || !fits_budget(
+                        &bundler,
+                        budget,
+                        self.wire_format.push_hdr_len() + push.body().len(),
+                    )
+                {
+                    is_burst_cut = true;
+                    break;
+                }
+                if let Some(max) = self.max_retransmissions {
+                    if max <= push.retransmissions() {
+                        self.is_broken = true;
+                        break;
+                    }
+                }
+                {
+                    // add push to collection
+                    let frag = FragBuilder {
+                        seq,
+                        cmd: push.frag_cmd(),
+                    }
+                    .build()
+                    .unwrap();
+                    // cut for a roomier packet than this one; left to the RTO
+                    // test case: `test_retransmit_too_large`
+                    if let Err(PackError::FragTooLarge) = bundler.pack(frag) {
+                        self.stat.retransmits_too_large += 1;
+                        continue;
+                    }
+                    push.to_retransmit(*now); // test case: `test_rto_once`
+                    self.rto_wheel
+                        .push(seq, push.retransmissions(), push.last_sent());
+                }
+                self.fast_retransmission_wnd.retransmitted(seq);
+                self.stat.fast_retransmissions += 1;
+                self.stat.retransmissions += 1;
+                self.stat.pushes += 1;
+                self.stat.bytes_pushed += push.body().len() as u64;
+                self.stat.bytes_retransmitted += push.body().len() as u64;
+            }
+        }
+        if self.is_broken {
+            self.pack_acks(&mut bundler, space, 0, budget);
+            self.check_rep();
+            return bundler.into_bundles();
+        }
+        // the rest of the window has been acked already
+        if !is_burst_cut {
+            self.fast_retransmission_wnd
+                .skip_to(self.fast_retransmission_wnd.end());
+        }
+
+        // timing wheel for rto
+        let rto = self.rto();
+        let mut too_large = Vec::new();
+        loop {
+            let swnd = &self.swnd;
+            let seq = match self
+                .rto_wheel
+                .peek(|seq, generation| match swnd.value(&seq) {
+                    Some(push) => push.retransmissions() == generation,
+                    None => false,
+                }) {
+                Some(seq) => seq,
+                None => break,
+            };
+            // the rest of the wheel came after it
+            if too_large.contains(&seq) {
+                break;
+            }
+            let timeout = mul_f64_saturating(rto, self.rto_jitter_factor(seq));
+            let push = self.swnd.value_mut(&seq).unwrap();
+            if now.duration_since(push.last_sent()) < timeout {
+                break;
+            }
+            if let Some(max) = self.max_retransmissions {
+                if max <= push.retransmissions() {
+                    self.is_broken = true;
+                    break;
+                }
+            }
+            if !fits_budget(
+                &bundler,
+                budget,
+                self.wire_format.push_hdr_len() + push.body().len(),
+            ) {
+                break;
+            }
+            {
+                // add push to collection
+                let frag = FragBuilder {
+                    seq,
+                    cmd: push.frag_cmd(),
+                }
+                .build()
+                .unwrap();
+                // cut for a roomier packet than this one; tried again on the next call
+                // test case: `test_retransmit_too_large`
+                if let Err(PackError::FragTooLarge) = bundler.pack(frag) {
+                    self.stat.retransmits_too_large += 1;
+                    too_large.push(seq);
+                    self.rto_wheel.pop();
+                    self.rto_wheel.push(seq, push.retransmissions(), *now);
+                    continue;
+                }
+                push.rto_retransmit(*now);
+                self.rto_wheel.pop();
+                self.rto_wheel
+                    .push(seq, push.retransmissions(), push.last_sent());
+            }
+            self.stat.rto_hits += 1;
+            self.stat.retransmissions += 1;
+            self.stat.pushes += 1;
+            self.stat.bytes_pushed += push.body().len() as u64;
+            self.stat.bytes_retransmitted += push.body().len() as u64;
+        }
+
+        if self.is_broken {
+            self.pack_acks(&mut bundler, space, 0, budget);
+            self.check_rep();
+            return bundler.into_bundles();
+        }
+
+        // unreliable pushes still not acked
+        for (seq, last_sent) in self.skips.iter_mut() {
+            if now.duration_since(*last_sent) < rto {
+                continue;
+            }
+            if !fits_budget(&bundler, budget, ACK_HDR_LEN) {
+                break;
+            }
+            let frag = FragBuilder {
+                seq: *seq,
+                cmd: FragCommand::Skip,
+            }
+            .build()
+            .unwrap();
+            bundler.pack(frag).unwrap();
+            *last_sent = *now;
+        }
+
+        let expired = self.to_send_queue.remove_expired(*now);
+        self.stat.expired_writes += expired as u64;
+
+        // move data from to_send queue to sending queue and output those data
+        while self.pending_bytes() != 0 && !self.swnd.is_full() {
+            // urgent and unreliable pushes never carry bulk data
+            let is_urgent = !self.urgent_queue.is_empty();
+            let is_unreliable = !is_urgent && !self.unreliable_queue.is_empty();
+            self.pack_acks(&mut bundler, space, push_reserve, budget);
+            // get as many bytes from the queue to body
+            // a push not fitting the loading bundle opens a new one;
+            // test cases: `test_body_limit_fallback`, `test_push_opens_bundle`
+            let frag_body_limit = usize::min(
+                bundler.body_limit(self.wire_format.push_hdr_len()),
+                self.wire_format.max_body_len(),
+            );
+            // the parity of the group takes the longest body and a longer header
+            let frag_body_limit = match self.fec_group_size {
+                Some(_) => usize::min(frag_body_limit, space - self.wire_format.parity_hdr_len()),
+                None => frag_body_limit,
+            };
+            assert!(frag_body_limit != 0);
+            if !is_urgent
+                && !is_unreliable
+                && self.coalesce_small_writes
+                && !self.swnd.is_empty()
+                && self.to_send_queue.data_len() < frag_body_limit
+            {
+                // wait for more writes or for the swnd to drain
+                break;
+            }
+            let frag_body_limit = match budget {
+                Some(budget) => {
+                    // bytes taken by a push with an empty body
+                    let base = bundler
+                        .wire_len_after(self.wire_format.push_hdr_len() + 1, budget.hdr_len)
+                        - 1;
+                    if budget.max_bytes <= base {
+                        break;
+                    }
+                    usize::min(frag_body_limit, budget.max_bytes - base)
+                }
+                None => frag_body_limit,
+            };
+            let queue = match (is_urgent, is_unreliable) {
+                (true, _) => &mut self.urgent_queue,
+                (false, true) => &mut self.unreliable_queue,
+                (false, false) => &mut self.to_send_queue,
+            };
+            let first = queue.slice_front(frag_body_limit).unwrap();
+            let body = if first.len() == frag_body_limit || queue.is_empty() {
+                // a single slice needs no pasta
+                Body::Slice(first)
+            } else {
+                let mut pasta = BufPasta::new();
+                pasta.append(first);
+                while !queue.is_empty() {
+                    let free_space = frag_body_limit - pasta.len();
+                    if free_space == 0 {
+                        break;
+                    }
+                    let buf = queue.slice_front(free_space).unwrap();
+                    pasta.append(buf);
+                }
+                Body::Pasta(Arc::new(pasta))
+            };
+            assert!(body.len() <= frag_body_limit);
+            assert!(body.len() > 0);
+
+            // the parity covers the data as written
+            let seq = self.swnd.end();
+            self.add_to_parity_group(seq, &body);
+            let push = match self.compress(&body) {
+                Some(compressed) => SendingPush::new_compressed(compressed, *now, self.delivered),
+                None => SendingPush::new(body, *now, self.delivered),
+            };
+
+            // write the frag, including its hdr and body, to output buffer
+            let frag = FragBuilder {
+                seq,
+                cmd: push.frag_cmd(),
+            }
+            .build()
+            .unwrap();
+            bundler.pack(frag).unwrap();
+            self.stat.pushes += 1;
+            self.stat.bytes_pushed += push.body().len() as u64;
+
+            if is_unreliable {
+                // only the seq is taken
+                self.swnd.push_back(push);
+                self.swnd.remove(&seq);
+                self.skips.push_back((seq, *now));
+                continue;
+            }
+
+            // register seq to the rto lookup
+            self.rto_wheel
+                .push(seq, push.retransmissions(), push.last_sent());
+
+            // register the body to swnd
+            if self.swnd.is_empty() {
+                self.no_ack_since = Some(*now);
+            }
+            self.bytes_in_flight += push.body().len();
+            self.swnd.push_back(push);
+        }
+
+        // the fin follows the last of the data
+        if self.is_send_closed
+            && self.fin_seq.is_none()
+            && self.pending_bytes() == 0
+            && !self.swnd.is_full()
+            && fits_budget(&bundler, budget, ACK_HDR_LEN)
+        {
+            let seq = self.swnd.end();
+            let frag = FragBuilder {
+                seq,
+                cmd: FragCommand::Fin,
+            }
+            .build()
+            .unwrap();
+            bundler.pack(frag).unwrap();
+            let push = SendingPush::new_fin(*now, self.delivered);
+            self.rto_wheel
+                .push(seq, push.retransmissions(), push.last_sent());
+            if self.swnd.is_empty() {
+                self.no_ack_since = Some(*now);
+            }
+            self.swnd.push_back(push);
+            self.fin_seq = Some(seq);
+        }
+
+        while let Some(parity) = self.parities_to_send.front() {
+            let parity_len = parity.len_with(self.wire_format, self.local_next_seq_to_receive);
+            if !fits_budget(&bundler, budget, parity_len) {
+                break;
+            }
+            let parity = self.parities_to_send.pop_front().unwrap();
+            // too large for a packet after `set_mtu`; the pushes are retransmitted if lost
+            let _ = bundler.pack(parity);
+        }
+
+        // the acks left
+        self.pack_acks(&mut bundler, space, 0, budget);
+
+        let is_nack_due = self.is_nack_pending && fits_budget(&bundler, budget, 0);
+        let mut bundles = bundler.into_bundles();
+        if bundles.is_empty() && is_nack_due {
+            // the header alone
+            bundles.push(Vec::new());
+        }
+        self.check_rep();
+        bundles
+    }
+
+    /// The compressed body if the codec makes it shorter
+    fn compress(&self, body: &Body) -> Option<Body> {
+        let codec = self.codec.as_ref()?;
+        let compressed = match body {
+            Body::Slice(x) => codec.compress(x.data()),
+            Body::Pasta(x) => {
+                let mut wtr = OwnedBufWtr::new(x.len(), 0);
+                x.append_to(&mut wtr).unwrap();
+                codec.compress(wtr.data())
+            }
+        };
+        if compressed.is_empty() || body.len() <= compressed.len() {
+            return None;
+        }
+        Some(Body::Slice(BufSlice::from_bytes(compressed)))
+    }
+
+    /// Queue a parity once `fec_group_size` consecutive new pushes have been sent
+    fn add_to_parity_group(&mut self, seq: Seq32, body: &Body) {
+        let fec_group_size = match self.fec_group_size {
+            Some(x) => x,
+            None => return,
+        };
+        let group = match &mut self.parity_group {
+            Some(group) if group.is_next(seq) => group,
+            // a fin or nothing before it
+            group => group.insert(ParityGroup::new(seq)),
+        };
+        group.add(body);
+        if group.count() == fec_group_size {
+            let group = self.parity_group.take().unwrap();
+            self.parities_to_send.push_back(group.into_frag());
+        }
+    }
+
+    /// Pack acks as long as `reserve` bytes are left for a push in the packet taking them
+    ///
+    /// An ack landing in space too small for a push is always packed.
+    fn pack_acks(
+        &mut self,
+        bundler: &mut FragBundler,
+        space: usize,
+        reserve: usize,
+        budget: Option<Budget>,
+    ) {
+        while let Some(&ack) = self.to_ack_queue.front() {
+            // the nack of every packet covers it
+            if self.cumulative_ack_only && ack < self.local_next_seq_to_receive {
+                self.to_ack_queue.pop_front();
+                self.is_nack_pending = true;
+                continue;
+            }
+            let (frag, len, count) = match self.ack_bitmap {
+                true => self.ack_bitmap_at_front(),
+                false => None,
+            }
+            .unwrap_or_else(|| {
+                let frag = FragBuilder {
+                    seq: ack,
+                    cmd: FragCommand::Ack,
+                }
+                .build()
+                .unwrap();
+                (frag, ACK_HDR_LEN, 1)
+            });
+            let loading_space = bundler.loading_space();
+            let fits = match len <= loading_space {
+                true => loading_space < reserve || len + reserve <= loading_space,
+                // the ack starts a new packet
+                false => len + reserve <= space,
+            };
+            if !fits || !fits_budget(bundler, budget, len) {
+                break;
+            }
+            self.to_ack_queue.drain(..count);
+            bundler.pack(frag).unwrap();
+            self.stat.acks += count as u64;
+        }
+    }
+
+    /// An `AckBitmap` of the acks queued from the front on within its span, the bytes it
+    /// takes and how many acks it covers; `None` if the acks would take no more bytes
+    fn ack_bitmap_at_front(&self) -> Option<(Frag, usize, usize)> {
+        let base = *self.to_ack_queue.front()?;
+        let mut bitmap: u64 = 0;
+        let mut count = 0;
+        let mut acks_len = 0;
+        for &seq in &self.to_ack_queue {
+            if seq < base || ACK_BITMAP_SPAN <= seq.sub(&base) {
+                break;
+            }
+            bitmap |= 1 << seq.sub(&base);
+            count += 1;
+            acks_len += FragBuilder {
+                seq,
+                cmd: FragCommand::Ack,
+            }
+            .build()
+            .unwrap()
+            .len_with(self.wire_format, self.local_next_seq_to_receive);
+        }
+        let frag = FragBuilder {
+            seq: base,
+            cmd: FragCommand::AckBitmap { bitmap },
+        }
+        .build()
+        .unwrap();
+        let len = frag.len_with(self.wire_format, self.local_next_seq_to_receive);
+        match len < acks_len {
+            true => Some((frag, len, count)),
+            false => None,
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn rto(&self) -> time::Duration {
+        match self.stat.srtt {
+            Some(srtt) => {
+                let rto = mul_f64_saturating(srtt, self.ratio_rto_to_one_rtt);
+                let rto = Duration::min(rto, self.max_rto);
+                let rto = Duration::max(rto, self.min_rto);
+                rto
+            }
+            None => self.initial_rto,
+        }
+    }
+
+    /// Stable pseudo-random factor in `[1, 1 + rto_jitter]`
+    #[inline]
+    fn rto_jitter_factor(&self, seq: Seq32) -> f64 {
+        if self.rto_jitter == 0.0 {
+            return 1.0;
+        }
+        // splitmix64
+        let mut x = self.rto_jitter_seed ^ seq.to_u32() as u64;
+        x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+        let unit = (x >> 11) as f64 / (1u64 << 53) as f64;
+        1.0 + self.rto_jitter * unit
+    }
+
+    #[must_use]
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Change the maximum number of pushes in flight
+    ///
+    /// Shrinking below the current occupancy holds back new pushes until enough are acked.
+    pub fn set_swnd_size_cap(&mut self, cap: usize) {
+        self.swnd.set_wnd_size_cap(cap);
+        self.check_rep();
+    }
+
+    /// Change the size of the packets emitted from now on
+    ///
+    /// Pushes in flight are retransmitted as they are, so `mtu` has to fit the largest of them.
+    /// A smaller `mtu` is accepted once they are acked.
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<(), BuildError> {
+        self.check_in_flight_fits(mtu)?;
+        self.mtu = mtu;
+        self.check_rep();
+        Ok(())
+    }
+
+    /// Check that packets of `mtu` with the longest header still hold every push in flight
+    fn check_in_flight_fits(&self, mtu: usize) -> Result<(), BuildError> {
+        let hdr_len = self.max_packet_hdr_len();
+        check_mtu(
+            mtu,
+            hdr_len,
+            self.wire_format,
+            self.fec_group_size.is_some(),
+        )?;
+        for (_, push) in self.swnd.range(self.swnd.start(), self.swnd.end()) {
+            let push_len = self.wire_format.push_hdr_len() + push.body().len();
+            if mtu < hdr_len + push_len {
+                return Err(BuildError::MtuBelowInFlightPush);
+            }
+        }
+        Ok(())
+    }
+
+    /// The longest packet header this uploader emits, the pending echo counted
+    #[inline]
+    fn max_packet_hdr_len(&self) -> usize {
+        packet_hdr_len(
+            self.legacy_header,
+            self.timestamps || self.timestamp_to_echo.is_some(),
+            self.conn_id,
+            self.next_packet_seq.is_some(),
+        ) + self.tag_len()
+    }
+
+    /// Bytes of `frags` in a packet of this uploader
+    fn frags_len(&self, frags: &[Frag]) -> usize {
+        let mut len = 0;
+        for frag in frags {
+            len += frag.len_with(self.wire_format, self.local_next_seq_to_receive);
+        }
+        len
+    }
+
+    /// Append a padding frag to `frags` so that the packet takes `pad_packets_to` bytes
+    ///
+    /// The packet stays within `packet_space` and the padding frag within `max_len`.
+    /// Returns the bytes added.
+    fn pad(
+        &self,
+        hdr: &PacketHeader,
+        frags: &mut Vec<Frag>,
+        packet_space: usize,
+        max_len: Option<usize>,
+    ) -> usize {
+        let pad_to = match self.pad_packets_to {
+            Some(pad_to) => usize::min(pad_to, packet_space),
+            None => return 0,
+        };
+        self.pad_to(hdr, frags, pad_to, max_len)
+    }
+
+    /// Append a padding frag to `frags` so that the packet takes `pad_to` bytes
+    ///
+    /// The padding frag stays within `max_len`. Returns the bytes added.
+    fn pad_to(
+        &self,
+        hdr: &PacketHeader,
+        frags: &mut Vec<Frag>,
+        pad_to: usize,
+        max_len: Option<usize>,
+    ) -> usize {
+        let len = hdr.len_hint() + self.frags_len(frags) + self.tag_len();
+        let gap = pad_to.saturating_sub(len);
+        let gap = usize::min(gap, max_len.unwrap_or(usize::MAX));
+        // a shorter body can shorten the varint `len` of `WireFormat::V3` too
+        let mut padding_len = usize::min(gap, self.wire_format.max_body_len());
+        loop {
+            let frag = FragBuilder {
+                seq: hdr.nack(),
+                cmd: FragCommand::Padding { len: padding_len },
+            }
+            .build()
+            .unwrap();
+            let frag_len = frag.len_with(self.wire_format, hdr.nack());
+            if frag_len <= gap {
+                frags.push(frag);
+                return frag_len;
+            }
+            padding_len = match padding_len.checked_sub(frag_len - gap) {
+                Some(padding_len) => padding_len,
+                None => return 0,
+            };
+        }
+    }
+
+    /// Bytes of the tag after every packet
+    #[inline]
+    fn tag_len(&self) -> usize {
+        self.auth.as_ref().map_or(0, |auth| auth.tag_len())
+    }
+
+    #[inline]
+    fn header_version(&self) -> u8 {
+        match self.legacy_header {
+            true => LEGACY_VERSION,
+            false => PROTOCOL_VERSION,
+        }
+    }
+
+    /// The packet seq for the header of the next packet
+    #[inline]
+    fn take_packet_seq(&mut self) -> Option<Seq32> {
+        let packet_seq = self.next_packet_seq;
+        if let Some(next_packet_seq) = &mut self.next_packet_seq {
+            next_packet_seq.increment();
+        }
+        packet_seq
+    }
+
+    /// Milliseconds since the first emit, wrapping
+    #[inline]
+    fn local_timestamp(&mut self, now: &Instant) -> u32 {
+        let epoch = *self.timestamp_epoch.get_or_insert(*now);
+        now.saturating_duration_since(epoch).as_millis() as u32
+    }
+
+    /// The timestamp and the echo for the headers of the next packets
+    ///
+    /// The echo is left out if it does not fit in `packet_space`.
+    fn packet_hdr_options(
+        &mut self,
+        packet_space: usize,
+        now: &Instant,
+    ) -> (Option<u32>, Option<u32>) {
+        let timestamp = match self.timestamps {
+            true => Some(self.local_timestamp(now)),
+            false => None,
+        };
+        let fits_options = packet_hdr_len(
+            self.legacy_header,
+            true,
+            self.conn_id,
+            self.next_packet_seq.is_some(),
+        ) + self.wire_format.push_hdr_len()
+            + self.tag_len()
+            < packet_space;
+        let timestamp_echo = self.timestamp_to_echo.filter(|_| fits_options);
+        (timestamp, timestamp_echo)
+    }
+
+    #[inline]
+    fn set_remote_rwnd_size(&mut self, wnd: u16) {
+        self.swnd.set_remote_rwnd_size(wnd as usize);
+        self.check_rep();
+    }
+
+    #[inline]
+    fn set_local_next_seq_to_receive(&mut self, local_next_seq_to_receive: Seq32) {
+        self.local_next_seq_to_receive = local_next_seq_to_receive;
+        self.check_rep();
+    }
+
+    #[inline]
+    fn add_remote_seq_to_ack(&mut self, remote_seq_to_ack: Seq32) {
+        self.to_ack_queue.push_back(remote_seq_to_ack);
+        self.check_rep();
+    }
+
+    /// Return whether the push was still in flight
+    ///
+    /// `sample_rtt` is off when the RTT is sampled from a timestamp echo instead.
+    #[inline]
+    fn set_acked_local_seq(
+        &mut self,
+        acked_local_seq: Seq32,
+        now: &Instant,
+        sample_rtt: bool,
+    ) -> bool {
+        // remove the selected sequence
+        let frag = self.swnd.remove(&acked_local_seq);
+        // acked already, also when below `swnd.start()`; seqs never sent do not count
+        if frag.is_none()
+            && acked_local_seq < self.swnd.end()
+            && !self.skips.iter().any(|&(seq, _)| seq == acked_local_seq)
+        {
+            self.stat.duplicate_acks_received += 1;
+        }
+        if let Some(frag) = &frag {
+            self.bytes_in_flight -= frag.body().len();
+            self.delivered += frag.body().len() as u64;
+            self.set_acked(now);
+            let is_spurious_rto = match self.stat.srtt {
+                // the ack comes too soon to be for the retransmission
+                Some(srtt) => {
+                    frag.retransmissions() == 1
+                        && frag.is_rto_retransmitted()
+                        && frag.since_last_sent(now) < srtt
+                }
+                None => false,
+            };
+            if is_spurious_rto {
+                self.stat.spurious_rtos += 1;
+                // the RTT of the original send, which outlasted the timeout
+                if sample_rtt {
+                    self.sample_srtt(now.duration_since(frag.first_sent()));
+                }
+            }
+            if !frag.is_retransmitted() {
+                let frag_rtt = frag.since_last_sent(now);
+                self.sample_delivery_rate(frag, frag_rtt);
+                if sample_rtt {
+                    self.rtt_min.update(frag_rtt, *now);
+                    self.sample_srtt(frag_rtt);
+                }
+            }
+            // else, `last_seen` might just been modified, letting `srtt` become smaller
+        }
+        self.check_rep();
+        frag.is_some()
+    }
+
+    /// Set smooth RTT
+    #[inline]
+    fn sample_srtt(&mut self, rtt: Duration) {
+        self.stat.srtt = Some(match self.stat.srtt {
+            Some(srtt) => srtt.mul_f64(1.0 - ALPHA) + rtt.mul_f64(ALPHA),
+            None => rtt,
+        });
+    }
+
+    #[inline]
+    fn sample_delivery_rate(&mut self, push: &SendingPush, rtt: Duration) {
+        if rtt.is_zero() {
+            return;
+        }
+        let delivered = self.delivered - push.delivered_at_send();
+        let sample = delivered as f64 / rtt.as_secs_f64();
+        self.stat.delivery_rate = Some(match self.stat.delivery_rate {
+            Some(rate) => rate * (1.0 - ALPHA) + sample * ALPHA,
+            None => sample,
+        });
+    }
+
+    /// Return the seqs removed
+    ///
+    /// `sample_rtt` is off when the RTT is sampled from a timestamp echo instead.
+    #[inline]
+    fn remove_sending_before(
+        &mut self,
+        remote_nack: Seq32,
+        now: &Instant,
+        sample_rtt: bool,
+    ) -> Vec<Seq32> {
+        let removed = self.swnd.remove_before(remote_nack);
+        for (_, push) in &removed {
+            self.bytes_in_flight -= push.body().len();
+            self.delivered += push.body().len() as u64;
+        }
+        // the peer acks the pushes received in order with the nack alone
+        let newest_sent_once = removed
+            .iter()
+            .rev()
+            .find(|(_, push)| !push.is_retransmitted());
+        if let (true, Some((_, push))) = (self.cumulative_ack_only, newest_sent_once) {
+            let rtt = push.since_last_sent(now);
+            self.sample_delivery_rate(push, rtt);
+            if sample_rtt {
+                self.rtt_min.update(rtt, *now);
+                self.sample_srtt(rtt);
+            }
+        }
+        if !removed.is_empty() {
+            self.set_acked(now);
+        }
+        self.check_rep();
+        removed.into_iter().map(|(seq, _)| seq).collect()
+    }
+
+    #[inline]
+    fn set_acked(&mut self, now: &Instant) {
+        self.no_ack_since = match self.swnd.is_empty() {
+            true => None,
+            false => Some(*now),
+        };
+    }
+
+    #[inline]
+    fn set_local_rwnd_size(&mut self, local_rwnd_size: usize) {
+        self.local_rwnd_size = local_rwnd_size;
+        self.check_rep();
+    }
+
+    /// Nothing is changed if `delta` is rejected
+    #[inline]
+    pub fn set_state(&mut self, delta: SetUploadState, now: &Instant) -> Result<(), SetStateError> {
+        self.validate_state(&delta)?;
+        let nacks = [(delta.remote_nack, max_acked_local_seq(&delta))];
+        self.apply_state(delta, &nacks, now);
+        Ok(())
+    }
+
+    /// Apply the deltas of a burst of packets at once
+    ///
+    /// The deltas are merged first: the nack and the remote rwnd come from the newest nack,
+    /// the ack lists are concatenated, and the local fields come from the last delta. The
+    /// resulting `swnd` and fast retransmission window are the same as from calling
+    /// `set_state` on each delta in order, while the observers are notified only once.
+    ///
+    /// Every delta is validated before any of them is applied.
+    pub fn set_states(
+        &mut self,
+        deltas: impl IntoIterator<Item = SetUploadState>,
+        now: &Instant,
+    ) -> Result<(), SetStateError> {
+        let mut deltas = deltas.into_iter();
+        let mut merged = match deltas.next() {
+            Some(delta) => delta,
+            None => return Ok(()),
+        };
+        self.validate_state(&merged)?;
+        // the nacks that `set_state` would not take as stale, for the duplicate nack count
+        let mut nacks = Vec::new();
+        let mut max_remote_nack = self.max_remote_nack;
+        let mut on_nack = |delta: &SetUploadState| {
+            if delta.is_established && max_remote_nack <= delta.remote_nack {
+                max_remote_nack = delta.remote_nack;
+                nacks.push((delta.remote_nack, max_acked_local_seq(delta)));
+            }
+        };
+        on_nack(&merged);
+        for delta in deltas {
+            self.validate_state(&delta)?;
+            on_nack(&delta);
+            // the nacks from before the establishment do not count
+            let is_first_established = delta.is_established && !merged.is_established;
+            if is_first_established || merged.remote_nack <= delta.remote_nack {
+                merged.remote_nack = delta.remote_nack;
+                merged.remote_rwnd_size = delta.remote_rwnd_size;
+            }
+            merged.is_established |= delta.is_established;
+            merged.syn_to_answer = delta.syn_to_answer.or(merged.syn_to_answer);
+            merged.local_next_seq_to_receive = delta.local_next_seq_to_receive;
+            merged.local_rwnd_size = delta.local_rwnd_size;
+            merged.remote_seqs_to_ack.extend(delta.remote_seqs_to_ack);
+            merged.acked_local_seqs.extend(delta.acked_local_seqs);
+            merged.remote_timestamp = delta.remote_timestamp.or(merged.remote_timestamp);
+            merged.timestamp_echo = delta.timestamp_echo.or(merged.timestamp_echo);
+            merged.pings_to_answer.extend(delta.pings_to_answer);
+            merged.pong = delta.pong.or(merged.pong);
+            merged.probe_to_answer = delta.probe_to_answer.or(merged.probe_to_answer);
+            merged.probe_ack = delta.probe_ack.or(merged.probe_ack);
+            merged.conn_id = delta.conn_id.or(merged.conn_id);
+            merged.sacked_local_ranges.extend(delta.sacked_local_ranges);
+            if !delta.remote_ranges_to_sack.is_empty() {
+                merged.remote_ranges_to_sack = delta.remote_ranges_to_sack;
+            }
+        }
+        // an ack can only meet a newer nack if the peer is broken
+        self.validate_state(&merged)?;
+        self.apply_state(merged, &nacks, now);
+        Ok(())
+    }
+
+    /// Check all of `delta` before any of it is applied
+    ///
+    /// A nack behind the send window is not rejected; reordering makes it common.
+    #[inline]
+    fn validate_state(&self, delta: &SetUploadState) -> Result<(), SetStateError> {
+        if delta.local_rwnd_size > u16::MAX as usize {
+            return Err(SetStateError::LocalRwndTooLarge {
+                size: delta.local_rwnd_size,
+            });
+        }
+        for &acked_local_seq in &delta.acked_local_seqs {
+            if acked_local_seq == delta.remote_nack {
+                return Err(SetStateError::AckedNack {
+                    seq: acked_local_seq,
+                });
+            }
+            if self.strict && self.swnd.end() <= acked_local_seq {
+                return Err(SetStateError::UnknownAck {
+                    seq: acked_local_seq,
+                });
+            }
+        }
+        for range in &delta.sacked_local_ranges {
+            if range.contains(&delta.remote_nack) {
+                return Err(SetStateError::SackedNack {
+                    range: range.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// `nacks` are the nacks and the largest acks along with them, in the order they arrived
+    fn apply_state(
+        &mut self,
+        delta: SetUploadState,
+        nacks: &[(Seq32, Option<Seq32>)],
+        now: &Instant,
+    ) {
+        if let Some(nonce) = delta.syn_to_answer {
+            if self.handshake.map(|x| x.role) == Some(Role::Server) {
+                self.syn_ack_to_send = Some(nonce);
+                if self.handshake_state == HandshakeState::Idle {
+                    self.handshake_state = HandshakeState::SynReceived;
+                }
+            }
+        }
+        if delta.is_established {
+            self.handshake_state = HandshakeState::Established;
+        }
+        // a packet overtaken by a newer one carries an outdated nack and rwnd;
+        // only its explicit acks are still news
+        // and the peer knows nothing of our initial seq before the establishment
+        let is_stale = !delta.is_established || delta.remote_nack < self.max_remote_nack;
+        if !is_stale {
+            self.max_remote_nack = delta.remote_nack;
+            self.set_remote_rwnd_size(delta.remote_rwnd_size);
+        }
+        self.set_local_next_seq_to_receive(delta.local_next_seq_to_receive);
+        self.set_local_rwnd_size(delta.local_rwnd_size);
+        self.pongs_to_send.extend(delta.pings_to_answer);
+        if delta.pong.is_some() && delta.pong == self.last_ping_token {
+            self.last_pong_received = Some(*now);
+        }
+        if let Some(id) = delta.probe_to_answer {
+            self.probe_ack_to_send = Some(id);
+        }
+        let probed_mtu = match (&mut self.mtu_prober, delta.probe_ack) {
+            (Some(prober), Some(id)) => prober.on_ack(id),
+            _ => None,
+        };
+        if let Some(mtu) = probed_mtu {
+            self.stat.probes_acked += 1;
+            // the packets in flight are no larger
+            self.mtu = usize::max(self.mtu, mtu);
+        }
+        // unambiguous even for retransmitted pushes
+        let echo_rtt = match (self.timestamps, delta.timestamp_echo) {
+            (true, Some(echo)) => {
+                let rtt = self.local_timestamp(now).wrapping_sub(echo);
+                Some(Duration::from_millis(rtt as u64)).filter(|&rtt| rtt <= self.max_rto)
+            }
+            _ => None,
+        };
+        let spurious_rtos_then = self.stat.spurious_rtos;
+        let mut acked = Vec::new();
+        for &acked_local_seq in &delta.acked_local_seqs {
+            if self.set_acked_local_seq(acked_local_seq, now, echo_rtt.is_none()) {
+                acked.push(acked_local_seq);
+            }
+        }
+        for range in &delta.sacked_local_ranges {
+            let sacked: Vec<Seq32> = self
+                .swnd
+                .range(range.start, range.end)
+                .map(|(&seq, _)| seq)
+                .collect();
+            for seq in sacked {
+                if self.set_acked_local_seq(seq, now, echo_rtt.is_none()) {
+                    acked.push(seq);
+                }
+            }
+        }
+        // the peer has the unreliable pushes acked or passed by the nack
+        // after `set_acked_local_seq`s so that their first acks are not duplicates
+        self.skips.retain(|&(seq, _)| {
+            delta.remote_nack <= seq
+                && !delta.acked_local_seqs.contains(&seq)
+                && !delta
+                    .sacked_local_ranges
+                    .iter()
+                    .any(|range| range.contains(&seq))
+        });
+        if !is_stale {
+            acked.extend(self.remove_sending_before(delta.remote_nack, now, echo_rtt.is_none())); // must after `set_acked_local_seq`s
+                                                                                                  // must after `remove_sending_before` so that the window only covers unacked pushes
+            self.set_fast_retransmission_wnd(nacks);
+        }
+
+        if let Some(rtt) = echo_rtt {
+            if !acked.is_empty() {
+                self.rtt_min.update(rtt, *now);
+                self.sample_srtt(rtt);
+            }
+        }
+
+        if let Some(timestamp) = delta.remote_timestamp {
+            let is_echo_pending = self.timestamp_to_echo.is_some();
+            self.timestamp_to_echo = Some(timestamp);
+            // the echo may grow the header by the options; the pushes still in flight were cut
+            // without them
+            if !is_echo_pending && self.check_in_flight_fits(self.mtu).is_err() {
+                self.timestamp_to_echo = None;
+                self.stat.echoes_skipped += 1;
+            }
+        }
+        if self.conn_id.is_none() && delta.conn_id.is_some() {
+            self.conn_id = delta.conn_id;
+            // the header grows by the ID; the pushes still in flight were cut without it
+            if self.check_in_flight_fits(self.mtu).is_err() {
+                self.conn_id = None;
+                self.stat.conn_ids_deferred += 1;
+            }
+        }
+
+        for remote_seq_to_ack in delta.remote_seqs_to_ack {
+            self.add_remote_seq_to_ack(remote_seq_to_ack);
+        }
+        if self.sack && !delta.remote_ranges_to_sack.is_empty() {
+            let ranges = delta.remote_ranges_to_sack;
+            self.to_ack_queue
+                .retain(|seq| !ranges.iter().any(|range| range.contains(seq)));
+            self.sack_to_send = Some(ranges);
+        }
+        self.check_rep();
+
+        // after `check_rep` so that the observer sees a consistent state
+        if !acked.is_empty() {
+            let spurious_rtos = (self.stat.spurious_rtos - spurious_rtos_then) as usize;
+            let is_drained = self.pending_bytes() == 0 && self.swnd.is_empty();
+            self.notify(Notifications {
+                acked,
+                spurious_rtos,
+                is_drained,
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Notify `on_ack`, `on_rto` and `on_drained` of `notifications` unless they are deferred
+    fn notify(&mut self, notifications: Notifications) {
+        match &mut self.deferred_notifications {
+            Some(deferred) => deferred.merge(notifications),
+            None => notifications.deliver(
+                self.on_ack.as_ref(),
+                self.on_rto.as_ref(),
+                self.on_drained.as_ref(),
+            ),
+        }
+    }
+
+    /// Hold back the notifications of `on_ack`, `on_rto` and `on_drained` until
+    /// `take_notifications`
+    pub(crate) fn defer_notifications(&mut self) {
+        self.deferred_notifications
+            .get_or_insert_with(Default::default);
+    }
+
+    /// The notifications held back since the last call, with the observers to deliver them to
+    pub(crate) fn take_notifications(&mut self) -> DeferredNotifications {
+        DeferredNotifications {
+            notifications: self
+                .deferred_notifications
+                .as_mut()
+                .map(std::mem::take)
+                .unwrap_or_default(),
+            on_ack: self.on_ack.clone(),
+            on_rto: self.on_rto.clone(),
+            on_drained: self.on_drained.clone(),
+        }
+    }
+
+    /// Retransmit all sequences before the largest out-of-order sequence
+    ///
+    /// The window is fitted to what is left in `swnd`.
+    #[inline]
+    fn set_fast_retransmission_wnd(&mut self, nacks: &[(Seq32, Option<Seq32>)]) {
+        for &(remote_nack, max_acked) in nacks {
+            self.fast_retransmission_wnd.on_nack(remote_nack, max_acked);
+        }
+        self.trim_fast_retransmission_wnd();
+    }
+
+    /// Make the fast retransmission window start from a sequence still in `swnd`
+    #[inline]
+    fn trim_fast_retransmission_wnd(&mut self) {
+        let end = self.fast_retransmission_wnd.end();
+        let start = match self
+            .swnd
+            .range(self.fast_retransmission_wnd.start(), end)
+            .next()
+        {
+            Some((&seq, _)) => seq,
+            None => end,
+        };
+        self.fast_retransmission_wnd.skip_to(start);
+    }
+}
+
+/// Events for `on_ack`, `on_rto` and `on_drained`
+#[derive(Default)]
+struct Notifications {
+    acked: Vec<Seq32>,
+    rto_hits: usize,
+    spurious_rtos: usize,
+    is_drained: bool,
+}
+
+impl Notifications {
+    fn merge(&mut self, other: Notifications) {
+        self.acked.extend(other.acked);
+        self.rto_hits += other.rto_hits;
+        self.spurious_rtos += other.spurious_rtos;
+        self.is_drained |= other.is_drained;
+    }
+
+    fn deliver(
+        self,
+        on_ack: Option<&Weak<dyn IAckObserver + Send + Sync + 'static>>,
+        on_rto: Option<&Weak<dyn IRtoObserver + Send + Sync + 'static>>,
+        on_drained: Option<&Weak<dyn IObserver + Send + Sync + 'static>>,
+    ) {
+        if self.rto_hits != 0 {
+            if let Some(x) = on_rto.and_then(|x| x.upgrade()) {
+                x.on_rto(self.rto_hits);
+            }
+        }
+        if !self.acked.is_empty() {
+            if let Some(x) = on_ack.and_then(|x| x.upgrade()) {
+                x.on_acked(&self.acked);
+            }
+        }
+        if self.spurious_rtos != 0 {
+            if let Some(x) = on_rto.and_then(|x| x.upgrade()) {
+                x.on_spurious_rto(self.spurious_rtos);
+            }
+        }
+        if self.is_drained {
+            if let Some(x) = on_drained.and_then(|x| x.upgrade()) {
+                x.notify();
+            }
+        }
+    }
+}
+
+/// Notifications taken out of an uploader to be delivered without borrowing it
+pub(crate) struct DeferredNotifications {
+    notifications: Notifications,
+    on_ack: Option<Weak<dyn IAckObserver + Send + Sync + 'static>>,
+    on_rto: Option<Weak<dyn IRtoObserver + Send + Sync + 'static>>,
+    on_drained: Option<Weak<dyn IObserver + Send + Sync + 'static>>,
+}
+
+impl DeferredNotifications {
+    pub(crate) fn deliver(self) {
+        self.notifications.deliver(
+            self.on_ack.as_ref(),
+            self.on_rto.as_ref(),
+            self.on_drained.as_ref(),
+        );
+    }
+}
+
+#[derive(Default)]
+struct LocalStat {
+    srtt: Option<time::Duration>,
+    delivery_rate: Option<f64>,
+    retransmissions: u64,
+    rto_hits: u64,
+    spurious_rtos: u64,
+    duplicate_acks_received: u64,
+    fast_retransmissions: u64,
+    pushes: u64,
+    acks: u64,
+    bytes_pushed: u64,
+    bytes_retransmitted: u64,
+    packets_emitted: u64,
+    packets_on_ack: u64,
+    bytes_on_ack: u64,
+    packets_on_timer: u64,
+    bytes_on_timer: u64,
+    expired_writes: u64,
+    probes_sent: u64,
+    probes_acked: u64,
+    conn_ids_deferred: u64,
+    echoes_skipped: u64,
+    retransmits_too_large: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Stat {
+    pub srtt: Option<time::Duration>,
+    pub retransmissions: u64,
+    pub rto_hits: u64,
+    /// Timeouts found spurious by an ack for the original send
+    pub spurious_rtos: u64,
+    /// Acks for pushes acked already, a sign of needless retransmissions
+    pub duplicate_acks_received: u64,
+    pub fast_retransmissions: u64,
+    pub pushes: u64,
+    pub acks: u64,
+    pub bytes_pushed: u64,
+    pub bytes_retransmitted: u64,
+    pub packets_emitted: u64,
+    pub packets_on_ack: u64,
+    pub bytes_on_ack: u64,
+    pub packets_on_timer: u64,
+    pub bytes_on_timer: u64,
+    /// Writes dropped by `write_with_deadline` before being sent
+    pub expired_writes: u64,
+    /// Packets sent to probe a larger MTU
+    pub probes_sent: u64,
+    /// Probes the peer received, each raising the MTU
+    pub probes_acked: u64,
+    /// Times the connection ID of the peer was not adopted yet as the pushes in flight left no
+    /// room for it in the header
+    pub conn_ids_deferred: u64,
+    /// Timestamps of the peer left unechoed as the pushes in flight left no room for the
+    /// options in the header
+    pub echoes_skipped: u64,
+    /// Retransmissions put off as the push no longer fit in a packet
+    pub retransmits_too_large: u64,
+    pub next_seq_to_send: Seq32,
+    pub bytes_in_flight: usize,
+    pub pending_bytes: usize,
+    pub queue_delay_estimate: Option<time::Duration>,
+    /// Smoothed rate at which pushes are acked
+    ///
+    /// Each sample divides the bytes acked while a push was in flight by its RTT. Unit: byte per
+    /// second
+    pub delivery_rate_bps: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        layer::{
+            uploader::{BuildError, SendError, SetStateError, Uploader, UploaderBuilder},
+            IAckObserver, IObserver, IRtoObserver, SeqList, SetUploadState,
+        },
+        protocol::{
+            frag::{Body, FragCommand, WireFormat, ACK_BITMAP_LEN, ACK_HDR_LEN, PUSH_HDR_LEN},
+            packet::Packet,
+            packet_hdr::PACKET_HDR_LEN,
+        },
+        utils::{
+            buf::{BufPool, BufSlice, BufWtr, OwnedBufWtr},
+            Seq32,
+        },
+    };
+    use std::{
+        ops::Range,
+        time::{Duration, Instant},
+    };
+
+    const MTU: usize = 512;
+
+    #[test]
+    fn test_empty() {
+        let now = Instant::now();
+        let mut uploader = UploaderBuilder::build(Default::default()).unwrap();
+        let buf = OwnedBufWtr::new(MTU / 2, 0);
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+    }
+
+    #[test]
+    fn test_few_1() {
+        let now = Instant::now();
+        let mut uploader = UploaderBuilder::build(Default::default()).unwrap();
+        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+        let origin = vec![0, 1, 2];
+        buf.append(&origin).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        assert!(!uploader.to_send_queue.is_empty());
+        let packets = uploader.emit(&now);
+        {
+            assert!(uploader.to_send_queue.is_empty());
+            let mut body = OwnedBufWtr::new(origin.len(), 0);
+            match packets[0].frags()[0].cmd() {
+                FragCommand::Push { body: x } => match x {
+                    Body::Slice(x) => body.append(x.data()).unwrap(),
+                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
+                },
+                _ => {
+                    panic!()
+                }
+            }
+            assert_eq!(body.data(), origin);
+        }
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+    }
+
+    #[test]
+    fn test_few_2() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+        let origin1 = vec![0, 1, 2];
+        buf.append(&origin1).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+        let origin2 = vec![3, 4];
+        buf.append(&origin2).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let packets = uploader.emit(&now);
+        {
+            assert!(uploader.to_send_queue.is_empty());
+            let mut body = OwnedBufWtr::new(origin1.len() + origin2.len(), 0);
+            match packets[0].frags()[0].cmd() {
+                FragCommand::Push { body: x } => match x {
+                    Body::Slice(x) => body.append(x.data()).unwrap(),
+                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
+                },
+                _ => {
+                    panic!()
+                }
+            }
+            assert_eq!(body.data()[..origin1.len()], origin1);
+            assert_eq!(body.data()[origin1.len()..], origin2);
+        }
+        assert_eq!(uploader.swnd_end().to_u32(), 1);
+        assert_eq!(uploader.emit(&now).len(), 0);
+    }
+
+    #[test]
+    fn test_few_many() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+        let origin1 = vec![0, 1, 2];
+        buf.append(&origin1).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let mut buf = OwnedBufWtr::new(MTU, 0);
+        let origin2 = vec![3; MTU];
+        buf.append(&origin2).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let packets = uploader.emit(&now);
+        {
+            assert_eq!(packets.len(), 1);
+            let mut body = OwnedBufWtr::new(MTU, 0);
+            match packets[0].frags()[0].cmd() {
+                FragCommand::Push { body: x } => match x {
+                    Body::Slice(x) => body.append(x.data()).unwrap(),
+                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
+                },
+                _ => {
+                    panic!()
+                }
+            }
+            assert_eq!(body.data()[..origin1.len()], origin1);
+            assert_eq!(
+                body.data()[origin1.len()..],
+                origin2[..MTU - PACKET_HDR_LEN - PUSH_HDR_LEN - origin1.len()]
+            );
+        }
+        assert_eq!(uploader.swnd_end().to_u32(), 1);
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+        assert_eq!(uploader.swnd_end().to_u32(), 1);
+
+        uploader.set_remote_rwnd_size(10);
+
+        let packets = uploader.emit(&now);
+        {
+            assert_eq!(packets.len(), 1);
+            let mut body = OwnedBufWtr::new(MTU, 0);
+            match packets[0].frags()[0].cmd() {
+                FragCommand::Push { body: x } => match x {
+                    Body::Slice(x) => body.append(x.data()).unwrap(),
+                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
+                },
+                _ => {
+                    panic!()
+                }
+            }
+            assert_eq!(
+                body.data(),
+                &origin2[MTU - PACKET_HDR_LEN - PUSH_HDR_LEN - origin1.len()..]
+            );
+        }
+        assert_eq!(uploader.swnd_end().to_u32(), 2);
+        assert_eq!(uploader.emit(&now).len(), 0);
+    }
+
+    #[test]
+    fn test_many_few() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        let mut buf = OwnedBufWtr::new(MTU, 0);
+        let origin1 = vec![3; MTU];
+        buf.append(&origin1).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+        let origin2 = vec![0, 1, 2];
+        buf.append(&origin2).unwrap();
+        let slice = buf.into_slice();
+        uploader.write(slice).map_err(|_| ()).unwrap();
+        let packets = uploader.emit(&now);
+        // packet: _hdr hdr mtu-_hdr-hdr
+        // origin:          1[0..mtu-_hdr-hdr]
+        {
+            assert_eq!(packets.len(), 1);
+            let mut body = OwnedBufWtr::new(MTU, 0);
+            match packets[0].frags()[0].cmd() {
+                FragCommand::Push { body: x } => match x {
+                    Body::Slice(x) => body.append(x.data()).unwrap(),
+                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
+                },
+                _ => {
+                    panic!()
+                }
+            }
+            assert_eq!(body.data(), &origin1[..MTU - PACKET_HDR_LEN - PUSH_HDR_LEN]);
+        }
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+
+        uploader.set_remote_rwnd_size(10);
+
+        let packets = uploader.emit(&now);
+        // packet: _hdr hdr _hdr+hdr             3
+        // origin:          1[mtu-_hdr-hdr..mtu] 2[0..3]
+        {
+            assert_eq!(packets.len(), 1);
+            let mut body = OwnedBufWtr::new(MTU, 0);
+            match packets[0].frags()[0].cmd() {
+                FragCommand::Push { body: x } => match x {
+                    Body::Slice(x) => body.append(x.data()).unwrap(),
+                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
+                },
+                _ => {
+                    panic!()
+                }
+            }
+            assert_eq!(
+                body.data()[..PACKET_HDR_LEN + PUSH_HDR_LEN],
+                origin1[MTU - PACKET_HDR_LEN - PUSH_HDR_LEN..]
+            );
+            assert_eq!(body.data()[PACKET_HDR_LEN + PUSH_HDR_LEN..], origin2);
+        }
+        assert_eq!(uploader.emit(&now).len(), 0);
+    }
+
+    #[test]
+    fn test_ack1() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        let origin1 = vec![0, 1, 2];
+        {
+            let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+            buf.append(&origin1).unwrap();
+            let slice = buf.into_slice();
+            uploader.write(slice).map_err(|_| ()).unwrap();
+        }
+        let origin2 = vec![3, 4];
+        {
+            let mut buf = OwnedBufWtr::new(MTU / 2, 0);
+            buf.append(&origin2).unwrap();
+            let slice = buf.into_slice();
+            uploader.write(slice).map_err(|_| ()).unwrap();
+        }
+        let _ = uploader.emit(&now);
+
+        assert_eq!(uploader.swnd_end().to_u32(), 1);
+        assert_eq!(uploader.swnd_len(), 1);
+
+        uploader.set_acked_local_seq(Seq32::from_u32(0), &now, true);
+
+        assert_eq!(uploader.swnd_len(), 0);
+    }
+
+    #[test]
+    fn test_bytes_in_flight() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(uploader.pending_bytes(), 3);
+        assert_eq!(uploader.bytes_in_flight(), 0);
+
+        let _ = uploader.emit(&now);
+        assert_eq!(uploader.pending_bytes(), 0);
+        assert_eq!(uploader.bytes_in_flight(), 3);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![3, 4]))
+            .map_err(|_| ())
+            .unwrap();
+        let _ = uploader.emit(&now);
+        assert_eq!(uploader.bytes_in_flight(), 5);
+
+        uploader.set_acked_local_seq(Seq32::from_u32(1), &now, true);
+        assert_eq!(uploader.bytes_in_flight(), 3);
+
+        uploader.remove_sending_before(Seq32::from_u32(1), &now, true);
+        assert_eq!(uploader.bytes_in_flight(), 0);
+        assert_eq!(uploader.stat().bytes_in_flight, 0);
+        assert_eq!(uploader.stat().pending_bytes, 0);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        for i in 0..3 {
+            uploader
+                .write(BufSlice::from_bytes(vec![i]))
+                .map_err(|_| ())
+                .unwrap();
+            let _ = uploader.emit(&now);
+        }
+        uploader.remove_sending_before(Seq32::from_u32(1), &now, true);
+        uploader.set_local_next_seq_to_receive(Seq32::from_u32(7));
+
+        let checkpoint = uploader.checkpoint();
+        assert_eq!(checkpoint.next_seq, Seq32::from_u32(3));
+        assert_eq!(checkpoint.acked_prefix, Seq32::from_u32(1));
+        assert_eq!(checkpoint.local_next_seq_to_receive, Seq32::from_u32(7));
+
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.resume_from(&checkpoint).unwrap();
+        uploader
+            .write(BufSlice::from_bytes(vec![1]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets[0].hdr().nack(), Seq32::from_u32(7));
+        assert_eq!(packets[0].frags()[0].seq(), Seq32::from_u32(1));
+    }
+
+    #[test]
+    fn test_emit_trigger() {
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit_on_timer(&now);
+        assert_eq!(packets.len(), 1);
+
+        uploader
+            .set_state(
+                SetUploadState {
+                    remote_rwnd_size: 2,
+                    remote_nack: Seq32::from_u32(1),
+                    local_next_seq_to_receive: Seq32::from_u32(0),
+                    remote_seqs_to_ack: [Seq32::from_u32(0)].into(),
+                    acked_local_seqs: [Seq32::from_u32(0)].into(),
+                    local_rwnd_size: 2,
+                    remote_timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    pings_to_answer: Vec::new(),
+                    pong: None,
+                    probe_to_answer: None,
+                    probe_ack: None,
+                    remote_ranges_to_sack: Vec::new(),
+                    sacked_local_ranges: Vec::new(),
+                    syn_to_answer: None,
+                    is_established: true,
+                },
+                &now,
+            )
+            .unwrap();
+        let packets = uploader.emit_on_ack(&now);
+        assert_eq!(packets.len(), 1);
+
+        now += uploader.rto();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+
+        let stat = uploader.stat();
+        assert_eq!(stat.packets_on_timer, 1);
+        assert_eq!(
+            stat.bytes_on_timer,
+            (PACKET_HDR_LEN + PUSH_HDR_LEN + 3) as u64
+        );
+        assert_eq!(stat.packets_on_ack, 1);
+        assert_eq!(stat.bytes_on_ack, (PACKET_HDR_LEN + ACK_HDR_LEN) as u64);
+        assert_eq!(stat.packets_emitted, 2);
+    }
+
+    #[test]
+    fn test_write_partial() {
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.to_send_bytes_cap = 4;
+        let mut uploader = builder.build().unwrap();
+
+        let (written, rest) = uploader.write_partial(BufSlice::from_bytes(vec![]));
+        assert_eq!(written, 0);
+        assert!(rest.is_none());
+
+        let (written, rest) = uploader.write_partial(BufSlice::from_bytes(vec![0, 1, 2]));
+        assert_eq!(written, 3);
+        assert!(rest.is_none());
+
+        let (written, rest) = uploader.write_partial(BufSlice::from_bytes(vec![3, 4]));
+        assert_eq!(written, 1);
+        assert_eq!(rest.unwrap().data(), vec![4]);
+
+        let (written, rest) = uploader.write_partial(BufSlice::from_bytes(vec![4]));
+        assert_eq!(written, 0);
+        assert_eq!(rest.unwrap().data(), vec![4]);
+        assert_eq!(uploader.pending_bytes(), 4);
+    }
+
+    #[test]
+    fn test_write_bytes() {
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.to_send_bytes_cap = 2;
+        let mut uploader = builder.build().unwrap();
+
+        uploader.write_bytes(&[]).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.pending_bytes(), 0);
+
+        uploader.write_bytes(&[0, 1]).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.pending_bytes(), 2);
+
+        match uploader.write_bytes(&[2]) {
+            Err(SendError::QueueFull(())) => (),
+            _ => panic!(),
+        }
+        assert_eq!(uploader.pending_bytes(), 2);
+    }
+
+    #[test]
+    fn test_write_vectored() {
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.to_send_bytes_cap = 3;
+        let mut uploader = builder.build().unwrap();
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0]))
+            .map_err(|_| ())
+            .unwrap();
+
+        let slices = vec![
+            BufSlice::from_bytes(vec![1, 2]),
+            BufSlice::from_bytes(vec![]),
+            BufSlice::from_bytes(vec![3]),
+        ];
+        let slices = match uploader.write_vectored(slices) {
+            Err(SendError::QueueFull(slices)) => slices,
+            _ => panic!(),
+        };
+        assert_eq!(slices.len(), 3);
+        assert_eq!(slices[0].data(), vec![1, 2]);
+        assert_eq!(slices[2].data(), vec![3]);
+        assert_eq!(uploader.pending_bytes(), 1);
+
+        let slices = vec![
+            BufSlice::from_bytes(vec![]),
+            BufSlice::from_bytes(vec![1, 2]),
+        ];
+        uploader.write_vectored(slices).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.pending_bytes(), 3);
+    }
+
+    #[test]
+    fn test_stat_reset() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+        uploader
+            .set_state(
+                SetUploadState {
+                    remote_rwnd_size: 2,
+                    remote_nack: Seq32::from_u32(1),
+                    local_next_seq_to_receive: Seq32::from_u32(0),
+                    remote_seqs_to_ack: SeqList::new(),
+                    acked_local_seqs: [Seq32::from_u32(0)].into(),
+                    local_rwnd_size: 2,
+                    remote_timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    pings_to_answer: Vec::new(),
+                    pong: None,
+                    probe_to_answer: None,
+                    probe_ack: None,
+                    remote_ranges_to_sack: Vec::new(),
+                    sacked_local_ranges: Vec::new(),
+                    syn_to_answer: None,
+                    is_established: true,
+                },
+                &now,
+            )
+            .unwrap();
+
+        let stat = uploader.stat_reset();
+        assert_eq!(stat.pushes, 1);
+        assert_eq!(stat.packets_emitted, 1);
+        assert_eq!(stat.next_seq_to_send.to_u32(), 1);
+        assert!(stat.srtt.is_some());
+
+        let stat = uploader.stat();
+        assert_eq!(stat.pushes, 0);
+        assert_eq!(stat.bytes_pushed, 0);
+        assert_eq!(stat.packets_emitted, 0);
+        assert_eq!(stat.next_seq_to_send.to_u32(), 1);
+        assert!(stat.srtt.is_some());
+    }
+
+    #[test]
+    fn test_lifetime_totals() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader.write_bytes(&[0, 1, 2]).map_err(|_| ()).unwrap();
+        uploader
+            .write_vectored(vec![BufSlice::from_bytes(vec![3])])
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+
+        let _ = uploader.stat_reset();
+        assert_eq!(uploader.stat().packets_emitted, 0);
+        let totals = uploader.lifetime_totals();
+        assert_eq!(totals.bytes_written, 4);
+        assert_eq!(totals.packets_emitted, 1);
+
+        // a new uploader starts from zero
+        let uploader = UploaderBuilder::build(Default::default()).unwrap();
+        assert_eq!(uploader.lifetime_totals().bytes_written, 0);
+        assert_eq!(uploader.lifetime_totals().packets_emitted, 0);
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let build = |f: fn(&mut UploaderBuilder)| {
+            let mut builder: UploaderBuilder = Default::default();
+            f(&mut builder);
+            builder.build().err().unwrap()
+        };
+        assert!(matches!(build(|b| b.mtu = 1), BuildError::MtuTooSmall));
+        assert!(matches!(
+            build(|b| b.rto_jitter = -0.1),
+            BuildError::RtoJitterNegative
+        ));
+        assert!(matches!(
+            build(|b| b.ratio_rto_to_one_rtt = 0.0),
+            BuildError::InvalidRtoRatio
+        ));
+        assert!(matches!(
+            build(|b| b.ratio_rto_to_one_rtt = f64::NAN),
+            BuildError::InvalidRtoRatio
+        ));
+        assert!(matches!(
+            build(|b| b.min_rto = Some(Duration::from_secs(4))),
+            BuildError::InvalidRtoBounds
+        ));
+        assert!(matches!(
+            build(|b| b.initial_rto = Some(Duration::from_secs(61))),
+            BuildError::InvalidRtoBounds
+        ));
+        assert!(matches!(
+            build(|b| {
+                b.min_rto = Some(Duration::from_secs(2));
+                b.max_rto = Some(Duration::from_secs(1));
+            }),
+            BuildError::InvalidRtoBounds
+        ));
+        assert!(matches!(
+            build(|b| b.local_recv_buf_len = u16::MAX as usize + 1),
+            BuildError::LocalRecvBufTooLarge
+        ));
+        assert!(matches!(
+            build(|b| b.to_send_bytes_cap = 0),
+            BuildError::ToSendBytesCapZero
+        ));
+        assert!(matches!(
+            build(|b| b.to_send_queue_len_cap = Some(0)),
+            BuildError::ToSendQueueLenCapZero
+        ));
+        assert!(matches!(
+            build(|b| b.swnd_size_cap = 0),
+            BuildError::SwndSizeCapZero
+        ));
+        assert!(matches!(
+            build(|b| b.max_fast_retransmit_burst = 0),
+            BuildError::FastRetransmitBurstZero
+        ));
+        assert!(matches!(
+            build(|b| b.send_available_low_water_mark = Some(0)),
+            BuildError::LowWaterMarkZero
+        ));
+    }
+
+    #[test]
+    fn test_set_mtu() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0; 100]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+
+        let in_flight_len = PACKET_HDR_LEN + PUSH_HDR_LEN + 100;
+        match uploader.set_mtu(in_flight_len - 1) {
+            Err(BuildError::MtuBelowInFlightPush) => (),
+            _ => panic!(),
+        }
+        match uploader.set_mtu(0) {
+            Err(BuildError::MtuTooSmall) => (),
+            _ => panic!(),
+        }
+        assert_eq!(uploader.mtu(), MTU);
+
+        uploader.set_mtu(in_flight_len).unwrap();
+        uploader
+            .write(BufSlice::from_bytes(vec![1; 300]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 3);
+        for packet in packets {
+            let mut wtr = OwnedBufWtr::new(MTU, 0);
+            packet.append_to(&mut wtr).unwrap();
+            assert!(wtr.data().len() <= in_flight_len);
+        }
+    }
+
+    #[test]
+    fn test_set_mtu_after_in_flight_acked() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0; 100]))
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+
+        let small_mtu = PACKET_HDR_LEN + PUSH_HDR_LEN + 10;
+        assert!(uploader.set_mtu(small_mtu).is_err());
+
+        // the push is still retransmitted whole
+        let packets = uploader.emit(&(now + uploader.rto()));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(uploader.stat().retransmissions, 1);
+
+        let state = SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(1),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: SeqList::new(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &now).unwrap();
+        uploader.set_mtu(small_mtu).unwrap();
+
+        uploader
+            .write(BufSlice::from_bytes(vec![1; 100]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 10);
+        let packets = uploader.emit(&(now + uploader.rto()));
+        assert_eq!(packets.len(), 10);
+    }
+
+    #[test]
+    fn test_set_swnd_size_cap() {
+        let now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.swnd_size_cap = 2;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        for i in 0..2 {
+            uploader
+                .write(BufSlice::from_bytes(vec![i]))
+                .map_err(|_| ())
+                .unwrap();
+            let packets = uploader.emit(&now);
+            assert_eq!(packets.len(), 1);
+        }
+        assert_eq!(uploader.swnd_len(), 2);
+
+        uploader.set_swnd_size_cap(1);
+        uploader
+            .write(BufSlice::from_bytes(vec![2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+
+        uploader.set_acked_local_seq(Seq32::from_u32(1), &now, true);
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+
+        uploader.set_swnd_size_cap(3);
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(uploader.swnd_end().to_u32(), 3);
+    }
+
+    #[test]
+    fn test_rto_once() {
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        let origin1 = vec![0, 1, 2];
+        {
+            let slice = BufSlice::from_bytes(origin1);
+            uploader.write(slice).map_err(|_| ()).unwrap();
+        }
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+
+        now += uploader.rto();
+
+        let packets = uploader.emit(&now);
+        // last_seen of the fragment is refreshed
+        assert_eq!(packets.len(), 1);
+
+        let packets = uploader.emit(&now);
+        // the fragment doesn't timeout
+        assert_eq!(packets.len(), 0);
+    }
+
+    #[test]
+    fn test_retransmit_too_large() {
+        let mut now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 0;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+        for _ in 0..2 {
+            let body = vec![0; MTU - PACKET_HDR_LEN - PUSH_HDR_LEN];
+            uploader
+                .write(BufSlice::from_bytes(body))
+                .map_err(|_| ())
+                .unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+        // as if the header had grown since
+        uploader.mtu -= 1;
+
+        let state = SetUploadState {
+            remote_rwnd_size: 2,
+            remote_nack: Seq32::from_u32(0),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(1)].into(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &now).unwrap();
+        assert!(uploader.emit(&now).is_empty());
+        assert_eq!(uploader.stat().fast_retransmissions, 0);
+        assert_eq!(uploader.stat().retransmits_too_large, 1);
+
+        now += uploader.rto();
+        assert!(uploader.emit(&now).is_empty());
+        assert_eq!(uploader.stat().rto_hits, 0);
+        assert_eq!(uploader.stat().retransmits_too_large, 2);
+        assert_eq!(uploader.swnd_len(), 1);
+
+        uploader.mtu += 1;
+        assert_eq!(uploader.emit(&now).len(), 1);
+        assert_eq!(uploader.stat().rto_hits, 1);
+    }
+
+    #[test]
+    fn test_rto_bounds() {
+        let ms = Duration::from_millis;
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            min_rto: Some(ms(10)),
+            initial_rto: Some(ms(500)),
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+        assert_eq!(uploader.rto(), ms(500));
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+        now += ms(1);
+        uploader.set_acked_local_seq(Seq32::from_u32(0), &now, true);
+        assert_eq!(uploader.srtt(), Some(ms(1)));
+        assert_eq!(uploader.rto(), ms(10));
+    }
+
+    #[test]
+    fn test_broken_max_retransmissions() {
+        let mut now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.max_retransmissions = Some(2);
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+
+        for _ in 0..2 {
+            now += uploader.rto();
+            let packets = uploader.emit(&now);
+            assert_eq!(packets.len(), 1);
+            assert!(!uploader.is_broken(&now));
+        }
+
+        now += uploader.rto();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+        assert!(uploader.is_broken(&now));
+
+        match uploader.write(BufSlice::from_bytes(vec![3])) {
+            Err(SendError::Broken(_)) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_broken_max_no_ack_duration() {
+        let mut now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.max_no_ack_duration = Some(Duration::from_secs(10));
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
 
-        self.set_remote_rwnd_size(delta.remote_rwnd_size);
-        self.set_local_next_seq_to_receive(delta.local_next_seq_to_receive);
-        self.set_local_rwnd_size(delta.local_rwnd_size);
-        let mut max_acked_local_seq = None;
-        for acked_local_seq in delta.acked_local_seqs {
-            self.set_acked_local_seq(acked_local_seq, now);
-            max_acked_local_seq = Some(match max_acked_local_seq {
-                Some(x) => Seq32::max(x, acked_local_seq),
-                None => acked_local_seq,
-            });
+        now += Duration::from_secs(9);
+        assert!(!uploader.is_broken(&now));
+
+        now += Duration::from_secs(1);
+        assert!(uploader.is_broken(&now));
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 0);
+    }
+
+    #[test]
+    fn test_not_broken_after_ack() {
+        let mut now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.max_no_ack_duration = Some(Duration::from_secs(10));
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+        let _ = uploader.emit(&now);
+
+        now += Duration::from_secs(9);
+        uploader.set_acked_local_seq(Seq32::from_u32(0), &now, true);
+
+        now += Duration::from_secs(9);
+        assert!(!uploader.is_broken(&now));
+    }
+
+    #[test]
+    fn test_fast_retransmit1() {
+        let now = Instant::now();
+        let dup = 1;
+        let mut uploader = UploaderBuilder {
+            local_recv_buf_len: 0,
+            nack_duplicate_threshold_to_activate_fast_retransmit: dup,
+            max_fast_retransmit_burst: usize::MAX,
+            ratio_rto_to_one_rtt: 1.5,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
+            swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            mtu: MTU,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
-        self.remove_sending_before(delta.remote_nack); // must after `set_acked_local_seq`s
-                                                       // to retransmit all sequences before the largest out-of-order sequence
-        if let Some(x) = max_acked_local_seq {
-            if delta.remote_nack < x {
-                self.fast_retransmission_wnd
-                    .try_set_boundaries(delta.remote_nack..x);
-            }
+        .build()
+        .unwrap();
+        uploader.set_remote_rwnd_size(2);
+
+        let origin1 = vec![0, 1, 2];
+        {
+            let slice = BufSlice::from_bytes(origin1);
+            uploader.write(slice).map_err(|_| ()).unwrap();
         }
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
 
-        for remote_seq_to_ack in delta.remote_seqs_to_ack {
-            self.add_remote_seq_to_ack(remote_seq_to_ack);
+        let origin2 = vec![3];
+        {
+            let slice = BufSlice::from_bytes(origin2);
+            uploader.write(slice).map_err(|_| ()).unwrap();
         }
-        self.check_rep();
-        Ok(())
-    }
-}
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
 
-struct LocalStat {
-    srtt: Option<time::Duration>,
-    retransmissions: u64,
-    rto_hits: u64,
-    fast_retransmissions: u64,
-    pushes: u64,
-    acks: u64,
-}
+        // the first nack of 0 is only recorded. When receiving another same nack, the fast retransmission gets activated since now the dup count becomes 1
 
-#[derive(Debug, PartialEq)]
-pub struct Stat {
-    pub srtt: Option<time::Duration>,
-    pub retransmissions: u64,
-    pub rto_hits: u64,
-    pub fast_retransmissions: u64,
-    pub pushes: u64,
-    pub acks: u64,
-    pub next_seq_to_send: Seq32,
-}
+        let state = || SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(0),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(1)].into(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state(), &now).unwrap();
+        assert!(uploader.emit(&now).is_empty());
+        uploader.set_state(state(), &now).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        layer::{uploader::UploaderBuilder, SetUploadState},
-        protocol::{
-            frag::{Body, FragCommand, ACK_HDR_LEN, PUSH_HDR_LEN},
-            packet_hdr::PACKET_HDR_LEN,
-        },
-        utils::{
-            buf::{BufSlice, BufWtr, OwnedBufWtr},
-            Seq32,
-        },
-    };
-    use std::time::Instant;
+        let packets = uploader.emit(&now);
 
-    const MTU: usize = 512;
+        assert_eq!(packets.len(), 1);
+    }
 
     #[test]
-    fn test_empty() {
+    fn test_write_urgent() {
         let now = Instant::now();
-        let mut uploader = UploaderBuilder::default().build().unwrap();
-        let buf = OwnedBufWtr::new(MTU / 2, 0);
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        uploader
+            .write_bytes(&vec![0; 1024 * 1024])
+            .map_err(|_| ())
+            .unwrap();
+        let urgent: Vec<u8> = (0..10).collect();
+        uploader
+            .write_urgent(BufSlice::from_bytes(urgent.clone()))
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(uploader.pending_bytes(), 1024 * 1024 + 10);
+
         let packets = uploader.emit(&now);
-        assert_eq!(packets.len(), 0);
+        let frag = &packets[0].frags()[0];
+        assert_eq!(frag.seq().to_u32(), 0);
+        let body = match frag.cmd() {
+            FragCommand::Push { body } => body,
+            _ => panic!(),
+        };
+        let mut wtr = OwnedBufWtr::new(MTU, 0);
+        match body {
+            Body::Slice(x) => wtr.append(x.data()).unwrap(),
+            Body::Pasta(x) => x.append_to(&mut wtr).unwrap(),
+        }
+        assert_eq!(wtr.data(), urgent);
+        // bulk data follows in the same packet
+        assert!(1 < packets[0].frags().len());
     }
 
     #[test]
-    fn test_few_1() {
-        let now = Instant::now();
-        let mut uploader = UploaderBuilder::default().build().unwrap();
-        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-        let origin = vec![0, 1, 2];
-        buf.append(&origin).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        assert!(!uploader.to_send_queue.is_empty());
+    fn test_emit_serialized() {
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let mut pool = BufPool::new();
+
+        uploader.write_bytes(&[0; MTU * 2]).map_err(|_| ()).unwrap();
+        let datagrams = uploader.emit_serialized(&now, &mut pool);
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(datagrams[0].data_len(), MTU);
+        assert_eq!(
+            datagrams[2].data_len(),
+            PACKET_HDR_LEN + PUSH_HDR_LEN + MTU * 2 - 2 * (MTU - PACKET_HDR_LEN - PUSH_HDR_LEN)
+        );
+        let mut ptrs: Vec<_> = datagrams.iter().map(|wtr| wtr.data().as_ptr()).collect();
+        for wtr in datagrams {
+            pool.put_back(wtr);
+        }
+
+        // retransmitted into the same buffers
+        now += uploader.rto();
+        let datagrams = uploader.emit_serialized(&now, &mut pool);
+        assert_eq!(datagrams.len(), 3);
+        assert_eq!(pool.free_count(), 0);
+        let mut reused: Vec<_> = datagrams.iter().map(|wtr| wtr.data().as_ptr()).collect();
+        ptrs.sort();
+        reused.sort();
+        assert_eq!(ptrs, reused);
+        for wtr in &datagrams {
+            assert_eq!(wtr.capacity(), MTU);
+        }
+    }
+
+    #[test]
+    fn test_write_with_deadline() {
+        let ms = Duration::from_millis;
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        uploader
+            .write_with_deadline(BufSlice::from_bytes(vec![0, 1]), now + ms(10))
+            .map_err(|_| ())
+            .unwrap();
+        uploader
+            .write_with_deadline(BufSlice::from_bytes(vec![2, 3]), now + ms(100))
+            .map_err(|_| ())
+            .unwrap();
+        now += ms(50);
+
         let packets = uploader.emit(&now);
-        {
-            assert!(uploader.to_send_queue.is_empty());
-            let mut body = OwnedBufWtr::new(origin.len(), 0);
-            match packets[0].frags()[0].cmd() {
-                FragCommand::Push { body: x } => match x {
-                    Body::Slice(_) => panic!(),
-                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
-                },
-                FragCommand::Ack => panic!(),
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frags().len(), 1);
+        let body = match packets[0].frags()[0].cmd() {
+            FragCommand::Push {
+                body: Body::Slice(body),
+            } => body,
+            _ => panic!(),
+        };
+        assert_eq!(body.data(), vec![2, 3]);
+        assert_eq!(uploader.stat().expired_writes, 1);
+        assert_eq!(uploader.pending_bytes(), 0);
+    }
+
+    struct Flag(std::sync::atomic::AtomicBool);
+
+    impl IObserver for Flag {
+        fn notify(&self) {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    struct AckLog(std::sync::Mutex<Vec<Vec<u32>>>);
+
+    impl IAckObserver for AckLog {
+        fn on_acked(&self, seqs: &[Seq32]) {
+            let seqs = seqs.iter().map(|seq| seq.to_u32()).collect();
+            self.0.lock().unwrap().push(seqs);
+        }
+    }
+
+    struct RtoLog(std::sync::Mutex<Vec<usize>>);
+
+    impl IRtoObserver for RtoLog {
+        fn on_rto(&self, retransmissions: usize) {
+            self.0.lock().unwrap().push(retransmissions);
+        }
+    }
+
+    #[test]
+    fn test_on_rto() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let log = std::sync::Arc::new(RtoLog(std::sync::Mutex::new(Vec::new())));
+        let weak = std::sync::Arc::downgrade(&log);
+        uploader.set_on_rto(Some(weak));
+
+        for i in 0..3 {
+            uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+        assert!(log.0.lock().unwrap().is_empty());
+
+        let later = now + uploader.rto();
+        assert_eq!(uploader.emit(&later).len(), 1);
+        assert!(uploader.emit(&later).is_empty());
+        assert_eq!(*log.0.lock().unwrap(), vec![3]);
+    }
+
+    struct SpuriousRtoLog(std::sync::Mutex<Vec<usize>>);
+
+    impl IRtoObserver for SpuriousRtoLog {
+        fn on_rto(&self, _retransmissions: usize) {}
+
+        fn on_spurious_rto(&self, count: usize) {
+            self.0.lock().unwrap().push(count);
+        }
+    }
+
+    #[test]
+    fn test_spurious_rto() {
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let log = std::sync::Arc::new(SpuriousRtoLog(std::sync::Mutex::new(Vec::new())));
+        let weak = std::sync::Arc::downgrade(&log);
+        uploader.set_on_rto(Some(weak));
+        let ack = |seq| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(seq + 1),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(seq)].into(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+
+        // srtt of 10 ms
+        uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        now += Duration::from_millis(10);
+        uploader.set_state(ack(0), &now).unwrap();
+        assert_eq!(uploader.srtt(), Some(Duration::from_millis(10)));
+
+        // the ack of the original arrives right after the retransmission
+        uploader.write_bytes(&[1]).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        now += uploader.rto();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        now += Duration::from_millis(1);
+        uploader.set_state(ack(1), &now).unwrap();
+        assert_eq!(uploader.stat().spurious_rtos, 1);
+        assert_eq!(*log.0.lock().unwrap(), vec![1]);
+        // the RTT of the original is sampled
+        assert!(Duration::from_millis(10) < uploader.srtt().unwrap());
+
+        // the ack of a retransmission comes an RTT later
+        uploader.write_bytes(&[2]).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        now += uploader.rto();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        now += uploader.srtt().unwrap();
+        uploader.set_state(ack(2), &now).unwrap();
+        assert_eq!(uploader.stat().spurious_rtos, 1);
+        assert_eq!(*log.0.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_on_ack() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let log = std::sync::Arc::new(AckLog(std::sync::Mutex::new(Vec::new())));
+        let weak = std::sync::Arc::downgrade(&log);
+        uploader.set_on_ack(Some(weak));
+
+        for i in 0..5 {
+            uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+
+        let state = |nack, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state(0, vec![2, 3, 3]), &now).unwrap();
+        uploader.set_state(state(0, vec![2, 3, 4]), &now).unwrap();
+        // seq(2), seq(3) and seq(4) are not reported again
+        uploader.set_state(state(5, vec![]), &now).unwrap();
+        uploader.set_state(state(5, vec![]), &now).unwrap();
+
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            vec![vec![2, 3], vec![4], vec![0, 1]]
+        );
+    }
+
+    #[test]
+    fn test_send_available_low_water_mark() {
+        let now = Instant::now();
+        let mut builder: UploaderBuilder = Default::default();
+        // one 10-byte push per emit
+        builder.mtu = PACKET_HDR_LEN + PUSH_HDR_LEN + 10;
+        builder.swnd_size_cap = 1;
+        builder.to_send_bytes_cap = 40;
+        builder.send_available_low_water_mark = Some(20);
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let flag = std::sync::Arc::new(Flag(std::sync::atomic::AtomicBool::new(false)));
+        let weak = std::sync::Arc::downgrade(&flag);
+        uploader.set_on_send_available(Some(weak));
+
+        uploader.write_bytes(&[0; 40]).map_err(|_| ()).unwrap();
+        assert!(uploader.is_send_queue_full());
+        for seq in 0..3 {
+            assert!(!flag.0.load(std::sync::atomic::Ordering::SeqCst));
+            assert_eq!(uploader.emit(&now).len(), 1);
+            let state = SetUploadState {
+                remote_rwnd_size: 99,
+                remote_nack: Seq32::from_u32(seq + 1),
+                local_next_seq_to_receive: Seq32::from_u32(0),
+                remote_seqs_to_ack: SeqList::new(),
+                acked_local_seqs: SeqList::new(),
+                local_rwnd_size: 1,
+                remote_timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                pings_to_answer: Vec::new(),
+                pong: None,
+                probe_to_answer: None,
+                probe_ack: None,
+                remote_ranges_to_sack: Vec::new(),
+                sacked_local_ranges: Vec::new(),
+                syn_to_answer: None,
+                is_established: true,
+            };
+            uploader.set_state(state, &now).unwrap();
+        }
+        // 10 bytes left
+        assert_eq!(uploader.pending_bytes(), 10);
+        assert!(flag.0.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_clear_pending() {
+        let now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.to_send_bytes_cap = 4;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let flag = std::sync::Arc::new(Flag(std::sync::atomic::AtomicBool::new(false)));
+        let weak = std::sync::Arc::downgrade(&flag);
+        uploader.set_on_send_available(Some(weak));
+
+        uploader.write_bytes(&[0, 1]).map_err(|_| ()).unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        uploader.write_bytes(&[2, 3, 4, 5]).map_err(|_| ()).unwrap();
+        assert!(uploader.is_send_queue_full());
+
+        assert_eq!(uploader.clear_pending(), 4);
+        assert_eq!(uploader.pending_bytes(), 0);
+        assert!(flag.0.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(uploader.clear_pending(), 0);
+
+        // the push in flight is still retransmitted
+        assert_eq!(uploader.bytes_in_flight(), 2);
+        let packets = uploader.emit(&(now + uploader.rto()));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frags().len(), 1);
+        assert_eq!(packets[0].frags()[0].seq().to_u32(), 0);
+        assert_eq!(uploader.stat().retransmissions, 1);
+
+        let state = SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(1),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: SeqList::new(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &now).unwrap();
+        assert_eq!(uploader.bytes_in_flight(), 0);
+        assert!(uploader.emit(&now).is_empty());
+    }
+
+    #[test]
+    fn test_delivery_rate() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        assert_eq!(uploader.stat().delivery_rate_bps, None);
+
+        // 100 bytes every 10 ms, each acked 50 ms after it is sent
+        for i in 0..100 {
+            let now = start + ms(10 * i);
+            if 5 <= i {
+                let seq = i as u32 - 5;
+                let state = SetUploadState {
+                    remote_rwnd_size: 99,
+                    remote_nack: Seq32::from_u32(seq + 1),
+                    local_next_seq_to_receive: Seq32::from_u32(0),
+                    remote_seqs_to_ack: SeqList::new(),
+                    acked_local_seqs: [Seq32::from_u32(seq)].into(),
+                    local_rwnd_size: 1,
+                    remote_timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    pings_to_answer: Vec::new(),
+                    pong: None,
+                    probe_to_answer: None,
+                    probe_ack: None,
+                    remote_ranges_to_sack: Vec::new(),
+                    sacked_local_ranges: Vec::new(),
+                    syn_to_answer: None,
+                    is_established: true,
+                };
+                uploader.set_state(state, &now).unwrap();
             }
-            assert_eq!(body.data(), origin);
+            uploader.write_bytes(&[0; 100]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
         }
-        let packets = uploader.emit(&now);
-        assert_eq!(packets.len(), 0);
+        let rate = uploader.stat().delivery_rate_bps.unwrap();
+        assert!((rate - 10_000.0).abs() < 100.0);
+        assert_eq!(uploader.stat_reset().delivery_rate_bps, Some(rate));
+        assert_eq!(uploader.stat().delivery_rate_bps, Some(rate));
     }
 
     #[test]
-    fn test_few_2() {
-        let now = Instant::now();
-        let mut builder = UploaderBuilder::default();
-        builder.mtu = MTU;
+    fn test_queue_delay_estimate() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
         let mut uploader = builder.build().unwrap();
-        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-        let origin1 = vec![0, 1, 2];
-        buf.append(&origin1).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-        let origin2 = vec![3, 4];
-        buf.append(&origin2).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        let packets = uploader.emit(&now);
-        {
-            assert!(uploader.to_send_queue.is_empty());
-            let mut body = OwnedBufWtr::new(origin1.len() + origin2.len(), 0);
-            match packets[0].frags()[0].cmd() {
-                FragCommand::Push { body: x } => match x {
-                    Body::Slice(_) => panic!(),
-                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
-                },
-                FragCommand::Ack => panic!(),
-            }
-            assert_eq!(body.data()[..origin1.len()], origin1);
-            assert_eq!(body.data()[origin1.len()..], origin2);
+        uploader.set_remote_rwnd_size(99);
+        assert_eq!(uploader.queue_delay_estimate(), None);
+
+        let mut seq = 0;
+        let mut sample = |uploader: &mut Uploader, sent: Instant, rtt: Duration| {
+            uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&sent).len(), 1);
+            seq += 1;
+            let state = SetUploadState {
+                remote_rwnd_size: 99,
+                remote_nack: Seq32::from_u32(seq),
+                local_next_seq_to_receive: Seq32::from_u32(0),
+                remote_seqs_to_ack: SeqList::new(),
+                acked_local_seqs: [Seq32::from_u32(seq - 1)].into(),
+                local_rwnd_size: 1,
+                remote_timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                pings_to_answer: Vec::new(),
+                pong: None,
+                probe_to_answer: None,
+                probe_ack: None,
+                remote_ranges_to_sack: Vec::new(),
+                sacked_local_ranges: Vec::new(),
+                syn_to_answer: None,
+                is_established: true,
+            };
+            uploader.set_state(state, &(sent + rtt)).unwrap();
+        };
+
+        // ramping up
+        let mut now = start;
+        for i in 0..10 {
+            sample(&mut uploader, now, ms(100 + i * 20));
+            now += ms(500);
+            let srtt = uploader.stat().srtt.unwrap();
+            assert_eq!(uploader.queue_delay_estimate(), Some(srtt - ms(100)));
         }
-        assert_eq!(uploader.swnd.end().to_u32(), 1);
-        assert_eq!(uploader.emit(&now).len(), 0);
+        assert!(ms(50) < uploader.queue_delay_estimate().unwrap());
+        assert_eq!(
+            uploader.stat().queue_delay_estimate,
+            uploader.queue_delay_estimate()
+        );
+
+        // the ramp is out of the window
+        now = start + ms(40_000);
+        sample(&mut uploader, now, ms(400));
+        // srtt is still catching up with the new min
+        assert!(uploader.stat().srtt.unwrap() < ms(400));
+        assert_eq!(uploader.queue_delay_estimate(), Some(Duration::ZERO));
     }
 
     #[test]
-    fn test_few_many() {
+    fn test_emit_window_update() {
         let now = Instant::now();
-        let mut builder = UploaderBuilder::default();
-        builder.mtu = MTU;
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
         let mut uploader = builder.build().unwrap();
-        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-        let origin1 = vec![0, 1, 2];
-        buf.append(&origin1).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        let mut buf = OwnedBufWtr::new(MTU, 0);
-        let origin2 = vec![3; MTU];
-        buf.append(&origin2).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        let packets = uploader.emit(&now);
-        {
-            assert_eq!(packets.len(), 1);
-            let mut body = OwnedBufWtr::new(MTU, 0);
-            match packets[0].frags()[0].cmd() {
-                FragCommand::Push { body: x } => match x {
-                    Body::Slice(_) => panic!(),
-                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
-                },
-                FragCommand::Ack => panic!(),
-            }
-            assert_eq!(body.data()[..origin1.len()], origin1);
-            assert_eq!(
-                body.data()[origin1.len()..],
-                origin2[..MTU - PACKET_HDR_LEN - PUSH_HDR_LEN - origin1.len()]
-            );
-        }
-        assert_eq!(uploader.swnd.end().to_u32(), 1);
-        let packets = uploader.emit(&now);
-        assert_eq!(packets.len(), 0);
-        assert_eq!(uploader.swnd.end().to_u32(), 1);
+        uploader.set_remote_rwnd_size(99);
+        assert!(uploader.emit(&now).is_empty());
 
-        uploader.set_remote_rwnd_size(10);
+        uploader.set_local_rwnd_size(7);
+        let packets = uploader.emit_window_update(&now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].hdr().rwnd(), 7);
+        assert!(packets[0].frags().is_empty());
+        assert_eq!(uploader.stat().packets_emitted, 1);
+
+        // piggybacked on pushes when there are any
+        uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+        let packets = uploader.emit_window_update(&now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frags().len(), 1);
+    }
 
+    #[test]
+    fn test_packet_seqs() {
+        let now = Instant::now();
+        let mut uploader = UploaderBuilder {
+            mtu: MTU,
+            packet_seqs: true,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        uploader.set_remote_rwnd_size(99);
+        uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
         let packets = uploader.emit(&now);
-        {
-            assert_eq!(packets.len(), 1);
-            let mut body = OwnedBufWtr::new(MTU, 0);
-            match packets[0].frags()[0].cmd() {
-                FragCommand::Push { body: x } => match x {
-                    Body::Slice(_) => panic!(),
-                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
-                },
-                FragCommand::Ack => panic!(),
-            }
-            assert_eq!(
-                body.data(),
-                &origin2[MTU - PACKET_HDR_LEN - PUSH_HDR_LEN - origin1.len()..]
-            );
+        assert_eq!(packets[0].hdr().packet_seq(), Some(Seq32::from_u32(0)));
+        let packets = uploader.emit_window_update(&now);
+        assert_eq!(packets[0].hdr().packet_seq(), Some(Seq32::from_u32(1)));
+        // taken by every packet emitted
+        uploader.write_bytes(&[1]).map_err(|_| ()).unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets[0].hdr().packet_seq(), Some(Seq32::from_u32(2)));
+
+        let mut uploader = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
         }
-        assert_eq!(uploader.swnd.end().to_u32(), 2);
-        assert_eq!(uploader.emit(&now).len(), 0);
+        .build()
+        .unwrap();
+        assert_eq!(
+            uploader.emit_window_update(&now)[0].hdr().packet_seq(),
+            None
+        );
     }
 
     #[test]
-    fn test_many_few() {
+    fn test_strict() {
         let now = Instant::now();
-        let mut builder = UploaderBuilder::default();
-        builder.mtu = MTU;
-        let mut uploader = builder.build().unwrap();
-        let mut buf = OwnedBufWtr::new(MTU, 0);
-        let origin1 = vec![3; MTU];
-        buf.append(&origin1).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-        let origin2 = vec![0, 1, 2];
-        buf.append(&origin2).unwrap();
-        let slice = buf.into_slice();
-        uploader.write(slice).map_err(|_| ()).unwrap();
-        let packets = uploader.emit(&now);
-        // packet: _hdr hdr mtu-_hdr-hdr
-        // origin:          1[0..mtu-_hdr-hdr]
-        {
-            assert_eq!(packets.len(), 1);
-            let mut body = OwnedBufWtr::new(MTU, 0);
-            match packets[0].frags()[0].cmd() {
-                FragCommand::Push { body: x } => match x {
-                    Body::Slice(_) => panic!(),
-                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
-                },
-                FragCommand::Ack => panic!(),
+        for strict in [false, true] {
+            let mut builder = UploaderBuilder {
+                mtu: MTU,
+                ..Default::default()
+            };
+            builder.strict = strict;
+            let mut uploader = builder.build().unwrap();
+            uploader.set_remote_rwnd_size(99);
+
+            let empty = uploader.write(BufSlice::from_bytes(vec![]));
+            let empty_bytes = uploader.write_bytes(&[]);
+            match strict {
+                true => {
+                    assert!(matches!(empty, Err(SendError::Empty(_))));
+                    assert!(matches!(empty_bytes, Err(SendError::Empty(()))));
+                }
+                false => {
+                    assert!(empty.is_ok());
+                    assert!(empty_bytes.is_ok());
+                }
             }
-            assert_eq!(body.data(), &origin1[..MTU - PACKET_HDR_LEN - PUSH_HDR_LEN]);
-        }
-        let packets = uploader.emit(&now);
-        assert_eq!(packets.len(), 0);
 
-        uploader.set_remote_rwnd_size(10);
+            uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
 
-        let packets = uploader.emit(&now);
-        // packet: _hdr hdr _hdr+hdr             3
-        // origin:          1[mtu-_hdr-hdr..mtu] 2[0..3]
-        {
-            assert_eq!(packets.len(), 1);
-            let mut body = OwnedBufWtr::new(MTU, 0);
-            match packets[0].frags()[0].cmd() {
-                FragCommand::Push { body: x } => match x {
-                    Body::Slice(_) => panic!(),
-                    Body::Pasta(x) => x.append_to(&mut body).unwrap(),
-                },
-                FragCommand::Ack => panic!(),
+            // seq(1) has never been sent
+            let state = SetUploadState {
+                remote_rwnd_size: 99,
+                remote_nack: Seq32::from_u32(0),
+                local_next_seq_to_receive: Seq32::from_u32(0),
+                remote_seqs_to_ack: SeqList::new(),
+                acked_local_seqs: [Seq32::from_u32(1)].into(),
+                local_rwnd_size: 1,
+                remote_timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                pings_to_answer: Vec::new(),
+                pong: None,
+                probe_to_answer: None,
+                probe_ack: None,
+                remote_ranges_to_sack: Vec::new(),
+                sacked_local_ranges: Vec::new(),
+                syn_to_answer: None,
+                is_established: true,
+            };
+            let result = uploader.set_state(state, &now);
+            match strict {
+                true => {
+                    assert!(matches!(
+                        result,
+                        Err(SetStateError::UnknownAck { seq }) if seq.to_u32() == 1
+                    ));
+                }
+                false => assert!(result.is_ok()),
             }
-            assert_eq!(
-                body.data()[..PACKET_HDR_LEN + PUSH_HDR_LEN],
-                origin1[MTU - PACKET_HDR_LEN - PUSH_HDR_LEN..]
-            );
-            assert_eq!(body.data()[PACKET_HDR_LEN + PUSH_HDR_LEN..], origin2);
+            assert_eq!(uploader.bytes_in_flight(), 1);
         }
-        assert_eq!(uploader.emit(&now).len(), 0);
     }
 
     #[test]
-    fn test_ack1() {
+    fn test_rejected_state_unchanged() {
         let now = Instant::now();
-        let mut builder = UploaderBuilder::default();
-        builder.mtu = MTU;
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
         let mut uploader = builder.build().unwrap();
-        uploader.set_remote_rwnd_size(2);
-
-        let origin1 = vec![0, 1, 2];
-        {
-            let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-            buf.append(&origin1).unwrap();
-            let slice = buf.into_slice();
-            uploader.write(slice).map_err(|_| ()).unwrap();
-        }
-        let origin2 = vec![3, 4];
-        {
-            let mut buf = OwnedBufWtr::new(MTU / 2, 0);
-            buf.append(&origin2).unwrap();
-            let slice = buf.into_slice();
-            uploader.write(slice).map_err(|_| ()).unwrap();
+        uploader.set_remote_rwnd_size(99);
+        for i in 0..4 {
+            uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
         }
-        let _ = uploader.emit(&now);
 
-        assert_eq!(uploader.swnd.end().to_u32(), 1);
-        assert_eq!(uploader.swnd.size(), 1);
+        let state =
+            |nack, acked: Vec<u32>, sacked: Vec<Range<u32>>, local_rwnd_size| SetUploadState {
+                remote_rwnd_size: 99,
+                remote_nack: Seq32::from_u32(nack),
+                local_next_seq_to_receive: Seq32::from_u32(0),
+                remote_seqs_to_ack: SeqList::new(),
+                acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+                local_rwnd_size,
+                remote_timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                pings_to_answer: Vec::new(),
+                pong: None,
+                probe_to_answer: None,
+                probe_ack: None,
+                remote_ranges_to_sack: Vec::new(),
+                sacked_local_ranges: sacked
+                    .into_iter()
+                    .map(|x| Seq32::from_u32(x.start)..Seq32::from_u32(x.end))
+                    .collect(),
+                syn_to_answer: None,
+                is_established: true,
+            };
+        let swnd = |uploader: &Uploader| -> Vec<u32> {
+            let (start, end) = (uploader.swnd.start(), uploader.swnd.end());
+            let seqs = uploader.swnd.range(start, end);
+            seqs.map(|(seq, _)| seq.to_u32()).collect()
+        };
+        let stat = uploader.stat();
+        assert_eq!(swnd(&uploader), [0, 1, 2, 3]);
+        let assert_unchanged = |uploader: &Uploader| {
+            assert_eq!(uploader.stat(), stat);
+            assert_eq!(swnd(uploader), [0, 1, 2, 3]);
+            assert_eq!(uploader.acked_watermark().to_u32(), 0);
+        };
+
+        // each would ack seq(0) and seq(2) if it were not for the invalid field
+        let result = uploader.set_state(state(1, vec![2, 1], vec![], 1), &now);
+        assert!(matches!(result, Err(SetStateError::AckedNack { seq }) if seq.to_u32() == 1));
+        assert_unchanged(&uploader);
+        let result = uploader.set_state(state(1, vec![2], vec![0..2], 1), &now);
+        assert!(matches!(
+            result,
+            Err(SetStateError::SackedNack { range })
+                if range == (Seq32::from_u32(0)..Seq32::from_u32(2))
+        ));
+        assert_unchanged(&uploader);
+        let result = uploader.set_state(state(1, vec![2], vec![], u16::MAX as usize + 1), &now);
+        assert!(matches!(
+            result,
+            Err(SetStateError::LocalRwndTooLarge { size }) if size == u16::MAX as usize + 1
+        ));
+        assert_unchanged(&uploader);
 
-        uploader.set_acked_local_seq(Seq32::from_u32(0), &now);
+        // nor does a burst with an invalid delta after a valid one
+        let deltas = [state(1, vec![2], vec![], 1), state(3, vec![3], vec![], 1)];
+        let result = uploader.set_states(deltas, &now);
+        assert!(matches!(result, Err(SetStateError::AckedNack { seq }) if seq.to_u32() == 3));
+        assert_unchanged(&uploader);
 
-        assert_eq!(uploader.swnd.size(), 0);
+        uploader
+            .set_state(state(1, vec![2], vec![], 1), &now)
+            .unwrap();
+        assert_eq!(swnd(&uploader), [1, 3]);
     }
 
     #[test]
-    fn test_rto_once() {
-        let mut now = Instant::now();
-        let mut builder = UploaderBuilder::default();
-        builder.mtu = MTU;
+    fn test_coalesce_small_writes() {
+        let now = Instant::now();
+        let pushes = |coalesce_small_writes| {
+            let mut builder = UploaderBuilder {
+                mtu: MTU,
+                ..Default::default()
+            };
+            builder.coalesce_small_writes = coalesce_small_writes;
+            let mut uploader = builder.build().unwrap();
+            uploader.set_remote_rwnd_size(99);
+
+            // an unacked push is outstanding
+            uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+
+            let mut pushes = 0;
+            for i in 1..4 {
+                uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+                for packet in uploader.emit(&now) {
+                    pushes += packet.frags().len();
+                }
+            }
+
+            let state = SetUploadState {
+                remote_rwnd_size: 99,
+                remote_nack: Seq32::from_u32(1),
+                local_next_seq_to_receive: Seq32::from_u32(0),
+                remote_seqs_to_ack: SeqList::new(),
+                acked_local_seqs: [Seq32::from_u32(0)].into(),
+                local_rwnd_size: 1,
+                remote_timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                pings_to_answer: Vec::new(),
+                pong: None,
+                probe_to_answer: None,
+                probe_ack: None,
+                remote_ranges_to_sack: Vec::new(),
+                sacked_local_ranges: Vec::new(),
+                syn_to_answer: None,
+                is_established: true,
+            };
+            uploader.set_state(state, &now).unwrap();
+            for packet in uploader.emit(&now) {
+                pushes += packet.frags().len();
+            }
+            assert_eq!(uploader.pending_bytes(), 0);
+            assert_eq!(uploader.stat().bytes_pushed, 4);
+            pushes
+        };
+        assert_eq!(pushes(false), 3);
+        assert_eq!(pushes(true), 1);
+    }
+
+    #[test]
+    fn test_fast_retransmit_skips_acked() {
+        let now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 0;
         let mut uploader = builder.build().unwrap();
-        uploader.set_remote_rwnd_size(2);
+        uploader.set_remote_rwnd_size(99);
 
-        let origin1 = vec![0, 1, 2];
-        {
-            let slice = BufSlice::from_bytes(origin1);
-            uploader.write(slice).map_err(|_| ()).unwrap();
+        for i in 0..7 {
+            uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
         }
-        let packets = uploader.emit(&now);
-        assert_eq!(packets.len(), 1);
 
-        now += uploader.rto();
+        let state = |nack, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state(1, vec![3]), &now).unwrap();
+        // seq(1) at the edge and seq(4) inside the window are acked in the same call
+        uploader.set_state(state(2, vec![1, 4, 6]), &now).unwrap();
 
+        // 0   1   2    3   4   5    6
+        //     ack nack ack ack      ack
         let packets = uploader.emit(&now);
-        // last_seen of the fragment is refreshed
         assert_eq!(packets.len(), 1);
+        let seqs: Vec<u32> = packets[0]
+            .frags()
+            .iter()
+            .map(|frag| frag.seq().to_u32())
+            .collect();
+        assert_eq!(seqs, vec![2, 5]);
+        assert_eq!(uploader.stat().fast_retransmissions, 2);
+    }
 
-        let packets = uploader.emit(&now);
-        // the fragment doesn't timeout
-        assert_eq!(packets.len(), 0);
+    #[test]
+    fn test_fast_retransmit_burst() {
+        let now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 0;
+        builder.max_fast_retransmit_burst = 10;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(u16::MAX);
+
+        for i in 0..101 {
+            uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+
+        let state = SetUploadState {
+            remote_rwnd_size: u16::MAX,
+            remote_nack: Seq32::from_u32(0),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(100)].into(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &now).unwrap();
+
+        for i in 0..10 {
+            let packets = uploader.emit(&now);
+            let seqs: Vec<u32> = packets
+                .iter()
+                .flat_map(|packet| packet.frags().iter().map(|frag| frag.seq().to_u32()))
+                .collect();
+            assert_eq!(seqs, (i * 10..(i + 1) * 10).collect::<Vec<_>>());
+            assert_eq!(uploader.stat().fast_retransmissions, (i as u64 + 1) * 10);
+        }
+        assert!(uploader.emit(&now).is_empty());
+        assert!(uploader.fast_retransmission_wnd.is_empty());
     }
 
     #[test]
-    fn test_fast_retransmit1() {
+    fn test_fast_retransmit_multi_gap_acks() {
         let now = Instant::now();
-        let dup = 1;
+        let dup = 0;
         let mut uploader = UploaderBuilder {
             local_recv_buf_len: 0,
             nack_duplicate_threshold_to_activate_fast_retransmit: dup,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
             mtu: MTU,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
         .build()
         .unwrap();
-        uploader.set_remote_rwnd_size(2);
+        uploader.set_remote_rwnd_size(99);
 
-        let origin1 = vec![0, 1, 2];
-        {
-            let slice = BufSlice::from_bytes(origin1);
+        for i in 0..6 {
+            let slice = BufSlice::from_bytes(vec![i]);
             uploader.write(slice).map_err(|_| ()).unwrap();
+            let packets = uploader.emit(&now);
+            assert_eq!(packets.len(), 1);
         }
-        let packets = uploader.emit(&now);
-        assert_eq!(packets.len(), 1);
+        assert_eq!(uploader.stat().pushes, 6);
+
+        let state = SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(1),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(2), Seq32::from_u32(4), Seq32::from_u32(5)].into(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &now).unwrap();
+
+        // 0    1    2   3    4   5
+        // nack nack ack      ack ack
+        //      ^ fast retransmission window ^
+
+        assert_eq!(uploader.fast_retransmission_wnd.start().to_u32(), 1);
+        assert_eq!(uploader.fast_retransmission_wnd.end().to_u32(), 5);
 
-        let origin2 = vec![3];
-        {
-            let slice = BufSlice::from_bytes(origin2);
-            uploader.write(slice).map_err(|_| ()).unwrap();
-        }
         let packets = uploader.emit(&now);
         assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frags().len(), 2);
+        assert_eq!(uploader.stat().fast_retransmissions, 2);
+        assert!(uploader.fast_retransmission_wnd.is_empty());
 
-        // nack is 0 by default. When receiving another same nack, the fast retransmission gets activated since now the dup count becomes 1
-
+        // seq(3) is acked before the retransmission
         let state = SetUploadState {
             remote_rwnd_size: 99,
-            remote_nack: Seq32::from_u32(0),
+            remote_nack: Seq32::from_u32(1),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(3)].into(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        uploader.set_state(state, &now).unwrap();
+        let state = SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(2),
             local_next_seq_to_receive: Seq32::from_u32(0),
-            remote_seqs_to_ack: vec![],
-            acked_local_seqs: vec![Seq32::from_u32(1)],
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: SeqList::new(),
             local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
         };
         uploader.set_state(state, &now).unwrap();
+        assert!(uploader.fast_retransmission_wnd.is_empty());
 
         let packets = uploader.emit(&now);
-
-        assert_eq!(packets.len(), 1);
+        assert_eq!(packets.len(), 0);
+        assert_eq!(uploader.stat().fast_retransmissions, 2);
     }
 
     #[test]
@@ -797,10 +5152,39 @@ mod tests {
         let mut uploader = UploaderBuilder {
             local_recv_buf_len: 0,
             nack_duplicate_threshold_to_activate_fast_retransmit: dup,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
             mtu: MTU,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
         .build()
         .unwrap();
@@ -826,9 +5210,20 @@ mod tests {
             remote_rwnd_size: 99,
             remote_nack: Seq32::from_u32(1),
             local_next_seq_to_receive: Seq32::from_u32(0),
-            remote_seqs_to_ack: vec![],
-            acked_local_seqs: vec![Seq32::from_u32(0)],
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(0)].into(),
             local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
         };
         uploader.set_state(state, &now).unwrap();
 
@@ -850,10 +5245,39 @@ mod tests {
         let mut uploader = UploaderBuilder {
             local_recv_buf_len: 0,
             nack_duplicate_threshold_to_activate_fast_retransmit: dup,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
             mtu: MTU,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
         .build()
         .unwrap();
@@ -887,9 +5311,20 @@ mod tests {
             remote_rwnd_size: 99,
             remote_nack: Seq32::from_u32(1),
             local_next_seq_to_receive: Seq32::from_u32(0),
-            remote_seqs_to_ack: vec![],
-            acked_local_seqs: vec![Seq32::from_u32(2)],
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(2)].into(),
             local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
         };
         uploader.set_state(state, &now).unwrap();
 
@@ -915,10 +5350,39 @@ mod tests {
         let mut uploader = UploaderBuilder {
             local_recv_buf_len: 0,
             nack_duplicate_threshold_to_activate_fast_retransmit: dup,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
             mtu: MTU,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
         .build()
         .unwrap();
@@ -952,9 +5416,20 @@ mod tests {
             remote_rwnd_size: 99,
             remote_nack: Seq32::from_u32(1),
             local_next_seq_to_receive: Seq32::from_u32(0),
-            remote_seqs_to_ack: vec![],
-            acked_local_seqs: vec![Seq32::from_u32(2)],
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(2)].into(),
             local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
         };
         uploader.set_state(state, &now).unwrap();
 
@@ -967,6 +5442,7 @@ mod tests {
         // seq(0) is implicitly acked by nack(1)
 
         // dup count for nack(1): 0
+        assert_eq!(uploader.fast_retransmission_wnd.dup_count(), 0);
 
         let packets = uploader.emit(&now);
 
@@ -976,29 +5452,528 @@ mod tests {
             remote_rwnd_size: 99,
             remote_nack: Seq32::from_u32(1),
             local_next_seq_to_receive: Seq32::from_u32(0),
-            remote_seqs_to_ack: vec![],
-            acked_local_seqs: vec![Seq32::from_u32(2)],
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: [Seq32::from_u32(2)].into(),
             local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
         };
         uploader.set_state(state, &now).unwrap();
 
-        // dup count for nack(1): 1
+        // dup count for nack(1): 1, which opens the window and restarts the count
+        assert_eq!(uploader.fast_retransmission_wnd.dup_count(), 0);
 
         let packets = uploader.emit(&now);
 
         assert_eq!(packets.len(), 1);
     }
 
+    #[test]
+    fn test_fast_retransmit_nack_advances() {
+        let now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 1;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        for byte in 0..4 {
+            uploader.write_bytes(&[byte]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+
+        let state = |nack, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+
+        uploader.set_state(state(1, vec![3]), &now).unwrap();
+        assert_eq!(uploader.fast_retransmission_wnd.dup_count(), 0);
+
+        // the nack advances, so the ack of seq(3) is not a duplicate of nack(1) any more
+        uploader.set_state(state(2, vec![3]), &now).unwrap();
+        assert_eq!(uploader.fast_retransmission_wnd.dup_count(), 0);
+        assert!(uploader.emit(&now).is_empty());
+
+        // a stale nack is ignored
+        uploader.set_state(state(1, vec![3]), &now).unwrap();
+        assert_eq!(uploader.fast_retransmission_wnd.dup_count(), 0);
+        assert!(uploader.emit(&now).is_empty());
+        assert_eq!(uploader.stat().retransmissions, 0);
+    }
+
+    #[test]
+    fn test_stale_state() {
+        let now = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 0;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        for byte in 0..6 {
+            uploader.write_bytes(&[byte]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+
+        let state = |nack, rwnd, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: rwnd,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+
+        // the newer state arrives first
+        uploader.set_state(state(3, 99, vec![]), &now).unwrap();
+        assert_eq!(uploader.swnd_len(), 3);
+
+        // the older state only contributes its ack
+        uploader.set_state(state(1, 0, vec![4]), &now).unwrap();
+        assert_eq!(uploader.swnd_len(), 2);
+        assert_eq!(uploader.remote_rwnd(), 99);
+        assert!(uploader.emit(&now).is_empty());
+        assert_eq!(uploader.stat().retransmissions, 0);
+        assert_eq!(uploader.stat().fast_retransmissions, 0);
+
+        // the newer nack still counts for fast retransmission
+        uploader.set_state(state(3, 99, vec![4]), &now).unwrap();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frags()[0].seq().to_u32(), 3);
+    }
+
+    #[test]
+    fn test_duplicate_acks_received() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        for i in 0..3 {
+            uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+
+        let state = |nack, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+
+        uploader.set_state(state(0, vec![1]), &now).unwrap();
+        assert_eq!(uploader.stat().duplicate_acks_received, 0);
+        uploader.set_state(state(0, vec![1]), &now).unwrap();
+        assert_eq!(uploader.stat().duplicate_acks_received, 1);
+
+        // below `swnd.start()`
+        uploader.set_state(state(2, vec![]), &now).unwrap();
+        uploader.set_state(state(2, vec![0]), &now).unwrap();
+        assert_eq!(uploader.stat().duplicate_acks_received, 2);
+
+        // never sent
+        uploader.set_state(state(2, vec![9]), &now).unwrap();
+        assert_eq!(uploader.stat().duplicate_acks_received, 2);
+
+        // the first ack of an unreliable push, which has left swnd
+        uploader
+            .write_unreliable(BufSlice::from_bytes(vec![3]))
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+        uploader.set_state(state(2, vec![3]), &now).unwrap();
+        assert_eq!(uploader.stat().duplicate_acks_received, 2);
+        uploader.set_state(state(2, vec![3]), &now).unwrap();
+        assert_eq!(uploader.stat().duplicate_acks_received, 3);
+    }
+
+    #[test]
+    fn test_set_states() {
+        let now = Instant::now();
+        let build = || {
+            let mut builder = UploaderBuilder {
+                mtu: MTU,
+                ..Default::default()
+            };
+            builder.nack_duplicate_threshold_to_activate_fast_retransmit = 2;
+            let mut uploader = builder.build().unwrap();
+            uploader.set_remote_rwnd_size(99);
+            for i in 0..8 {
+                uploader.write_bytes(&[i]).map_err(|_| ()).unwrap();
+                assert_eq!(uploader.emit(&now).len(), 1);
+            }
+            uploader
+        };
+        let state = |nack, acked: Vec<u32>, rwnd| SetUploadState {
+            remote_rwnd_size: rwnd,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        let deltas = || {
+            vec![
+                state(0, vec![2], 10),
+                state(0, vec![3], 11),
+                state(0, vec![4], 12),
+                state(1, vec![5], 13),
+                // stale
+                state(0, vec![6], 14),
+                state(1, vec![6], 15),
+            ]
+        };
+
+        let mut sequential = build();
+        for delta in deltas() {
+            sequential.set_state(delta, &now).unwrap();
+        }
+        let mut batched = build();
+        batched.set_states(deltas(), &now).unwrap();
+
+        let seqs = |uploader: &Uploader| -> Vec<u32> {
+            uploader
+                .swnd
+                .range(uploader.swnd.start(), uploader.swnd.end())
+                .map(|(seq, _)| seq.to_u32())
+                .collect()
+        };
+        assert_eq!(seqs(&batched), vec![1, 7]);
+        assert_eq!(seqs(&batched), seqs(&sequential));
+        assert_eq!(batched.swnd.remote_rwnd_size(), 15);
+        assert_eq!(
+            batched.swnd.remote_rwnd_size(),
+            sequential.swnd.remote_rwnd_size()
+        );
+        let frw = |uploader: &Uploader| {
+            (
+                uploader.fast_retransmission_wnd.start().to_u32(),
+                uploader.fast_retransmission_wnd.end().to_u32(),
+                uploader.fast_retransmission_wnd.dup_count(),
+            )
+        };
+        assert_eq!(frw(&batched), (1, 4, 1));
+        assert_eq!(frw(&batched), frw(&sequential));
+
+        // nothing is applied if any delta is invalid
+        let mut uploader = build();
+        let deltas = vec![state(0, vec![2], 10), state(3, vec![3], 11)];
+        assert!(uploader.set_states(deltas, &now).is_err());
+        assert_eq!(uploader.swnd_len(), 8);
+        assert!(uploader.set_states(vec![], &now).is_ok());
+    }
+
+    #[test]
+    fn test_acked_watermark() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        for len in 1..4 {
+            uploader.write_bytes(&vec![0; len]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+        }
+        assert_eq!(uploader.acked_watermark().to_u32(), 0);
+        assert_eq!(uploader.bytes_acked(), 0);
+
+        let state = |nack, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+
+        // explicit ack of seq(2)
+        uploader.set_state(state(0, vec![2]), &now).unwrap();
+        assert_eq!(uploader.acked_watermark().to_u32(), 0);
+        assert_eq!(uploader.bytes_acked(), 3);
+
+        // the nack takes seq(0) and seq(1)
+        uploader.set_state(state(2, vec![]), &now).unwrap();
+        assert_eq!(uploader.acked_watermark().to_u32(), 2);
+        assert_eq!(uploader.bytes_acked(), 6);
+
+        // nothing is counted twice
+        uploader.set_state(state(3, vec![2]), &now).unwrap();
+        uploader.set_state(state(1, vec![]), &now).unwrap();
+        assert_eq!(uploader.acked_watermark().to_u32(), 3);
+        assert_eq!(uploader.bytes_acked(), 6);
+    }
+
+    /// Lossy transfer with jitter, fast retransmissions and timeouts
+    ///
+    /// Returns the seqs of the pushes in every emit.
+    fn run_script(steps: u32) -> Vec<Vec<u32>> {
+        let start = Instant::now();
+        let mut builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        builder.rto_jitter = 0.25;
+        builder.rto_jitter_seed = 7;
+        builder.nack_duplicate_threshold_to_activate_fast_retransmit = 1;
+        builder.swnd_size_cap = 64;
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(u16::MAX);
+
+        let mut emits = Vec::new();
+        // times each seq has been sent
+        let mut sent: Vec<u32> = Vec::new();
+        let mut nack = 0;
+        for step in 0..steps {
+            let now = start + Duration::from_millis(20 * step as u64);
+            // a push fills a packet, so the order of retransmissions does not change the cuts
+            if step % 3 != 2 {
+                let _ = uploader.write_bytes(&[step as u8; MTU - PACKET_HDR_LEN - PUSH_HDR_LEN]);
+            }
+            let mut seqs = Vec::new();
+            for packet in uploader.emit(&now) {
+                for frag in packet.frags() {
+                    if let FragCommand::Push { .. } = frag.cmd() {
+                        let seq = frag.seq().to_u32() as usize;
+                        if sent.len() <= seq {
+                            sent.resize(seq + 1, 0);
+                        }
+                        sent[seq] += 1;
+                        seqs.push(seq as u32);
+                    }
+                }
+            }
+            // pushes sent at the same time come in no particular order
+            seqs.sort_unstable();
+            emits.push(seqs);
+
+            if step % 4 != 3 {
+                continue;
+            }
+            // the first transmission of every fifth seq and the first two of every seventh are lost
+            let is_received = |seq: usize| match (seq % 5, seq % 7) {
+                (0, _) if sent[seq] < 2 => false,
+                (_, 0) => 3 <= sent[seq],
+                _ => sent[seq] != 0,
+            };
+            while nack < sent.len() && is_received(nack) {
+                nack += 1;
+            }
+            let state = SetUploadState {
+                remote_rwnd_size: u16::MAX,
+                remote_nack: Seq32::from_u32(nack as u32),
+                local_next_seq_to_receive: Seq32::from_u32(0),
+                remote_seqs_to_ack: SeqList::new(),
+                acked_local_seqs: (nack + 1..sent.len())
+                    .filter(|&seq| is_received(seq))
+                    .map(|seq| Seq32::from_u32(seq as u32))
+                    .collect(),
+                local_rwnd_size: 1,
+                remote_timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                pings_to_answer: Vec::new(),
+                pong: None,
+                probe_to_answer: None,
+                probe_ack: None,
+                remote_ranges_to_sack: Vec::new(),
+                sacked_local_ranges: Vec::new(),
+                syn_to_answer: None,
+                is_established: true,
+            };
+            uploader.set_state(state, &now).unwrap();
+        }
+        emits
+    }
+
+    /// The output recorded with the `KeyedPriorityQueue` that `RtoWheel` replaced
+    #[test]
+    fn test_rto_script() {
+        let emits = run_script(400);
+        // FNV-1a over the emitted seqs
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for seq in emits.iter().flatten() {
+            hash ^= *seq as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+        assert_eq!(emits.iter().flatten().count(), 388);
+        assert_eq!(hash, 0xcf00_8ccc_55ad_4cfc);
+    }
+
+    /// A long script stays linear in the number of pushes
+    #[test]
+    fn test_rto_script_long() {
+        let start = Instant::now();
+        let emits = run_script(100_000);
+        assert_eq!(emits.iter().flatten().count(), 97142);
+        // about a second in a debug build
+        assert!(start.elapsed() < Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_rto_wheel_pruned() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+
+        let state = |nack, acked: Vec<u32>| SetUploadState {
+            remote_rwnd_size: 99,
+            remote_nack: Seq32::from_u32(nack),
+            local_next_seq_to_receive: Seq32::from_u32(0),
+            remote_seqs_to_ack: SeqList::new(),
+            acked_local_seqs: acked.into_iter().map(Seq32::from_u32).collect(),
+            local_rwnd_size: 1,
+            remote_timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            pings_to_answer: Vec::new(),
+            pong: None,
+            probe_to_answer: None,
+            probe_ack: None,
+            remote_ranges_to_sack: Vec::new(),
+            sacked_local_ranges: Vec::new(),
+            syn_to_answer: None,
+            is_established: true,
+        };
+        for i in (0..10_000).step_by(2) {
+            uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+            uploader.write_bytes(&[1]).map_err(|_| ()).unwrap();
+            assert_eq!(uploader.emit(&now).len(), 1);
+            // ack the odd seq by itself and the even one by the nack
+            uploader.set_state(state(i, vec![i + 1]), &now).unwrap();
+            uploader.set_state(state(i + 2, vec![]), &now).unwrap();
+            // stale entries are discarded by the next emit
+            assert!(uploader.rto_wheel.len() <= 2);
+        }
+        assert_eq!(uploader.bytes_in_flight(), 0);
+        assert!(uploader.emit(&now).is_empty());
+        assert!(uploader.rto_wheel.is_empty());
+    }
+
     #[test]
     fn test_multiple_frags() {
         let now = Instant::now();
         let mut uploader = UploaderBuilder {
             local_recv_buf_len: 0,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
             mtu: PACKET_HDR_LEN + ACK_HDR_LEN * 2 + PUSH_HDR_LEN + 1,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
         .build()
         .unwrap();
@@ -1026,9 +6001,20 @@ mod tests {
                     remote_rwnd_size: 99,
                     remote_nack: Seq32::from_u32(2),
                     local_next_seq_to_receive: Seq32::from_u32(88),
-                    remote_seqs_to_ack: vec![Seq32::from_u32(0), Seq32::from_u32(1)],
-                    acked_local_seqs: Vec::new(),
+                    remote_seqs_to_ack: [Seq32::from_u32(0), Seq32::from_u32(1)].into(),
+                    acked_local_seqs: SeqList::new(),
                     local_rwnd_size: 99,
+                    remote_timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    pings_to_answer: Vec::new(),
+                    pong: None,
+                    probe_to_answer: None,
+                    probe_ack: None,
+                    remote_ranges_to_sack: Vec::new(),
+                    sacked_local_ranges: Vec::new(),
+                    syn_to_answer: None,
+                    is_established: true,
                 },
                 &now,
             )
@@ -1038,7 +6024,7 @@ mod tests {
         // to_ack    x  x
         // swnd    ][
         // to_send  [[9, 8, 7]]
-        assert_eq!(uploader.to_ack_queue.len(), 2);
+        assert_eq!(uploader.pending_acks(), 2);
 
         let packets = uploader.emit(&now);
 
@@ -1051,8 +6037,8 @@ mod tests {
         // to_ack
         // swnd     [    ]
         // to_send  []
-        assert!(uploader.to_ack_queue.is_empty());
-        assert_eq!(uploader.swnd.size(), 2);
+        assert_eq!(uploader.pending_acks(), 0);
+        assert_eq!(uploader.swnd_len(), 2);
 
         {
             assert_eq!(packets.len(), 2);
@@ -1062,22 +6048,24 @@ mod tests {
                 assert_eq!(packets[0].frags().len(), 3);
                 assert_eq!(packets[0].frags()[0].seq().to_u32(), 0);
                 match packets[0].frags()[0].cmd() {
-                    FragCommand::Push { body: _ } => panic!(),
                     FragCommand::Ack => (),
+                    _ => panic!(),
                 }
                 assert_eq!(packets[0].frags()[1].seq().to_u32(), 1);
                 match packets[0].frags()[1].cmd() {
-                    FragCommand::Push { body: _ } => panic!(),
                     FragCommand::Ack => (),
+                    _ => panic!(),
                 }
                 assert_eq!(packets[0].frags()[2].seq().to_u32(), 0);
                 let mut body = OwnedBufWtr::new(1, 0);
                 match packets[0].frags()[2].cmd() {
                     FragCommand::Push { body: x } => match x {
-                        Body::Slice(_) => panic!(),
+                        Body::Slice(x) => body.append(x.data()).unwrap(),
                         Body::Pasta(x) => x.append_to(&mut body).unwrap(),
                     },
-                    FragCommand::Ack => panic!(),
+                    _ => {
+                        panic!()
+                    }
                 }
                 assert_eq!(body.data(), vec![9]);
             }
@@ -1086,10 +6074,12 @@ mod tests {
                 let mut body = OwnedBufWtr::new(2, 0);
                 match packets[1].frags()[0].cmd() {
                     FragCommand::Push { body: x } => match x {
-                        Body::Slice(_) => panic!(),
+                        Body::Slice(x) => body.append(x.data()).unwrap(),
                         Body::Pasta(x) => x.append_to(&mut body).unwrap(),
                     },
-                    FragCommand::Ack => panic!(),
+                    _ => {
+                        panic!()
+                    }
                 }
                 assert_eq!(body.data(), vec![8, 7]);
             }
@@ -1105,11 +6095,12 @@ mod tests {
         // // swnd     [    ]
         // // to_send  []
         // assert!(uploader.to_send_queue.is_empty());
-        // assert_eq!(uploader.swnd.size(), 2);
+        // assert_eq!(uploader.swnd_len(), 2);
 
         // assert_eq!(
         //     wtr.data(),
         //     vec![
+        //         1, // version
         //         0, 99, // rwnd
         //         0, 0, 0, 88, // nack
         //         // push
@@ -1126,16 +6117,260 @@ mod tests {
         // );
     }
 
+    /// The kinds of the frags in each packet: `a` for ack and `p` for push
+    fn frag_kinds(packets: &[Packet]) -> Vec<String> {
+        packets
+            .iter()
+            .map(|packet| {
+                packet
+                    .frags()
+                    .iter()
+                    .map(|frag| match frag.cmd() {
+                        FragCommand::Push { .. } => 'p',
+                        FragCommand::CompressedPush { .. } => 'c',
+                        FragCommand::Ack => 'a',
+                        FragCommand::AckBitmap { .. } => 'b',
+                        FragCommand::Skip => 's',
+                        FragCommand::Fin => 'f',
+                        FragCommand::Rst => 'r',
+                        FragCommand::Ping { .. } => 'i',
+                        FragCommand::Pong { .. } => 'o',
+                        FragCommand::Probe { .. } => 'e',
+                        FragCommand::ProbeAck { .. } => 'q',
+                        FragCommand::Sack { .. } => 'k',
+                        FragCommand::Syn { .. } => 'y',
+                        FragCommand::SynAck { .. } => 'z',
+                        FragCommand::Padding { .. } => 'd',
+                        FragCommand::Parity { .. } => 'x',
+                        FragCommand::Window { .. } => 'w',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_acks_leave_room_for_pushes() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: PACKET_HDR_LEN + ACK_HDR_LEN * 2 + PUSH_HDR_LEN + 2,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        for seq in 0..4 {
+            uploader.add_remote_seq_to_ack(Seq32::from_u32(seq));
+        }
+        uploader.write_bytes(&[0; 6]).map_err(|_| ()).unwrap();
+
+        let packets = uploader.emit(&now);
+        assert_eq!(frag_kinds(&packets), vec!["aap", "aap", "p"]);
+        assert_eq!(uploader.pending_bytes(), 0);
+    }
+
+    #[test]
+    fn test_ack_bitmap() {
+        let now = Instant::now();
+        let queue_acks = |uploader: &mut Uploader| {
+            for seq in (0..64).chain([100, 101]).chain([200, 202, 203]) {
+                uploader.add_remote_seq_to_ack(Seq32::from_u32(seq));
+            }
+        };
+        let mut uploader = UploaderBuilder {
+            mtu: MTU,
+            ack_bitmap: true,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        queue_acks(&mut uploader);
+        let packets = uploader.emit(&now);
+        assert_eq!(frag_kinds(&packets), vec!["baab"]);
+        let bitmaps: Vec<_> = packets[0]
+            .frags()
+            .iter()
+            .filter_map(|frag| match frag.cmd() {
+                FragCommand::AckBitmap { bitmap } => Some((frag.seq().to_u32(), *bitmap)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bitmaps, [(0, u64::MAX), (200, 0b1101)]);
+        assert_eq!(uploader.stat().acks, 69);
+        assert_eq!(uploader.pending_acks(), 0);
+        let bitmap_len = packets[0].wire_len();
+
+        // an ack each otherwise
+        let mut uploader = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        }
+        .build()
+        .unwrap();
+        queue_acks(&mut uploader);
+        let packets = uploader.emit(&now);
+        assert_eq!(frag_kinds(&packets), vec!["a".repeat(69)]);
+        assert_eq!(
+            packets[0].wire_len() - bitmap_len,
+            ACK_HDR_LEN * 69 - 2 * ACK_HDR_LEN - 2 * ACK_BITMAP_LEN
+        );
+    }
+
+    #[test]
+    fn test_emit_budgeted() {
+        let mut now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: MTU,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(u16::MAX);
+        uploader.write_bytes(&[0; 10_000]).map_err(|_| ()).unwrap();
+
+        let packets = uploader.emit_budgeted(&now, 3_000);
+        assert!(!packets.is_empty());
+        let bytes: usize = packets.iter().map(|x| x.wire_len()).sum();
+        assert!(bytes <= 3_000);
+        assert!(3_000 - MTU < bytes);
+        let sent = uploader.stat().bytes_pushed as usize;
+        assert_eq!(uploader.pending_bytes(), 10_000 - sent);
+
+        // the rest comes out later
+        let packets = uploader.emit(&now);
+        let pushed: usize = packets
+            .iter()
+            .flat_map(|x| x.frags())
+            .map(|x| match x.cmd() {
+                FragCommand::Push { body } => body.len(),
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(sent + pushed, 10_000);
+        assert_eq!(uploader.pending_bytes(), 0);
+
+        // retransmissions take the budget first
+        uploader.write_bytes(&[1; 100]).map_err(|_| ()).unwrap();
+        now += uploader.rto();
+        let retransmissions = uploader.stat().retransmissions;
+        let packets = uploader.emit_budgeted(&now, MTU);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].frags()[0].seq().to_u32(), 0);
+        assert_eq!(
+            uploader.stat().retransmissions - retransmissions,
+            packets[0].frags().len() as u64
+        );
+        assert_eq!(uploader.pending_bytes(), 100);
+
+        // nothing fits
+        assert!(uploader
+            .emit_budgeted(&now, PACKET_HDR_LEN + PUSH_HDR_LEN)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_body_limit_fallback() {
+        let now = Instant::now();
+        let builder = UploaderBuilder {
+            mtu: PACKET_HDR_LEN + PUSH_HDR_LEN + 1,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        for seq in 0..3 {
+            uploader.add_remote_seq_to_ack(Seq32::from_u32(seq));
+        }
+        uploader.write_bytes(&[0, 1]).map_err(|_| ()).unwrap();
+
+        // an ack and a push do not fit in one packet, so the pushes go first
+        let packets = uploader.emit(&now);
+        assert_eq!(frag_kinds(&packets), vec!["p", "p", "aa", "a"]);
+        assert_eq!(uploader.stat().acks, 3);
+
+        // without data to send, the acks fill the packets
+        for seq in 3..6 {
+            uploader.add_remote_seq_to_ack(Seq32::from_u32(seq));
+        }
+        let packets = uploader.emit(&now);
+        assert_eq!(frag_kinds(&packets), vec!["aa", "a"]);
+    }
+
+    #[test]
+    fn test_push_opens_bundle() {
+        let mut now = Instant::now();
+        let mtu = PACKET_HDR_LEN + 2 * ACK_HDR_LEN + PUSH_HDR_LEN + 1;
+        let builder = UploaderBuilder {
+            mtu,
+            ..Default::default()
+        };
+        let mut uploader = builder.build().unwrap();
+        uploader.set_remote_rwnd_size(99);
+        let body_len = 2 * ACK_HDR_LEN - 4;
+        uploader
+            .write_bytes(&vec![0; body_len])
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(uploader.emit(&now).len(), 1);
+
+        for seq in 0..3 {
+            uploader.add_remote_seq_to_ack(Seq32::from_u32(seq));
+        }
+        let body_len = mtu - PACKET_HDR_LEN - PUSH_HDR_LEN;
+        uploader
+            .write_bytes(&vec![1; body_len])
+            .map_err(|_| ())
+            .unwrap();
+        now += uploader.rto();
+
+        // the first two acks leave room for a push; the retransmission does not fit there,
+        // and the last ack exactly fills what the retransmission leaves
+        let packets = uploader.emit(&now);
+        assert_eq!(frag_kinds(&packets), vec!["aa", "pa", "p"]);
+        for packet in &packets {
+            assert!(packet.wire_len() <= mtu);
+        }
+        // the new push fills a packet of its own
+        assert_eq!(packets[2].wire_len(), mtu);
+        assert_eq!(uploader.stat().acks, 3);
+    }
+
     #[test]
     fn test_body_pasta() {
         let now = Instant::now();
         let mut uploader = UploaderBuilder {
             local_recv_buf_len: 0,
             nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
             ratio_rto_to_one_rtt: 1.5,
-            to_send_queue_len_cap: usize::MAX,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
             swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
             mtu: PACKET_HDR_LEN + PUSH_HDR_LEN + 6,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
         }
         .build()
         .unwrap();
@@ -1166,9 +6401,82 @@ mod tests {
                     Body::Slice(_) => panic!(),
                     Body::Pasta(x) => x.append_to(&mut body).unwrap(),
                 },
-                FragCommand::Ack => panic!(),
+                _ => {
+                    panic!()
+                }
             };
             assert_eq!(body.data(), vec![0, 1, 2, 3, 4, 5]);
         }
     }
+
+    #[test]
+    fn test_body_slice() {
+        let mut now = Instant::now();
+        let mut uploader = UploaderBuilder {
+            local_recv_buf_len: 0,
+            nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+            max_fast_retransmit_burst: usize::MAX,
+            ratio_rto_to_one_rtt: 1.5,
+            rto_jitter: 0.0,
+            rto_jitter_seed: 0,
+            min_rto: None,
+            max_rto: None,
+            initial_rto: None,
+            coalesce_small_writes: false,
+            timestamps: false,
+            legacy_header: false,
+            strict: false,
+            to_send_bytes_cap: usize::MAX,
+            to_send_queue_len_cap: None,
+            send_available_low_water_mark: None,
+            swnd_size_cap: usize::MAX,
+            max_retransmissions: None,
+            max_no_ack_duration: None,
+            rst_transmissions: 3,
+            keepalive_interval: None,
+            sack: false,
+            packet_seqs: false,
+            conn_id: None,
+            mtu: PACKET_HDR_LEN + PUSH_HDR_LEN + 6,
+            handshake: None,
+            initial_seq: None,
+            auth: None,
+            wire_format: WireFormat::V1,
+            pad_packets_to: None,
+            fec_group_size: None,
+            codec: None,
+            cumulative_ack_only: false,
+            ack_bitmap: false,
+            mtu_probing: None,
+        }
+        .build()
+        .unwrap();
+
+        uploader
+            .write(BufSlice::from_bytes(vec![0, 1, 2]))
+            .map_err(|_| ())
+            .unwrap();
+
+        // a single slice is pushed without a pasta
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+        match packets[0].frags()[0].cmd() {
+            FragCommand::Push {
+                body: Body::Slice(x),
+            } => assert_eq!(x.data(), vec![0, 1, 2]),
+            _ => panic!(),
+        }
+
+        // the retransmission carries the same slice
+        now += uploader.rto();
+        let packets = uploader.emit(&now);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(uploader.stat().retransmissions, 1);
+        match packets[0].frags()[0].cmd() {
+            FragCommand::Push {
+                body: Body::Slice(x),
+            } => assert_eq!(x.data(), vec![0, 1, 2]),
+            _ => panic!(),
+        }
+    }
 }
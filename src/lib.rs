@@ -1,3 +1,14 @@
+//! Panic policy
+//!
+//! A panic reachable from the public API with inputs a well-behaved application or peer can
+//! produce is a bug; such conditions return errors instead. The remaining panics are intentional:
+//!
+//! - `check_rep` assertions guard internal invariants and only fire on a bug in this crate;
+//! - constructors and utilities documenting a `# Panics` section expect the caller to uphold a
+//!   precondition, as slice indexing does;
+//! - `SharedUploader` propagates the panic of another thread holding its lock.
+
+pub mod changelog;
 pub mod layer;
 pub mod protocol;
 pub mod utils;
@@ -0,0 +1,86 @@
+use super::PacketAuth;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Bytes of an `HmacSha256` tag
+pub const HMAC_SHA256_TAG_LEN: usize = 32;
+
+/// RFC 2104 HMAC over SHA-256 with a key shared by both peers
+#[derive(Clone)]
+pub struct HmacSha256 {
+    // the state after the padded key
+    mac: Hmac<Sha256>,
+}
+
+impl HmacSha256 {
+    pub fn new(key: &[u8]) -> Self {
+        // HMAC takes keys of any length
+        let mac = Hmac::new_from_slice(key).unwrap();
+        HmacSha256 { mac }
+    }
+
+    #[must_use]
+    pub fn tag(&self, data: &[u8]) -> [u8; HMAC_SHA256_TAG_LEN] {
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+}
+
+impl PacketAuth for HmacSha256 {
+    fn tag_len(&self) -> usize {
+        HMAC_SHA256_TAG_LEN
+    }
+
+    fn sign(&self, data: &[u8], tag: &mut [u8]) {
+        tag.copy_from_slice(&self.tag(data));
+    }
+
+    fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        // in constant time
+        let mut mac = self.mac.clone();
+        mac.update(data);
+        mac.verify_slice(tag).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HmacSha256, PacketAuth};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|x| format!("{:02x}", x)).collect()
+    }
+
+    #[test]
+    fn test_rfc4231() {
+        // test cases 1, 2 and 6
+        for (key, data, tag) in [
+            (
+                vec![0x0b; 20],
+                &b"Hi There"[..],
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe".to_vec(),
+                &b"what do ya want for nothing?"[..],
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            (
+                vec![0xaa; 131],
+                &b"Test Using Larger Than Block-Size Key - Hash Key First"[..],
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+        ] {
+            let auth = HmacSha256::new(&key);
+            assert_eq!(hex(&auth.tag(data)), tag);
+
+            let mut signed = vec![0; auth.tag_len()];
+            auth.sign(data, &mut signed);
+            assert!(auth.verify(data, &signed));
+            signed[0] ^= 1;
+            assert!(!auth.verify(data, &signed));
+            assert!(!auth.verify(data, &signed[1..]));
+        }
+    }
+}
@@ -0,0 +1,34 @@
+#[cfg(feature = "hmac-sha256")]
+mod hmac_sha256;
+#[cfg(feature = "hmac-sha256")]
+pub use hmac_sha256::*;
+
+/// Longest tag a `PacketAuth` can produce
+pub const MAX_TAG_LEN: usize = 64;
+
+/// Tags outgoing packets and checks the tag of incoming ones
+///
+/// The tag covers every byte of the packet before it and is appended after the last frag.
+pub trait PacketAuth: Send + Sync {
+    /// Bytes of every tag; at most `MAX_TAG_LEN`
+    fn tag_len(&self) -> usize;
+
+    /// Write the tag of `data` into `tag`, which is `tag_len` bytes long
+    fn sign(&self, data: &[u8], tag: &mut [u8]);
+
+    /// Whether `tag` is the tag of `data`
+    fn verify(&self, data: &[u8], tag: &[u8]) -> bool;
+}
+
+/// Compare without exiting at the first differing byte
+#[must_use]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0;
+    for (x, y) in a.iter().zip(b) {
+        diff |= x ^ y;
+    }
+    diff == 0
+}
@@ -0,0 +1,99 @@
+use super::BodyCodec;
+
+// the longest an LZ4 block can expand to per byte
+const MAX_RATIO: usize = 255;
+// the last bytes are always literals
+const LAST_LITERALS: usize = 5;
+
+/// LZ4 block format preceded by the length of the input as a little-endian u32
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Codec;
+
+impl BodyCodec for Lz4Codec {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::block::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        let len = match data {
+            [a, b, c, d, ..] => u32::from_le_bytes([*a, *b, *c, *d]) as usize,
+            _ => return Err(()),
+        };
+        // no allocation bigger than the input can have come from
+        if MAX_RATIO * (data.len() - 4) + LAST_LITERALS < len {
+            return Err(());
+        }
+        match lz4_flex::block::decompress_size_prepended(data) {
+            Ok(out) if out.len() == len => Ok(out),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BodyCodec, Lz4Codec};
+
+    #[test]
+    fn test_round_trip() {
+        let codec = Lz4Codec;
+        let mut inputs = vec![
+            vec![],
+            vec![7],
+            b"abcdabcdabcdabcdabcdabcd".to_vec(),
+            vec![0; 10000],
+            (0..300).map(|x| x as u8).collect(),
+        ];
+        // xorshift32 over a small alphabet, so matches of every length and offset show up
+        let mut state: u32 = 0x9e3779b9;
+        for len in [13, 100, 1000, 70000] {
+            let input = (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    b"ab{}\": 0"[(state % 8) as usize]
+                })
+                .collect();
+            inputs.push(input);
+        }
+        for input in inputs {
+            let compressed = codec.compress(&input);
+            assert_eq!(codec.decompress(&compressed).unwrap(), input);
+        }
+
+        let json = br#"{"level":"info","msg":"ok"}"#.repeat(40);
+        assert!(codec.compress(&json).len() < json.len() / 4);
+    }
+
+    #[test]
+    fn test_golden() {
+        // 4 literals, a match of 8 at offset 4, then 5 literals
+        let compressed = [
+            17, 0, 0, 0, 0x44, b'a', b'b', b'c', b'd', 4, 0, 0x50, b'a', b'b', b'c', b'd', b'e',
+        ];
+        assert_eq!(
+            Lz4Codec.decompress(&compressed).unwrap(),
+            b"abcdabcdabcdabcde"
+        );
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let codec = Lz4Codec;
+        let compressed = codec.compress(b"abcdabcdabcdabcdabcdabcd");
+        // cut short
+        assert!(codec
+            .decompress(&compressed[..compressed.len() - 1])
+            .is_err());
+        assert!(codec.decompress(&compressed[..3]).is_err());
+        // the length does not match
+        let mut wrong_len = compressed.clone();
+        wrong_len[0] += 1;
+        assert!(codec.decompress(&wrong_len).is_err());
+        // an offset before the start
+        assert!(codec.decompress(&[8, 0, 0, 0, 0x04, 1, 0, 0x00]).is_err());
+        // claims more than the input can expand to
+        assert!(codec.decompress(&[0xff, 0xff, 0xff, 0xff, 0x00]).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+#[cfg(feature = "lz4")]
+mod lz4;
+#[cfg(feature = "lz4")]
+pub use lz4::*;
+
+/// Compresses the bodies of pushes on the way out and restores them on the way in
+///
+/// Both peers need the same codec. A body is only sent compressed if that makes it shorter.
+pub trait BodyCodec: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Undo `compress`; `Err` if `data` cannot have come from it
+    #[allow(clippy::result_unit_err)]
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ()>;
+}
+
+/// Leaves the bodies as they are, so none is ever sent compressed
+pub struct IdentityCodec;
+
+impl BodyCodec for IdentityCodec {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        data.to_vec()
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ()> {
+        Ok(data.to_vec())
+    }
+}
@@ -1,18 +1,109 @@
-use super::{DecodingError, EncodingError};
+use super::{varint, DecodingError, EncodingError};
 use crate::utils::{
     buf::{BufPasta, BufSlice, BufWtr},
-    Seq32,
+    Seq, Seq32,
 };
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::{io::Cursor, sync::Arc};
+use std::{
+    fmt,
+    io::{self, Cursor},
+    ops::Range,
+    sync::Arc,
+};
 
 pub const PUSH_HDR_LEN: usize = 9;
+/// Bytes of a push header in `WireFormat::V2`
+pub const COMPACT_PUSH_HDR_LEN: usize = 7;
 pub const ACK_HDR_LEN: usize = 5;
+pub const PING_LEN: usize = 9;
+pub const SYN_LEN: usize = 13;
+/// Bytes of a `Sack` frag before its ranges
+pub const SACK_HDR_LEN: usize = 6;
+pub const SACK_RANGE_LEN: usize = 6;
+pub const MAX_SACK_RANGES: usize = 8;
+/// Bytes of an `AckBitmap` frag in `WireFormat::V1`
+pub const ACK_BITMAP_LEN: usize = 13;
+/// Seqs an `AckBitmap` frag covers from its own on
+pub const ACK_BITMAP_SPAN: usize = 64;
+/// Bytes of a `Parity` header in `WireFormat::V1`
+pub const PARITY_HDR_LEN: usize = 14;
+/// Bytes of a `Window` frag in `WireFormat::V1`
+pub const WINDOW_LEN: usize = 7;
+/// Bytes of the stream ID following the `cmd` of a frag with `STREAM_FLAG`
+pub const STREAM_ID_LEN: usize = 2;
+/// Command bytes from here on are extensions, laid out like a push with a possibly empty body
+///
+/// Decoders that do not know an extension step over it.
+pub const EXTENSION_CMD_START: u8 = 0x80;
+/// Set in the `cmd` of a push whose body went through the `BodyCodec` of the sender
+pub const COMPRESSED_FLAG: u8 = 0x40;
+/// Set in the `cmd` of a frag of a `Mux` stream, whose ID follows the `cmd`
+pub const STREAM_FLAG: u8 = 0x20;
+
+/// How frags are laid out; both peers have to agree on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// `len` of a push is a u32
+    #[default]
+    V1,
+    /// `len` of a push is a u16 under the `CompactPush` command
+    V2,
+    /// `cmd` comes first; `seq`, relative to the nack of the packet, and `len` are varints
+    V3,
+}
+
+impl WireFormat {
+    /// The longest push header; most `V3` headers are shorter
+    #[must_use]
+    pub const fn push_hdr_len(&self) -> usize {
+        match self {
+            WireFormat::V1 => PUSH_HDR_LEN,
+            WireFormat::V2 => COMPACT_PUSH_HDR_LEN,
+            // `cmd`, a full `seq` and a `len` of at most 3 bytes
+            WireFormat::V3 => 1 + varint::MAX_VARINT_LEN + 3,
+        }
+    }
+
+    /// The longest parity header; `count` and `len_xor` come before the `len` of a push
+    #[must_use]
+    pub const fn parity_hdr_len(&self) -> usize {
+        self.push_hdr_len() + PARITY_HDR_LEN - PUSH_HDR_LEN
+    }
+
+    /// The longest body a push can carry
+    #[must_use]
+    pub const fn max_body_len(&self) -> usize {
+        match self {
+            WireFormat::V1 => u32::MAX as usize,
+            WireFormat::V2 | WireFormat::V3 => u16::MAX as usize,
+        }
+    }
+
+    /// The longest `Window` frag; most `V3` ones are shorter
+    #[must_use]
+    pub const fn window_len(&self) -> usize {
+        match self {
+            WireFormat::V1 | WireFormat::V2 => WINDOW_LEN,
+            WireFormat::V3 => 1 + varint::MAX_VARINT_LEN + 2,
+        }
+    }
+
+    /// Bytes a frag grows by at most in a packet of another nack
+    #[must_use]
+    pub const fn max_seq_growth(&self) -> usize {
+        match self {
+            WireFormat::V1 | WireFormat::V2 => 0,
+            WireFormat::V3 => varint::MAX_VARINT_LEN - 1,
+        }
+    }
+}
 
+#[derive(Debug, Clone)]
 pub struct Frag {
     seq: Seq32,
     cmd: FragCommand,
+    stream_id: Option<u16>,
 }
 
 pub struct FragBuilder {
@@ -22,14 +113,36 @@ pub struct FragBuilder {
 
 impl FragBuilder {
     pub fn build(self) -> Result<Frag, Error> {
-        if let FragCommand::Push { body } = &self.cmd {
+        if let FragCommand::Push { body } | FragCommand::CompressedPush { body } = &self.cmd {
             if body.is_empty() {
                 return Err(Error::EmptyBody);
             }
         }
+        if let FragCommand::Parity { count, body, .. } = &self.cmd {
+            if *count == 0 {
+                return Err(Error::ParityCount);
+            }
+            if body.is_empty() {
+                return Err(Error::EmptyBody);
+            }
+        }
+        if let FragCommand::AckBitmap { bitmap: 0 } = &self.cmd {
+            return Err(Error::EmptyBitmap);
+        }
+        if let FragCommand::Sack { ranges } = &self.cmd {
+            if ranges.is_empty() || MAX_SACK_RANGES < ranges.len() {
+                return Err(Error::SackRanges);
+            }
+            for range in ranges {
+                if range.end <= range.start || (u16::MAX as usize) < range.end.sub(&range.start) {
+                    return Err(Error::SackRanges);
+                }
+            }
+        }
         let this = Frag {
             seq: self.seq,
             cmd: self.cmd,
+            stream_id: None,
         };
         this.check_rep();
         Ok(this)
@@ -37,8 +150,72 @@ impl FragBuilder {
 }
 
 pub enum FragCommand {
-    Push { body: Body },
+    Push {
+        body: Body,
+    },
+    /// A push whose body the receiver decompresses with its `BodyCodec` before delivery
+    CompressedPush {
+        body: Body,
+    },
     Ack,
+    /// Acks the push of seq `seq + i` for every bit `i` set in `bitmap`, counted from the
+    /// least significant one
+    AckBitmap {
+        bitmap: u64,
+    },
+    /// The push of this seq will never be retransmitted; the receiver stops waiting for it
+    Skip,
+    /// The stream ends before this seq; sent and acked like a push without a body
+    Fin,
+    /// The sender has torn the stream down; the receiver drops what it has buffered
+    Rst,
+    /// Keeps the path alive; answered by a `Pong` carrying the same opaque token
+    Ping {
+        token: u32,
+    },
+    Pong {
+        token: u32,
+    },
+    /// Sent in a padded packet larger than the MTU; answered by a `ProbeAck` of the same ID
+    /// if the packet made it through
+    Probe {
+        id: u32,
+    },
+    ProbeAck {
+        id: u32,
+    },
+    /// The seqs of these ranges have been received; acks all the pushes they cover
+    Sack {
+        ranges: Vec<Range<Seq32>>,
+    },
+    /// Opens a session whose pushes start from this seq; the nonce tells the session apart
+    Syn {
+        nonce: u64,
+    },
+    /// Accepts the `Syn` of the same nonce; the pushes of the answering side start from this seq
+    SynAck {
+        nonce: u64,
+    },
+    /// `len` zero bytes the receiver throws away; makes packets look alike in size
+    ///
+    /// The seq means nothing.
+    Padding {
+        len: usize,
+    },
+    /// The XOR of the bodies of the `count` pushes from this seq on, each padded with zeros
+    ///
+    /// `len_xor` is the XOR of their lengths. Lets the receiver rebuild the one push of the
+    /// group lost on the way; never acked nor retransmitted.
+    Parity {
+        count: u8,
+        len_xor: u32,
+        body: BufSlice,
+    },
+    /// The rwnd of a `Mux` stream, and its nack as the seq, in place of those of the packet
+    /// header
+    Window {
+        rwnd: u16,
+    },
 }
 
 pub enum Body {
@@ -62,90 +239,483 @@ impl Body {
     }
 }
 
+impl Clone for Body {
+    fn clone(&self) -> Self {
+        match self {
+            Body::Slice(x) => Body::Slice(BufSlice::clone(x)),
+            Body::Pasta(x) => Body::Pasta(Arc::clone(x)),
+        }
+    }
+}
+
+/// Shows the length instead of the bytes
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Body::Slice(_) => "Slice",
+            Body::Pasta(_) => "Pasta",
+        };
+        f.debug_struct(name).field("len", &self.len()).finish()
+    }
+}
+
+impl Clone for FragCommand {
+    fn clone(&self) -> Self {
+        match self {
+            FragCommand::Push { body } => FragCommand::Push { body: body.clone() },
+            FragCommand::CompressedPush { body } => {
+                FragCommand::CompressedPush { body: body.clone() }
+            }
+            FragCommand::Ack => FragCommand::Ack,
+            FragCommand::AckBitmap { bitmap } => FragCommand::AckBitmap { bitmap: *bitmap },
+            FragCommand::Skip => FragCommand::Skip,
+            FragCommand::Fin => FragCommand::Fin,
+            FragCommand::Rst => FragCommand::Rst,
+            FragCommand::Ping { token } => FragCommand::Ping { token: *token },
+            FragCommand::Pong { token } => FragCommand::Pong { token: *token },
+            FragCommand::Probe { id } => FragCommand::Probe { id: *id },
+            FragCommand::ProbeAck { id } => FragCommand::ProbeAck { id: *id },
+            FragCommand::Sack { ranges } => FragCommand::Sack {
+                ranges: ranges.clone(),
+            },
+            FragCommand::Syn { nonce } => FragCommand::Syn { nonce: *nonce },
+            FragCommand::SynAck { nonce } => FragCommand::SynAck { nonce: *nonce },
+            FragCommand::Padding { len } => FragCommand::Padding { len: *len },
+            FragCommand::Parity {
+                count,
+                len_xor,
+                body,
+            } => FragCommand::Parity {
+                count: *count,
+                len_xor: *len_xor,
+                body: BufSlice::clone(body),
+            },
+            FragCommand::Window { rwnd } => FragCommand::Window { rwnd: *rwnd },
+        }
+    }
+}
+
+/// Shows the lengths of bodies instead of their bytes
+impl fmt::Debug for FragCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragCommand::Push { body } => f.debug_struct("Push").field("body", body).finish(),
+            FragCommand::CompressedPush { body } => f
+                .debug_struct("CompressedPush")
+                .field("body", body)
+                .finish(),
+            FragCommand::Ack => f.write_str("Ack"),
+            FragCommand::AckBitmap { bitmap } => f
+                .debug_struct("AckBitmap")
+                .field("bitmap", &format_args!("{bitmap:#b}"))
+                .finish(),
+            FragCommand::Skip => f.write_str("Skip"),
+            FragCommand::Fin => f.write_str("Fin"),
+            FragCommand::Rst => f.write_str("Rst"),
+            FragCommand::Ping { token } => f.debug_struct("Ping").field("token", token).finish(),
+            FragCommand::Pong { token } => f.debug_struct("Pong").field("token", token).finish(),
+            FragCommand::Probe { id } => f.debug_struct("Probe").field("id", id).finish(),
+            FragCommand::ProbeAck { id } => f.debug_struct("ProbeAck").field("id", id).finish(),
+            FragCommand::Sack { ranges } => f.debug_struct("Sack").field("ranges", ranges).finish(),
+            FragCommand::Syn { nonce } => f.debug_struct("Syn").field("nonce", nonce).finish(),
+            FragCommand::SynAck { nonce } => {
+                f.debug_struct("SynAck").field("nonce", nonce).finish()
+            }
+            FragCommand::Padding { len } => f.debug_struct("Padding").field("len", len).finish(),
+            FragCommand::Parity {
+                count,
+                len_xor,
+                body,
+            } => f
+                .debug_struct("Parity")
+                .field("count", count)
+                .field("len_xor", len_xor)
+                .field("body_len", &body.len())
+                .finish(),
+            FragCommand::Window { rwnd } => f.debug_struct("Window").field("rwnd", rwnd).finish(),
+        }
+    }
+}
+
 impl Frag {
     fn check_rep(&self) {
-        if let FragCommand::Push { body } = &self.cmd {
+        if let FragCommand::Push { body } | FragCommand::CompressedPush { body } = &self.cmd {
+            assert!(!body.is_empty());
+        }
+        if let FragCommand::Parity { count, body, .. } = &self.cmd {
+            assert!(*count != 0);
             assert!(!body.is_empty());
         }
     }
 
     pub fn from_slice(slice: &mut BufSlice) -> Result<Self, DecodingError> {
+        Self::from_slice_or_skip(slice)?.ok_or(DecodingError::Decoding {
+            field: "cmd",
+            offset: ACK_HDR_LEN - 1,
+        })
+    }
+
+    /// Like `from_slice` but steps over an extension frag, returning `None` for it
+    pub fn from_slice_or_skip(slice: &mut BufSlice) -> Result<Option<Self>, DecodingError> {
+        Self::from_slice_or_skip_with(slice, WireFormat::V1, Seq32::from_u32(0))
+    }
+
+    /// Like `from_slice_or_skip` but for frags laid out in `format`
+    ///
+    /// `nack` is the one of the packet header; `WireFormat::V3` encodes `seq` relative to it.
+    pub fn from_slice_or_skip_with(
+        slice: &mut BufSlice,
+        format: WireFormat,
+        nack: Seq32,
+    ) -> Result<Option<Self>, DecodingError> {
         let mut rdr = Cursor::new(slice.data());
-        let seq = rdr
-            .read_u32::<BigEndian>()
-            .map_err(|_e| DecodingError::Decoding { field: "seq" })?;
-        let seq = Seq32::from_u32(seq);
-        let cmd = rdr
-            .read_u8()
-            .map_err(|_e| DecodingError::Decoding { field: "cmd" })?;
-        let cmd =
-            CommandType::try_from(cmd).map_err(|_e| DecodingError::Decoding { field: "cmd" })?;
+        let cmd_at = match format {
+            WireFormat::V1 | WireFormat::V2 => ACK_HDR_LEN - 1,
+            WireFormat::V3 => 0,
+        };
+        let (seq, cmd, stream_id) = match format {
+            WireFormat::V1 | WireFormat::V2 => {
+                let seq = read_field(&mut rdr, "seq", |rdr| rdr.read_u32::<BigEndian>())?;
+                let cmd = read_field(&mut rdr, "cmd", |rdr| rdr.read_u8())?;
+                let stream_id = read_stream_id(&mut rdr, cmd)?;
+                (Seq32::from_u32(seq), cmd, stream_id)
+            }
+            WireFormat::V3 => {
+                let cmd = read_field(&mut rdr, "cmd", |rdr| rdr.read_u8())?;
+                let stream_id = read_stream_id(&mut rdr, cmd)?;
+                let delta = read_field(&mut rdr, "seq", varint::read)?;
+                let seq = nack.to_u32().wrapping_add(varint::unzigzag(delta) as u32);
+                (Seq32::from_u32(seq), cmd, stream_id)
+            }
+        };
+        if EXTENSION_CMD_START <= cmd {
+            let len_at = rdr.position() as usize;
+            let len = read_field(&mut rdr, "len", |rdr| match format {
+                WireFormat::V1 | WireFormat::V2 => rdr.read_u32::<BigEndian>(),
+                WireFormat::V3 => varint::read(rdr),
+            })? as usize;
+            let rdr_len = rdr.position() as usize;
+            check_len(slice, len_at, rdr_len, len)?;
+            slice.pop_front(rdr_len + len).unwrap();
+            return Ok(None);
+        }
+        let is_compressed = cmd & COMPRESSED_FLAG != 0;
+        let cmd = CommandType::try_from(cmd & !(COMPRESSED_FLAG | STREAM_FLAG)).map_err(|_e| {
+            DecodingError::Decoding {
+                field: "cmd",
+                offset: cmd_at,
+            }
+        })?;
+        // only pushes carry the flag
+        if is_compressed && !matches!(cmd, CommandType::Push | CommandType::CompactPush) {
+            return Err(DecodingError::Decoding {
+                field: "cmd",
+                offset: cmd_at,
+            });
+        }
         let cmd = match cmd {
-            CommandType::Push => {
-                let len = rdr
-                    .read_u32::<BigEndian>()
-                    .map_err(|_e| DecodingError::Decoding { field: "len" })?
-                    as usize;
+            CommandType::Push | CommandType::CompactPush => {
+                match (cmd, format) {
+                    (CommandType::Push, WireFormat::V1 | WireFormat::V3)
+                    | (CommandType::CompactPush, WireFormat::V2) => (),
+                    // the push of the other format
+                    _ => {
+                        return Err(DecodingError::Decoding {
+                            field: "cmd",
+                            offset: cmd_at,
+                        })
+                    }
+                };
+                let len_at = rdr.position() as usize;
+                let len = read_field(&mut rdr, "len", |rdr| read_len(rdr, format))? as usize;
                 if len == 0 {
-                    return Err(DecodingError::Decoding { field: "len" });
+                    return Err(DecodingError::Decoding {
+                        field: "len",
+                        offset: len_at,
+                    });
                 }
                 let rdr_len = rdr.position() as usize;
                 drop(rdr);
+                check_len(slice, len_at, rdr_len, len)?;
                 slice.pop_front(rdr_len).unwrap();
-                let body = slice
-                    .pop_front(len)
-                    .map_err(|_e| DecodingError::Decoding { field: "body" })?;
-                let body = Body::Slice(body);
-                FragCommand::Push { body }
+                let body = Body::Slice(slice.pop_front(len).unwrap());
+                match is_compressed {
+                    true => FragCommand::CompressedPush { body },
+                    false => FragCommand::Push { body },
+                }
             }
             CommandType::Ack => {
                 let rdr_len = rdr.position() as usize;
                 slice.pop_front(rdr_len).unwrap();
                 FragCommand::Ack
             }
+            CommandType::Skip => {
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                FragCommand::Skip
+            }
+            CommandType::Fin => {
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                FragCommand::Fin
+            }
+            CommandType::Rst => {
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                FragCommand::Rst
+            }
+            CommandType::Sack => {
+                let count_at = rdr.position() as usize;
+                let count = read_field(&mut rdr, "count", |rdr| rdr.read_u8())? as usize;
+                if count == 0 || MAX_SACK_RANGES < count {
+                    return Err(DecodingError::Decoding {
+                        field: "count",
+                        offset: count_at,
+                    });
+                }
+                let mut ranges = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let start = read_field(&mut rdr, "start", |rdr| rdr.read_u32::<BigEndian>())?;
+                    let len_at = rdr.position() as usize;
+                    let len = read_field(&mut rdr, "len", |rdr| rdr.read_u16::<BigEndian>())?;
+                    if len == 0 {
+                        return Err(DecodingError::Decoding {
+                            field: "len",
+                            offset: len_at,
+                        });
+                    }
+                    let start = Seq32::from_u32(start);
+                    ranges.push(start..start.add_usize(len as usize));
+                }
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                FragCommand::Sack { ranges }
+            }
+            CommandType::AckBitmap => {
+                let bitmap_at = rdr.position() as usize;
+                let bitmap = read_field(&mut rdr, "bitmap", |rdr| rdr.read_u64::<BigEndian>())?;
+                if bitmap == 0 {
+                    return Err(DecodingError::Decoding {
+                        field: "bitmap",
+                        offset: bitmap_at,
+                    });
+                }
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                FragCommand::AckBitmap { bitmap }
+            }
+            CommandType::Ping | CommandType::Pong => {
+                let token = read_field(&mut rdr, "token", |rdr| rdr.read_u32::<BigEndian>())?;
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                match cmd {
+                    CommandType::Ping => FragCommand::Ping { token },
+                    _ => FragCommand::Pong { token },
+                }
+            }
+            CommandType::Probe | CommandType::ProbeAck => {
+                let id = read_field(&mut rdr, "id", |rdr| rdr.read_u32::<BigEndian>())?;
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                match cmd {
+                    CommandType::Probe => FragCommand::Probe { id },
+                    _ => FragCommand::ProbeAck { id },
+                }
+            }
+            CommandType::Syn | CommandType::SynAck => {
+                let nonce = read_field(&mut rdr, "nonce", |rdr| rdr.read_u64::<BigEndian>())?;
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                match cmd {
+                    CommandType::Syn => FragCommand::Syn { nonce },
+                    _ => FragCommand::SynAck { nonce },
+                }
+            }
+            CommandType::Padding => {
+                let len_at = rdr.position() as usize;
+                let len = read_field(&mut rdr, "len", |rdr| read_len(rdr, format))? as usize;
+                let rdr_len = rdr.position() as usize;
+                check_len(slice, len_at, rdr_len, len)?;
+                slice.pop_front(rdr_len + len).unwrap();
+                FragCommand::Padding { len }
+            }
+            CommandType::Parity => {
+                let count_at = rdr.position() as usize;
+                let count = read_field(&mut rdr, "count", |rdr| rdr.read_u8())?;
+                if count == 0 {
+                    return Err(DecodingError::Decoding {
+                        field: "count",
+                        offset: count_at,
+                    });
+                }
+                let len_xor = read_field(&mut rdr, "len_xor", |rdr| rdr.read_u32::<BigEndian>())?;
+                let len_at = rdr.position() as usize;
+                let len = read_field(&mut rdr, "len", |rdr| read_len(rdr, format))? as usize;
+                if len == 0 {
+                    return Err(DecodingError::Decoding {
+                        field: "len",
+                        offset: len_at,
+                    });
+                }
+                let rdr_len = rdr.position() as usize;
+                check_len(slice, len_at, rdr_len, len)?;
+                slice.pop_front(rdr_len).unwrap();
+                let body = slice.pop_front(len).unwrap();
+                FragCommand::Parity {
+                    count,
+                    len_xor,
+                    body,
+                }
+            }
+            CommandType::Window => {
+                let rwnd = read_field(&mut rdr, "rwnd", |rdr| rdr.read_u16::<BigEndian>())?;
+                let rdr_len = rdr.position() as usize;
+                slice.pop_front(rdr_len).unwrap();
+                FragCommand::Window { rwnd }
+            }
         };
 
-        let this = Frag { seq, cmd };
+        let this = Frag {
+            seq,
+            cmd,
+            stream_id,
+        };
         this.check_rep();
-        Ok(this)
+        Ok(Some(this))
     }
 
     pub fn append_to(&self, wtr: &mut impl BufWtr) -> Result<(), EncodingError> {
-        let mut hdr = Vec::new();
-        hdr.write_u32::<BigEndian>(self.seq.to_u32()).unwrap();
-        let cmd = match self.cmd {
-            FragCommand::Push { body: _ } => CommandType::Push,
-            FragCommand::Ack => CommandType::Ack,
+        self.append_to_with(wtr, WireFormat::V1, Seq32::from_u32(0))
+    }
+
+    /// Like `append_to` but lays the frag out in `format`
+    ///
+    /// `nack` is the one of the packet header. A push with a body longer than `format`
+    /// allows is an error.
+    pub fn append_to_with(
+        &self,
+        wtr: &mut impl BufWtr,
+        format: WireFormat,
+        nack: Seq32,
+    ) -> Result<(), EncodingError> {
+        // nothing is written if the frag does not fit
+        if wtr.back_len() < self.len_with(format, nack) {
+            return Err(EncodingError::NotEnoughSpace);
+        }
+        let cmd = match (&self.cmd, format) {
+            (FragCommand::Push { body } | FragCommand::CompressedPush { body }, _)
+                if format.max_body_len() < body.len() =>
+            {
+                return Err(EncodingError::BodyTooLong);
+            }
+            (FragCommand::Padding { len }, _) if format.max_body_len() < *len => {
+                return Err(EncodingError::BodyTooLong);
+            }
+            (FragCommand::Parity { body, .. }, _) if format.max_body_len() < body.len() => {
+                return Err(EncodingError::BodyTooLong);
+            }
+            (FragCommand::Push { body: _ }, WireFormat::V1 | WireFormat::V3) => CommandType::Push,
+            (FragCommand::Push { body: _ }, WireFormat::V2) => CommandType::CompactPush,
+            (FragCommand::CompressedPush { body: _ }, WireFormat::V1 | WireFormat::V3) => {
+                CommandType::Push
+            }
+            (FragCommand::CompressedPush { body: _ }, WireFormat::V2) => CommandType::CompactPush,
+            (FragCommand::Ack, _) => CommandType::Ack,
+            (FragCommand::AckBitmap { bitmap: _ }, _) => CommandType::AckBitmap,
+            (FragCommand::Skip, _) => CommandType::Skip,
+            (FragCommand::Fin, _) => CommandType::Fin,
+            (FragCommand::Rst, _) => CommandType::Rst,
+            (FragCommand::Ping { token: _ }, _) => CommandType::Ping,
+            (FragCommand::Pong { token: _ }, _) => CommandType::Pong,
+            (FragCommand::Probe { id: _ }, _) => CommandType::Probe,
+            (FragCommand::ProbeAck { id: _ }, _) => CommandType::ProbeAck,
+            (FragCommand::Sack { ranges: _ }, _) => CommandType::Sack,
+            (FragCommand::Syn { nonce: _ }, _) => CommandType::Syn,
+            (FragCommand::SynAck { nonce: _ }, _) => CommandType::SynAck,
+            (FragCommand::Padding { len: _ }, _) => CommandType::Padding,
+            (FragCommand::Parity { .. }, _) => CommandType::Parity,
+            (FragCommand::Window { rwnd: _ }, _) => CommandType::Window,
+        };
+        let mut cmd = match &self.cmd {
+            FragCommand::CompressedPush { body: _ } => u8::from(cmd) | COMPRESSED_FLAG,
+            _ => cmd.into(),
         };
-        hdr.write_u8(cmd.into()).unwrap();
+        if self.stream_id.is_some() {
+            cmd |= STREAM_FLAG;
+        }
+        // the header goes straight into the free space
+        let hdr = wtr.back_free_space();
+        let prefix_len = match format {
+            WireFormat::V1 | WireFormat::V2 => {
+                BigEndian::write_u32(&mut hdr[0..4], self.seq.to_u32());
+                hdr[4] = cmd;
+                ACK_HDR_LEN + write_stream_id(&mut hdr[ACK_HDR_LEN..], self.stream_id)
+            }
+            WireFormat::V3 => {
+                hdr[0] = cmd;
+                let id_len = write_stream_id(&mut hdr[1..], self.stream_id);
+                1 + id_len + varint::write(&mut hdr[1 + id_len..], seq_delta(self.seq, nack))
+            }
+        };
+        // the fields after `seq` and `cmd`
+        let fields = &mut hdr[prefix_len..];
         match &self.cmd {
-            FragCommand::Push { body } => {
-                hdr.write_u32::<BigEndian>(body.len() as u32).unwrap();
-                assert_eq!(hdr.len(), PUSH_HDR_LEN);
+            FragCommand::Push { body } | FragCommand::CompressedPush { body } => {
+                let len_len = write_len(fields, format, body.len());
+                wtr.grow_back(prefix_len + len_len).unwrap();
                 match body {
-                    Body::Slice(body) => {
-                        wtr.append(&hdr)
-                            .map_err(|_| EncodingError::NotEnoughSpace)?;
-                        wtr.append(body.data())
-                            .map_err(|_| EncodingError::NotEnoughSpace)?;
-                    }
-                    Body::Pasta(body) => {
-                        wtr.append(&hdr)
-                            .map_err(|_| EncodingError::NotEnoughSpace)?;
-                        body.append_to(wtr)
-                            .map_err(|_| EncodingError::NotEnoughSpace)?;
-                    }
+                    Body::Slice(body) => wtr.append(body.data()).unwrap(),
+                    Body::Pasta(body) => body.append_to(wtr).unwrap(),
                 }
+                return Ok(());
+            }
+            FragCommand::Ack | FragCommand::Skip | FragCommand::Fin | FragCommand::Rst => (),
+            FragCommand::Ping { token } | FragCommand::Pong { token } => {
+                BigEndian::write_u32(&mut fields[..4], *token);
+            }
+            FragCommand::Probe { id } | FragCommand::ProbeAck { id } => {
+                BigEndian::write_u32(&mut fields[..4], *id);
+            }
+            FragCommand::Sack { ranges } => {
+                fields[0] = ranges.len() as u8;
+                for (i, range) in ranges.iter().enumerate() {
+                    let pos = SACK_HDR_LEN - ACK_HDR_LEN + SACK_RANGE_LEN * i;
+                    BigEndian::write_u32(&mut fields[pos..pos + 4], range.start.to_u32());
+                    let len = range.end.sub(&range.start) as u16;
+                    BigEndian::write_u16(&mut fields[pos + 4..pos + SACK_RANGE_LEN], len);
+                }
+            }
+            FragCommand::Syn { nonce } | FragCommand::SynAck { nonce } => {
+                BigEndian::write_u64(&mut fields[..8], *nonce);
+            }
+            FragCommand::AckBitmap { bitmap } => {
+                BigEndian::write_u64(&mut fields[..8], *bitmap);
+            }
+            FragCommand::Padding { len } => {
+                let len_len = write_len(fields, format, *len);
+                fields[len_len..len_len + len].fill(0);
             }
-            FragCommand::Ack => {
-                assert_eq!(hdr.len(), ACK_HDR_LEN);
-                wtr.append(&hdr)
-                    .map_err(|_| EncodingError::NotEnoughSpace)?;
+            FragCommand::Parity {
+                count,
+                len_xor,
+                body,
+            } => {
+                fields[0] = *count;
+                BigEndian::write_u32(&mut fields[1..5], *len_xor);
+                let len_len = write_len(&mut fields[5..], format, body.len());
+                wtr.grow_back(prefix_len + 5 + len_len).unwrap();
+                wtr.append(body.data()).unwrap();
+                return Ok(());
+            }
+            FragCommand::Window { rwnd } => {
+                BigEndian::write_u16(&mut fields[..2], *rwnd);
             }
         }
+        wtr.grow_back(self.len_with(format, nack)).unwrap();
         Ok(())
     }
 
+    /// The frag without its stream ID
     #[must_use]
     pub fn into_builder(self) -> FragBuilder {
         FragBuilder {
@@ -166,27 +736,192 @@ impl Frag {
         self.seq
     }
 
+    /// The `Mux` stream the frag belongs to, if sent on one
+    #[must_use]
+    #[inline]
+    pub fn stream_id(&self) -> Option<u16> {
+        self.stream_id
+    }
+
+    #[must_use]
+    pub fn with_stream_id(mut self, stream_id: Option<u16>) -> Self {
+        self.stream_id = stream_id;
+        self
+    }
+
+    /// Bytes of the frag encoded in `WireFormat::V1`, exactly what `append_to` writes
+    ///
+    /// `Packet::wire_len` sums `len_with` of its frags the same way.
     #[must_use]
     pub fn len(&self) -> usize {
-        match &self.cmd {
-            FragCommand::Push { body } => PUSH_HDR_LEN + body.len(),
-            FragCommand::Ack => ACK_HDR_LEN,
+        self.len_with(WireFormat::V1, Seq32::from_u32(0))
+    }
+
+    /// Bytes of the frag encoded in `format` in a packet with `nack`
+    #[must_use]
+    pub fn len_with(&self, format: WireFormat, nack: Seq32) -> usize {
+        let mut prefix_len = match format {
+            WireFormat::V1 | WireFormat::V2 => ACK_HDR_LEN,
+            WireFormat::V3 => 1 + varint::len(seq_delta(self.seq, nack)),
+        };
+        if self.stream_id.is_some() {
+            prefix_len += STREAM_ID_LEN;
+        }
+        let fields_len = match &self.cmd {
+            FragCommand::Push { body } | FragCommand::CompressedPush { body } => {
+                len_len(format, body.len()) + body.len()
+            }
+            FragCommand::Ack | FragCommand::Skip | FragCommand::Fin | FragCommand::Rst => 0,
+            FragCommand::Ping { token: _ }
+            | FragCommand::Pong { token: _ }
+            | FragCommand::Probe { id: _ }
+            | FragCommand::ProbeAck { id: _ } => PING_LEN - ACK_HDR_LEN,
+            FragCommand::AckBitmap { bitmap: _ } => ACK_BITMAP_LEN - ACK_HDR_LEN,
+            FragCommand::Sack { ranges } => {
+                SACK_HDR_LEN - ACK_HDR_LEN + SACK_RANGE_LEN * ranges.len()
+            }
+            FragCommand::Syn { nonce: _ } | FragCommand::SynAck { nonce: _ } => {
+                SYN_LEN - ACK_HDR_LEN
+            }
+            FragCommand::Padding { len } => len_len(format, *len) + len,
+            FragCommand::Parity { body, .. } => {
+                PARITY_HDR_LEN - PUSH_HDR_LEN + len_len(format, body.len()) + body.len()
+            }
+            FragCommand::Window { rwnd: _ } => WINDOW_LEN - ACK_HDR_LEN,
+        };
+        prefix_len + fields_len
+    }
+}
+
+/// Bytes of the `len` of a push, a padding or a parity in `format`
+#[inline]
+fn len_len(format: WireFormat, len: usize) -> usize {
+    match format {
+        WireFormat::V1 => 4,
+        WireFormat::V2 => 2,
+        WireFormat::V3 => varint::len(len as u32),
+    }
+}
+
+/// Write the `len` of a push, a padding or a parity; returns the bytes written
+fn write_len(buf: &mut [u8], format: WireFormat, len: usize) -> usize {
+    match format {
+        WireFormat::V1 => BigEndian::write_u32(&mut buf[..4], len as u32),
+        WireFormat::V2 => BigEndian::write_u16(&mut buf[..2], len as u16),
+        WireFormat::V3 => return varint::write(buf, len as u32),
+    }
+    len_len(format, len)
+}
+
+/// The stream ID following a `cmd` with `STREAM_FLAG`
+fn read_stream_id(rdr: &mut Cursor<&[u8]>, cmd: u8) -> Result<Option<u16>, DecodingError> {
+    // the flag means nothing in an extension
+    if EXTENSION_CMD_START <= cmd || cmd & STREAM_FLAG == 0 {
+        return Ok(None);
+    }
+    read_field(rdr, "stream_id", |rdr| rdr.read_u16::<BigEndian>()).map(Some)
+}
+
+/// Write the stream ID, if any; returns the bytes written
+fn write_stream_id(buf: &mut [u8], stream_id: Option<u16>) -> usize {
+    match stream_id {
+        Some(id) => {
+            BigEndian::write_u16(&mut buf[..STREAM_ID_LEN], id);
+            STREAM_ID_LEN
         }
+        None => 0,
     }
 }
 
+/// Read a field with `read`; a failure is reported at where the field starts
+fn read_field<'a, T>(
+    rdr: &mut Cursor<&'a [u8]>,
+    field: &'static str,
+    read: impl FnOnce(&mut Cursor<&'a [u8]>) -> io::Result<T>,
+) -> Result<T, DecodingError> {
+    let offset = rdr.position() as usize;
+    read(rdr).map_err(|_e| DecodingError::Decoding { field, offset })
+}
+
+/// Whether the `len` bytes after the `rdr_len` read so far are all there
+///
+/// A hostile `len` is turned away before anything is consumed.
+#[inline]
+fn check_len(
+    slice: &BufSlice,
+    len_at: usize,
+    rdr_len: usize,
+    len: usize,
+) -> Result<(), DecodingError> {
+    match rdr_len.checked_add(len) {
+        Some(end) if end <= slice.len() => Ok(()),
+        _ => Err(DecodingError::LenPastEnd { offset: len_at }),
+    }
+}
+
+fn read_len(rdr: &mut Cursor<&[u8]>, format: WireFormat) -> io::Result<u32> {
+    match format {
+        WireFormat::V1 => rdr.read_u32::<BigEndian>(),
+        WireFormat::V2 => rdr.read_u16::<BigEndian>().map(u32::from),
+        WireFormat::V3 => varint::read(rdr),
+    }
+}
+
+/// `seq` as `WireFormat::V3` puts it on the wire
+#[inline]
+fn seq_delta(seq: Seq32, nack: Seq32) -> u32 {
+    varint::zigzag(seq.to_u32().wrapping_sub(nack.to_u32()) as i32)
+}
+
 #[derive(IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub enum CommandType {
     Push,
     Ack,
+    Skip,
+    // 3 marks the packet header options
+    Fin = 4,
+    Rst,
+    Ping,
+    Pong,
+    Sack,
+    Syn,
+    SynAck,
+    /// A push of `WireFormat::V2`
+    CompactPush,
+    Padding,
+    Parity,
+    AckBitmap,
+    Probe,
+    ProbeAck,
+    Window,
+    // `EXTENSION_CMD_START` and above are extensions
 }
 
 #[derive(Debug)]
 pub enum Error {
     EmptyBody,
+    /// Between 1 and `MAX_SACK_RANGES` non-empty ranges of at most `u16::MAX` seqs
+    SackRanges,
+    /// A parity covers at least one push
+    ParityCount,
+    /// An ack bitmap acks at least one push
+    EmptyBitmap,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::EmptyBody => write!(f, "empty body"),
+            Error::SackRanges => write!(f, "invalid sack ranges"),
+            Error::ParityCount => write!(f, "parity covering no push"),
+            Error::EmptyBitmap => write!(f, "ack bitmap acking no push"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[cfg(test)]
 mod tests {
 
@@ -290,4 +1025,741 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_skip() {
+        let frag1 = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::Skip,
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 512);
+        frag1.append_to(&mut wtr).unwrap();
+        assert_eq!(frag1.len(), ACK_HDR_LEN);
+        assert_eq!(wtr.data(), vec![0, 0, 1, 89, 2]);
+        let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+        assert_eq!(frag1.seq, frag2.seq);
+        match frag2.cmd {
+            FragCommand::Skip => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_fin() {
+        let frag1 = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::Fin,
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 512);
+        frag1.append_to(&mut wtr).unwrap();
+        assert_eq!(frag1.len(), ACK_HDR_LEN);
+        assert_eq!(wtr.data(), vec![0, 0, 1, 89, 4]);
+        let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+        assert_eq!(frag1.seq, frag2.seq);
+        assert!(matches!(frag2.cmd, FragCommand::Fin));
+    }
+
+    #[test]
+    fn test_rst() {
+        let frag1 = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::Rst,
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 512);
+        frag1.append_to(&mut wtr).unwrap();
+        assert_eq!(frag1.len(), ACK_HDR_LEN);
+        assert_eq!(wtr.data(), vec![0, 0, 1, 89, 5]);
+        let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+        assert_eq!(frag1.seq, frag2.seq);
+        assert!(matches!(frag2.cmd, FragCommand::Rst));
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        for cmd in [
+            FragCommand::Ping { token: 7 },
+            FragCommand::Pong { token: 7 },
+        ] {
+            let frag1 = FragBuilder {
+                seq: Seq32::from_u32(345),
+                cmd,
+            }
+            .build()
+            .unwrap();
+            let mut wtr = OwnedBufWtr::new(1024, 512);
+            frag1.append_to(&mut wtr).unwrap();
+            assert_eq!(frag1.len(), wtr.data_len());
+            let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+            assert_eq!(frag1.seq, frag2.seq);
+            match (frag1.cmd, frag2.cmd) {
+                (FragCommand::Ping { token: a }, FragCommand::Ping { token: b }) => {
+                    assert_eq!(a, b)
+                }
+                (FragCommand::Pong { token: a }, FragCommand::Pong { token: b }) => {
+                    assert_eq!(a, b)
+                }
+                _ => panic!(),
+            }
+        }
+        // the token cut short
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 6, 0, 0]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_probe() {
+        for cmd in [
+            FragCommand::Probe { id: 7 },
+            FragCommand::ProbeAck { id: 7 },
+        ] {
+            let frag1 = FragBuilder {
+                seq: Seq32::from_u32(345),
+                cmd,
+            }
+            .build()
+            .unwrap();
+            let mut wtr = OwnedBufWtr::new(1024, 512);
+            frag1.append_to(&mut wtr).unwrap();
+            assert_eq!(frag1.len(), PING_LEN);
+            assert_eq!(frag1.len(), wtr.data_len());
+            let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+            match (frag1.cmd, frag2.cmd) {
+                (FragCommand::Probe { id: a }, FragCommand::Probe { id: b })
+                | (FragCommand::ProbeAck { id: a }, FragCommand::ProbeAck { id: b }) => {
+                    assert_eq!(a, b)
+                }
+                _ => panic!(),
+            }
+        }
+        // the id cut short
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 15, 0, 0]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_syn() {
+        for cmd in [
+            FragCommand::Syn { nonce: u64::MAX },
+            FragCommand::SynAck { nonce: 7 },
+        ] {
+            let frag1 = FragBuilder {
+                seq: Seq32::from_u32(345),
+                cmd,
+            }
+            .build()
+            .unwrap();
+            let mut wtr = OwnedBufWtr::new(1024, 512);
+            frag1.append_to(&mut wtr).unwrap();
+            assert_eq!(frag1.len(), wtr.data_len());
+            let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+            assert_eq!(frag1.seq, frag2.seq);
+            match (frag1.cmd, frag2.cmd) {
+                (FragCommand::Syn { nonce: a }, FragCommand::Syn { nonce: b }) => {
+                    assert_eq!(a, b)
+                }
+                (FragCommand::SynAck { nonce: a }, FragCommand::SynAck { nonce: b }) => {
+                    assert_eq!(a, b)
+                }
+                _ => panic!(),
+            }
+        }
+        // the nonce cut short
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 9, 0, 0, 0, 0]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_sack() {
+        let ranges = vec![
+            Seq32::from_u32(3)..Seq32::from_u32(5),
+            Seq32::from_u32(u32::MAX)..Seq32::from_u32(1),
+        ];
+        let frag1 = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::Sack {
+                ranges: ranges.clone(),
+            },
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 512);
+        frag1.append_to(&mut wtr).unwrap();
+        assert_eq!(frag1.len(), SACK_HDR_LEN + SACK_RANGE_LEN * 2);
+        assert_eq!(frag1.len(), wtr.data_len());
+        let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+        assert_eq!(frag1.seq, frag2.seq);
+        match frag2.cmd {
+            FragCommand::Sack { ranges: x } => assert_eq!(x, ranges),
+            _ => panic!(),
+        }
+
+        for ranges in [
+            vec![],
+            vec![Seq32::from_u32(3)..Seq32::from_u32(3)],
+            vec![Seq32::from_u32(0)..Seq32::from_u32(1); MAX_SACK_RANGES + 1],
+        ] {
+            let frag = FragBuilder {
+                seq: Seq32::from_u32(0),
+                cmd: FragCommand::Sack { ranges },
+            }
+            .build();
+            assert!(matches!(frag, Err(Error::SackRanges)));
+        }
+    }
+
+    #[test]
+    fn test_ack_bitmap() {
+        let frag1 = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::AckBitmap {
+                bitmap: 1 << 63 | 0b101,
+            },
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 512);
+        frag1.append_to(&mut wtr).unwrap();
+        assert_eq!(frag1.len(), ACK_BITMAP_LEN);
+        assert_eq!(
+            wtr.data(),
+            vec![0, 0, 1, 89, 14, 0x80, 0, 0, 0, 0, 0, 0, 0b101]
+        );
+        let frag2 = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+        assert_eq!(frag1.seq, frag2.seq);
+        assert!(matches!(
+            frag2.cmd,
+            FragCommand::AckBitmap {
+                bitmap: 0x8000_0000_0000_0005
+            }
+        ));
+
+        // acks nothing
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::AckBitmap { bitmap: 0 },
+        }
+        .build();
+        assert!(matches!(frag, Err(Error::EmptyBitmap)));
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 1, 89, 14, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+        // the bitmap cut short
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 1, 89, 14, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_padding() {
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(1),
+            cmd: FragCommand::Padding { len: 3 },
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        // whatever the buffer held before
+        wtr.back_free_space().fill(0xff);
+        frag.append_to(&mut wtr).unwrap();
+        assert_eq!(wtr.data(), [0, 0, 0, 1, 12, 0, 0, 0, 3, 0, 0, 0]);
+        assert_eq!(frag.len(), wtr.data_len());
+        wtr.append(&[9]).unwrap();
+        let mut slice = wtr.into_slice();
+        let frag = Frag::from_slice(&mut slice).unwrap();
+        assert!(matches!(frag.cmd(), FragCommand::Padding { len: 3 }));
+        assert_eq!(slice.data(), [9]);
+
+        // empty
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 12, 0, 0, 0, 0]);
+        let frag = Frag::from_slice(&mut slice).unwrap();
+        assert!(matches!(frag.cmd(), FragCommand::Padding { len: 0 }));
+        // the zeros run past the end
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 12, 0, 0, 0, 3, 0, 0]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_compressed_push() {
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(1),
+            cmd: FragCommand::CompressedPush {
+                body: Body::Slice(BufSlice::from_bytes(vec![0xaa])),
+            },
+        }
+        .build()
+        .unwrap();
+        let v1 = vec![0, 0, 0, 1, 0x40, 0, 0, 0, 1, 0xaa];
+        let v2 = vec![0, 0, 0, 1, 0x4b, 0, 1, 0xaa];
+        let v3 = vec![0x40, 2, 1, 0xaa];
+        for (format, bytes) in [
+            (WireFormat::V1, v1),
+            (WireFormat::V2, v2),
+            (WireFormat::V3, v3),
+        ] {
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            frag.append_to_with(&mut wtr, format, Seq32::from_u32(0))
+                .unwrap();
+            assert_eq!(wtr.data(), &bytes[..]);
+            assert_eq!(frag.len_with(format, Seq32::from_u32(0)), bytes.len());
+            let mut slice = wtr.into_slice();
+            let decoded = Frag::from_slice_or_skip_with(&mut slice, format, Seq32::from_u32(0))
+                .unwrap()
+                .unwrap();
+            match decoded.cmd() {
+                FragCommand::CompressedPush {
+                    body: Body::Slice(body),
+                } => assert_eq!(body.data(), [0xaa]),
+                _ => panic!(),
+            }
+        }
+
+        // only pushes carry the flag
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 0x41]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(1),
+            cmd: FragCommand::CompressedPush {
+                body: Body::Slice(BufSlice::from_bytes(vec![])),
+            },
+        }
+        .build();
+        assert!(matches!(frag, Err(Error::EmptyBody)));
+    }
+
+    #[test]
+    fn test_parity() {
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(1),
+            cmd: FragCommand::Parity {
+                count: 2,
+                len_xor: 3,
+                body: BufSlice::from_bytes(vec![0xaa, 0xbb]),
+            },
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        frag.append_to(&mut wtr).unwrap();
+        assert_eq!(
+            wtr.data(),
+            [0, 0, 0, 1, 13, 2, 0, 0, 0, 3, 0, 0, 0, 2, 0xaa, 0xbb]
+        );
+        assert_eq!(frag.len(), PARITY_HDR_LEN + 2);
+        let frag = Frag::from_slice(&mut wtr.into_slice()).unwrap();
+        match frag.cmd() {
+            FragCommand::Parity {
+                count: 2,
+                len_xor: 3,
+                body,
+            } => assert_eq!(body.data(), [0xaa, 0xbb]),
+            _ => panic!(),
+        }
+
+        // no pushes covered
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(1),
+            cmd: FragCommand::Parity {
+                count: 0,
+                len_xor: 0,
+                body: BufSlice::from_bytes(vec![0xaa]),
+            },
+        }
+        .build();
+        assert!(matches!(frag, Err(Error::ParityCount)));
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 13, 0, 0, 0, 0, 3, 0, 0, 0, 1, 1]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+        // the body runs past the end
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 13, 2, 0, 0, 0, 3, 0, 0, 0, 2, 1]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_stream_id() {
+        let window = FragBuilder {
+            seq: Seq32::from_u32(345),
+            cmd: FragCommand::Window { rwnd: 7 },
+        }
+        .build()
+        .unwrap()
+        .with_stream_id(Some(0x0102));
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        window.append_to(&mut wtr).unwrap();
+        assert_eq!(window.len(), WINDOW_LEN + STREAM_ID_LEN);
+        assert_eq!(wtr.data(), vec![0, 0, 1, 89, 17 | STREAM_FLAG, 1, 2, 0, 7]);
+
+        let push = FragBuilder {
+            seq: Seq32::from_u32(u32::MAX),
+            cmd: FragCommand::CompressedPush {
+                body: Body::Slice(BufSlice::from_bytes(vec![0xaa, 0xbb])),
+            },
+        }
+        .build()
+        .unwrap()
+        .with_stream_id(Some(u16::MAX));
+        for format in [WireFormat::V1, WireFormat::V2, WireFormat::V3] {
+            for frag in [&window, &push] {
+                let nack = frag.seq();
+                let mut wtr = OwnedBufWtr::new(1024, 0);
+                frag.append_to_with(&mut wtr, format, nack).unwrap();
+                assert_eq!(frag.len_with(format, nack), wtr.data_len());
+                let mut slice = wtr.into_slice();
+                let decoded = Frag::from_slice_or_skip_with(&mut slice, format, nack)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(decoded.seq(), frag.seq());
+                assert_eq!(decoded.stream_id(), frag.stream_id());
+                assert_eq!(format!("{:?}", decoded.cmd()), format!("{:?}", frag.cmd()));
+                assert!(slice.is_empty());
+
+                // in a packet of any other nack
+                let far = Seq32::from_u32(frag.seq().to_u32().wrapping_add(1 << 31));
+                let growth = frag.len_with(format, far) - frag.len_with(format, nack);
+                assert!(growth <= format.max_seq_growth());
+            }
+            assert!(
+                window.len_with(format, Seq32::from_u32(0)) <= format.window_len() + STREAM_ID_LEN
+            );
+        }
+
+        // dropped with the builder
+        assert_eq!(
+            window.clone().into_builder().build().unwrap().stream_id(),
+            None
+        );
+        // the stream ID cut short
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 1 | STREAM_FLAG, 0]);
+        assert!(Frag::from_slice(&mut slice).is_err());
+        // part of the command of an extension
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 0x80 | STREAM_FLAG, 0, 0, 0, 0]);
+        assert!(Frag::from_slice_or_skip(&mut slice).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_skip_extension() {
+        let mut slice =
+            BufSlice::from_bytes(vec![0, 0, 0, 1, 0x80, 0, 0, 0, 2, 7, 7, 0, 0, 0, 2, 1]);
+        assert!(Frag::from_slice_or_skip(&mut slice).unwrap().is_none());
+        let frag = Frag::from_slice_or_skip(&mut slice).unwrap().unwrap();
+        assert_eq!(frag.seq().to_u32(), 2);
+        assert!(matches!(frag.cmd(), FragCommand::Ack));
+        assert!(slice.is_empty());
+
+        // the length runs past the end
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 0xff, 0, 0, 0, 2, 7]);
+        assert!(Frag::from_slice_or_skip(&mut slice).is_err());
+
+        // unknown but not an extension
+        let mut slice = BufSlice::from_bytes(vec![0, 0, 0, 1, 18]);
+        assert!(Frag::from_slice_or_skip(&mut slice).is_err());
+    }
+
+    #[test]
+    fn test_wire_formats() {
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(0x01020304),
+            cmd: FragCommand::Push {
+                body: Body::Slice(BufSlice::from_bytes(vec![0xaa, 0xbb])),
+            },
+        }
+        .build()
+        .unwrap();
+        let v1 = vec![1, 2, 3, 4, 0, 0, 0, 0, 2, 0xaa, 0xbb];
+        let v2 = vec![1, 2, 3, 4, 11, 0, 2, 0xaa, 0xbb];
+        for (format, bytes, hdr_len) in [
+            (WireFormat::V1, &v1, PUSH_HDR_LEN),
+            (WireFormat::V2, &v2, COMPACT_PUSH_HDR_LEN),
+        ] {
+            assert_eq!(format.push_hdr_len(), hdr_len);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            frag.append_to_with(&mut wtr, format, Seq32::from_u32(0))
+                .unwrap();
+            assert_eq!(wtr.data(), &bytes[..]);
+            assert_eq!(frag.len_with(format, Seq32::from_u32(0)), hdr_len + 2);
+
+            let mut slice = BufSlice::from_bytes(bytes.clone());
+            let decoded = Frag::from_slice_or_skip_with(&mut slice, format, Seq32::from_u32(0))
+                .unwrap()
+                .unwrap();
+            assert_eq!(decoded.seq(), frag.seq());
+            match decoded.cmd() {
+                FragCommand::Push {
+                    body: Body::Slice(body),
+                } => {
+                    assert_eq!(body.data(), [0xaa, 0xbb])
+                }
+                _ => panic!(),
+            }
+            assert!(slice.is_empty());
+        }
+
+        // a push of the other format is rejected
+        let mut slice = BufSlice::from_bytes(v1.clone());
+        assert!(
+            Frag::from_slice_or_skip_with(&mut slice, WireFormat::V2, Seq32::from_u32(0)).is_err()
+        );
+        let mut slice = BufSlice::from_bytes(v2.clone());
+        assert!(
+            Frag::from_slice_or_skip_with(&mut slice, WireFormat::V1, Seq32::from_u32(0)).is_err()
+        );
+        let mut slice = BufSlice::from_bytes(v2);
+        assert!(Frag::from_slice(&mut slice).is_err());
+
+        // the other frags are laid out the same
+        let ack = FragBuilder {
+            seq: Seq32::from_u32(7),
+            cmd: FragCommand::Ack,
+        }
+        .build()
+        .unwrap();
+        let mut wtr1 = OwnedBufWtr::new(1024, 0);
+        ack.append_to_with(&mut wtr1, WireFormat::V1, Seq32::from_u32(0))
+            .unwrap();
+        let mut wtr2 = OwnedBufWtr::new(1024, 0);
+        ack.append_to_with(&mut wtr2, WireFormat::V2, Seq32::from_u32(0))
+            .unwrap();
+        assert_eq!(wtr1.data(), wtr2.data());
+
+        let long = FragBuilder {
+            seq: Seq32::from_u32(0),
+            cmd: FragCommand::Push {
+                body: Body::Slice(BufSlice::from_bytes(vec![0; u16::MAX as usize + 1])),
+            },
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(u16::MAX as usize * 2, 0);
+        assert!(matches!(
+            long.append_to_with(&mut wtr, WireFormat::V2, Seq32::from_u32(0)),
+            Err(EncodingError::BodyTooLong)
+        ));
+        long.append_to_with(&mut wtr, WireFormat::V1, Seq32::from_u32(0))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        // xorshift64
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let nack = Seq32::from_u32(next() as u32);
+            // mostly near the nack, sometimes anywhere
+            let seq = match next() % 4 {
+                0 => Seq32::from_u32(next() as u32),
+                _ => Seq32::from_u32(
+                    nack.to_u32()
+                        .wrapping_add((next() % 512) as u32)
+                        .wrapping_sub(256),
+                ),
+            };
+            let body_len = 1 + (next() % u16::MAX as u64) as usize;
+            let start = Seq32::from_u32(next() as u32);
+            let cmds = [
+                FragCommand::Push {
+                    body: Body::Slice(BufSlice::from_bytes(vec![7; body_len])),
+                },
+                FragCommand::Ack,
+                FragCommand::Fin,
+                FragCommand::Ping {
+                    token: next() as u32,
+                },
+                FragCommand::Sack {
+                    ranges: vec![start..start.add_usize(1 + (next() % 100) as usize)],
+                },
+                FragCommand::Syn { nonce: next() },
+                FragCommand::AckBitmap { bitmap: next() | 1 },
+                FragCommand::Padding {
+                    len: (next() % 300) as usize,
+                },
+                FragCommand::Parity {
+                    count: 1 + (next() % 255) as u8,
+                    len_xor: next() as u32,
+                    body: BufSlice::from_bytes(vec![7; body_len]),
+                },
+            ];
+            for cmd in cmds {
+                let frag = FragBuilder { seq, cmd }.build().unwrap();
+                for format in [WireFormat::V1, WireFormat::V2, WireFormat::V3] {
+                    let mut wtr = OwnedBufWtr::new(u16::MAX as usize + 64, 0);
+                    frag.append_to_with(&mut wtr, format, nack).unwrap();
+                    assert_eq!(wtr.data_len(), frag.len_with(format, nack));
+                    let mut slice = wtr.into_slice();
+                    let decoded = Frag::from_slice_or_skip_with(&mut slice, format, nack)
+                        .unwrap()
+                        .unwrap();
+                    assert!(slice.is_empty());
+                    assert_eq!(decoded.seq(), seq);
+                    match (frag.cmd(), decoded.cmd()) {
+                        (FragCommand::Push { body: a }, FragCommand::Push { body: b }) => {
+                            assert_eq!(a.len(), b.len())
+                        }
+                        (FragCommand::Ack, FragCommand::Ack) => (),
+                        (FragCommand::Fin, FragCommand::Fin) => (),
+                        (FragCommand::Ping { token: a }, FragCommand::Ping { token: b }) => {
+                            assert_eq!(a, b)
+                        }
+                        (FragCommand::Sack { ranges: a }, FragCommand::Sack { ranges: b }) => {
+                            assert_eq!(a, b)
+                        }
+                        (FragCommand::Syn { nonce: a }, FragCommand::Syn { nonce: b }) => {
+                            assert_eq!(a, b)
+                        }
+                        (
+                            FragCommand::AckBitmap { bitmap: a },
+                            FragCommand::AckBitmap { bitmap: b },
+                        ) => assert_eq!(a, b),
+                        (FragCommand::Padding { len: a }, FragCommand::Padding { len: b }) => {
+                            assert_eq!(a, b)
+                        }
+                        (
+                            FragCommand::Parity {
+                                count: a,
+                                len_xor: c,
+                                body: e,
+                            },
+                            FragCommand::Parity {
+                                count: b,
+                                len_xor: d,
+                                body: f,
+                            },
+                        ) => {
+                            assert_eq!((a, c, e.data()), (b, d, f.data()))
+                        }
+                        _ => panic!(),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_varint_hdr_len() {
+        let nack = Seq32::from_u32(1000);
+        let push = |seq: u32, body_len: usize| {
+            FragBuilder {
+                seq: Seq32::from_u32(seq),
+                cmd: FragCommand::Push {
+                    body: Body::Slice(BufSlice::from_bytes(vec![0xaa; body_len])),
+                },
+            }
+            .build()
+            .unwrap()
+        };
+
+        // golden bytes: cmd, zigzag(3) and len
+        let mut wtr = OwnedBufWtr::new(16, 0);
+        push(1003, 1)
+            .append_to_with(&mut wtr, WireFormat::V3, nack)
+            .unwrap();
+        assert_eq!(wtr.data(), [0, 6, 1, 0xaa]);
+        // behind the nack
+        let mut wtr = OwnedBufWtr::new(16, 0);
+        push(999, 1)
+            .append_to_with(&mut wtr, WireFormat::V3, nack)
+            .unwrap();
+        assert_eq!(wtr.data(), [0, 1, 1, 0xaa]);
+
+        // a small delta and a short body take 3 header bytes instead of 9
+        let hdr_len = |frag: &Frag, format| frag.len_with(format, nack) - 1;
+        assert_eq!(hdr_len(&push(1003, 1), WireFormat::V1), PUSH_HDR_LEN);
+        assert_eq!(
+            hdr_len(&push(1003, 1), WireFormat::V2),
+            COMPACT_PUSH_HDR_LEN
+        );
+        assert_eq!(hdr_len(&push(1003, 1), WireFormat::V3), 3);
+        for delta in [0, 1, 10, 63] {
+            let frag = push(1000 + delta, 1);
+            assert!(hdr_len(&frag, WireFormat::V3) < hdr_len(&frag, WireFormat::V2));
+        }
+        let ack = FragBuilder {
+            seq: Seq32::from_u32(1001),
+            cmd: FragCommand::Ack,
+        }
+        .build()
+        .unwrap();
+        assert_eq!(ack.len_with(WireFormat::V3, nack), 2);
+        // never longer than the bound the uploader plans with
+        let far = push(1000 + (1 << 30), u16::MAX as usize);
+        assert!(
+            far.len_with(WireFormat::V3, nack) - u16::MAX as usize <= WireFormat::V3.push_hdr_len()
+        );
+    }
+
+    #[test]
+    fn test_truncated_push_offsets() {
+        let frag = FragBuilder {
+            seq: Seq32::from_u32(1003),
+            cmd: FragCommand::Push {
+                body: Body::Slice(BufSlice::from_bytes(vec![0xaa; 5])),
+            },
+        }
+        .build()
+        .unwrap();
+        let nack = Seq32::from_u32(1000);
+        // V1: seq, cmd and a 4-byte len; V3: cmd, a varint seq and a varint len
+        for (format, cases, (body_cut, len_at)) in [
+            (
+                WireFormat::V1,
+                [(3, "seq", 0), (4, "cmd", 4), (7, "len", 5)],
+                (12, 5),
+            ),
+            (
+                WireFormat::V3,
+                [(0, "cmd", 0), (1, "seq", 1), (2, "len", 2)],
+                (5, 2),
+            ),
+        ] {
+            let mut wtr = OwnedBufWtr::new(64, 0);
+            frag.append_to_with(&mut wtr, format, nack).unwrap();
+            for (len, field, offset) in cases {
+                let mut slice = BufSlice::from_bytes(wtr.data()[..len].to_vec());
+                let e = match Frag::from_slice_or_skip_with(&mut slice, format, nack) {
+                    Err(e) => e,
+                    Ok(_) => panic!(),
+                };
+                assert!(matches!(
+                    e,
+                    DecodingError::Decoding { field: f, offset: o } if (f, o) == (field, offset)
+                ));
+                assert_eq!(e.to_string(), format!("invalid `{field}` at byte {offset}"));
+            }
+
+            // the body cut short is blamed on the len, and nothing is consumed
+            let mut slice = BufSlice::from_bytes(wtr.data()[..body_cut].to_vec());
+            match Frag::from_slice_or_skip_with(&mut slice, format, nack) {
+                Err(DecodingError::LenPastEnd { offset }) => assert_eq!(offset, len_at),
+                _ => panic!(),
+            }
+            assert_eq!(slice.len(), body_cut);
+        }
+    }
+
+    #[test]
+    fn test_hostile_len() {
+        // a push, a padding and an extension each claiming u32::MAX bytes
+        for cmd in [
+            CommandType::Push as u8,
+            CommandType::Padding as u8,
+            EXTENSION_CMD_START,
+        ] {
+            let mut bytes = vec![0, 0, 0, 1, cmd];
+            bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+            bytes.extend_from_slice(&[0xaa; 16]);
+            let mut slice = BufSlice::from_bytes(bytes);
+            match Frag::from_slice_or_skip(&mut slice) {
+                Err(DecodingError::LenPastEnd { offset }) => assert_eq!(offset, ACK_HDR_LEN),
+                _ => panic!(),
+            }
+            assert_eq!(slice.len(), ACK_HDR_LEN + 4 + 16);
+        }
+    }
 }
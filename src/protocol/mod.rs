@@ -1,12 +1,41 @@
 //! # Packet header
 //!
 //! ```text
-//! 0       2               6         (BYTE)
-//! +-------+---------------+
-//! | rwnd  |     nack      |
-//! +-------+---------------+
+//! 0   1       3               7     (BYTE)
+//! +---+-------+---------------+
+//! |ver| rwnd  |     nack      |
+//! +---+-------+---------------+
 //! ```
 //!
+//! The low 4 bits of `ver` are `PROTOCOL_VERSION`; a header of any other version is rejected.
+//! The high 4 bits flag the options: bit 4 the timestamp, bit 5 the echo, bit 6 the connection
+//! ID and bit 7 the packet seq. They follow in that order, the timestamp and the echo as a pair
+//! if either is flagged:
+//!
+//! ```text
+//! 0               4               8 (BYTE)
+//! +---------------+---------------+
+//! |   timestamp   |timestamp echo |
+//! +---------------+---------------+
+//! |            conn_id            |
+//! +---------------+---------------+
+//! |  packet seq   |
+//! +---------------+
+//! ```
+//!
+//! A peer predating `ver` sends the header without it, which only the legacy decoders take.
+//! Its options are all behind the fixed fields, timestamps or not:
+//!
+//! ```text
+//! 0               4   5   6               10 (BYTE)
+//! +---------------+---+---+---------------+
+//! |   timestamp   | 3 |flg|timestamp echo |
+//! +---------------+---+---+---------------+
+//! ```
+//!
+//! The `3` sits where the `cmd` of a first fragment would be, a value no fragment uses.
+//! `flg` holds the flags of `ver` in its low 4 bits.
+//!
 //! # Fragment
 //!
 //! ```text
@@ -30,22 +59,115 @@
 //! (Fragment header of type Ack)*
 //! ((Fragment header of type Push) (Body))*
 //! ((Fragment header of type Push) (Body))*
+//! (Tag)?
 //! ```
 //!
+//! The tag is there if both peers are configured with a `PacketAuth`. A `Padding` fragment,
+//! laid out like a push of zeros, may come last before it.
+//!
+//! A `Parity` fragment has the seq of the first push it covers, then a 1-byte `count`, the
+//! 4-byte XOR of the lengths and the XOR of the bodies, laid out like a push.
+//!
+//! An `AckBitmap` fragment is followed by an 8-byte bitmap whose bit `i`, counted from the
+//! least significant one, acks the push of `seq + i`.
+//!
+//! `Probe` and `ProbeAck` fragments are laid out like a `Ping`, with the probe ID in place of
+//! the token.
+//!
+//! Bit `0x40` of the `cmd` of a push marks its body as compressed by the `BodyCodec` of the
+//! sender.
+//!
+//! Bit `0x20` of the `cmd` of a fragment marks it as one of a stream of a `Mux`, whose 2-byte
+//! ID follows the `cmd`, before the `seq` in `WireFormat::V3`. A `Window` fragment is laid out
+//! like a `Ping` with a 2-byte `rwnd` in place of the token; its seq is the nack of the stream.
+//!
+//! In `WireFormat::V2` a push has the cmd `CompactPush` and a 2-byte `len`.
+//!
+//! In `WireFormat::V3` a fragment starts with `cmd`, followed by `seq` minus the `nack` of the
+//! packet, zigzagged, and the `len` of a push or an extension, both as LEB128 varints. The
+//! legacy options start with the `3` there, then `flg`, the timestamp and the echo.
+//!
 //! # Invariants
 //!
 //! - `len` (`Push`) should not be `0`
 
+pub mod auth;
+pub mod codec;
 pub mod frag;
 pub mod packet;
 pub mod packet_hdr;
+pub mod transform;
+mod varint;
+
+use std::fmt;
 
 #[derive(Debug)]
 pub enum DecodingError {
-    Decoding { field: &'static str },
+    /// `field`, starting `offset` bytes into the datagram, is cut short or invalid
+    Decoding { field: &'static str, offset: usize },
+    /// The `len` at `offset` covers more bytes than the datagram has left
+    LenPastEnd { offset: usize },
+    /// The frag at `offset` is one more than a packet may carry
+    TooManyFrags { offset: usize },
+    /// The header is of a protocol version this side does not speak
+    UnsupportedVersion { version: u8 },
+}
+
+impl DecodingError {
+    /// The same error `len` bytes further into the datagram
+    #[must_use]
+    pub(crate) fn offset_by(self, len: usize) -> Self {
+        match self {
+            DecodingError::Decoding { field, offset } => DecodingError::Decoding {
+                field,
+                offset: offset + len,
+            },
+            DecodingError::LenPastEnd { offset } => DecodingError::LenPastEnd {
+                offset: offset + len,
+            },
+            DecodingError::TooManyFrags { offset } => DecodingError::TooManyFrags {
+                offset: offset + len,
+            },
+            DecodingError::UnsupportedVersion { .. } => self,
+        }
+    }
+}
+
+impl fmt::Display for DecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodingError::Decoding { field, offset } => {
+                write!(f, "invalid `{field}` at byte {offset}")
+            }
+            DecodingError::LenPastEnd { offset } => {
+                write!(f, "`len` at byte {offset} past the end of the datagram")
+            }
+            DecodingError::TooManyFrags { offset } => {
+                write!(f, "frag at byte {offset} over the limit of the packet")
+            }
+            DecodingError::UnsupportedVersion { version } => {
+                write!(f, "unsupported protocol version {version}")
+            }
+        }
+    }
 }
 
+impl std::error::Error for DecodingError {}
+
 #[derive(Debug)]
 pub enum EncodingError {
     NotEnoughSpace,
+    /// The body of a push is too long for the wire format
+    BodyTooLong,
 }
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodingError::NotEnoughSpace => write!(f, "not enough space in the buffer"),
+            EncodingError::BodyTooLong => write!(f, "push body too long for the wire format"),
+        }
+    }
+}
+
+impl std::error::Error for EncodingError {}
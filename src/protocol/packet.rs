@@ -1,9 +1,20 @@
-use super::{frag::Frag, packet_hdr::PacketHeader, DecodingError, EncodingError};
+use super::{
+    auth::{PacketAuth, MAX_TAG_LEN},
+    frag::{Frag, FragCommand, WireFormat},
+    packet_hdr::PacketHeader,
+    DecodingError, EncodingError,
+};
 use crate::utils::buf::{BufSlice, BufWtr};
+use std::{fmt, sync::Arc};
 
+#[derive(Clone)]
 pub struct Packet {
     hdr: PacketHeader,
     frags: Vec<Frag>,
+    unknown_frags: usize,
+    // signs the packet on `append_to`
+    auth: Option<Arc<dyn PacketAuth>>,
+    format: WireFormat,
 }
 
 pub struct PacketBuilder {
@@ -16,6 +27,9 @@ impl PacketBuilder {
         let this = Packet {
             hdr: self.hdr,
             frags: self.frags,
+            unknown_frags: 0,
+            auth: None,
+            format: WireFormat::V1,
         };
         this.check_rep();
         Ok(this)
@@ -26,26 +40,111 @@ impl Packet {
     fn check_rep(&self) {}
 
     pub fn from_slice(slice: &mut BufSlice) -> Result<Self, DecodingError> {
-        let hdr = PacketHeader::from_slice(slice)?;
+        Self::from_slice_with(slice, WireFormat::V1)
+    }
+
+    /// Like `from_slice` but takes only the pushes of `format`
+    pub fn from_slice_with(
+        slice: &mut BufSlice,
+        format: WireFormat,
+    ) -> Result<Self, DecodingError> {
+        Self::from_slice_limited(slice, format, usize::MAX)
+    }
+
+    /// Like `from_slice_with` but gives up on a packet of more than `max_frags` frags, unknown
+    /// ones included
+    pub fn from_slice_limited(
+        slice: &mut BufSlice,
+        format: WireFormat,
+        max_frags: usize,
+    ) -> Result<Self, DecodingError> {
+        let hdr = PacketHeader::from_slice_with(slice, format)?;
+        Self::from_frags_slice(hdr, slice, format, max_frags)
+    }
+
+    /// Like `from_slice_limited` for a packet from a peer predating the version byte
+    pub fn from_legacy_slice(
+        slice: &mut BufSlice,
+        format: WireFormat,
+        max_frags: usize,
+    ) -> Result<Self, DecodingError> {
+        let hdr = PacketHeader::from_legacy_slice(slice, format)?;
+        Self::from_frags_slice(hdr, slice, format, max_frags)
+    }
+
+    /// `slice` is what follows `hdr`
+    fn from_frags_slice(
+        hdr: PacketHeader,
+        slice: &mut BufSlice,
+        format: WireFormat,
+        max_frags: usize,
+    ) -> Result<Self, DecodingError> {
+        let start_len = slice.len() + hdr.len_hint();
         let mut frags = Vec::new();
+        let mut unknown_frags = 0;
         while !slice.is_empty() {
-            let frag = Frag::from_slice(slice)?;
-            frags.push(frag);
+            let frag_at = start_len - slice.len();
+            if max_frags <= frags.len() + unknown_frags {
+                return Err(DecodingError::TooManyFrags { offset: frag_at });
+            }
+            let frag = Frag::from_slice_or_skip_with(slice, format, hdr.nack())
+                .map_err(|e| e.offset_by(frag_at))?;
+            match frag {
+                Some(frag) => frags.push(frag),
+                None => unknown_frags += 1,
+            }
         }
 
-        let this = Packet { hdr, frags };
+        let this = Packet {
+            hdr,
+            frags,
+            unknown_frags,
+            auth: None,
+            format,
+        };
         this.check_rep();
         Ok(this)
     }
 
     pub fn append_to(&self, wtr: &mut impl BufWtr) -> Result<(), EncodingError> {
-        self.hdr.append_to(wtr)?;
+        let start = wtr.data_len();
+        self.hdr.append_to_with(wtr, self.format)?;
         for frag in &self.frags {
-            frag.append_to(wtr)?;
+            frag.append_to_with(wtr, self.format, self.hdr.nack())?;
+        }
+        if let Some(auth) = &self.auth {
+            let tag = &mut [0; MAX_TAG_LEN][..auth.tag_len()];
+            auth.sign(&wtr.data()[start..], tag);
+            wtr.append(tag).map_err(|_| EncodingError::NotEnoughSpace)?;
         }
         Ok(())
     }
 
+    /// Lay the pushes out in `format` on `append_to`
+    #[must_use]
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    #[must_use]
+    pub fn format(&self) -> WireFormat {
+        self.format
+    }
+
+    /// Append a tag made by `auth` on `append_to`
+    #[must_use]
+    pub fn with_auth(mut self, auth: Option<Arc<dyn PacketAuth>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// The header and the frags, without `into_builder`
+    #[must_use]
+    pub fn into_frags(self) -> (PacketHeader, Vec<Frag>) {
+        (self.hdr, self.frags)
+    }
+
     #[must_use]
     pub fn into_builder(self) -> PacketBuilder {
         PacketBuilder {
@@ -63,26 +162,83 @@ impl Packet {
     pub fn frags(&self) -> &Vec<Frag> {
         &self.frags
     }
+
+    /// Extension frags stepped over while decoding
+    #[must_use]
+    pub fn unknown_frags(&self) -> usize {
+        self.unknown_frags
+    }
+
+    /// Bytes of the encoded packet
+    #[must_use]
+    pub fn wire_len(&self) -> usize {
+        let mut len = self.hdr.len_hint() + self.frags_len();
+        if let Some(auth) = &self.auth {
+            len += auth.tag_len();
+        }
+        len
+    }
+
+    /// Bytes of the encoded frags, without the header and the tag
+    #[must_use]
+    pub fn frags_len(&self) -> usize {
+        self.frags
+            .iter()
+            .map(|frag| frag.len_with(self.format, self.hdr.nack()))
+            .sum()
+    }
+
+    /// Bytes of the push bodies as carried, compressed or not
+    #[must_use]
+    pub fn push_bytes(&self) -> usize {
+        self.frags
+            .iter()
+            .map(|frag| match frag.cmd() {
+                FragCommand::Push { body } | FragCommand::CompressedPush { body } => body.len(),
+                _ => 0,
+            })
+            .sum()
+    }
+}
+
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Packet")
+            .field("hdr", &self.hdr)
+            .field("frags", &self.frags)
+            .field("unknown_frags", &self.unknown_frags)
+            .field("tag_len", &self.auth.as_ref().map(|auth| auth.tag_len()))
+            .field("format", &self.format)
+            .finish()
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {}
 
+impl fmt::Display for Error {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {}
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[cfg(test)]
 mod tests {
 
     use crate::{
         protocol::{
-            frag::{Body, FragBuilder, FragCommand},
-            packet_hdr::PacketHeaderBuilder,
+            frag::{Body, FragBuilder, FragCommand, WireFormat, ACK_HDR_LEN},
+            packet_hdr::{PacketHeaderBuilder, PACKET_HDR_LEN},
         },
         utils::{
-            buf::{BufSlice, OwnedBufWtr},
+            buf::{BufSlice, BufWtr, OwnedBufWtr},
             Seq32,
         },
     };
 
-    use super::{Packet, PacketBuilder};
+    use super::{DecodingError, Packet, PacketBuilder};
 
     #[test]
     fn test1() {
@@ -90,6 +246,10 @@ mod tests {
             hdr: PacketHeaderBuilder {
                 rwnd: 123,
                 nack: Seq32::from_u32(456),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
             }
             .build()
             .unwrap(),
@@ -114,11 +274,306 @@ mod tests {
         .unwrap();
         let mut wtr = OwnedBufWtr::new(1024, 512);
         packet1.append_to(&mut wtr).unwrap();
+        assert_eq!(packet1.wire_len(), wtr.data_len());
+        assert_eq!(packet1.wire_len(), PACKET_HDR_LEN + packet1.frags_len());
+        assert_eq!(
+            packet1.frags_len(),
+            packet1.frags.iter().map(|frag| frag.len()).sum::<usize>()
+        );
+        assert_eq!(packet1.push_bytes(), 5);
         let packet2 = Packet::from_slice(&mut wtr.into_slice()).unwrap();
+        assert_eq!(packet1.wire_len(), packet2.wire_len());
         assert_eq!(packet1.hdr.rwnd(), packet2.hdr.rwnd());
         assert_eq!(packet1.hdr.nack(), packet2.hdr.nack());
         assert_eq!(packet1.frags.len(), packet2.frags.len());
         assert_eq!(packet1.frags[0].seq(), packet2.frags[0].seq());
         assert_eq!(packet1.frags[1].seq(), packet2.frags[1].seq());
     }
+
+    #[test]
+    fn test_ack_and_ack_bitmap() {
+        let build = || {
+            PacketBuilder {
+                hdr: PacketHeaderBuilder {
+                    rwnd: 123,
+                    nack: Seq32::from_u32(456),
+                    timestamp: None,
+                    timestamp_echo: None,
+                    conn_id: None,
+                    packet_seq: None,
+                }
+                .build()
+                .unwrap(),
+                frags: vec![
+                    FragBuilder {
+                        seq: Seq32::from_u32(300),
+                        cmd: FragCommand::Ack,
+                    }
+                    .build()
+                    .unwrap(),
+                    FragBuilder {
+                        seq: Seq32::from_u32(345),
+                        cmd: FragCommand::AckBitmap { bitmap: 0b1011 },
+                    }
+                    .build()
+                    .unwrap(),
+                    FragBuilder {
+                        seq: Seq32::from_u32(400),
+                        cmd: FragCommand::Ack,
+                    }
+                    .build()
+                    .unwrap(),
+                ],
+            }
+            .build()
+            .unwrap()
+        };
+        for format in [WireFormat::V1, WireFormat::V2, WireFormat::V3] {
+            let packet1 = build().with_format(format);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            packet1.append_to(&mut wtr).unwrap();
+            assert_eq!(packet1.wire_len(), wtr.data_len());
+            assert_eq!(packet1.push_bytes(), 0);
+            let packet2 = Packet::from_slice_with(&mut wtr.into_slice(), format).unwrap();
+            let frags: Vec<_> = packet2
+                .frags()
+                .iter()
+                .map(|frag| match frag.cmd() {
+                    FragCommand::Ack => (frag.seq().to_u32(), None),
+                    FragCommand::AckBitmap { bitmap } => (frag.seq().to_u32(), Some(*bitmap)),
+                    _ => panic!(),
+                })
+                .collect();
+            assert_eq!(frags, [(300, None), (345, Some(0b1011)), (400, None)]);
+        }
+    }
+
+    #[test]
+    fn test_clone() {
+        let packet1 = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp: Some(7),
+                timestamp_echo: None,
+                conn_id: Some(8),
+                packet_seq: None,
+            }
+            .build()
+            .unwrap(),
+            frags: vec![
+                FragBuilder {
+                    seq: Seq32::from_u32(456),
+                    cmd: FragCommand::Push {
+                        body: Body::Slice(BufSlice::from_bytes(vec![0, 1, 2, 3, 4])),
+                    },
+                }
+                .build()
+                .unwrap(),
+                FragBuilder {
+                    seq: Seq32::from_u32(300),
+                    cmd: FragCommand::Ack,
+                }
+                .build()
+                .unwrap(),
+            ],
+        }
+        .build()
+        .unwrap()
+        .with_format(WireFormat::V3);
+        let packet2 = packet1.clone();
+        let mut wtr1 = OwnedBufWtr::new(1024, 0);
+        packet1.append_to(&mut wtr1).unwrap();
+        // the original goes away before the clone is encoded
+        let (hdr, frags) = packet1.into_frags();
+        assert_eq!(hdr.conn_id(), Some(8));
+        assert_eq!(frags.len(), 2);
+        drop(frags);
+        let mut wtr2 = OwnedBufWtr::new(1024, 0);
+        packet2.append_to(&mut wtr2).unwrap();
+        assert_eq!(wtr1.data(), wtr2.data());
+
+        let debug = format!("{packet2:?}");
+        assert!(debug.contains("Push { body: Slice { len: 5 } }"));
+    }
+
+    #[test]
+    fn test_truncated_push_offset() {
+        let packet = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap(),
+            frags: vec![
+                FragBuilder {
+                    seq: Seq32::from_u32(300),
+                    cmd: FragCommand::Ack,
+                }
+                .build()
+                .unwrap(),
+                FragBuilder {
+                    seq: Seq32::from_u32(456),
+                    cmd: FragCommand::Push {
+                        body: Body::Slice(BufSlice::from_bytes(vec![0, 1, 2, 3, 4])),
+                    },
+                }
+                .build()
+                .unwrap(),
+            ],
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        packet.append_to(&mut wtr).unwrap();
+        // the len of the push is cut after 2 of its 4 bytes
+        let push_at = PACKET_HDR_LEN + ACK_HDR_LEN;
+        let len = push_at + ACK_HDR_LEN + 2;
+        let mut slice = BufSlice::from_bytes(wtr.data()[..len].to_vec());
+        match Packet::from_slice(&mut slice) {
+            Err(DecodingError::Decoding { field, offset }) => {
+                assert_eq!(field, "len");
+                assert_eq!(offset, push_at + ACK_HDR_LEN);
+            }
+            _ => panic!(),
+        }
+        // a header cut short
+        let mut slice = BufSlice::from_bytes(wtr.data()[..3].to_vec());
+        match Packet::from_slice(&mut slice) {
+            Err(DecodingError::Decoding { field, offset }) => {
+                assert_eq!((field, offset), ("nack", 3));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_frag_limit() {
+        let packet = PacketBuilder {
+            hdr: PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap(),
+            frags: (0..10)
+                .map(|i| {
+                    FragBuilder {
+                        seq: Seq32::from_u32(i),
+                        cmd: FragCommand::Ack,
+                    }
+                    .build()
+                    .unwrap()
+                })
+                .collect(),
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        packet.append_to(&mut wtr).unwrap();
+        let decode = |max_frags| {
+            Packet::from_slice_limited(
+                &mut BufSlice::from_bytes(wtr.data().to_vec()),
+                WireFormat::V1,
+                max_frags,
+            )
+        };
+        assert_eq!(decode(10).unwrap().frags().len(), 10);
+        match decode(9) {
+            Err(DecodingError::TooManyFrags { offset }) => {
+                assert_eq!(offset, PACKET_HDR_LEN + 9 * ACK_HDR_LEN);
+            }
+            _ => panic!(),
+        }
+    }
+
+    /// Every prefix and every single-byte corruption of packets of every frag kind decodes or
+    /// fails without a panic
+    #[test]
+    fn test_truncations() {
+        let body = |len| Body::Slice(BufSlice::from_bytes(vec![0xaa; len]));
+        let cmds = || {
+            vec![
+                FragCommand::Push { body: body(5) },
+                FragCommand::CompressedPush { body: body(3) },
+                FragCommand::Ack,
+                FragCommand::AckBitmap { bitmap: 0b101 },
+                FragCommand::Skip,
+                FragCommand::Fin,
+                FragCommand::Rst,
+                FragCommand::Ping { token: 1 },
+                FragCommand::Pong { token: 2 },
+                FragCommand::Probe { id: 3 },
+                FragCommand::ProbeAck { id: 4 },
+                FragCommand::Sack {
+                    ranges: vec![Seq32::from_u32(10)..Seq32::from_u32(12)],
+                },
+                FragCommand::Syn { nonce: 5 },
+                FragCommand::SynAck { nonce: 6 },
+                FragCommand::Padding { len: 4 },
+                FragCommand::Parity {
+                    count: 2,
+                    len_xor: 7,
+                    body: BufSlice::from_bytes(vec![0x55; 6]),
+                },
+            ]
+        };
+        for format in [WireFormat::V1, WireFormat::V2, WireFormat::V3] {
+            let packet = PacketBuilder {
+                hdr: PacketHeaderBuilder {
+                    rwnd: 123,
+                    nack: Seq32::from_u32(456),
+                    timestamp: Some(1),
+                    timestamp_echo: Some(2),
+                    conn_id: Some(3),
+                    packet_seq: Some(Seq32::from_u32(4)),
+                }
+                .build()
+                .unwrap(),
+                frags: cmds()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, cmd)| {
+                        FragBuilder {
+                            seq: Seq32::from_u32(450 + i as u32),
+                            cmd,
+                        }
+                        .build()
+                        .unwrap()
+                    })
+                    .collect(),
+            }
+            .build()
+            .unwrap()
+            .with_format(format);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            packet.append_to(&mut wtr).unwrap();
+            let data = wtr.data();
+            let decoded =
+                Packet::from_slice_with(&mut BufSlice::from_bytes(wtr.data().to_vec()), format)
+                    .unwrap();
+            assert_eq!(decoded.frags().len(), cmds().len());
+
+            for len in 0..data.len() {
+                let mut slice = BufSlice::from_bytes(data[..len].to_vec());
+                let _ = Packet::from_slice_with(&mut slice, format);
+            }
+            for i in 0..data.len() {
+                for byte in [0x00, 0x7f, 0x80, 0xff] {
+                    let mut bytes = data.to_vec();
+                    bytes[i] = byte;
+                    let _ = Packet::from_slice_with(&mut BufSlice::from_bytes(bytes), format);
+                }
+            }
+        }
+    }
 }
@@ -1,28 +1,70 @@
-use super::{DecodingError, EncodingError};
+use super::{frag::WireFormat, DecodingError, EncodingError};
 use crate::utils::{
     buf::{BufSlice, BufWtr},
     Seq32,
 };
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use std::io::Cursor;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use std::{fmt, io::Cursor};
 
-pub const PACKET_HDR_LEN: usize = 6;
+pub const PACKET_HDR_LEN: usize = 7;
+/// Bytes of a header from before the version byte
+pub const LEGACY_PACKET_HDR_LEN: usize = PACKET_HDR_LEN - 1;
+/// The version put in the headers sent and the only one taken from the peer
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Reported for a header without the version byte; never put on the wire
+pub const LEGACY_VERSION: u8 = 0;
+/// Extra bytes of a header carrying a timestamp, an echo or both
+pub const PACKET_OPTIONS_LEN: usize = 8;
+/// Extra bytes of a header without the version byte carrying any option
+pub const LEGACY_PACKET_OPTIONS_LEN: usize = PACKET_OPTIONS_LEN + 2;
+/// Extra bytes after the options of a header carrying a connection ID
+pub const CONN_ID_LEN: usize = 8;
+/// Extra bytes after the options and the connection ID of a header carrying a packet seq
+pub const PACKET_SEQ_LEN: usize = 4;
+// the low bits of the version byte; the flags take the high ones
+const VERSION_MASK: u8 = 0x0f;
+const FLAGS_SHIFT: u32 = 4;
+// takes the place of the cmd byte of a frag in a legacy header; no frag uses it
+const OPTIONS_MARKER: u8 = 3;
+const FLAG_TIMESTAMP: u8 = 0b01;
+const FLAG_TIMESTAMP_ECHO: u8 = 0b10;
+const FLAG_CONN_ID: u8 = 0b100;
+const FLAG_PACKET_SEQ: u8 = 0b1000;
 
+#[derive(Debug, Clone)]
 pub struct PacketHeader {
+    version: u8,
     rwnd: u16,
     nack: Seq32,
+    timestamp: Option<u32>,
+    timestamp_echo: Option<u32>,
+    conn_id: Option<u64>,
+    packet_seq: Option<Seq32>,
 }
 
 pub struct PacketHeaderBuilder {
     pub rwnd: u16,
     pub nack: Seq32,
+    /// Milliseconds on the clock of the sender
+    pub timestamp: Option<u32>,
+    /// The latest `timestamp` received from the peer
+    pub timestamp_echo: Option<u32>,
+    /// Identifies the connection regardless of the source address
+    pub conn_id: Option<u64>,
+    /// One more for every packet emitted; tells duplicated packets apart from new ones
+    pub packet_seq: Option<Seq32>,
 }
 
 impl PacketHeaderBuilder {
     pub fn build(self) -> Result<PacketHeader, Error> {
         let this = PacketHeader {
+            version: PROTOCOL_VERSION,
             rwnd: self.rwnd,
             nack: self.nack,
+            timestamp: self.timestamp,
+            timestamp_echo: self.timestamp_echo,
+            conn_id: self.conn_id,
+            packet_seq: self.packet_seq,
         };
         this.check_rep();
         Ok(this)
@@ -32,41 +74,304 @@ impl PacketHeaderBuilder {
 #[derive(Debug)]
 pub enum Error {}
 
+impl fmt::Display for Error {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {}
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Decode only the packet header at the front of `data`
+///
+/// Nothing is consumed; skip `len_hint` bytes to get to the frags.
+pub fn peek(data: &[u8]) -> Result<PacketHeader, DecodingError> {
+    peek_with(data, WireFormat::V1)
+}
+
+/// Offsets of the marker, the flags and the timestamp in the options of a legacy header
+///
+/// The marker sits where the `cmd` of a first frag of `format` would be.
+#[inline]
+const fn legacy_options_offsets(format: WireFormat) -> (usize, usize, usize) {
+    match format {
+        WireFormat::V1 | WireFormat::V2 => (4, 5, 0),
+        WireFormat::V3 => (0, 1, 2),
+    }
+}
+
+/// Offsets of the timestamp and the echo in the options
+#[inline]
+const fn timestamp_offsets(version: u8, format: WireFormat) -> (usize, usize) {
+    match version {
+        LEGACY_VERSION => (legacy_options_offsets(format).2, 6),
+        _ => (0, 4),
+    }
+}
+
+/// Like `peek` for a packet whose frags are laid out in `format`
+pub fn peek_with(data: &[u8], format: WireFormat) -> Result<PacketHeader, DecodingError> {
+    let version = *data.first().ok_or(DecodingError::Decoding {
+        field: "version",
+        offset: 0,
+    })? & VERSION_MASK;
+    if version != PROTOCOL_VERSION {
+        return Err(DecodingError::UnsupportedVersion { version });
+    }
+    peek_at(data, format, version)
+}
+
+/// Like `peek_with` for a header from a peer predating the version byte
+///
+/// The header is reported as of `LEGACY_VERSION` and is written back without the version byte.
+pub fn peek_legacy_with(data: &[u8], format: WireFormat) -> Result<PacketHeader, DecodingError> {
+    peek_at(data, format, LEGACY_VERSION)
+}
+
+#[inline]
+const fn base_len(version: u8) -> usize {
+    match version {
+        LEGACY_VERSION => LEGACY_PACKET_HDR_LEN,
+        _ => PACKET_HDR_LEN,
+    }
+}
+
+fn peek_at(data: &[u8], format: WireFormat, version: u8) -> Result<PacketHeader, DecodingError> {
+    let base_len = base_len(version);
+    // the version byte, if any, is in front of the rest
+    let version_len = match version {
+        LEGACY_VERSION => 0,
+        _ => 1,
+    };
+    let mut rdr = Cursor::new(&data[version_len..]);
+    let rwnd = rdr
+        .read_u16::<BigEndian>()
+        .map_err(|_e| DecodingError::Decoding {
+            field: "rwnd",
+            offset: version_len,
+        })?;
+    let nack = rdr
+        .read_u32::<BigEndian>()
+        .map_err(|_e| DecodingError::Decoding {
+            field: "nack",
+            offset: version_len + 2,
+        })?;
+    let nack = Seq32::from_u32(nack);
+
+    // a legacy header flags its options in them, behind a marker
+    let (flags, options) = match version {
+        LEGACY_VERSION => {
+            let (marker_pos, flags_pos, _) = legacy_options_offsets(format);
+            match data.get(base_len + marker_pos) {
+                Some(&OPTIONS_MARKER) => {
+                    let options = options_at(data, base_len, LEGACY_PACKET_OPTIONS_LEN)?;
+                    (options[flags_pos], options)
+                }
+                _ => (0, &[][..]),
+            }
+        }
+        _ => {
+            let flags = data[0] >> FLAGS_SHIFT;
+            let len = match flags & (FLAG_TIMESTAMP | FLAG_TIMESTAMP_ECHO) {
+                0 => 0,
+                _ => PACKET_OPTIONS_LEN,
+            };
+            (flags, options_at(data, base_len, len)?)
+        }
+    };
+    let (timestamp_pos, echo_pos) = timestamp_offsets(version, format);
+    let timestamp = (flags & FLAG_TIMESTAMP != 0)
+        .then(|| BigEndian::read_u32(&options[timestamp_pos..timestamp_pos + 4]));
+    let timestamp_echo = (flags & FLAG_TIMESTAMP_ECHO != 0)
+        .then(|| BigEndian::read_u32(&options[echo_pos..echo_pos + 4]));
+    let mut start = base_len + options.len();
+    let mut conn_id = None;
+    if flags & FLAG_CONN_ID != 0 {
+        let bytes = data
+            .get(start..start + CONN_ID_LEN)
+            .ok_or(DecodingError::Decoding {
+                field: "conn_id",
+                offset: start,
+            })?;
+        conn_id = Some(BigEndian::read_u64(bytes));
+        start += CONN_ID_LEN;
+    }
+    let mut packet_seq = None;
+    if flags & FLAG_PACKET_SEQ != 0 {
+        let bytes = data
+            .get(start..start + PACKET_SEQ_LEN)
+            .ok_or(DecodingError::Decoding {
+                field: "packet_seq",
+                offset: start,
+            })?;
+        packet_seq = Some(Seq32::from_u32(BigEndian::read_u32(bytes)));
+    }
+
+    let this = PacketHeader {
+        version,
+        rwnd,
+        nack,
+        timestamp,
+        timestamp_echo,
+        conn_id,
+        packet_seq,
+    };
+    this.check_rep();
+    Ok(this)
+}
+
+#[inline]
+fn options_at(data: &[u8], start: usize, len: usize) -> Result<&[u8], DecodingError> {
+    data.get(start..start + len).ok_or(DecodingError::Decoding {
+        field: "options",
+        offset: start,
+    })
+}
+
 impl PacketHeader {
     #[inline]
     fn check_rep(&self) {}
 
     #[must_use]
     pub fn from_slice(slice: &mut BufSlice) -> Result<Self, DecodingError> {
-        let mut rdr = Cursor::new(slice.data());
-        let rwnd = rdr
-            .read_u16::<BigEndian>()
-            .map_err(|_e| DecodingError::Decoding { field: "rwnd" })?;
-        let nack = rdr
-            .read_u32::<BigEndian>()
-            .map_err(|_e| DecodingError::Decoding { field: "nack" })?;
-        let nack = Seq32::from_u32(nack);
-
-        let rdr_len = rdr.position() as usize;
-        slice.pop_front(rdr_len).unwrap();
-
-        let this = PacketHeader { rwnd, nack };
-        this.check_rep();
+        Self::from_slice_with(slice, WireFormat::V1)
+    }
+
+    /// Like `from_slice` for a packet whose frags are laid out in `format`
+    pub fn from_slice_with(
+        slice: &mut BufSlice,
+        format: WireFormat,
+    ) -> Result<Self, DecodingError> {
+        let this = peek_with(slice.data(), format)?;
+        slice.pop_front(this.len_hint()).unwrap();
+        Ok(this)
+    }
+
+    /// Like `from_slice_with` for a header from a peer predating the version byte
+    pub fn from_legacy_slice(
+        slice: &mut BufSlice,
+        format: WireFormat,
+    ) -> Result<Self, DecodingError> {
+        let this = peek_legacy_with(slice.data(), format)?;
+        slice.pop_front(this.len_hint()).unwrap();
         Ok(this)
     }
 
+    /// The same header to be written with `version`; `LEGACY_VERSION` leaves the version byte
+    /// out for a peer predating it
+    #[must_use]
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Number of bytes the header takes on the wire
+    #[must_use]
+    #[inline]
+    pub fn len_hint(&self) -> usize {
+        let mut len = base_len(self.version) + self.options_len();
+        if self.conn_id.is_some() {
+            len += CONN_ID_LEN;
+        }
+        if self.packet_seq.is_some() {
+            len += PACKET_SEQ_LEN;
+        }
+        len
+    }
+
+    #[inline]
+    fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.timestamp.is_some() {
+            flags |= FLAG_TIMESTAMP;
+        }
+        if self.timestamp_echo.is_some() {
+            flags |= FLAG_TIMESTAMP_ECHO;
+        }
+        if self.conn_id.is_some() {
+            flags |= FLAG_CONN_ID;
+        }
+        if self.packet_seq.is_some() {
+            flags |= FLAG_PACKET_SEQ;
+        }
+        flags
+    }
+
+    /// Bytes of the options between the fixed fields and the connection ID
+    #[inline]
+    fn options_len(&self) -> usize {
+        let flags = self.flags();
+        match self.version {
+            LEGACY_VERSION if flags != 0 => LEGACY_PACKET_OPTIONS_LEN,
+            LEGACY_VERSION => 0,
+            _ if flags & (FLAG_TIMESTAMP | FLAG_TIMESTAMP_ECHO) != 0 => PACKET_OPTIONS_LEN,
+            _ => 0,
+        }
+    }
+
     #[must_use]
     pub fn append_to(&self, wtr: &mut impl BufWtr) -> Result<(), EncodingError> {
-        let mut hdr = Vec::new();
-        hdr.write_u16::<BigEndian>(self.rwnd).unwrap();
-        hdr.write_u32::<BigEndian>(self.nack.to_u32()).unwrap();
-        assert_eq!(hdr.len(), PACKET_HDR_LEN);
+        self.append_to_with(wtr, WireFormat::V1)
+    }
 
-        wtr.append(&hdr)
-            .map_err(|_| EncodingError::NotEnoughSpace)?;
+    /// Like `append_to` for a packet whose frags are laid out in `format`
+    pub fn append_to_with(
+        &self,
+        wtr: &mut impl BufWtr,
+        format: WireFormat,
+    ) -> Result<(), EncodingError> {
+        let len = self.len_hint();
+        if wtr.back_len() < len {
+            return Err(EncodingError::NotEnoughSpace);
+        }
+        let hdr = &mut wtr.back_free_space()[..len];
+        let base_len = base_len(self.version);
+        let flags = self.flags();
+        let fixed = match self.version {
+            LEGACY_VERSION => &mut hdr[..base_len],
+            version => {
+                hdr[0] = version | flags << FLAGS_SHIFT;
+                &mut hdr[1..base_len]
+            }
+        };
+        BigEndian::write_u16(&mut fixed[0..2], self.rwnd);
+        BigEndian::write_u32(&mut fixed[2..6], self.nack.to_u32());
+        let options_len = self.options_len();
+        let (options, mut extra) = hdr[base_len..].split_at_mut(options_len);
+        if options_len != 0 {
+            let (timestamp_pos, echo_pos) = timestamp_offsets(self.version, format);
+            BigEndian::write_u32(
+                &mut options[timestamp_pos..timestamp_pos + 4],
+                self.timestamp.unwrap_or(0),
+            );
+            BigEndian::write_u32(
+                &mut options[echo_pos..echo_pos + 4],
+                self.timestamp_echo.unwrap_or(0),
+            );
+            if self.version == LEGACY_VERSION {
+                let (marker_pos, flags_pos, _) = legacy_options_offsets(format);
+                options[marker_pos] = OPTIONS_MARKER;
+                options[flags_pos] = flags;
+            }
+        }
+        if let Some(conn_id) = self.conn_id {
+            BigEndian::write_u64(&mut extra[..CONN_ID_LEN], conn_id);
+            extra = &mut extra[CONN_ID_LEN..];
+        }
+        if let Some(packet_seq) = self.packet_seq {
+            BigEndian::write_u32(&mut extra[..PACKET_SEQ_LEN], packet_seq.to_u32());
+        }
+        wtr.grow_back(len).unwrap();
         Ok(())
     }
 
+    /// `LEGACY_VERSION` for a header without the version byte
+    #[must_use]
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     #[must_use]
     #[inline]
     pub fn rwnd(&self) -> u16 {
@@ -78,6 +383,30 @@ impl PacketHeader {
     pub fn nack(&self) -> Seq32 {
         self.nack
     }
+
+    #[must_use]
+    #[inline]
+    pub fn timestamp(&self) -> Option<u32> {
+        self.timestamp
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn timestamp_echo(&self) -> Option<u32> {
+        self.timestamp_echo
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn conn_id(&self) -> Option<u64> {
+        self.conn_id
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn packet_seq(&self) -> Option<Seq32> {
+        self.packet_seq
+    }
 }
 
 #[cfg(test)]
@@ -92,6 +421,10 @@ mod tests {
         let hdr1 = PacketHeaderBuilder {
             rwnd: 123,
             nack: Seq32::from_u32(456),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: None,
         }
         .build()
         .unwrap();
@@ -101,4 +434,314 @@ mod tests {
         assert_eq!(hdr1.rwnd, hdr2.rwnd);
         assert_eq!(hdr1.nack, hdr2.nack);
     }
+
+    #[test]
+    fn test_peek() {
+        let hdr1 = PacketHeaderBuilder {
+            rwnd: 123,
+            nack: Seq32::from_u32(456),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: None,
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        hdr1.append_to(&mut wtr).unwrap();
+        wtr.append(&[7, 8, 9]).unwrap();
+
+        let hdr2 = peek(wtr.data()).unwrap();
+        assert_eq!(hdr2.len_hint(), PACKET_HDR_LEN);
+        assert_eq!(&wtr.data()[hdr2.len_hint()..], &[7, 8, 9]);
+
+        let mut slice = wtr.into_slice();
+        let hdr3 = PacketHeader::from_slice(&mut slice).unwrap();
+        assert_eq!(hdr2.rwnd, hdr3.rwnd);
+        assert_eq!(hdr2.nack, hdr3.nack);
+        assert_eq!(slice.data(), vec![7, 8, 9]);
+
+        assert!(peek(&[]).is_err());
+        assert!(peek(&[PROTOCOL_VERSION, 0, 1, 2, 3, 4]).is_err());
+    }
+
+    #[test]
+    fn test_version() {
+        let hdr1 = PacketHeaderBuilder {
+            rwnd: 0x0102,
+            nack: Seq32::from_u32(0x03040506),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: None,
+        }
+        .build()
+        .unwrap();
+        assert_eq!(hdr1.version(), PROTOCOL_VERSION);
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        hdr1.append_to(&mut wtr).unwrap();
+        assert_eq!(wtr.data(), [PROTOCOL_VERSION, 1, 2, 3, 4, 5, 6]);
+        let hdr2 = peek(wtr.data()).unwrap();
+        assert_eq!(hdr2.version(), PROTOCOL_VERSION);
+        assert_eq!(hdr2.rwnd(), 0x0102);
+        assert_eq!(hdr2.nack().to_u32(), 0x03040506);
+
+        for version in [LEGACY_VERSION, PROTOCOL_VERSION + 1, VERSION_MASK] {
+            let mut data = wtr.data().to_vec();
+            data[0] = version;
+            match peek(&data) {
+                Err(DecodingError::UnsupportedVersion { version: x }) => assert_eq!(x, version),
+                _ => panic!(),
+            }
+        }
+        // the flags are not part of the version
+        let mut data = wtr.data().to_vec();
+        data[0] = PROTOCOL_VERSION | FLAG_TIMESTAMP << FLAGS_SHIFT;
+        assert!(matches!(
+            peek(&data),
+            Err(DecodingError::Decoding {
+                field: "options",
+                offset: PACKET_HDR_LEN
+            })
+        ));
+        assert!(matches!(
+            peek(&[]),
+            Err(DecodingError::Decoding {
+                field: "version",
+                offset: 0
+            })
+        ));
+        assert!(matches!(
+            peek(&[PROTOCOL_VERSION, 1, 2, 3]),
+            Err(DecodingError::Decoding {
+                field: "nack",
+                offset: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_legacy() {
+        for (timestamp, conn_id) in [(None, None), (Some(7), None), (None, Some(u64::MAX - 1))] {
+            let hdr1 = PacketHeaderBuilder {
+                rwnd: 0x0102,
+                nack: Seq32::from_u32(0x03040506),
+                timestamp,
+                timestamp_echo: None,
+                conn_id,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap()
+            .with_version(LEGACY_VERSION);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            hdr1.append_to(&mut wtr).unwrap();
+            assert_eq!(wtr.data_len(), hdr1.len_hint());
+            assert_eq!(&wtr.data()[..LEGACY_PACKET_HDR_LEN], [1, 2, 3, 4, 5, 6]);
+            wtr.append(&[7, 8, 9]).unwrap();
+
+            let mut slice = wtr.into_slice();
+            let hdr2 = PacketHeader::from_legacy_slice(&mut slice, WireFormat::V1).unwrap();
+            assert_eq!(hdr2.version(), LEGACY_VERSION);
+            assert_eq!(hdr2.len_hint(), hdr1.len_hint());
+            assert_eq!(hdr2.rwnd(), 0x0102);
+            assert_eq!(hdr2.nack().to_u32(), 0x03040506);
+            assert_eq!(hdr2.timestamp(), timestamp);
+            assert_eq!(hdr2.conn_id(), conn_id);
+            assert_eq!(slice.data(), vec![7, 8, 9]);
+        }
+        // the options flag the connection ID behind a marker
+        let hdr = PacketHeaderBuilder {
+            rwnd: 0,
+            nack: Seq32::from_u32(0),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: Some(1),
+            packet_seq: None,
+        }
+        .build()
+        .unwrap()
+        .with_version(LEGACY_VERSION);
+        assert_eq!(
+            hdr.len_hint(),
+            LEGACY_PACKET_HDR_LEN + LEGACY_PACKET_OPTIONS_LEN + CONN_ID_LEN
+        );
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        hdr.append_to(&mut wtr).unwrap();
+        assert_eq!(wtr.data()[LEGACY_PACKET_HDR_LEN + 4], OPTIONS_MARKER);
+        assert_eq!(wtr.data()[LEGACY_PACKET_HDR_LEN + 5], FLAG_CONN_ID);
+
+        // the same fields a byte further in once versioned
+        let legacy = [1, 2, 3, 4, 5, 6];
+        let hdr = peek_legacy_with(&legacy, WireFormat::V1).unwrap();
+        assert_eq!(hdr.len_hint(), LEGACY_PACKET_HDR_LEN);
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        hdr.with_version(PROTOCOL_VERSION)
+            .append_to(&mut wtr)
+            .unwrap();
+        assert_eq!(wtr.data(), [PROTOCOL_VERSION, 1, 2, 3, 4, 5, 6]);
+        assert!(peek_legacy_with(&legacy[..5], WireFormat::V1).is_err());
+    }
+
+    #[test]
+    fn test_options() {
+        for (timestamp, timestamp_echo) in
+            [(Some(7), None), (None, Some(u32::MAX)), (Some(0), Some(9))]
+        {
+            let hdr1 = PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp,
+                timestamp_echo,
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap();
+            assert_eq!(hdr1.len_hint(), PACKET_HDR_LEN + PACKET_OPTIONS_LEN);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            hdr1.append_to(&mut wtr).unwrap();
+            assert_eq!(wtr.data_len(), hdr1.len_hint());
+            wtr.append(&[7, 8, 9]).unwrap();
+
+            let mut slice = wtr.into_slice();
+            let hdr2 = PacketHeader::from_slice(&mut slice).unwrap();
+            assert_eq!(hdr2.rwnd(), 123);
+            assert_eq!(hdr2.nack(), Seq32::from_u32(456));
+            assert_eq!(hdr2.timestamp(), timestamp);
+            assert_eq!(hdr2.timestamp_echo(), timestamp_echo);
+            assert_eq!(slice.data(), vec![7, 8, 9]);
+        }
+
+        // no options, no extra bytes
+        let hdr = PacketHeaderBuilder {
+            rwnd: 123,
+            nack: Seq32::from_u32(456),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: None,
+        }
+        .build()
+        .unwrap();
+        let mut wtr = OwnedBufWtr::new(1024, 0);
+        hdr.append_to(&mut wtr).unwrap();
+        assert_eq!(wtr.data_len(), PACKET_HDR_LEN);
+
+        // cut short
+        let mut data = [0; PACKET_HDR_LEN + PACKET_OPTIONS_LEN - 1];
+        data[0] = PROTOCOL_VERSION | FLAG_TIMESTAMP_ECHO << FLAGS_SHIFT;
+        assert!(peek(&data).is_err());
+    }
+
+    #[test]
+    fn test_conn_id() {
+        for timestamp in [None, Some(7)] {
+            let hdr1 = PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp,
+                timestamp_echo: None,
+                conn_id: Some(u64::MAX - 1),
+                packet_seq: None,
+            }
+            .build()
+            .unwrap();
+            let options_len = timestamp.map(|_| PACKET_OPTIONS_LEN).unwrap_or(0);
+            assert_eq!(hdr1.len_hint(), PACKET_HDR_LEN + options_len + CONN_ID_LEN);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            hdr1.append_to(&mut wtr).unwrap();
+            assert_eq!(wtr.data_len(), hdr1.len_hint());
+            wtr.append(&[7, 8, 9]).unwrap();
+
+            assert_eq!(peek(wtr.data()).unwrap().conn_id(), Some(u64::MAX - 1));
+            let mut slice = wtr.into_slice();
+            let hdr2 = PacketHeader::from_slice(&mut slice).unwrap();
+            assert_eq!(hdr2.nack(), Seq32::from_u32(456));
+            assert_eq!(hdr2.timestamp(), timestamp);
+            assert_eq!(hdr2.timestamp_echo(), None);
+            assert_eq!(hdr2.conn_id(), Some(u64::MAX - 1));
+            assert_eq!(slice.data(), vec![7, 8, 9]);
+        }
+
+        // cut short
+        let mut data = [0; PACKET_HDR_LEN + CONN_ID_LEN - 1];
+        data[0] = PROTOCOL_VERSION | FLAG_CONN_ID << FLAGS_SHIFT;
+        assert!(peek(&data).is_err());
+    }
+
+    #[test]
+    fn test_packet_seq() {
+        for conn_id in [None, Some(u64::MAX - 1)] {
+            let hdr1 = PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp: None,
+                timestamp_echo: None,
+                conn_id,
+                packet_seq: Some(Seq32::from_u32(u32::MAX)),
+            }
+            .build()
+            .unwrap();
+            let conn_id_len = conn_id.map(|_| CONN_ID_LEN).unwrap_or(0);
+            assert_eq!(
+                hdr1.len_hint(),
+                PACKET_HDR_LEN + conn_id_len + PACKET_SEQ_LEN
+            );
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            hdr1.append_to(&mut wtr).unwrap();
+            assert_eq!(wtr.data_len(), hdr1.len_hint());
+            wtr.append(&[7, 8, 9]).unwrap();
+
+            let mut slice = wtr.into_slice();
+            let hdr2 = PacketHeader::from_slice(&mut slice).unwrap();
+            assert_eq!(hdr2.conn_id(), conn_id);
+            assert_eq!(hdr2.packet_seq(), Some(Seq32::from_u32(u32::MAX)));
+            assert_eq!(slice.data(), vec![7, 8, 9]);
+        }
+
+        // cut short
+        let mut data = [0; PACKET_HDR_LEN + PACKET_SEQ_LEN - 1];
+        data[0] = PROTOCOL_VERSION | FLAG_PACKET_SEQ << FLAGS_SHIFT;
+        assert!(peek(&data).is_err());
+    }
+
+    #[test]
+    fn test_options_v3() {
+        for version in [PROTOCOL_VERSION, LEGACY_VERSION] {
+            let hdr1 = PacketHeaderBuilder {
+                rwnd: 123,
+                nack: Seq32::from_u32(456),
+                timestamp: Some(7),
+                timestamp_echo: Some(9),
+                conn_id: None,
+                packet_seq: None,
+            }
+            .build()
+            .unwrap()
+            .with_version(version);
+            let mut wtr = OwnedBufWtr::new(1024, 0);
+            hdr1.append_to_with(&mut wtr, WireFormat::V3).unwrap();
+            let hdr2 = match version {
+                LEGACY_VERSION => {
+                    assert_eq!(wtr.data()[LEGACY_PACKET_HDR_LEN], OPTIONS_MARKER);
+                    peek_legacy_with(wtr.data(), WireFormat::V3).unwrap()
+                }
+                _ => peek_with(wtr.data(), WireFormat::V3).unwrap(),
+            };
+            assert_eq!(hdr2.timestamp(), Some(7));
+            assert_eq!(hdr2.timestamp_echo(), Some(9));
+            assert_eq!(hdr2.len_hint(), wtr.data_len());
+        }
+
+        // a `V3` push whose body has a 3 where a `V1` frag has its `cmd`
+        let mut data = vec![0, 0, 0, 0, 0, 0];
+        data.extend([0, 0, 3, 0xaa, 3, 0xcc]);
+        let hdr = peek_legacy_with(&data, WireFormat::V3).unwrap();
+        assert_eq!(hdr.len_hint(), LEGACY_PACKET_HDR_LEN);
+        assert!(peek_legacy_with(&data, WireFormat::V1).is_err());
+        // the version byte flags the options; the frags are not looked at
+        data.insert(0, PROTOCOL_VERSION);
+        assert_eq!(peek(&data).unwrap().len_hint(), PACKET_HDR_LEN);
+    }
 }
@@ -0,0 +1,85 @@
+use crate::utils::buf::{BufSlice, BufWtr, OwnedBufWtr};
+
+/// Rewrites whole datagrams on the way out and back on the way in
+///
+/// Meant for obfuscation or encryption the protocol knows nothing about. Keys are up to the
+/// implementation.
+pub trait PacketTransform {
+    /// Rewrite the encoded packet in `wtr`
+    fn encode(&self, wtr: &mut OwnedBufWtr);
+
+    /// Undo `encode`; `Err` if `slice` cannot have come from it
+    #[allow(clippy::result_unit_err)]
+    fn decode(&self, slice: &mut BufSlice) -> Result<(), ()>;
+}
+
+/// Leaves the datagrams as they are
+pub struct IdentityTransform;
+
+impl PacketTransform for IdentityTransform {
+    fn encode(&self, _wtr: &mut OwnedBufWtr) {}
+
+    fn decode(&self, _slice: &mut BufSlice) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+/// XORs every byte with the key, repeated; obfuscation only
+pub struct XorTransform {
+    key: Vec<u8>,
+}
+
+impl XorTransform {
+    /// `key` must not be empty
+    pub fn new(key: Vec<u8>) -> Self {
+        assert!(!key.is_empty());
+        XorTransform { key }
+    }
+
+    fn apply(&self, data: &mut [u8]) {
+        for (x, k) in data.iter_mut().zip(self.key.iter().cycle()) {
+            *x ^= k;
+        }
+    }
+}
+
+impl PacketTransform for XorTransform {
+    fn encode(&self, wtr: &mut OwnedBufWtr) {
+        self.apply(wtr.data_mut());
+    }
+
+    fn decode(&self, slice: &mut BufSlice) -> Result<(), ()> {
+        let mut data = slice.data().to_vec();
+        self.apply(&mut data);
+        *slice = BufSlice::from_bytes(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IdentityTransform, PacketTransform, XorTransform};
+    use crate::utils::buf::{BufWtr, OwnedBufWtr};
+
+    #[test]
+    fn test_round_trip() {
+        let transforms: [Box<dyn PacketTransform>; 2] = [
+            Box::new(IdentityTransform),
+            Box::new(XorTransform::new(vec![0x5a, 0xc3, 0x17])),
+        ];
+        for transform in transforms {
+            let mut wtr = OwnedBufWtr::new(16, 0);
+            wtr.append(&[1, 2, 3, 4, 5]).unwrap();
+            transform.encode(&mut wtr);
+            let mut slice = wtr.into_slice();
+            transform.decode(&mut slice).unwrap();
+            assert_eq!(slice.data(), [1, 2, 3, 4, 5]);
+        }
+
+        let xor = XorTransform::new(vec![0xff]);
+        let mut wtr = OwnedBufWtr::new(16, 0);
+        wtr.append(&[0, 0xf0]).unwrap();
+        xor.encode(&mut wtr);
+        assert_eq!(wtr.data(), [0xff, 0x0f]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{self, Read};
+
+use byteorder::ReadBytesExt;
+
+/// Bytes of the longest LEB128 encoding of a u32
+pub const MAX_VARINT_LEN: usize = 5;
+
+/// Bytes `write` takes for `x`
+#[must_use]
+pub fn len(x: u32) -> usize {
+    let bits = 32 - x.leading_zeros() as usize;
+    usize::max(1, bits.div_ceil(7))
+}
+
+/// Write `x` as LEB128 to the front of `buf`; returns the bytes written
+pub fn write(buf: &mut [u8], mut x: u32) -> usize {
+    let mut i = 0;
+    while 0x80 <= x {
+        buf[i] = x as u8 | 0x80;
+        x >>= 7;
+        i += 1;
+    }
+    buf[i] = x as u8;
+    i + 1
+}
+
+/// Read a LEB128 value that fits a u32
+pub fn read(rdr: &mut impl Read) -> io::Result<u32> {
+    let mut x = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let byte = rdr.read_u8()?;
+        let bits = (byte & 0x7f) as u32;
+        // the last byte carries the top 4 bits
+        if i == MAX_VARINT_LEN - 1 && 0x0f < byte {
+            break;
+        }
+        x |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(io::ErrorKind::InvalidData.into())
+}
+
+/// Map small negative and positive values to small unsigned ones
+#[must_use]
+pub fn zigzag(x: i32) -> u32 {
+    ((x << 1) ^ (x >> 31)) as u32
+}
+
+#[must_use]
+pub fn unzigzag(x: u32) -> i32 {
+    ((x >> 1) as i32) ^ -((x & 1) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{len, read, unzigzag, write, zigzag, MAX_VARINT_LEN};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let mut values = vec![0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX - 1, u32::MAX];
+        // xorshift32
+        let mut state: u32 = 0x9e3779b9;
+        for _ in 0..10000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            // spread over every encoded length
+            values.push(state >> (state % 32));
+        }
+        for x in values {
+            let mut buf = [0; MAX_VARINT_LEN + 1];
+            let n = write(&mut buf, x);
+            assert_eq!(n, len(x));
+            let mut rdr = Cursor::new(&buf[..]);
+            assert_eq!(read(&mut rdr).unwrap(), x);
+            assert_eq!(rdr.position() as usize, n);
+
+            let signed = x as i32;
+            assert_eq!(unzigzag(zigzag(signed)), signed);
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut buf = [0; MAX_VARINT_LEN];
+        assert_eq!(write(&mut buf, 300), 2);
+        assert_eq!(buf[..2], [0xac, 0x02]);
+        assert_eq!(len(0), 1);
+        assert_eq!(len(0x7f), 1);
+        assert_eq!(len(0x80), 2);
+        assert_eq!(len(u16::MAX as u32), 3);
+        assert_eq!(len(u32::MAX), MAX_VARINT_LEN);
+
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(i32::MIN), u32::MAX);
+
+        // cut short
+        assert!(read(&mut Cursor::new(&[0x80][..])).is_err());
+        // more than 32 bits
+        assert!(read(&mut Cursor::new(&[0xff, 0xff, 0xff, 0xff, 0x10][..])).is_err());
+        assert!(read(&mut Cursor::new(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00][..])).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use super::{BufSlice, BufWtr, OwnedBufWtr};
+use std::fmt;
 
 pub struct BufPasta {
     slices: Vec<BufSlice>,
@@ -10,6 +11,16 @@ pub enum Error {
     NotEnoughSpace,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotEnoughSpace => write!(f, "not enough space"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl BufPasta {
     #[inline]
     fn check_rep(&self) {
@@ -0,0 +1,64 @@
+use super::{BufWtr, OwnedBufWtr};
+use std::collections::HashMap;
+
+/// Free list of `OwnedBufWtr`s keyed by capacity
+#[derive(Default)]
+pub struct BufPool {
+    free: HashMap<usize, Vec<OwnedBufWtr>>,
+}
+
+impl BufPool {
+    #[must_use]
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// An empty writer of `capacity` bytes; a returned one is reused if there is any
+    #[must_use]
+    pub fn take(&mut self, capacity: usize) -> OwnedBufWtr {
+        match self.free.get_mut(&capacity).and_then(|free| free.pop()) {
+            Some(mut wtr) => {
+                wtr.reset_data(0);
+                wtr
+            }
+            None => OwnedBufWtr::new(capacity, 0),
+        }
+    }
+
+    /// Return `wtr` to be handed out again by `take`
+    pub fn put_back(&mut self, wtr: OwnedBufWtr) {
+        self.free.entry(wtr.capacity()).or_default().push(wtr);
+    }
+
+    /// Number of writers waiting to be reused
+    #[must_use]
+    pub fn free_count(&self) -> usize {
+        self.free.values().map(|free| free.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufPool;
+    use crate::utils::buf::BufWtr;
+
+    #[test]
+    fn test_reuse() {
+        let mut pool = BufPool::new();
+        let mut wtr = pool.take(8);
+        wtr.append(&[0, 1, 2]).unwrap();
+        let ptr = wtr.data().as_ptr();
+        pool.put_back(wtr);
+        assert_eq!(pool.free_count(), 1);
+
+        // another capacity
+        let other = pool.take(4);
+        assert_eq!(other.capacity(), 4);
+        assert_eq!(pool.free_count(), 1);
+
+        let wtr = pool.take(8);
+        assert!(wtr.is_empty());
+        assert_eq!(wtr.data().as_ptr(), ptr);
+        assert_eq!(pool.free_count(), 0);
+    }
+}
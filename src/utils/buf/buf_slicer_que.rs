@@ -1,64 +1,160 @@
 use super::BufSlice;
-use std::collections::VecDeque;
+use std::{collections::VecDeque, fmt, time::Instant};
 
 pub struct BufSlicerQue {
-    queue: VecDeque<BufSlice>,
-    len_cap: usize,
+    queue: VecDeque<(BufSlice, Option<Instant>)>,
+    // the front slice has been partly taken by `slice_front`
+    is_front_sliced: bool,
+    bytes_cap: usize,
+    len_cap: Option<usize>,
+    data_len: usize,
 }
 
 impl BufSlicerQue {
     fn check_rep(&self) {
-        assert!(self.queue.len() <= self.len_cap);
-        for slice in &self.queue {
+        if let Some(len_cap) = self.len_cap {
+            assert!(self.queue.len() <= len_cap);
+        }
+        assert!(self.data_len <= self.bytes_cap);
+        let mut data_len = 0;
+        assert!(!self.is_front_sliced || !self.queue.is_empty());
+        for (slice, _) in &self.queue {
             assert!(!slice.is_empty());
+            data_len += slice.len();
         }
+        assert_eq!(data_len, self.data_len);
     }
 
-    pub fn new(len_cap: usize) -> Self {
+    /// `bytes_cap` bounds the queued bytes; `len_cap` optionally bounds the number of slices
+    pub fn new(bytes_cap: usize, len_cap: Option<usize>) -> Self {
         let this = BufSlicerQue {
             queue: VecDeque::new(),
+            is_front_sliced: false,
+            bytes_cap,
             len_cap,
+            data_len: 0,
         };
         this.check_rep();
         this
     }
 
     pub fn push_back(&mut self, slice: BufSlice) -> Result<(), PushError<BufSlice>> {
-        if self.is_full() {
+        self.push_back_by(slice, None)
+    }
+
+    /// Same as `push_back` but `remove_expired` drops `slice` once `deadline` has passed
+    pub fn push_back_with_deadline(
+        &mut self,
+        slice: BufSlice,
+        deadline: Instant,
+    ) -> Result<(), PushError<BufSlice>> {
+        self.push_back_by(slice, Some(deadline))
+    }
+
+    fn push_back_by(
+        &mut self,
+        slice: BufSlice,
+        deadline: Option<Instant>,
+    ) -> Result<(), PushError<BufSlice>> {
+        if self.is_full() || self.free_bytes() < slice.len() {
             return Err(PushError(slice));
         }
         if slice.is_empty() {
             return Ok(());
         }
 
-        self.queue.push_back(slice);
+        self.data_len += slice.len();
+        self.queue.push_back((slice, deadline));
         self.check_rep();
         Ok(())
     }
 
     pub fn slice_front(&mut self, max_len: usize) -> Result<BufSlice, Error> {
-        let slice = match self.queue.pop_front() {
+        let (slice, deadline) = match self.queue.pop_front() {
             Some(x) => x,
             None => return Err(Error::NothingToSlice),
         };
         if slice.len() <= max_len {
+            self.data_len -= slice.len();
+            self.is_front_sliced = false;
             self.check_rep();
             Ok(slice)
         } else {
             let mut slice = slice;
             let front = slice.pop_front(max_len).unwrap();
-            self.queue.push_front(slice);
+            self.data_len -= front.len();
+            self.queue.push_front((slice, deadline));
+            self.is_front_sliced = true;
             self.check_rep();
             Ok(front)
         }
     }
 
+    /// Drop the slices whose deadline is before `now`
+    ///
+    /// A slice partly taken by `slice_front` is kept. Returns the number of slices dropped.
+    pub fn remove_expired(&mut self, now: Instant) -> usize {
+        let kept_front = match self.is_front_sliced {
+            true => self.queue.pop_front(),
+            false => None,
+        };
+        let len = self.queue.len();
+        let mut data_len = 0;
+        self.queue.retain(|(slice, deadline)| match deadline {
+            Some(deadline) if *deadline < now => {
+                data_len += slice.len();
+                false
+            }
+            _ => true,
+        });
+        self.data_len -= data_len;
+        let removed = len - self.queue.len();
+        if let Some(front) = kept_front {
+            self.queue.push_front(front);
+        }
+        self.check_rep();
+        removed
+    }
+
+    /// Drop everything queued
+    ///
+    /// Returns the number of bytes dropped.
+    pub fn clear(&mut self) -> usize {
+        let data_len = self.data_len;
+        self.queue.clear();
+        self.is_front_sliced = false;
+        self.data_len = 0;
+        self.check_rep();
+        data_len
+    }
+
+    /// Unit: byte
+    #[must_use]
+    pub fn data_len(&self) -> usize {
+        self.data_len
+    }
+
+    /// Number of non-empty slices that can still be pushed
+    #[must_use]
+    pub fn free_len(&self) -> usize {
+        match self.len_cap {
+            Some(len_cap) => len_cap - self.queue.len(),
+            None => usize::MAX,
+        }
+    }
+
+    /// Unit: byte
+    #[must_use]
+    pub fn free_bytes(&self) -> usize {
+        self.bytes_cap - self.data_len
+    }
+
     pub fn is_empty(&self) -> bool {
         self.queue.is_empty()
     }
 
     pub fn is_full(&self) -> bool {
-        self.queue.len() == self.len_cap
+        self.free_bytes() == 0 || self.free_len() == 0
     }
 }
 
@@ -67,32 +163,128 @@ pub enum Error {
     NothingToSlice,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NothingToSlice => write!(f, "nothing to slice"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 pub struct PushError<T>(pub T);
 
+/// Leaves out the rejected slice
+impl<T> fmt::Debug for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PushError").finish_non_exhaustive()
+    }
+}
+
+impl<T> fmt::Display for PushError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "queue full")
+    }
+}
+
+impl<T> std::error::Error for PushError<T> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test1() {
-        let mut slicer = BufSlicerQue::new(2);
+        let mut slicer = BufSlicerQue::new(usize::MAX, Some(2));
 
         let slice1 = BufSlice::from_bytes(vec![0]);
         let slice2 = BufSlice::from_bytes(vec![1, 2]);
 
+        assert_eq!(slicer.free_len(), 2);
         slicer.push_back(slice1).map_err(|_| ()).unwrap();
         slicer.push_back(slice2).map_err(|_| ()).unwrap();
         assert!(slicer.is_full());
+        assert_eq!(slicer.free_len(), 0);
+        assert_eq!(slicer.data_len(), 3);
 
         let slice1 = slicer.slice_front(2).unwrap();
         assert_eq!(slice1.data(), vec![0]);
+        assert_eq!(slicer.data_len(), 2);
 
         let slice2 = slicer.slice_front(1).unwrap();
         assert_eq!(slice2.data(), vec![1]);
+        assert_eq!(slicer.data_len(), 1);
 
         let slice3 = slicer.slice_front(2).unwrap();
         assert_eq!(slice3.data(), vec![2]);
+        assert_eq!(slicer.data_len(), 0);
+
+        assert!(slicer.is_empty());
+    }
+
+    #[test]
+    fn test_bytes_cap() {
+        let mut slicer = BufSlicerQue::new(3, None);
+
+        slicer
+            .push_back(BufSlice::from_bytes(vec![0, 1]))
+            .map_err(|_| ())
+            .unwrap();
+        assert!(!slicer.is_full());
+        assert_eq!(slicer.free_bytes(), 1);
+
+        let slice = match slicer.push_back(BufSlice::from_bytes(vec![2, 3])) {
+            Err(PushError(slice)) => slice,
+            Ok(()) => panic!(),
+        };
+        assert_eq!(slice.data(), vec![2, 3]);
+
+        slicer
+            .push_back(BufSlice::from_bytes(vec![2]))
+            .map_err(|_| ())
+            .unwrap();
+        assert!(slicer.is_full());
 
+        let _ = slicer.slice_front(1).unwrap();
+        assert!(!slicer.is_full());
+        assert_eq!(slicer.free_bytes(), 1);
+
+        assert_eq!(slicer.clear(), 2);
         assert!(slicer.is_empty());
+        assert_eq!(slicer.free_bytes(), 3);
+    }
+
+    #[test]
+    fn test_remove_expired() {
+        let now = Instant::now();
+        let later = now + std::time::Duration::from_secs(1);
+        let mut slicer = BufSlicerQue::new(usize::MAX, None);
+
+        slicer
+            .push_back_with_deadline(BufSlice::from_bytes(vec![0, 1]), now)
+            .map_err(|_| ())
+            .unwrap();
+        slicer
+            .push_back_with_deadline(BufSlice::from_bytes(vec![2]), now)
+            .map_err(|_| ())
+            .unwrap();
+        slicer
+            .push_back(BufSlice::from_bytes(vec![3]))
+            .map_err(|_| ())
+            .unwrap();
+        slicer
+            .push_back_with_deadline(BufSlice::from_bytes(vec![4]), later)
+            .map_err(|_| ())
+            .unwrap();
+        assert_eq!(slicer.remove_expired(now), 0);
+
+        // the partly taken front stays
+        assert_eq!(slicer.slice_front(1).unwrap().data(), vec![0]);
+        assert_eq!(slicer.remove_expired(later), 1);
+        assert_eq!(slicer.data_len(), 3);
+        assert_eq!(slicer.slice_front(9).unwrap().data(), vec![1]);
+        assert_eq!(slicer.slice_front(9).unwrap().data(), vec![3]);
+        assert_eq!(slicer.slice_front(9).unwrap().data(), vec![4]);
     }
 }
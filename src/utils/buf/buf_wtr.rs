@@ -1,8 +1,20 @@
+use std::fmt;
+
 #[derive(Debug)]
 pub enum Error {
     NotEnoughSpace,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotEnoughSpace => write!(f, "not enough space"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 pub trait BufWtr {
     fn data_len(&self) -> usize;
     fn front_len(&self) -> usize;
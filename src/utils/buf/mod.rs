@@ -1,4 +1,5 @@
 mod buf_pasta;
+mod buf_pool;
 mod buf_slice;
 mod buf_slicer_que;
 mod buf_wtr;
@@ -6,6 +7,7 @@ mod owned_buf_wtr;
 mod sub_buf_wtr;
 
 pub use buf_pasta::*;
+pub use buf_pool::*;
 pub use buf_slice::*;
 pub use buf_slicer_que::*;
 pub use buf_wtr::*;
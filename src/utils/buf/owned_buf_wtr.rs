@@ -15,11 +15,17 @@ impl OwnedBufWtr {
         assert!(self.start <= self.end);
         assert!(self.end <= self.buf.len());
     }
+    /// # Panics
+    ///
+    /// If `start..end` is not a range within `buf`.
     pub fn from_bytes(buf: Vec<u8>, start: usize, end: usize) -> Self {
         let this = Self { buf, start, end };
         this.check_rep();
         this
     }
+    /// # Panics
+    ///
+    /// If `len < start`.
     pub fn new(len: usize, start: usize) -> Self {
         let this = Self {
             buf: vec![0; len],
@@ -29,6 +35,12 @@ impl OwnedBufWtr {
         this.check_rep();
         this
     }
+    /// Unit: byte
+    #[must_use]
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
     #[inline]
     pub fn assign(&mut self, other: OwnedBufWtr) {
         self.buf = other.buf;
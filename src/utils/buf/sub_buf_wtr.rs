@@ -13,11 +13,17 @@ impl<'a> SubBufWtr<'a> {
         assert!(self.start <= self.end);
         assert!(self.end <= self.buf.len());
     }
+    /// # Panics
+    ///
+    /// If `start..end` is not a range within `buf`.
     pub fn from_vec(buf: &'a mut [u8], start: usize, end: usize) -> Self {
         let this = Self { buf, start, end };
         this.check_rep();
         this
     }
+    /// # Panics
+    ///
+    /// If `buf.len() < start`.
     pub fn new(buf: &'a mut [u8], start: usize) -> Self {
         let this = Self {
             buf,
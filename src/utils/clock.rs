@@ -0,0 +1,59 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Source of the `now` passed to `Uploader` and `SharedUploader`
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// `Instant::now`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdClock;
+
+impl Clock for StdClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Stands still until `advance`d; for deterministic tests and simulations
+pub struct ManualClock {
+    now: Mutex<Instant>,
+}
+
+impl ManualClock {
+    #[must_use]
+    pub fn new(start: Instant) -> Self {
+        ManualClock {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, ManualClock};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_manual_clock() {
+        let start = Instant::now();
+        let clock = ManualClock::new(start);
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(5));
+        assert_eq!(clock.now(), start + Duration::from_millis(5));
+    }
+}
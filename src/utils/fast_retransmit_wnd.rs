@@ -1,5 +1,4 @@
-use crate::utils::{dup::DuplicateThreshold, Seq};
-use std::ops::Range;
+use crate::utils::Seq;
 
 pub struct FastRetransmissionWnd<TSeq>
 where
@@ -7,7 +6,11 @@ where
 {
     start: TSeq,
     end: TSeq, // exclusive
-    duplicate_threshold: DuplicateThreshold<TSeq>,
+
+    // duplicate nacks
+    last_nack: Option<TSeq>,
+    dup_count: usize,
+    dup_threshold_to_activate: usize, // activation: inclusive
 }
 
 impl<TSeq> FastRetransmissionWnd<TSeq>
@@ -22,17 +25,16 @@ where
         let this = FastRetransmissionWnd {
             start: Seq::zero(),
             end: Seq::zero(),
-            duplicate_threshold: DuplicateThreshold::new(
-                Seq::zero(),
-                nack_duplicate_limit_to_activate,
-            ),
+            last_nack: None,
+            dup_count: 0,
+            dup_threshold_to_activate: nack_duplicate_limit_to_activate,
         };
         this.check_rep();
         this
     }
 
     pub fn contains(&self, seq: TSeq) -> bool {
-        self.start <= seq && seq < self.end
+        self.start <= seq && self.start.dist(&seq) < self.len()
     }
 
     pub fn start(&self) -> TSeq {
@@ -43,24 +45,69 @@ where
         self.end
     }
 
+    /// Unit: sequence
+    pub fn len(&self) -> usize {
+        self.start.dist(&self.end)
+    }
+
     pub fn is_empty(&self) -> bool {
-        self.end == self.start
+        self.len() == 0
     }
 
+    /// # Panics
+    ///
+    /// If the window does not contain `seq`.
     pub fn retransmitted(&mut self, seq: TSeq) {
         assert!(self.contains(seq));
         self.start = seq.add_usize(1);
         self.check_rep();
     }
 
-    pub fn try_set_boundaries(&mut self, range: Range<TSeq>) {
-        assert!(range.start <= range.end);
-        self.duplicate_threshold.set(range.start);
-        if self.duplicate_threshold.is_activated() {
-            self.start = range.start;
-            self.end = range.end;
-            self.duplicate_threshold.recount();
+    /// Drop the sequences before `seq` from the window
+    ///
+    /// # Panics
+    ///
+    /// If `seq` is outside of `start..=end`.
+    pub fn skip_to(&mut self, seq: TSeq) {
+        assert!(self.start <= seq && self.start.dist(&seq) <= self.len());
+        self.start = seq;
+        self.check_rep();
+    }
+
+    /// Count a nack and open the window once it is duplicated enough times
+    ///
+    /// `max_acked` is the largest out-of-order ack arriving with `nack`. The count restarts
+    /// whenever the nack advances and only grows when the same nack comes with an ack after it.
+    /// Nacks older than the last one are ignored.
+    pub fn on_nack(&mut self, nack: TSeq, max_acked: Option<TSeq>) {
+        let max_acked = max_acked.filter(|&x| nack < x);
+        match self.last_nack {
+            Some(last_nack) if nack < last_nack => {
+                self.check_rep();
+                return;
+            }
+            Some(last_nack) if nack == last_nack => {
+                if max_acked.is_some() {
+                    self.dup_count += 1;
+                }
+            }
+            _ => {
+                self.last_nack = Some(nack);
+                self.dup_count = 0;
+            }
+        }
+        if let Some(max_acked) = max_acked {
+            if self.dup_threshold_to_activate <= self.dup_count {
+                self.start = nack;
+                self.end = max_acked;
+                self.dup_count = 0;
+            }
         }
         self.check_rep();
     }
+
+    /// Duplicates of the last nack counted so far
+    pub fn dup_count(&self) -> usize {
+        self.dup_count
+    }
 }
@@ -0,0 +1,175 @@
+use std::{fmt, ops::Deref, slice};
+
+/// A list keeping its first `N` items inline and spilling to the heap beyond that
+pub struct InlineVec<T, const N: usize> {
+    inline: [T; N],
+    // all the items once more than `N`
+    heap: Vec<T>,
+    len: usize,
+}
+
+impl<T: Copy + Default, const N: usize> InlineVec<T, N> {
+    fn check_rep(&self) {
+        assert_eq!(self.heap.is_empty(), self.len <= N);
+        if N < self.len {
+            assert_eq!(self.heap.len(), self.len);
+        }
+    }
+
+    #[must_use]
+    pub fn new() -> Self {
+        let this = InlineVec {
+            inline: [T::default(); N],
+            heap: Vec::new(),
+            len: 0,
+        };
+        this.check_rep();
+        this
+    }
+
+    pub fn push(&mut self, item: T) {
+        if self.len < N {
+            self.inline[self.len] = item;
+        } else {
+            if self.len == N {
+                self.heap.reserve(N * 2);
+                self.heap.extend_from_slice(&self.inline);
+            }
+            self.heap.push(item);
+        }
+        self.len += 1;
+        self.check_rep();
+    }
+
+    #[must_use]
+    pub fn as_slice(&self) -> &[T] {
+        match self.is_spilled() {
+            true => &self.heap,
+            false => &self.inline[..self.len],
+        }
+    }
+
+    /// Whether the items have moved to the heap
+    #[must_use]
+    pub fn is_spilled(&self) -> bool {
+        N < self.len
+    }
+}
+
+impl<T: Copy + Default, const N: usize> Default for InlineVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Copy + Default, const N: usize> Deref for InlineVec<T, N> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Copy + Default, const N: usize> Clone for InlineVec<T, N> {
+    fn clone(&self) -> Self {
+        self.iter().copied().collect()
+    }
+}
+
+impl<T: Copy + Default + fmt::Debug, const N: usize> fmt::Debug for InlineVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Copy + Default + PartialEq, const N: usize> PartialEq for InlineVec<T, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl<T: Copy + Default + Eq, const N: usize> Eq for InlineVec<T, N> {}
+
+impl<T: Copy + Default, const N: usize> Extend<T> for InlineVec<T, N> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<T: Copy + Default, const N: usize> FromIterator<T> for InlineVec<T, N> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut this = Self::new();
+        this.extend(iter);
+        this
+    }
+}
+
+impl<T: Copy + Default, const N: usize, const M: usize> From<[T; M]> for InlineVec<T, N> {
+    fn from(items: [T; M]) -> Self {
+        items.into_iter().collect()
+    }
+}
+
+impl<T: Copy + Default, const N: usize> From<Vec<T>> for InlineVec<T, N> {
+    fn from(items: Vec<T>) -> Self {
+        items.into_iter().collect()
+    }
+}
+
+impl<'a, T: Copy + Default, const N: usize> IntoIterator for &'a InlineVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.as_slice().iter()
+    }
+}
+
+impl<T: Copy + Default, const N: usize> IntoIterator for InlineVec<T, N> {
+    type Item = T;
+    type IntoIter = IntoIter<T, N>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            list: self,
+            next: 0,
+        }
+    }
+}
+
+pub struct IntoIter<T, const N: usize> {
+    list: InlineVec<T, N>,
+    next: usize,
+}
+
+impl<T: Copy + Default, const N: usize> Iterator for IntoIter<T, N> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let item = self.list.as_slice().get(self.next).copied();
+        self.next += 1;
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InlineVec;
+
+    #[test]
+    fn test_spill() {
+        let mut list = InlineVec::<u32, 2>::new();
+        assert!(list.is_empty());
+        list.push(0);
+        list.push(1);
+        assert!(!list.is_spilled());
+        assert_eq!(list.as_slice(), [0, 1]);
+        list.push(2);
+        assert!(list.is_spilled());
+        assert_eq!(list.as_slice(), [0, 1, 2]);
+        assert_eq!(list.clone().into_iter().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(list, InlineVec::from([0, 1, 2]));
+    }
+}
@@ -1,13 +1,21 @@
 pub mod buf;
+mod clock;
 pub mod dup;
 mod fast_retransmit_wnd;
+mod inline_vec;
 mod recv_buf;
 mod seq;
 mod seq32;
+mod seq64;
 mod swnd;
+mod windowed_min;
 
+pub use clock::*;
 pub use fast_retransmit_wnd::*;
+pub use inline_vec::*;
 pub use recv_buf::*;
 pub use seq::*;
 pub use seq32::*;
+pub use seq64::*;
 pub use swnd::*;
+pub use windowed_min::*;
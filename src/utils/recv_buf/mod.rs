@@ -9,3 +9,9 @@ pub enum SeqLocationToRwnd {
     TooLate,
     TooEarly,
 }
+
+pub struct Insertion {
+    pub location: SeqLocationToRwnd,
+    /// A value already waited in the window under the same seq and got replaced
+    pub is_replacement: bool,
+}
@@ -1,6 +1,6 @@
-use super::{rwnd::Rwnd, SeqLocationToRwnd};
+use super::{rwnd::Rwnd, Insertion, SeqLocationToRwnd};
 use crate::utils::Seq;
-use std::collections::VecDeque;
+use std::{collections::VecDeque, ops::Range};
 
 pub struct RecvBuf<TSeq, T>
 where
@@ -9,6 +9,9 @@ where
     rwnd: Rwnd<TSeq, T>,
     sorted: VecDeque<T>,
     len: usize,
+    // sum of `bytes_of` over `sorted`
+    ready_bytes: usize,
+    bytes_of: fn(&T) -> usize,
 }
 
 impl<TSeq, T> RecvBuf<TSeq, T>
@@ -18,14 +21,30 @@ where
     fn check_rep(&self) {
         let ofo_len = self.rwnd.size();
         assert_eq!(ofo_len + self.sorted.len(), self.len);
+        let ready_bytes: usize = self.sorted.iter().map(self.bytes_of).sum();
+        assert_eq!(ready_bytes, self.ready_bytes);
     }
 
     #[must_use]
     pub fn new(len: usize) -> Self {
+        Self::new_at(len, TSeq::zero())
+    }
+
+    /// `start` is the first sequence to receive
+    #[must_use]
+    pub fn new_at(len: usize, start: TSeq) -> Self {
+        Self::new_at_counted(len, start, |_| 0)
+    }
+
+    /// `bytes_of` gives the size of a value counted by `ready_bytes`
+    #[must_use]
+    pub fn new_at_counted(len: usize, start: TSeq, bytes_of: fn(&T) -> usize) -> Self {
         let this = RecvBuf {
-            rwnd: Rwnd::new(len),
+            rwnd: Rwnd::new_at(len, start),
             sorted: VecDeque::new(),
             len,
+            ready_bytes: 0,
+            bytes_of,
         };
         this.check_rep();
         this
@@ -34,6 +53,7 @@ where
     #[must_use]
     pub fn pop_front(&mut self) -> Option<T> {
         if let Some(x) = self.sorted.pop_front() {
+            self.ready_bytes -= (self.bytes_of)(&x);
             self.rwnd.increment_size();
             self.check_rep();
             Some(x)
@@ -44,11 +64,12 @@ where
     }
 
     #[must_use]
-    pub fn insert(&mut self, seq: TSeq, v: T) -> SeqLocationToRwnd {
+    pub fn insert(&mut self, seq: TSeq, v: T) -> Insertion {
         let location = self.rwnd.location(seq);
+        let mut is_replacement = false;
         match location {
             SeqLocationToRwnd::InRecvWindow => {
-                self.rwnd.insert(seq, v);
+                is_replacement = self.rwnd.insert(seq, v).is_some();
             }
             SeqLocationToRwnd::TooLate => (),
             SeqLocationToRwnd::TooEarly => (),
@@ -56,15 +77,26 @@ where
                 // skip inserting this consecutive fragment to rwnd
                 // hot path
                 let v = self.rwnd.insert_then_pop_next(seq, v).unwrap();
+                self.ready_bytes += (self.bytes_of)(&v);
                 self.sorted.push_back(v);
 
                 while let Some(v) = self.rwnd.pop_next() {
+                    self.ready_bytes += (self.bytes_of)(&v);
                     self.sorted.push_back(v);
                 }
             }
         }
         self.check_rep();
-        location
+        Insertion {
+            location,
+            is_replacement,
+        }
+    }
+
+    /// Whether `seq` waits in the window for the gap before it to be filled
+    #[must_use]
+    pub fn contains(&self, seq: TSeq) -> bool {
+        self.rwnd.contains(seq)
     }
 
     #[must_use]
@@ -76,6 +108,46 @@ where
     pub fn rwnd_size(&self) -> usize {
         self.rwnd.size()
     }
+
+    /// Number of out-of-order values waiting for the gap before them to be filled
+    #[must_use]
+    pub fn buffered_count(&self) -> usize {
+        self.rwnd.buffered_count()
+    }
+
+    /// Number of in-order values ready to pop
+    #[must_use]
+    pub fn ready_count(&self) -> usize {
+        self.sorted.len()
+    }
+
+    /// Sum of `bytes_of` over the values ready to pop
+    #[must_use]
+    pub fn ready_bytes(&self) -> usize {
+        self.ready_bytes
+    }
+
+    /// The in-order values ready to pop, front first
+    pub fn ready(&self) -> impl Iterator<Item = &T> {
+        self.sorted.iter()
+    }
+
+    #[must_use]
+    pub fn last_buffered_seq(&self) -> Option<TSeq> {
+        self.rwnd.last_buffered_seq()
+    }
+
+    /// The runs of consecutive seqs waiting for the gaps before them, in order
+    #[must_use]
+    pub fn buffered_ranges(&self) -> Vec<Range<TSeq>> {
+        self.rwnd.buffered_ranges()
+    }
+
+    /// The seqs not received yet below the highest buffered one, in order
+    #[must_use]
+    pub fn missing_ranges(&self) -> Vec<Range<TSeq>> {
+        self.rwnd.missing_ranges()
+    }
 }
 
 #[cfg(test)]
@@ -94,14 +166,14 @@ mod tests {
 
         assert!(buf.pop_front().is_none());
 
-        let location = buf.insert(Seq32::from_u32(5), 5);
+        let location = buf.insert(Seq32::from_u32(5), 5).location;
 
         match location {
             SeqLocationToRwnd::TooEarly => (),
             _ => panic!(),
         }
 
-        let location = buf.insert(Seq32::from_u32(1), 1);
+        let location = buf.insert(Seq32::from_u32(1), 1).location;
 
         //         0  1  2  3  4
         // rwnd   [   1         ]
@@ -111,10 +183,12 @@ mod tests {
             SeqLocationToRwnd::InRecvWindow => (),
             _ => panic!(),
         }
+        assert_eq!(buf.buffered_count(), 1);
+        assert_eq!(buf.last_buffered_seq(), Some(Seq32::from_u32(1)));
 
         assert!(buf.pop_front().is_none());
 
-        let location = buf.insert(Seq32::from_u32(0), 0);
+        let location = buf.insert(Seq32::from_u32(0), 0).location;
 
         //         0  1  2  3  4
         // rwnd         [       ]
@@ -124,6 +198,8 @@ mod tests {
             SeqLocationToRwnd::AtRecvWindowStart => (),
             _ => panic!(),
         }
+        assert_eq!(buf.buffered_count(), 0);
+        assert_eq!(buf.last_buffered_seq(), None);
 
         assert_eq!(buf.pop_front().unwrap(), 0);
 
@@ -131,14 +207,14 @@ mod tests {
         // rwnd         [          ]
         // sorted    [1]
 
-        let location = buf.insert(Seq32::from_u32(1), 1);
+        let location = buf.insert(Seq32::from_u32(1), 1).location;
 
         match location {
             SeqLocationToRwnd::TooLate => (),
             _ => panic!(),
         }
 
-        let location = buf.insert(Seq32::from_u32(5), 5);
+        let location = buf.insert(Seq32::from_u32(5), 5).location;
 
         //         0  1  2  3  4  5
         // rwnd         [         5]
@@ -155,4 +231,57 @@ mod tests {
         // rwnd         [         5   ]
         // sorted      ][
     }
+
+    #[test]
+    fn test_missing_ranges() {
+        let mut buf = RecvBuf::new(8);
+        assert!(buf.missing_ranges().is_empty());
+
+        for seq in [2, 3, 6] {
+            let _ = buf.insert(Seq32::from_u32(seq), seq);
+        }
+
+        //         0  1  2  3  4  5  6  7
+        // rwnd   [      2  3        6   ]
+        // sorted I
+
+        assert_eq!(
+            buf.missing_ranges(),
+            vec![
+                Seq32::from_u32(0)..Seq32::from_u32(2),
+                Seq32::from_u32(4)..Seq32::from_u32(6),
+            ]
+        );
+
+        let _ = buf.insert(Seq32::from_u32(0), 0);
+        let _ = buf.insert(Seq32::from_u32(5), 5);
+
+        //         0  1  2  3  4  5  6  7
+        // rwnd      [   2  3     5  6   ]
+        // sorted [0]
+
+        assert_eq!(
+            buf.missing_ranges(),
+            vec![
+                Seq32::from_u32(1)..Seq32::from_u32(2),
+                Seq32::from_u32(4)..Seq32::from_u32(5),
+            ]
+        );
+        assert_eq!(
+            buf.buffered_ranges(),
+            vec![
+                Seq32::from_u32(2)..Seq32::from_u32(4),
+                Seq32::from_u32(5)..Seq32::from_u32(7),
+            ]
+        );
+
+        let _ = buf.insert(Seq32::from_u32(1), 1);
+        let _ = buf.insert(Seq32::from_u32(4), 4);
+
+        //         0  1  2  3  4  5  6  7
+        // rwnd                        [   ]
+        // sorted [0  1  2  3  4  5  6]
+
+        assert!(buf.missing_ranges().is_empty());
+    }
 }
@@ -1,6 +1,6 @@
 use super::SeqLocationToRwnd;
 use crate::utils::Seq;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, ops::Range};
 
 pub struct Rwnd<TSeq, T>
 where
@@ -24,11 +24,11 @@ where
     }
 
     #[must_use]
-    pub fn new(size: usize) -> Self {
+    pub fn new_at(size: usize, start: TSeq) -> Self {
         let this = Rwnd {
             wnd: BTreeMap::new(),
             size,
-            start: TSeq::zero(),
+            start,
         };
         this.check_rep();
         this
@@ -52,6 +52,55 @@ where
         self.start
     }
 
+    /// Number of out-of-order values waiting in the window
+    #[must_use]
+    #[inline]
+    pub fn buffered_count(&self) -> usize {
+        self.wnd.len()
+    }
+
+    /// The highest sequence waiting in the window
+    ///
+    /// All keys lie within `start..start + size`, so the map order agrees with the wraparound order.
+    #[must_use]
+    #[inline]
+    pub fn last_buffered_seq(&self) -> Option<TSeq> {
+        self.wnd.keys().next_back().copied()
+    }
+
+    /// The gaps from `start` up to the highest sequence waiting in the window
+    #[must_use]
+    pub fn missing_ranges(&self) -> Vec<Range<TSeq>> {
+        let mut ranges = Vec::new();
+        let mut next = self.start;
+        for &seq in self.wnd.keys() {
+            if next < seq {
+                ranges.push(next..seq);
+            }
+            next = seq.add_usize(1);
+        }
+        ranges
+    }
+
+    /// The runs of consecutive sequences waiting in the window, in order
+    #[must_use]
+    pub fn buffered_ranges(&self) -> Vec<Range<TSeq>> {
+        let mut ranges: Vec<Range<TSeq>> = Vec::new();
+        for &seq in self.wnd.keys() {
+            match ranges.last_mut() {
+                Some(range) if range.end == seq => range.end = seq.add_usize(1),
+                _ => ranges.push(seq..seq.add_usize(1)),
+            }
+        }
+        ranges
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn contains(&self, seq: TSeq) -> bool {
+        self.wnd.contains_key(&seq)
+    }
+
     #[must_use]
     #[inline]
     pub fn is_acceptable(&self, seq: TSeq) -> bool {
@@ -133,7 +182,7 @@ mod tests {
 
     #[test]
     fn test1() {
-        let mut rwnd = Rwnd::new(4);
+        let mut rwnd = Rwnd::new_at(4, Seq32::from_u32(0));
         rwnd.insert(Seq32::from_u32(2), 2);
         // _ _ 2 _
         assert_eq!(rwnd.size, 4);
@@ -142,6 +191,9 @@ mod tests {
         // 0 _ 2 _
         assert_eq!(rwnd.size, 4);
 
+        assert_eq!(rwnd.buffered_count(), 2);
+        assert_eq!(rwnd.last_buffered_seq(), Some(Seq32::from_u32(2)));
+
         let zero = rwnd.pop_next().unwrap();
         assert_eq!(zero, 0);
         // _ 2 _
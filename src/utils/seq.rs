@@ -1,7 +1,29 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, hash::Hash};
 
-pub trait Seq: PartialOrd + Ord + Copy + Debug {
+/// A sequence number that wraps around
+///
+/// `Ord` compares the two ways round the wrap point and picks the shorter one, so `min` and
+/// `max` from `Ord` hold across it as well.
+pub trait Seq: Ord + Copy + Debug + Hash {
     fn add_usize(&self, n: usize) -> Self;
+    /// Increments from `other` forward to `self`
     fn sub(&self, other: &Self) -> usize;
     fn zero() -> Self;
+
+    /// Increments between the two, whichever comes first
+    fn dist(&self, other: &Self) -> usize {
+        match self < other {
+            true => other.sub(self),
+            false => self.sub(other),
+        }
+    }
+
+    /// The sequences of `start..end`, across the wrap point; none unless `start < end`
+    fn range_iter(start: Self, end: Self) -> impl Iterator<Item = Self> {
+        let len = match start < end {
+            true => end.sub(&start),
+            false => 0,
+        };
+        (0..len).map(move |i| start.add_usize(i))
+    }
 }
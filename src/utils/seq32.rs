@@ -1,7 +1,7 @@
 use crate::utils::Seq;
 use std::{cmp::Ordering, num::Wrapping};
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
 pub struct Seq32 {
     n: u32,
 }
@@ -20,11 +20,7 @@ impl Seq32 {
     }
 
     pub fn max(lhs: Seq32, rhs: Seq32) -> Seq32 {
-        if lhs < rhs {
-            rhs
-        } else {
-            lhs
-        }
+        Ord::max(lhs, rhs)
     }
 }
 
@@ -143,4 +139,35 @@ mod tests {
         let b = Seq32::from_u32(1);
         assert_eq!(a.sub(&b), 2);
     }
+
+    #[test]
+    fn dist_wraparound() {
+        let a = Seq32::from_u32(u32::MAX - 1);
+        let b = Seq32::from_u32(2);
+        assert_eq!(a.dist(&b), 4);
+        assert_eq!(b.dist(&a), 4);
+        assert_eq!(a.dist(&a), 0);
+    }
+
+    #[test]
+    fn range_iter_wraparound() {
+        let start = Seq32::from_u32(u32::MAX - 1);
+        let end = Seq32::from_u32(2);
+        let seqs: Vec<u32> = Seq32::range_iter(start, end).map(|x| x.to_u32()).collect();
+        assert_eq!(seqs, [u32::MAX - 1, u32::MAX, 0, 1]);
+
+        // empty the other way round
+        assert_eq!(Seq32::range_iter(end, start).count(), 0);
+        assert_eq!(Seq32::range_iter(start, start).count(), 0);
+    }
+
+    #[test]
+    fn min_max_wraparound() {
+        let a = Seq32::from_u32(u32::MAX);
+        let b = Seq32::from_u32(0);
+        assert_eq!(Ord::max(a, b), b);
+        assert_eq!(Ord::min(a, b), a);
+        assert_eq!(Seq32::max(a, b), b);
+        assert_eq!([b, a].into_iter().max(), Some(b));
+    }
 }
@@ -0,0 +1,180 @@
+use crate::utils::Seq;
+use std::{cmp::Ordering, num::Wrapping};
+
+/// A sequence number that takes 2^63 values to wrap where `Seq32` takes 2^31
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct Seq64 {
+    n: u64,
+}
+
+impl Seq64 {
+    pub fn from_u64(n: u64) -> Self {
+        Seq64 { n }
+    }
+
+    pub fn to_u64(&self) -> u64 {
+        self.n
+    }
+
+    pub fn increment(&mut self) {
+        *self = self.add_usize(1);
+    }
+
+    pub fn max(lhs: Seq64, rhs: Seq64) -> Seq64 {
+        Ord::max(lhs, rhs)
+    }
+}
+
+impl Seq for Seq64 {
+    fn add_usize(&self, n: usize) -> Self {
+        let s = Wrapping(self.n) + Wrapping(n as u64);
+        Seq64 { n: s.0 }
+    }
+
+    fn sub(&self, other: &Self) -> usize {
+        let s = Wrapping(self.n) - Wrapping(other.n);
+        s.0 as usize
+    }
+
+    fn zero() -> Self {
+        Seq64::from_u64(0)
+    }
+}
+
+impl PartialOrd for Seq64 {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Seq64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match self.n.cmp(&other.n) {
+            Ordering::Less => match other.n - self.n <= u64::MAX / 2 {
+                true => Ordering::Less,
+                false => Ordering::Greater,
+            },
+            Ordering::Equal => Ordering::Equal,
+            Ordering::Greater => match self.n - other.n <= u64::MAX / 2 {
+                true => Ordering::Greater,
+                false => Ordering::Less,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::{RecvBuf, Seq, Swnd};
+
+    use super::Seq64;
+
+    #[test]
+    fn cmp_wraparound() {
+        let a = Seq64::from_u64(u64::MAX);
+        let b = Seq64::from_u64(u64::MIN);
+        assert!(a < b);
+    }
+
+    #[test]
+    fn cmp_far() {
+        let a = Seq64::from_u64(0);
+        let b = Seq64::from_u64(i64::MAX as u64);
+        let c = Seq64::from_u64(i64::MAX as u64 + 1);
+        assert!(a < b);
+        assert!(c < a);
+    }
+
+    #[test]
+    fn add_wraparound() {
+        let a = Seq64::from_u64(u64::MAX);
+        let b = a.add_usize(1);
+        assert_eq!(b.to_u64(), 0);
+    }
+
+    #[test]
+    fn sub_wraparound() {
+        let a = Seq64::from_u64(1);
+        let b = Seq64::from_u64(u64::MAX);
+        assert_eq!(a.sub(&b), 2);
+    }
+
+    #[test]
+    fn increment_wraparound() {
+        let mut a = Seq64::from_u64(u64::MAX);
+        a.increment();
+        assert_eq!(a.to_u64(), 0);
+        assert_eq!(Seq64::max(a, Seq64::from_u64(u64::MAX)), a);
+    }
+
+    #[test]
+    fn range_iter_wraparound() {
+        let start = Seq64::from_u64(u64::MAX);
+        let end = Seq64::from_u64(1);
+        let seqs: Vec<u64> = Seq64::range_iter(start, end).map(|x| x.to_u64()).collect();
+        assert_eq!(seqs, [u64::MAX, 0]);
+        assert_eq!(start.dist(&end), 2);
+        assert_eq!(Seq64::range_iter(end, start).count(), 0);
+    }
+
+    #[test]
+    fn cmp_near_wrap_point() {
+        // xorshift64
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            // a base within a short distance of either side of the wrap point
+            let base = Seq64::from_u64(u64::MAX - 32).add_usize((next() % 64) as usize);
+            // any distance up to half the space keeps the order
+            let n = (next() % (u64::MAX / 2).min(usize::MAX as u64)) as usize + 1;
+            let later = base.add_usize(n);
+            assert!(base < later);
+            assert!(later > base);
+            assert_eq!(later.sub(&base), n);
+            assert_eq!(Seq64::max(base, later), later);
+            // and the order is antisymmetric across the wrap point
+            assert_eq!(base.cmp(&later), later.cmp(&base).reverse());
+        }
+    }
+
+    #[test]
+    fn swnd() {
+        let start = Seq64::from_u64(u64::MAX - 1);
+        let mut wnd = Swnd::<Seq64, u64>::new_at(4, start);
+        wnd.set_remote_rwnd_size(4);
+        for i in 0..4 {
+            wnd.push_back(i);
+        }
+        assert!(wnd.is_full());
+        assert_eq!(wnd.end().to_u64(), 2);
+
+        // removes the two before the wrap point and the first after it
+        let removed = wnd.remove_before(Seq64::from_u64(1));
+        let removed: Vec<_> = removed.into_iter().map(|(_, v)| v).collect();
+        assert_eq!(removed, [0, 1, 2]);
+        assert_eq!(wnd.start().to_u64(), 1);
+        assert_eq!(wnd.size(), 1);
+    }
+
+    #[test]
+    fn recv_buf() {
+        let start = Seq64::from_u64(u64::MAX - 1);
+        let mut buf = RecvBuf::<Seq64, u64>::new_at(4, start);
+        let _ = buf.insert(Seq64::from_u64(0), 2);
+        let _ = buf.insert(Seq64::from_u64(u64::MAX), 1);
+        assert!(buf.pop_front().is_none());
+        let missing = buf.missing_ranges();
+        assert_eq!(missing, [start..Seq64::from_u64(u64::MAX)]);
+
+        let _ = buf.insert(start, 0);
+        for i in 0..3 {
+            assert_eq!(buf.pop_front(), Some(i));
+        }
+        assert!(buf.pop_front().is_none());
+    }
+}
@@ -16,7 +16,7 @@ where
     TSeq: Seq,
 {
     fn check_rep(&self) {
-        assert!(self.wnd.len() <= self.wnd_size_cap);
+        // `wnd` may outgrow `wnd_size_cap` after `set_wnd_size_cap` shrinks it
         assert!(self.start() <= self.end);
         for (&seq, _) in &self.wnd {
             assert!(seq < self.end);
@@ -25,24 +25,58 @@ where
 
     #[must_use]
     pub fn new(wnd_size_cap: usize) -> Self {
+        Self::new_at(wnd_size_cap, TSeq::zero())
+    }
+
+    /// The first pushed value will be assigned `start`
+    #[must_use]
+    pub fn new_at(wnd_size_cap: usize, start: TSeq) -> Self {
         let this = Swnd {
             wnd: BTreeMap::new(),
             remote_rwnd_size: 0,
-            end: TSeq::zero(),
+            end: start,
             wnd_size_cap,
         };
         this.check_rep();
         this
     }
 
+    pub fn value(&self, seq: &TSeq) -> Option<&T> {
+        self.wnd.get(seq)
+    }
+
     pub fn value_mut(&mut self, seq: &TSeq) -> Option<&mut T> {
         self.wnd.get_mut(&seq)
     }
 
+    #[must_use]
+    pub fn remote_rwnd_size(&self) -> usize {
+        self.remote_rwnd_size
+    }
+
     pub fn set_remote_rwnd_size(&mut self, n: usize) {
         self.remote_rwnd_size = n;
     }
 
+    #[must_use]
+    pub fn wnd_size_cap(&self) -> usize {
+        self.wnd_size_cap
+    }
+
+    /// Values already in the window stay; `push_back` waits until the window drains below `cap`
+    pub fn set_wnd_size_cap(&mut self, cap: usize) {
+        self.wnd_size_cap = cap;
+        self.check_rep();
+    }
+
+    /// Number of values in the window
+    ///
+    /// Less than `size` once values are removed out of order.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.wnd.len()
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
         self.wnd.len() == 0
@@ -53,6 +87,10 @@ where
         self.end
     }
 
+    pub fn range(&self, start: TSeq, end: TSeq) -> btree_map::Range<'_, TSeq, T> {
+        self.wnd.range(start..end)
+    }
+
     #[must_use]
     pub fn range_mut(&mut self, start: TSeq, end: TSeq) -> btree_map::RangeMut<'_, TSeq, T> {
         self.wnd.range_mut(start..end)
@@ -65,7 +103,7 @@ where
     }
 
     #[must_use]
-    fn start(&self) -> TSeq {
+    pub fn start(&self) -> TSeq {
         let mut first = None;
         for (&seq, _) in &self.wnd {
             first = Some(seq);
@@ -83,6 +121,9 @@ where
         self.end.sub(&self.start()) as usize
     }
 
+    /// # Panics
+    ///
+    /// If the window `is_full`.
     pub fn push_back(&mut self, v: T) {
         assert!(!self.is_full());
         // println!("swnd: push_back: start: {:?}", self.start());
@@ -99,20 +140,13 @@ where
         ret
     }
 
-    pub fn remove_before(&mut self, nack: TSeq) {
-        let mut to_removes = Vec::new();
-        for (&seq, _) in &self.wnd {
-            if seq < nack {
-                to_removes.push(seq);
-            } else {
-                break;
-            }
-        }
-        for to_remove in to_removes {
-            // println!("swnd: remove_before: {:?}", to_remove);
-            self.wnd.remove(&to_remove);
-        }
+    /// Remove the values before `nack` and return them with their keys
+    pub fn remove_before(&mut self, nack: TSeq) -> Vec<(TSeq, T)> {
+        let removed = TSeq::range_iter(self.start(), nack.min(self.end))
+            .filter_map(|seq| self.wnd.remove(&seq).map(|v| (seq, v)))
+            .collect();
         self.check_rep();
+        removed
     }
 }
 
@@ -225,5 +259,71 @@ mod tests {
         assert_eq!(wnd.end().to_u32(), 3);
 
         assert!(wnd.is_full());
+
+        wnd.remove(&Seq32::from_u32(1));
+
+        //                0  1  2  3  4  5  6
+        //                     [2]
+
+        assert_eq!(wnd.size(), 1);
+        assert_eq!(wnd.len(), 1);
+    }
+
+    #[test]
+    fn test_set_wnd_size_cap() {
+        let mut wnd = Swnd::<Seq32, i32>::new(3);
+        wnd.set_remote_rwnd_size(99);
+        wnd.push_back(0);
+        wnd.push_back(1);
+        wnd.push_back(2);
+        assert!(wnd.is_full());
+        assert_eq!(wnd.wnd_size_cap(), 3);
+        assert_eq!(wnd.remote_rwnd_size(), 99);
+
+        // out of order
+        wnd.remove(&Seq32::from_u32(1));
+        assert_eq!(wnd.len(), 2);
+        assert_eq!(wnd.size(), 3);
+        assert!(wnd.is_full());
+
+        // shrink while full
+        wnd.set_wnd_size_cap(1);
+        assert!(wnd.is_full());
+        wnd.remove_before(Seq32::from_u32(2));
+        assert!(wnd.is_full());
+        wnd.remove(&Seq32::from_u32(2));
+        assert!(!wnd.is_full());
+        wnd.push_back(3);
+        assert!(wnd.is_full());
+
+        // grow
+        wnd.set_wnd_size_cap(2);
+        assert!(!wnd.is_full());
+        wnd.push_back(4);
+        assert!(wnd.is_full());
+    }
+
+    #[test]
+    fn test_remove_before_wraparound() {
+        let mut wnd = Swnd::<Seq32, i32>::new_at(5, Seq32::from_u32(u32::MAX - 1));
+        wnd.set_remote_rwnd_size(99);
+        for i in 0..5 {
+            wnd.push_back(i);
+        }
+        wnd.remove(&Seq32::from_u32(u32::MAX));
+
+        let removed = wnd.remove_before(Seq32::from_u32(1));
+        let removed: Vec<_> = removed
+            .into_iter()
+            .map(|(seq, v)| (seq.to_u32(), v))
+            .collect();
+        assert_eq!(removed, [(u32::MAX - 1, 0), (0, 2)]);
+        assert_eq!(wnd.start().to_u32(), 1);
+
+        // a nack past the end removes what is left
+        let removed = wnd.remove_before(Seq32::from_u32(99));
+        assert_eq!(removed.len(), 2);
+        assert!(wnd.is_empty());
+        assert_eq!(wnd.start().to_u32(), 3);
     }
 }
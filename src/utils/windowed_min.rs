@@ -0,0 +1,83 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Minimum of the samples taken within the last `window`
+pub struct WindowedMin {
+    window: Duration,
+    // increasing in both time and value
+    samples: VecDeque<(Instant, Duration)>,
+}
+
+impl WindowedMin {
+    fn check_rep(&self) {
+        for (a, b) in self.samples.iter().zip(self.samples.iter().skip(1)) {
+            assert!(a.0 <= b.0);
+            assert!(a.1 < b.1);
+        }
+    }
+
+    #[must_use]
+    pub fn new(window: Duration) -> Self {
+        let this = WindowedMin {
+            window,
+            samples: VecDeque::new(),
+        };
+        this.check_rep();
+        this
+    }
+
+    pub fn update(&mut self, sample: Duration, now: Instant) {
+        // samples not smaller than the new one can never be the minimum again
+        while let Some(&(_, last)) = self.samples.back() {
+            if last < sample {
+                break;
+            }
+            self.samples.pop_back();
+        }
+        self.samples.push_back((now, sample));
+        while let Some(&(time, _)) = self.samples.front() {
+            if now.duration_since(time) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        self.check_rep();
+    }
+
+    /// As of the last `update`
+    #[must_use]
+    pub fn get(&self) -> Option<Duration> {
+        self.samples.front().map(|&(_, sample)| sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WindowedMin;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_expire() {
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut min = WindowedMin::new(ms(100));
+        assert_eq!(min.get(), None);
+
+        min.update(ms(10), start);
+        min.update(ms(30), start + ms(50));
+        min.update(ms(20), start + ms(60));
+        assert_eq!(min.get(), Some(ms(10)));
+
+        // the sample of 10 expires
+        min.update(ms(40), start + ms(101));
+        assert_eq!(min.get(), Some(ms(20)));
+
+        min.update(ms(50), start + ms(161));
+        assert_eq!(min.get(), Some(ms(40)));
+
+        min.update(ms(5), start + ms(170));
+        assert_eq!(min.get(), Some(ms(5)));
+    }
+}
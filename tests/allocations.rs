@@ -0,0 +1,105 @@
+//! Counts the heap allocations on the receiving path.
+//!
+//! A single test runs in this binary so that no other thread allocates while counting.
+
+use ardl::{
+    layer::{DownloaderBuilder, SeqList},
+    protocol::{
+        frag::{FragBuilder, FragCommand},
+        packet::{Packet, PacketBuilder},
+        packet_hdr::PacketHeaderBuilder,
+    },
+    utils::{
+        buf::{BufSlice, OwnedBufWtr},
+        Seq32,
+    },
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let then = ALLOCATIONS.load(Ordering::SeqCst);
+    let out = f();
+    (out, ALLOCATIONS.load(Ordering::SeqCst) - then)
+}
+
+fn acks(seqs: &[u32]) -> BufSlice {
+    let packet = PacketBuilder {
+        hdr: PacketHeaderBuilder {
+            rwnd: 1,
+            nack: Seq32::from_u32(0),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: None,
+        }
+        .build()
+        .unwrap(),
+        frags: seqs
+            .iter()
+            .map(|&seq| {
+                FragBuilder {
+                    seq: Seq32::from_u32(seq),
+                    cmd: FragCommand::Ack,
+                }
+                .build()
+                .unwrap()
+            })
+            .collect(),
+    }
+    .build()
+    .unwrap();
+    let mut wtr = OwnedBufWtr::new(1024, 0);
+    packet.append_to(&mut wtr).unwrap();
+    wtr.into_slice()
+}
+
+#[test]
+fn seq_lists_stay_inline() {
+    let (list, n) = allocations(|| (1..=4).map(Seq32::from_u32).collect::<SeqList>());
+    assert_eq!(n, 0);
+    let (_, n) = allocations(|| {
+        let mut list = list;
+        list.push(Seq32::from_u32(5));
+        list
+    });
+    assert_eq!(n, 1);
+
+    // decoding a packet with a few acks is all the allocation there is
+    let mut downloader = DownloaderBuilder::default().build().unwrap();
+    for len in 1..=4 {
+        let seqs: Vec<u32> = (1..=len).collect();
+        let mut slice = acks(&seqs);
+        let (packet, decoding) = allocations(|| Packet::from_slice(&mut slice).unwrap());
+        drop(packet);
+        let (state, writing) = allocations(|| downloader.write(acks(&seqs)).unwrap());
+        let (_, encoding) = allocations(|| acks(&seqs));
+        assert_eq!(state.acked_local_seqs.len(), len as usize);
+        assert_eq!(writing, encoding + decoding);
+    }
+}
@@ -0,0 +1,1010 @@
+//! Pins the signatures of the public API.
+//!
+//! Each public method is coerced to a function pointer of its exact current
+//! signature. A breaking change fails to compile here and has to be updated
+//! deliberately together with an entry in `ardl::changelog::ENTRIES`.
+
+use ardl::{
+    changelog,
+    layer::mux::{self, Mux, StreamHandle},
+    layer::shared::SharedUploader,
+    layer::{
+        BuildError, BuildWarning, Builder, Checkpoint, Downloader, DownloaderBuilder,
+        DownloaderCheckpoint, DownloaderLifetimeTotals, Handshake, HandshakeState, IAckObserver,
+        IObserver, IRtoObserver, LifetimeTotals, MtuProbing, Role, SendError, SeqList,
+        SetStateError, SetUploadState, Uploader, UploaderBuilder,
+    },
+    protocol::{
+        auth::{constant_time_eq, PacketAuth, MAX_TAG_LEN},
+        codec::{BodyCodec, IdentityCodec},
+        frag::{
+            Body, CommandType, Frag, FragBuilder, FragCommand, WireFormat, ACK_BITMAP_LEN,
+            ACK_BITMAP_SPAN, ACK_HDR_LEN, COMPACT_PUSH_HDR_LEN, COMPRESSED_FLAG,
+            EXTENSION_CMD_START, MAX_SACK_RANGES, PARITY_HDR_LEN, PING_LEN, PUSH_HDR_LEN,
+            SACK_HDR_LEN, SACK_RANGE_LEN, STREAM_FLAG, STREAM_ID_LEN, SYN_LEN, WINDOW_LEN,
+        },
+        packet::{Packet, PacketBuilder},
+        packet_hdr::{
+            PacketHeader, PacketHeaderBuilder, CONN_ID_LEN, PACKET_HDR_LEN, PACKET_OPTIONS_LEN,
+            PACKET_SEQ_LEN,
+        },
+        transform::{IdentityTransform, PacketTransform, XorTransform},
+        DecodingError, EncodingError,
+    },
+    utils::{
+        buf::{BufPasta, BufPool, BufSlice, BufSliceBuilder, BufWtr, OwnedBufWtr, SubBufWtr},
+        Clock, ManualClock, Seq, Seq32, Seq64, StdClock,
+    },
+};
+use std::{
+    ops::Range,
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+#[test]
+fn changelog_has_current_version() {
+    let entry = changelog::entry(env!("CARGO_PKG_VERSION")).unwrap();
+    assert!(!entry.changes.is_empty());
+    // changes go under the newest version, never one already released
+    assert_eq!(changelog::ENTRIES[0].version, env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn layer_builder() {
+    let builder = Builder {
+        local_recv_buf_len: 0,
+        nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+        max_fast_retransmit_burst: usize::MAX,
+        ratio_rto_to_one_rtt: 0.0,
+        rto_jitter: 0.0,
+        rto_jitter_seed: 0,
+        min_rto: None,
+        max_rto: None,
+        initial_rto: None,
+        coalesce_small_writes: false,
+        timestamps: false,
+        legacy_header: false,
+        strict: false,
+        window_update_chunk: 0,
+        ack_late_pushes: true,
+        to_send_bytes_cap: 0,
+        to_send_queue_len_cap: None::<usize>,
+        send_available_low_water_mark: None::<usize>,
+        swnd_size_cap: 0,
+        mtu: 0,
+        max_retransmissions: None::<usize>,
+        max_no_ack_duration: None::<Duration>,
+        rst_transmissions: 3,
+        keepalive_interval: None,
+        sack: false,
+        packet_seqs: false,
+        conn_id: None,
+        handshake: None,
+        drop_duplicate_packets: true,
+        auth: None,
+        wire_format: WireFormat::V1,
+        pad_packets_to: None,
+        fec_group_size: None,
+        codec: None,
+        cumulative_ack_only: false,
+        ack_bitmap: false,
+        mtu_probing: None,
+        max_frags_per_packet: None,
+    };
+    let _ = builder;
+    let _: fn(Builder) -> Result<(Uploader, Downloader), BuildError> = Builder::build;
+    #[allow(deprecated)]
+    let _: fn() -> Builder = Builder::default;
+    let _: fn() -> Builder = <Builder as Default>::default;
+    let _: fn(&Builder) -> Result<(), BuildError> = Builder::validate;
+    let _: fn(&Builder) -> Vec<BuildWarning> = Builder::warnings;
+    let _ = |e: BuildError| match e {
+        BuildError::Downloader(_) => (),
+        BuildError::Uploader(_) => (),
+        BuildError::ToSendBytesCapZero => (),
+        BuildError::ToSendQueueLenCapZero => (),
+        BuildError::SwndSizeCapZero => (),
+        BuildError::FastRetransmitBurstZero => (),
+        BuildError::FastRetransmitThresholdUnreachable => (),
+    };
+    let _ = |w: BuildWarning| match w {
+        BuildWarning::FastRetransmitThresholdHigh => (),
+    };
+}
+
+#[test]
+fn set_upload_state() {
+    let state = SetUploadState {
+        remote_rwnd_size: 0u16,
+        remote_nack: Seq32::zero(),
+        local_next_seq_to_receive: Seq32::zero(),
+        remote_seqs_to_ack: SeqList::new(),
+        acked_local_seqs: SeqList::new(),
+        local_rwnd_size: 0usize,
+        remote_timestamp: None,
+        timestamp_echo: None,
+        conn_id: None::<u64>,
+        pings_to_answer: Vec::<u32>::new(),
+        pong: None::<u32>,
+        probe_to_answer: None::<u32>,
+        probe_ack: None::<u32>,
+        remote_ranges_to_sack: Vec::<Range<Seq32>>::new(),
+        sacked_local_ranges: Vec::<Range<Seq32>>::new(),
+        syn_to_answer: None::<u64>,
+        is_established: true,
+    };
+    let _ = state;
+}
+
+#[test]
+fn uploader() {
+    let builder = UploaderBuilder {
+        local_recv_buf_len: 0,
+        nack_duplicate_threshold_to_activate_fast_retransmit: 0,
+        max_fast_retransmit_burst: usize::MAX,
+        ratio_rto_to_one_rtt: 0.0,
+        rto_jitter: 0.0,
+        rto_jitter_seed: 0,
+        min_rto: None,
+        max_rto: None,
+        initial_rto: None,
+        coalesce_small_writes: false,
+        timestamps: false,
+        legacy_header: false,
+        strict: false,
+        mtu: 0,
+        to_send_bytes_cap: 0,
+        to_send_queue_len_cap: None::<usize>,
+        send_available_low_water_mark: None::<usize>,
+        swnd_size_cap: 0,
+        max_retransmissions: None::<usize>,
+        max_no_ack_duration: None::<Duration>,
+        rst_transmissions: 3,
+        keepalive_interval: None,
+        sack: false,
+        packet_seqs: false,
+        conn_id: None,
+        handshake: None,
+        initial_seq: None,
+        auth: None,
+        wire_format: WireFormat::V1,
+        pad_packets_to: None,
+        fec_group_size: None,
+        codec: None,
+        cumulative_ack_only: false,
+        ack_bitmap: false,
+        mtu_probing: None,
+    };
+    let _ = builder;
+    #[allow(deprecated)]
+    let _: fn() -> UploaderBuilder = UploaderBuilder::default;
+    let _: fn() -> UploaderBuilder = <UploaderBuilder as Default>::default;
+    let _: fn(UploaderBuilder) -> Result<Uploader, _> = UploaderBuilder::build;
+    let _: fn(UploaderBuilder, &Checkpoint) -> Result<Uploader, _> = UploaderBuilder::resume_from;
+    let _ = |checkpoint: Checkpoint| -> (Seq32, Seq32, Seq32) {
+        (
+            checkpoint.next_seq,
+            checkpoint.acked_prefix,
+            checkpoint.local_next_seq_to_receive,
+        )
+    };
+    let _: fn(&Uploader) -> Checkpoint = Uploader::checkpoint;
+
+    let _: fn(&mut Uploader, Option<Weak<dyn IObserver + Send + Sync + 'static>>) =
+        Uploader::set_on_send_available;
+    let _: fn(&mut Uploader, Option<Weak<dyn IAckObserver + Send + Sync + 'static>>) =
+        Uploader::set_on_ack;
+    let _: fn(&mut Uploader, Option<Weak<dyn IRtoObserver + Send + Sync + 'static>>) =
+        Uploader::set_on_rto;
+    let _: fn(&mut Uploader, Option<Weak<dyn IObserver + Send + Sync + 'static>>) =
+        Uploader::set_on_drained;
+    let _: fn(&mut Uploader, BufSlice) -> Result<(), SendError<BufSlice>> = Uploader::write;
+    let _: fn(&Uploader) -> LifetimeTotals = Uploader::lifetime_totals;
+    let _ =
+        |totals: LifetimeTotals| -> (u64, u64) { (totals.bytes_written, totals.packets_emitted) };
+    let _: fn(&mut Uploader, BufSlice) -> (usize, Option<BufSlice>) = Uploader::write_partial;
+    let _: fn(&mut Uploader, &[u8]) -> Result<(), SendError<()>> = Uploader::write_bytes;
+    let _: fn(&mut Uploader, BufSlice) -> Result<(), SendError<BufSlice>> = Uploader::write_urgent;
+    let _: fn(&mut Uploader, BufSlice) -> Result<(), SendError<BufSlice>> =
+        Uploader::write_unreliable;
+    let _: fn(&mut Uploader, BufSlice, Instant) -> Result<(), SendError<BufSlice>> =
+        Uploader::write_with_deadline;
+    let _: fn(&mut Uploader) -> usize = Uploader::clear_pending;
+    let _: fn(&mut Uploader, Vec<BufSlice>) -> Result<(), SendError<Vec<BufSlice>>> =
+        Uploader::write_vectored;
+    let _: fn(&mut Uploader, &Instant) -> Vec<Packet> = Uploader::emit;
+    let _: fn(&mut Uploader, &Instant) -> Vec<Packet> = Uploader::emit_on_ack;
+    let _: fn(&mut Uploader, &Instant) -> Vec<Packet> = Uploader::emit_on_timer;
+    let _: fn(&mut Uploader, &Instant) -> Vec<Packet> = Uploader::emit_window_update;
+    let _: fn(&mut Uploader, &Instant, usize) -> Vec<Packet> = Uploader::emit_budgeted;
+    let _: fn(&Uploader) -> bool = Uploader::is_drained;
+    let _: fn(&mut Uploader) = Uploader::close;
+    let _: fn(&Uploader) -> bool = Uploader::is_send_closed;
+    let _: fn(&mut Uploader) = Uploader::abort;
+    let _: fn(&Uploader) -> bool = Uploader::is_aborted;
+    let _: fn(&Uploader, &Instant) -> bool = Uploader::keepalive_due;
+    let _: fn(&mut Uploader, &Instant) -> u32 = Uploader::send_ping;
+    let _: fn(&Uploader) -> Option<Instant> = Uploader::last_pong_received;
+    let _: fn(&Uploader) -> Option<u64> = Uploader::conn_id;
+    let _: fn(&Uploader) -> HandshakeState = Uploader::handshake_state;
+    let _: fn(&Downloader) -> HandshakeState = Downloader::handshake_state;
+    let handshake: Handshake = Handshake::new(Role::Client);
+    let _: Role = handshake.role;
+    let _: Seq32 = handshake.initial_seq;
+    let _: u64 = handshake.nonce;
+    let _ = |state: HandshakeState| match state {
+        HandshakeState::Idle => (),
+        HandshakeState::SynSent => (),
+        HandshakeState::SynReceived => (),
+        HandshakeState::Established => (),
+    };
+    let _ = Role::Server;
+    let _: fn(&Uploader) -> Seq32 = Uploader::acked_watermark;
+    let _: fn(&Uploader) -> u64 = Uploader::bytes_acked;
+    let _: fn(&mut Uploader, &Instant, &mut BufPool) -> Vec<OwnedBufWtr> =
+        Uploader::emit_serialized;
+    let _: fn(&mut Uploader, &Instant, &mut BufPool, &dyn PacketTransform) -> Vec<OwnedBufWtr> =
+        Uploader::emit_transformed;
+    let _: fn(&Uploader, &Instant) -> bool = Uploader::is_broken;
+    let _: fn(&Uploader) -> bool = Uploader::is_send_queue_full;
+    let _: fn(&Uploader) -> usize = Uploader::pending_acks;
+    let _: fn(&Uploader) -> usize = Uploader::swnd_len;
+    let _: fn(&Uploader) -> usize = Uploader::swnd_cap;
+    let _: fn(&Uploader) -> usize = Uploader::remote_rwnd;
+    let _: fn(&Uploader) -> Option<Duration> = Uploader::srtt;
+    let _: fn(&Uploader) -> Seq32 = Uploader::swnd_end;
+    let _: fn(&Uploader) -> usize = Uploader::bytes_in_flight;
+    let _: fn(&Uploader) -> usize = Uploader::pending_bytes;
+    let _: fn(&Uploader) -> Option<Duration> = Uploader::queue_delay_estimate;
+    let _: fn(&Uploader) -> Duration = Uploader::rto;
+    let _: fn(&Uploader) -> usize = Uploader::mtu;
+    let _: fn(&mut Uploader, usize) -> Result<(), _> = Uploader::set_mtu;
+    let _: fn(&mut Uploader, usize) = Uploader::set_swnd_size_cap;
+    let _: fn(&mut Uploader, SetUploadState, &Instant) -> Result<(), SetStateError> =
+        Uploader::set_state;
+    let _: fn(&mut Uploader, Vec<SetUploadState>, &Instant) -> Result<(), SetStateError> =
+        Uploader::set_states;
+    let _ = |uploader: &Uploader| {
+        let stat = uploader.stat();
+        let _: Option<Duration> = stat.srtt;
+        let _: u64 = stat.retransmissions;
+        let _: u64 = stat.rto_hits;
+        let _: u64 = stat.fast_retransmissions;
+        let _: u64 = stat.pushes;
+        let _: u64 = stat.acks;
+        let _: u64 = stat.bytes_pushed;
+        let _: u64 = stat.bytes_retransmitted;
+        let _: u64 = stat.packets_emitted;
+        let _: u64 = stat.packets_on_ack;
+        let _: u64 = stat.bytes_on_ack;
+        let _: u64 = stat.packets_on_timer;
+        let _: u64 = stat.bytes_on_timer;
+        let _: u64 = stat.expired_writes;
+        let _: u64 = stat.spurious_rtos;
+        let _: u64 = stat.duplicate_acks_received;
+        let _: Seq32 = stat.next_seq_to_send;
+        let _: usize = stat.bytes_in_flight;
+        let _: usize = stat.pending_bytes;
+        let _: Option<Duration> = stat.queue_delay_estimate;
+        let _: Option<f64> = stat.delivery_rate_bps;
+        let _: u64 = stat.probes_sent;
+        let _: u64 = stat.probes_acked;
+        let _: u64 = stat.conn_ids_deferred;
+        let _: u64 = stat.echoes_skipped;
+        let _: u64 = stat.retransmits_too_large;
+    };
+    let _ = |uploader: &mut Uploader| {
+        let stat = uploader.stat_reset();
+        let _: Option<Duration> = stat.srtt;
+    };
+    let _ = |e: SetStateError| match e {
+        SetStateError::LocalRwndTooLarge { size } => {
+            let _: usize = size;
+        }
+        SetStateError::AckedNack { seq } => {
+            let _: Seq32 = seq;
+        }
+        SetStateError::SackedNack { range } => {
+            let _: std::ops::Range<Seq32> = range;
+        }
+        SetStateError::UnknownAck { seq } => {
+            let _: Seq32 = seq;
+        }
+    };
+    let _ = |e: SendError<BufSlice>| match e {
+        SendError::QueueFull(_) => (),
+        SendError::Broken(_) => (),
+        SendError::Empty(_) => (),
+        SendError::Closed(_) => (),
+    };
+    let _: fn(SendError<BufSlice>) -> BufSlice = SendError::into_inner;
+}
+
+#[test]
+fn downloader() {
+    let builder = DownloaderBuilder {
+        recv_buf_len: 0,
+        strict: false,
+        window_update_chunk: 0,
+        ack_late_pushes: true,
+        handshake: None,
+        initial_seq: None,
+        drop_duplicate_packets: true,
+        auth: None,
+        wire_format: WireFormat::V1,
+        fec_group_size: None,
+        codec: None,
+        max_frags_per_packet: None,
+        legacy_header: false,
+    };
+    let _ = builder;
+    let _: fn(DownloaderBuilder) -> Result<Downloader, _> = DownloaderBuilder::build;
+    let _: fn() -> DownloaderBuilder = <DownloaderBuilder as Default>::default;
+    let _: fn(DownloaderBuilder, &DownloaderCheckpoint) -> Result<Downloader, _> =
+        DownloaderBuilder::resume_from;
+    let _ = |checkpoint: DownloaderCheckpoint| -> Seq32 { checkpoint.next_seq_to_receive };
+    let _: fn(&Downloader) -> DownloaderCheckpoint = Downloader::checkpoint;
+    let _: fn(&Downloader) -> DownloaderLifetimeTotals = Downloader::lifetime_totals;
+    let _ = |totals: DownloaderLifetimeTotals| -> (u64, u64) {
+        (totals.bytes_delivered, totals.packets_received)
+    };
+
+    let _: fn(&Downloader) -> Option<(Seq32, Seq32)> = Downloader::window_span;
+    let _: fn(&Downloader) -> usize = Downloader::buffered_count;
+    let _: fn(&Downloader) -> usize = Downloader::ooo_buffered;
+    let _: fn(&Downloader) -> usize = Downloader::received_ready;
+    let _: fn(&Downloader) -> usize = Downloader::rwnd_free;
+    let _: fn(&Downloader) -> bool = Downloader::should_advertise_window;
+    let _: fn(&mut Downloader) -> Option<SetUploadState> = Downloader::window_update;
+    let _: fn(&mut Downloader, Option<Weak<dyn IObserver + Send + Sync + 'static>>) =
+        Downloader::set_on_rwnd_reopened;
+    let _: fn(&mut Downloader) -> Option<BufSlice> = Downloader::emit;
+    let _: fn(&mut Downloader, usize) -> Option<BufSlice> = Downloader::emit_max;
+    let _: fn(&mut Downloader) -> Vec<BufSlice> = Downloader::drain;
+    let _: fn(&mut Downloader, &mut [u8]) -> usize = Downloader::read;
+    let _: fn(&Downloader) -> Option<&[u8]> = Downloader::peek;
+    let _: fn(&Downloader) -> usize = Downloader::peek_len;
+    let _: fn(&Downloader) -> usize = Downloader::buffered_len;
+    let _: fn(&Downloader) -> usize = Downloader::reorder_len;
+    let _: fn(&Downloader) -> bool = Downloader::is_finished;
+    let _: fn(&Downloader) -> bool = Downloader::is_reset;
+    let _: fn(&Downloader) -> Vec<std::ops::Range<Seq32>> = Downloader::missing_ranges;
+    let _: fn(&Downloader) -> Option<u8> = Downloader::peer_version;
+    let _: fn(&mut Downloader, Option<Weak<dyn IObserver + Send + Sync>>) =
+        Downloader::set_on_recv_available;
+    let _: fn(&mut Downloader, BufSlice) -> Result<SetUploadState, ardl::layer::Error> =
+        Downloader::write;
+    let _: fn(
+        &mut Downloader,
+        BufSlice,
+        &dyn PacketTransform,
+    ) -> Result<SetUploadState, ardl::layer::Error> = Downloader::write_transformed;
+    #[allow(deprecated)]
+    let _: fn(&mut Downloader, BufSlice) -> Result<SetUploadState, ardl::layer::Error> =
+        Downloader::input_packet;
+    #[allow(deprecated)]
+    let _: fn(&mut Downloader) -> Option<BufSlice> = Downloader::recv;
+    let _ = |e: ardl::layer::Error| match e {
+        ardl::layer::Error::Decoding => (),
+        ardl::layer::Error::LatePush { seq } => {
+            let _: Seq32 = seq;
+        }
+        ardl::layer::Error::Reset => (),
+        ardl::layer::Error::DuplicatePacket { packet_seq } => {
+            let _: Seq32 = packet_seq;
+        }
+        ardl::layer::Error::Auth => (),
+    };
+    let _ = |downloader: &Downloader| {
+        let stat = downloader.stat();
+        let _: u64 = stat.late_pushes;
+        let _: u64 = stat.early_pushes;
+        let _: u64 = stat.out_of_orders;
+        let _: u64 = stat.duplicate_pushes;
+        let _: u64 = stat.late_acks_suppressed;
+        let _: u64 = stat.unknown_frags;
+        let _: u64 = stat.pushes_before_established;
+        let _: u64 = stat.duplicate_packets;
+        let _: u64 = stat.auth_failures;
+        let _: u64 = stat.padding_bytes;
+        let _: u64 = stat.fec_recoveries;
+        let _: u64 = stat.decompression_errors;
+        let _: u64 = stat.decoding_errors;
+        let _: u64 = stat.oversized_lens;
+        let _: u64 = stat.frag_limit_exceeded;
+        let _: u64 = stat.unsupported_versions;
+        let _: Seq32 = stat.next_seq_to_receive;
+        let _: u64 = stat.packets;
+        let _: u64 = stat.acks;
+        let _: u64 = stat.pushes;
+        let _: u64 = stat.bytes_received;
+        let _: u64 = stat.bytes_delivered;
+    };
+    let _ = |downloader: &mut Downloader| {
+        let stat = downloader.stat_reset();
+        let _: Seq32 = stat.next_seq_to_receive;
+    };
+}
+
+#[test]
+fn shared_uploader() {
+    let _: fn(Uploader) -> SharedUploader = SharedUploader::new;
+    let _: fn(&SharedUploader, Option<Weak<dyn IObserver + Send + Sync + 'static>>) =
+        SharedUploader::set_on_send_available;
+    let _: fn(&SharedUploader, BufSlice) -> Result<(), SendError<BufSlice>> = SharedUploader::write;
+    let _: fn(&SharedUploader, BufSlice) -> (usize, Option<BufSlice>) =
+        SharedUploader::write_partial;
+    let _: fn(&SharedUploader) -> usize = SharedUploader::clear_pending;
+    let _: fn(&SharedUploader, SetUploadState, &Instant) -> Result<(), SetStateError> =
+        SharedUploader::set_state;
+    let _: fn(&SharedUploader, Vec<SetUploadState>, &Instant) -> Result<(), SetStateError> =
+        SharedUploader::set_states;
+    let _: fn(&SharedUploader, &Instant) -> Vec<Packet> = SharedUploader::emit;
+    let _: fn(&SharedUploader, &Instant) -> Vec<Packet> = SharedUploader::emit_on_ack;
+    let _: fn(&SharedUploader, &Instant) -> Vec<Packet> = SharedUploader::emit_on_timer;
+    let _: fn(&SharedUploader, &Instant) -> bool = SharedUploader::is_broken;
+    let _ = |uploader: &SharedUploader| {
+        let _: Option<Duration> = uploader.stat().srtt;
+        let _: usize = uploader.lock().mtu();
+    };
+    let _ = |uploader: SharedUploader| -> SharedUploader { uploader.clone() };
+}
+
+#[test]
+fn mux() {
+    let _: usize = mux::DEFAULT_MAX_STREAMS;
+    let _: fn(Builder, Role) -> Result<Mux, BuildError> = Mux::new;
+    let _: fn(&mut Mux) -> Result<StreamHandle, mux::Error> = Mux::open_stream;
+    let _: fn(&mut Mux) -> Option<StreamHandle> = Mux::accept;
+    let _: fn(&mut Mux, StreamHandle) = Mux::close_stream;
+    let _: fn(&Mux) -> Vec<StreamHandle> = Mux::streams;
+    let _: fn(&mut Mux, StreamHandle, BufSlice) -> Result<(), SendError<BufSlice>> = Mux::write;
+    let _: fn(&mut Mux, StreamHandle) -> Option<BufSlice> = Mux::recv;
+    let _: fn(&Mux, StreamHandle) -> Option<&Uploader> = Mux::uploader;
+    let _: fn(&Mux, StreamHandle) -> Option<&Downloader> = Mux::downloader;
+    let _: fn(&mut Mux, &Instant) -> Vec<OwnedBufWtr> = Mux::emit;
+    let _: fn(&mut Mux, BufSlice, &Instant) -> Result<(), mux::Error> = Mux::input;
+    let _: fn(&mut Mux, usize) = Mux::set_max_streams;
+    let _: fn(&StreamHandle) -> u16 = StreamHandle::id;
+    let _ = |e: mux::Error| match e {
+        mux::Error::Decoding => (),
+        mux::Error::Auth => (),
+        mux::Error::UnknownStream { id } | mux::Error::TooManyStreams { id } => {
+            let _: u16 = id;
+        }
+        mux::Error::Stream { id: _, error: _ } => (),
+        mux::Error::SetState { id: _, error: _ } => (),
+        mux::Error::StreamIdsExhausted => (),
+    };
+    let _ = |builder: &Builder| -> Builder { builder.clone() };
+}
+
+#[test]
+fn errors() {
+    fn is_error<E: std::error::Error + Send + Sync + 'static>() {}
+    is_error::<DecodingError>();
+    is_error::<EncodingError>();
+    is_error::<ardl::protocol::frag::Error>();
+    is_error::<ardl::protocol::packet::Error>();
+    is_error::<ardl::protocol::packet_hdr::Error>();
+    is_error::<BuildError>();
+    is_error::<ardl::layer::Error>();
+    is_error::<SetStateError>();
+    is_error::<ardl::layer::OutputError>();
+    is_error::<SendError<BufSlice>>();
+    is_error::<mux::Error>();
+    let _ = |e: BuildError| -> Box<dyn std::error::Error> { Box::new(e) };
+    // their build errors are not nameable outside the crate
+    let _ = |builder: UploaderBuilder| -> Result<Uploader, Box<dyn std::error::Error>> {
+        Ok(builder.build()?)
+    };
+    let _ = |builder: DownloaderBuilder| -> Result<Downloader, Box<dyn std::error::Error>> {
+        Ok(builder.build()?)
+    };
+}
+
+#[test]
+fn builder_setters() {
+    let _: fn(Builder, usize) -> Builder = Builder::local_recv_buf_len;
+    let _: fn(Builder, usize) -> Builder =
+        Builder::nack_duplicate_threshold_to_activate_fast_retransmit;
+    let _: fn(Builder, usize) -> Builder = Builder::max_fast_retransmit_burst;
+    let _: fn(Builder, f64) -> Builder = Builder::ratio_rto_to_one_rtt;
+    let _: fn(Builder, f64) -> Builder = Builder::rto_jitter;
+    let _: fn(Builder, u64) -> Builder = Builder::rto_jitter_seed;
+    let _: fn(Builder, Option<Duration>) -> Builder = Builder::min_rto;
+    let _: fn(Builder, Option<Duration>) -> Builder = Builder::max_rto;
+    let _: fn(Builder, Option<Duration>) -> Builder = Builder::initial_rto;
+    let _: fn(Builder, bool) -> Builder = Builder::coalesce_small_writes;
+    let _: fn(Builder, bool) -> Builder = Builder::timestamps;
+    let _: fn(Builder, bool) -> Builder = Builder::legacy_header;
+    let _: fn(Builder, bool) -> Builder = Builder::strict;
+    let _: fn(Builder, usize) -> Builder = Builder::window_update_chunk;
+    let _: fn(Builder, bool) -> Builder = Builder::ack_late_pushes;
+    let _: fn(Builder, usize) -> Builder = Builder::to_send_bytes_cap;
+    let _: fn(Builder, Option<usize>) -> Builder = Builder::to_send_queue_len_cap;
+    let _: fn(Builder, Option<usize>) -> Builder = Builder::send_available_low_water_mark;
+    let _: fn(Builder, usize) -> Builder = Builder::swnd_size_cap;
+    let _: fn(Builder, usize) -> Builder = Builder::mtu;
+    let _: fn(Builder, Option<usize>) -> Builder = Builder::max_retransmissions;
+    let _: fn(Builder, Option<Duration>) -> Builder = Builder::max_no_ack_duration;
+    let _: fn(Builder, usize) -> Builder = Builder::rst_transmissions;
+    let _: fn(Builder, Option<Duration>) -> Builder = Builder::keepalive_interval;
+    let _: fn(Builder, bool) -> Builder = Builder::sack;
+    let _: fn(Builder, Option<u64>) -> Builder = Builder::conn_id;
+    let _: fn(Builder, bool) -> Builder = Builder::packet_seqs;
+    let _: fn(Builder, bool) -> Builder = Builder::drop_duplicate_packets;
+    let _: fn(Builder, Option<Role>) -> Builder = Builder::handshake;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder = UploaderBuilder::local_recv_buf_len;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder =
+        UploaderBuilder::nack_duplicate_threshold_to_activate_fast_retransmit;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder =
+        UploaderBuilder::max_fast_retransmit_burst;
+    let _: fn(UploaderBuilder, f64) -> UploaderBuilder = UploaderBuilder::ratio_rto_to_one_rtt;
+    let _: fn(UploaderBuilder, f64) -> UploaderBuilder = UploaderBuilder::rto_jitter;
+    let _: fn(UploaderBuilder, u64) -> UploaderBuilder = UploaderBuilder::rto_jitter_seed;
+    let _: fn(UploaderBuilder, Option<Duration>) -> UploaderBuilder = UploaderBuilder::min_rto;
+    let _: fn(UploaderBuilder, Option<Duration>) -> UploaderBuilder = UploaderBuilder::max_rto;
+    let _: fn(UploaderBuilder, Option<Duration>) -> UploaderBuilder = UploaderBuilder::initial_rto;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::coalesce_small_writes;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::timestamps;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::legacy_header;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::strict;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder = UploaderBuilder::mtu;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder = UploaderBuilder::to_send_bytes_cap;
+    let _: fn(UploaderBuilder, Option<usize>) -> UploaderBuilder =
+        UploaderBuilder::to_send_queue_len_cap;
+    let _: fn(UploaderBuilder, Option<usize>) -> UploaderBuilder =
+        UploaderBuilder::send_available_low_water_mark;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder = UploaderBuilder::swnd_size_cap;
+    let _: fn(UploaderBuilder, Option<usize>) -> UploaderBuilder =
+        UploaderBuilder::max_retransmissions;
+    let _: fn(UploaderBuilder, Option<Duration>) -> UploaderBuilder =
+        UploaderBuilder::max_no_ack_duration;
+    let _: fn(UploaderBuilder, usize) -> UploaderBuilder = UploaderBuilder::rst_transmissions;
+    let _: fn(UploaderBuilder, Option<Duration>) -> UploaderBuilder =
+        UploaderBuilder::keepalive_interval;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::sack;
+    let _: fn(UploaderBuilder, Option<u64>) -> UploaderBuilder = UploaderBuilder::conn_id;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::packet_seqs;
+    let _: fn(UploaderBuilder, Option<Handshake>) -> UploaderBuilder = UploaderBuilder::handshake;
+    let _: fn(DownloaderBuilder, Option<Handshake>) -> DownloaderBuilder =
+        DownloaderBuilder::handshake;
+    let _: fn(UploaderBuilder, Option<Seq32>) -> UploaderBuilder = UploaderBuilder::initial_seq;
+    let _: fn(DownloaderBuilder, Option<Seq32>) -> DownloaderBuilder =
+        DownloaderBuilder::initial_seq;
+    let _: fn(DownloaderBuilder, bool) -> DownloaderBuilder =
+        DownloaderBuilder::drop_duplicate_packets;
+    let _: usize = ardl::layer::PACKET_REORDER_WINDOW;
+    let _: fn(DownloaderBuilder, usize) -> DownloaderBuilder = DownloaderBuilder::recv_buf_len;
+    let _: fn(DownloaderBuilder, bool) -> DownloaderBuilder = DownloaderBuilder::strict;
+    let _: fn(DownloaderBuilder, usize) -> DownloaderBuilder =
+        DownloaderBuilder::window_update_chunk;
+    let _: fn(DownloaderBuilder, bool) -> DownloaderBuilder = DownloaderBuilder::ack_late_pushes;
+    let _: fn(Builder, Option<Arc<dyn PacketAuth>>) -> Builder = Builder::auth;
+    let _: fn(UploaderBuilder, Option<Arc<dyn PacketAuth>>) -> UploaderBuilder =
+        UploaderBuilder::auth;
+    let _: fn(DownloaderBuilder, Option<Arc<dyn PacketAuth>>) -> DownloaderBuilder =
+        DownloaderBuilder::auth;
+    let _: fn(Builder, WireFormat) -> Builder = Builder::wire_format;
+    let _: fn(Builder, Option<usize>) -> Builder = Builder::pad_packets_to;
+    let _: fn(UploaderBuilder, Option<usize>) -> UploaderBuilder = UploaderBuilder::pad_packets_to;
+    let _: fn(UploaderBuilder, WireFormat) -> UploaderBuilder = UploaderBuilder::wire_format;
+    let _: fn(DownloaderBuilder, WireFormat) -> DownloaderBuilder = DownloaderBuilder::wire_format;
+    let _: fn(Builder, Option<usize>) -> Builder = Builder::fec_group_size;
+    let _: fn(UploaderBuilder, Option<usize>) -> UploaderBuilder = UploaderBuilder::fec_group_size;
+    let _: fn(DownloaderBuilder, Option<usize>) -> DownloaderBuilder =
+        DownloaderBuilder::fec_group_size;
+    let _: fn(Builder, Option<Arc<dyn BodyCodec>>) -> Builder = Builder::codec;
+    let _: fn(UploaderBuilder, Option<Arc<dyn BodyCodec>>) -> UploaderBuilder =
+        UploaderBuilder::codec;
+    let _: fn(DownloaderBuilder, Option<Arc<dyn BodyCodec>>) -> DownloaderBuilder =
+        DownloaderBuilder::codec;
+    let _: fn(Builder, bool) -> Builder = Builder::cumulative_ack_only;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::cumulative_ack_only;
+    let _: fn(Builder, bool) -> Builder = Builder::ack_bitmap;
+    let _: fn(UploaderBuilder, bool) -> UploaderBuilder = UploaderBuilder::ack_bitmap;
+    let _: fn(Builder, Option<MtuProbing>) -> Builder = Builder::mtu_probing;
+    let _: fn(Builder, Option<usize>) -> Builder = Builder::max_frags_per_packet;
+    let _: fn(DownloaderBuilder, Option<usize>) -> DownloaderBuilder =
+        DownloaderBuilder::max_frags_per_packet;
+    let _: fn(UploaderBuilder, Option<MtuProbing>) -> UploaderBuilder =
+        UploaderBuilder::mtu_probing;
+    let _ = MtuProbing {
+        max_mtu: 1500,
+        interval: Duration::from_secs(600),
+    };
+    let _: fn(DownloaderBuilder, bool) -> DownloaderBuilder = DownloaderBuilder::legacy_header;
+}
+
+#[test]
+fn observer() {
+    struct Observer;
+    impl IObserver for Observer {
+        fn notify(&self) {}
+    }
+    let observer: Arc<dyn IObserver + Send + Sync> = Arc::new(Observer);
+    observer.notify();
+
+    struct AckObserver;
+    impl IAckObserver for AckObserver {
+        fn on_acked(&self, _: &[Seq32]) {}
+    }
+    let observer: Arc<dyn IAckObserver + Send + Sync> = Arc::new(AckObserver);
+    observer.on_acked(&[]);
+
+    struct RtoObserver;
+    impl IRtoObserver for RtoObserver {
+        fn on_rto(&self, _: usize) {}
+    }
+    let observer: Arc<dyn IRtoObserver + Send + Sync> = Arc::new(RtoObserver);
+    observer.on_rto(0);
+}
+
+#[test]
+fn buf_slice() {
+    let _ = BufSliceBuilder {
+        buf: Arc::new(Vec::new()),
+        range: 0..0,
+    };
+    let _: fn(BufSliceBuilder) -> Result<BufSlice, _> = BufSliceBuilder::build;
+    let _: fn(&BufSlice) -> BufSlice = BufSlice::clone;
+    let _: fn(Vec<u8>) -> BufSlice = BufSlice::from_bytes;
+    let _: fn(&BufSlice) -> &[u8] = BufSlice::data;
+    let _: fn(&BufSlice) -> usize = BufSlice::len;
+    let _: fn(&BufSlice) -> bool = BufSlice::is_empty;
+    let _: fn(&BufSlice, Range<usize>) -> Result<BufSlice, _> = BufSlice::slice;
+    let _: fn(&BufSlice, usize) -> Result<(BufSlice, BufSlice), _> = BufSlice::split;
+    let _: fn(&mut BufSlice, usize) -> Result<BufSlice, _> = BufSlice::pop_front;
+}
+
+#[test]
+fn buf_pasta() {
+    let _: fn() -> BufPasta = BufPasta::new;
+    let _: fn(&BufPasta) -> usize = BufPasta::len;
+    let _: fn(&BufPasta) -> bool = BufPasta::is_empty;
+    let _: fn(&mut BufPasta, BufSlice) = BufPasta::append;
+    let _: fn(&BufPasta, &mut OwnedBufWtr) -> Result<(), _> = BufPasta::append_to;
+    let _: fn(&BufPasta, &mut OwnedBufWtr) -> Result<(), _> = BufPasta::prepend_to;
+}
+
+fn buf_wtr<T: BufWtr>() {
+    let _: fn(&T) -> usize = T::data_len;
+    let _: fn(&T) -> usize = T::front_len;
+    let _: fn(&T) -> usize = T::back_len;
+    let _: fn(&T) -> bool = T::is_empty;
+    let _: fn(&T) -> bool = T::is_full;
+    let _: fn(&T) -> &[u8] = T::data;
+    let _: fn(&mut T) -> &mut [u8] = T::data_mut;
+    let _: fn(&mut T) -> &mut [u8] = T::front_free_space;
+    let _: fn(&mut T) -> &mut [u8] = T::back_free_space;
+    let _: fn(&mut T, usize) -> Result<(), _> = T::grow_front;
+    let _: fn(&mut T, usize) -> Result<(), _> = T::grow_back;
+    let _: fn(&mut T, usize) -> Result<(), _> = T::shrink_front;
+    let _: fn(&mut T, usize) -> Result<(), _> = T::shrink_back;
+    let _: fn(&mut T, usize) = T::reset_data;
+    let _: fn(&mut T, &[u8]) -> Result<(), _> = T::append;
+    let _: fn(&mut T, &[u8]) -> Result<(), _> = T::prepend;
+}
+
+#[test]
+fn owned_buf_wtr() {
+    buf_wtr::<OwnedBufWtr>();
+    let _: fn(Vec<u8>, usize, usize) -> OwnedBufWtr = OwnedBufWtr::from_bytes;
+    let _: fn(usize, usize) -> OwnedBufWtr = OwnedBufWtr::new;
+    let _: fn(&mut OwnedBufWtr, OwnedBufWtr) = OwnedBufWtr::assign;
+    let _: fn(OwnedBufWtr) -> BufSlice = OwnedBufWtr::into_slice;
+    let _: fn(&OwnedBufWtr) -> usize = OwnedBufWtr::capacity;
+}
+
+#[test]
+fn buf_pool() {
+    let _: fn() -> BufPool = BufPool::new;
+    let _: fn() -> BufPool = <BufPool as Default>::default;
+    let _: fn(&mut BufPool, usize) -> OwnedBufWtr = BufPool::take;
+    let _: fn(&mut BufPool, OwnedBufWtr) = BufPool::put_back;
+    let _: fn(&BufPool) -> usize = BufPool::free_count;
+}
+
+#[test]
+fn sub_buf_wtr() {
+    fn pin<'a>() {
+        buf_wtr::<SubBufWtr<'a>>();
+        let _: fn(&'a mut [u8], usize, usize) -> SubBufWtr<'a> = SubBufWtr::from_vec;
+        let _: fn(&'a mut [u8], usize) -> SubBufWtr<'a> = SubBufWtr::new;
+        let _: fn(&SubBufWtr<'a>) = SubBufWtr::check_rep;
+    }
+    pin();
+}
+
+#[test]
+fn seq32() {
+    let _: fn(u32) -> Seq32 = Seq32::from_u32;
+    let _: fn(&Seq32) -> u32 = Seq32::to_u32;
+    let _: fn(&mut Seq32) = Seq32::increment;
+    let _: fn(Seq32, Seq32) -> Seq32 = Seq32::max;
+    let _: fn(&Seq32, usize) -> Seq32 = <Seq32 as Seq>::add_usize;
+    let _: fn(&Seq32, &Seq32) -> usize = <Seq32 as Seq>::sub;
+    let _: fn() -> Seq32 = <Seq32 as Seq>::zero;
+    let _: fn(&Seq32, &Seq32) -> usize = <Seq32 as Seq>::dist;
+    let _: Vec<Seq32> = <Seq32 as Seq>::range_iter(Seq32::zero(), Seq32::zero()).collect();
+    let _: Seq32 = Default::default();
+}
+
+#[test]
+fn seq64() {
+    let _: fn(u64) -> Seq64 = Seq64::from_u64;
+    let _: fn(&Seq64) -> u64 = Seq64::to_u64;
+    let _: fn(&mut Seq64) = Seq64::increment;
+    let _: fn(Seq64, Seq64) -> Seq64 = Seq64::max;
+    let _: fn(&Seq64, usize) -> Seq64 = <Seq64 as Seq>::add_usize;
+    let _: fn(&Seq64, &Seq64) -> usize = <Seq64 as Seq>::sub;
+    let _: fn() -> Seq64 = <Seq64 as Seq>::zero;
+    let _: fn(&Seq64, &Seq64) -> usize = <Seq64 as Seq>::dist;
+    let _: Vec<Seq64> = <Seq64 as Seq>::range_iter(Seq64::zero(), Seq64::zero()).collect();
+    let _: Seq64 = Default::default();
+}
+
+#[test]
+fn seq_list() {
+    let _: fn() -> SeqList = SeqList::new;
+    let _: fn(&mut SeqList, Seq32) = SeqList::push;
+    let _: fn(&SeqList) -> &[Seq32] = SeqList::as_slice;
+    let _: fn(&SeqList) -> bool = SeqList::is_spilled;
+    let _: SeqList = [Seq32::zero()].into();
+    let _: SeqList = vec![Seq32::zero()].into();
+    let _: Vec<Seq32> = SeqList::new().into_iter().collect();
+}
+
+#[test]
+fn clock() {
+    let _: fn(&StdClock) -> Instant = <StdClock as Clock>::now;
+    let _: fn(Instant) -> ManualClock = ManualClock::new;
+    let _: fn(&ManualClock, Duration) = ManualClock::advance;
+    let _: fn(&ManualClock) -> Instant = <ManualClock as Clock>::now;
+    let _: &dyn Clock = &StdClock;
+}
+
+#[test]
+fn protocol_packet_hdr() {
+    let _: fn(&[u8]) -> Result<PacketHeader, DecodingError> = ardl::protocol::packet_hdr::peek;
+    let _: fn(&PacketHeader) -> usize = PacketHeader::len_hint;
+    let _: usize = PACKET_HDR_LEN;
+    let _: usize = ardl::protocol::packet_hdr::LEGACY_PACKET_HDR_LEN;
+    let _: usize = ardl::protocol::packet_hdr::LEGACY_PACKET_OPTIONS_LEN;
+    let _: u8 = ardl::protocol::packet_hdr::PROTOCOL_VERSION;
+    let _: u8 = ardl::protocol::packet_hdr::LEGACY_VERSION;
+    let _: usize = PACKET_OPTIONS_LEN;
+    let _: usize = CONN_ID_LEN;
+    let _: usize = PACKET_SEQ_LEN;
+    let _ = PacketHeaderBuilder {
+        rwnd: 0u16,
+        nack: Seq32::zero(),
+        timestamp: None,
+        timestamp_echo: None,
+        conn_id: None::<u64>,
+        packet_seq: None,
+    };
+    let _: fn(PacketHeaderBuilder) -> Result<PacketHeader, _> = PacketHeaderBuilder::build;
+    let _: fn(&mut BufSlice) -> Result<PacketHeader, DecodingError> = PacketHeader::from_slice;
+    let _: fn(&mut BufSlice, WireFormat) -> Result<PacketHeader, DecodingError> =
+        PacketHeader::from_slice_with;
+    let _: fn(&PacketHeader, &mut OwnedBufWtr, WireFormat) -> Result<(), EncodingError> =
+        PacketHeader::append_to_with;
+    let _: fn(&[u8], WireFormat) -> Result<PacketHeader, DecodingError> =
+        ardl::protocol::packet_hdr::peek_with;
+    let _: fn(&PacketHeader, &mut OwnedBufWtr) -> Result<(), EncodingError> =
+        PacketHeader::append_to;
+    let _: fn(&PacketHeader) -> u16 = PacketHeader::rwnd;
+    let _: fn(&PacketHeader) -> Seq32 = PacketHeader::nack;
+    let _: fn(&PacketHeader) -> Option<u32> = PacketHeader::timestamp;
+    let _: fn(&PacketHeader) -> Option<u32> = PacketHeader::timestamp_echo;
+    let _: fn(&PacketHeader) -> Option<u64> = PacketHeader::conn_id;
+    let _: fn(&PacketHeader) -> Option<Seq32> = PacketHeader::packet_seq;
+    let _: fn(&PacketHeader) -> u8 = PacketHeader::version;
+    let _: fn(PacketHeader, u8) -> PacketHeader = PacketHeader::with_version;
+    let _: fn(&mut BufSlice, WireFormat) -> Result<PacketHeader, DecodingError> =
+        PacketHeader::from_legacy_slice;
+    let _: fn(&[u8], WireFormat) -> Result<PacketHeader, DecodingError> =
+        ardl::protocol::packet_hdr::peek_legacy_with;
+}
+
+#[test]
+fn protocol_packet() {
+    let _: fn(PacketBuilder) -> Result<Packet, _> = PacketBuilder::build;
+    let _ = |hdr: PacketHeader, frags: Vec<Frag>| PacketBuilder { hdr, frags };
+    let _ = |builder: PacketBuilder| -> (PacketHeader, Vec<Frag>) { (builder.hdr, builder.frags) };
+    let _: fn(&mut BufSlice) -> Result<Packet, DecodingError> = Packet::from_slice;
+    let _: fn(&mut BufSlice, WireFormat, usize) -> Result<Packet, DecodingError> =
+        Packet::from_legacy_slice;
+    let _: fn(&Packet, &mut OwnedBufWtr) -> Result<(), EncodingError> = Packet::append_to;
+    let _: fn(Packet) -> PacketBuilder = Packet::into_builder;
+    let _: fn(Packet) -> (PacketHeader, Vec<Frag>) = Packet::into_frags;
+    let _ = |packet: &Packet| -> (Packet, String) { (packet.clone(), format!("{packet:?}")) };
+    let _ = |hdr: &PacketHeader| -> (PacketHeader, String) { (hdr.clone(), format!("{hdr:?}")) };
+    let _ = |frag: &Frag| -> (Frag, FragCommand, String) {
+        (frag.clone(), frag.cmd().clone(), format!("{frag:?}"))
+    };
+    let _ = |body: &Body| -> String { format!("{body:?}") };
+    let _: fn(&Packet) -> &PacketHeader = Packet::hdr;
+    let _: fn(&Packet) -> &Vec<Frag> = Packet::frags;
+    let _: fn(&Packet) -> usize = Packet::wire_len;
+    let _: fn(&Packet) -> usize = Packet::frags_len;
+    let _: fn(&Packet) -> usize = Packet::push_bytes;
+    let _: fn(&Packet) -> usize = Packet::unknown_frags;
+    let _: fn(Packet, Option<Arc<dyn PacketAuth>>) -> Packet = Packet::with_auth;
+    let _: fn(Packet, WireFormat) -> Packet = Packet::with_format;
+    let _: fn(&Packet) -> WireFormat = Packet::format;
+    let _: fn(&mut BufSlice, WireFormat) -> Result<Packet, DecodingError> = Packet::from_slice_with;
+    let _: fn(&mut BufSlice, WireFormat, usize) -> Result<Packet, DecodingError> =
+        Packet::from_slice_limited;
+}
+
+#[test]
+fn protocol_transform() {
+    let _ = |transform: &dyn PacketTransform, wtr: &mut OwnedBufWtr, slice: &mut BufSlice| {
+        transform.encode(wtr);
+        let _: Result<(), ()> = transform.decode(slice);
+    };
+    let _: &dyn PacketTransform = &IdentityTransform;
+    let _: fn(Vec<u8>) -> XorTransform = XorTransform::new;
+}
+
+#[test]
+fn protocol_codec() {
+    let _ = |codec: &dyn BodyCodec, data: &[u8]| {
+        let _: Vec<u8> = codec.compress(data);
+        let _: Result<Vec<u8>, ()> = codec.decompress(data);
+    };
+    let _: &dyn BodyCodec = &IdentityCodec;
+    #[cfg(feature = "lz4")]
+    {
+        let _: &dyn BodyCodec = &ardl::protocol::codec::Lz4Codec;
+    }
+}
+
+#[test]
+fn protocol_auth() {
+    let _: usize = MAX_TAG_LEN;
+    let _: fn(&[u8], &[u8]) -> bool = constant_time_eq;
+    let _ = |auth: &dyn PacketAuth, data: &[u8], tag: &mut [u8]| {
+        let _: usize = auth.tag_len();
+        auth.sign(data, tag);
+        let _: bool = auth.verify(data, tag);
+    };
+    #[cfg(feature = "hmac-sha256")]
+    {
+        use ardl::protocol::auth::{HmacSha256, HMAC_SHA256_TAG_LEN};
+        let _: fn(&[u8]) -> HmacSha256 = HmacSha256::new;
+        let _: fn(&HmacSha256, &[u8]) -> [u8; HMAC_SHA256_TAG_LEN] = HmacSha256::tag;
+    }
+}
+
+#[test]
+fn protocol_frag() {
+    let _: usize = PUSH_HDR_LEN;
+    let _: u8 = EXTENSION_CMD_START;
+    let _: usize = PING_LEN;
+    let _: usize = SACK_HDR_LEN;
+    let _: usize = SACK_RANGE_LEN;
+    let _: usize = MAX_SACK_RANGES;
+    let _: usize = SYN_LEN;
+    let _: usize = ACK_HDR_LEN;
+    let _: usize = ACK_BITMAP_LEN;
+    let _: usize = ACK_BITMAP_SPAN;
+    let _ = FragBuilder {
+        seq: Seq32::zero(),
+        cmd: FragCommand::Ack,
+    };
+    let _ = |body: Body| FragCommand::Push { body };
+    let _ = |cmd: &FragCommand| match cmd {
+        FragCommand::Push { body: _ } => (),
+        FragCommand::CompressedPush { body: _ } => (),
+        FragCommand::Ack => (),
+        FragCommand::AckBitmap { bitmap } => {
+            let _: u64 = *bitmap;
+        }
+        FragCommand::Skip => (),
+        FragCommand::Fin => (),
+        FragCommand::Rst => (),
+        FragCommand::Ping { token } | FragCommand::Pong { token } => {
+            let _: u32 = *token;
+        }
+        FragCommand::Probe { id } | FragCommand::ProbeAck { id } => {
+            let _: u32 = *id;
+        }
+        FragCommand::Sack { ranges } => {
+            let _: &Vec<Range<Seq32>> = ranges;
+        }
+        FragCommand::Syn { nonce } | FragCommand::SynAck { nonce } => {
+            let _: u64 = *nonce;
+        }
+        FragCommand::Padding { len } => {
+            let _: usize = *len;
+        }
+        FragCommand::Parity {
+            count,
+            len_xor,
+            body,
+        } => {
+            let _: u8 = *count;
+            let _: u32 = *len_xor;
+            let _: &BufSlice = body;
+        }
+        FragCommand::Window { rwnd } => {
+            let _: u16 = *rwnd;
+        }
+    };
+    let _ = |slice: BufSlice| Body::Slice(slice);
+    let _ = |pasta: Arc<BufPasta>| Body::Pasta(pasta);
+    let _: fn(FragBuilder) -> Result<Frag, _> = FragBuilder::build;
+    let _: fn(&Body) -> bool = Body::is_empty;
+    let _: fn(&Body) -> usize = Body::len;
+    let _: fn(&mut BufSlice) -> Result<Frag, DecodingError> = Frag::from_slice;
+    let _: fn(&mut BufSlice) -> Result<Option<Frag>, DecodingError> = Frag::from_slice_or_skip;
+    let _: fn(&Frag, &mut OwnedBufWtr) -> Result<(), EncodingError> = Frag::append_to;
+    let _: fn(Frag) -> FragBuilder = Frag::into_builder;
+    let _: fn(&Frag) -> &FragCommand = Frag::cmd;
+    let _: fn(&Frag) -> Seq32 = Frag::seq;
+    let _: fn(&Frag) -> usize = Frag::len;
+    let _: u8 = CommandType::Push.into();
+    let _: u8 = CommandType::Ack.into();
+    let _: u8 = CommandType::Skip.into();
+    let _: u8 = CommandType::Fin.into();
+    let _: u8 = CommandType::Rst.into();
+    let _: u8 = CommandType::Ping.into();
+    let _: u8 = CommandType::Pong.into();
+    let _: u8 = CommandType::Sack.into();
+    let _: u8 = CommandType::Syn.into();
+    let _: u8 = CommandType::SynAck.into();
+    let _: u8 = CommandType::CompactPush.into();
+    let _: u8 = CommandType::Padding.into();
+    let _: u8 = CommandType::Parity.into();
+    let _: u8 = CommandType::AckBitmap.into();
+    let _: u8 = CommandType::Probe.into();
+    let _: u8 = CommandType::ProbeAck.into();
+    let _: u8 = CommandType::Window.into();
+    let _: usize = PARITY_HDR_LEN;
+    let _: u8 = COMPRESSED_FLAG;
+    let _: fn(&WireFormat) -> usize = WireFormat::parity_hdr_len;
+    let _: usize = COMPACT_PUSH_HDR_LEN;
+    let _ = |format: WireFormat| match format {
+        WireFormat::V1 => (),
+        WireFormat::V2 => (),
+        WireFormat::V3 => (),
+    };
+    let _: fn(&WireFormat) -> usize = WireFormat::push_hdr_len;
+    let _: fn(&WireFormat) -> usize = WireFormat::max_body_len;
+    let _: fn(&WireFormat) -> usize = WireFormat::window_len;
+    let _: fn(&WireFormat) -> usize = WireFormat::max_seq_growth;
+    let _: usize = WINDOW_LEN;
+    let _: usize = STREAM_ID_LEN;
+    let _: u8 = STREAM_FLAG;
+    let _: fn(&Frag) -> Option<u16> = Frag::stream_id;
+    let _: fn(Frag, Option<u16>) -> Frag = Frag::with_stream_id;
+    let _: fn(&mut BufSlice, WireFormat, Seq32) -> Result<Option<Frag>, DecodingError> =
+        Frag::from_slice_or_skip_with;
+    let _: fn(&Frag, &mut OwnedBufWtr, WireFormat, Seq32) -> Result<(), EncodingError> =
+        Frag::append_to_with;
+    let _: fn(&Frag, WireFormat, Seq32) -> usize = Frag::len_with;
+    let _ = |e: DecodingError| match e {
+        DecodingError::Decoding { field, offset } => {
+            let _: &'static str = field;
+            let _: usize = offset;
+        }
+        DecodingError::LenPastEnd { offset } => {
+            let _: usize = offset;
+        }
+        DecodingError::TooManyFrags { offset } => {
+            let _: usize = offset;
+        }
+        DecodingError::UnsupportedVersion { version } => {
+            let _: u8 = version;
+        }
+    };
+    let _ = |e: EncodingError| match e {
+        EncodingError::NotEnoughSpace => (),
+        EncodingError::BodyTooLong => (),
+    };
+}
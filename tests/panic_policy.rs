@@ -0,0 +1,316 @@
+//! Drives the public API through boundary inputs that used to panic.
+//!
+//! Each case has to end in an error or in a harmless no-op.
+
+use ardl::{
+    layer::{
+        Builder, Downloader, DownloaderBuilder, SendError, SeqList, SetStateError, SetUploadState,
+        Uploader, UploaderBuilder,
+    },
+    protocol::{
+        frag::{Body, FragBuilder, FragCommand, WireFormat, PUSH_HDR_LEN},
+        packet::PacketBuilder,
+        packet_hdr::{PacketHeaderBuilder, PACKET_HDR_LEN},
+    },
+    utils::{
+        buf::{BufSlice, OwnedBufWtr},
+        Seq32,
+    },
+};
+use std::time::{Duration, Instant};
+
+fn downloader(recv_buf_len: usize) -> Downloader {
+    DownloaderBuilder {
+        recv_buf_len,
+        strict: false,
+        window_update_chunk: 0,
+        ack_late_pushes: true,
+        handshake: None,
+        initial_seq: None,
+        drop_duplicate_packets: true,
+        auth: None,
+        wire_format: WireFormat::V1,
+        fec_group_size: None,
+        codec: None,
+        max_frags_per_packet: None,
+        legacy_header: false,
+    }
+    .build()
+    .unwrap()
+}
+
+fn pushes(seqs: &[u32]) -> BufSlice {
+    let packet = PacketBuilder {
+        hdr: PacketHeaderBuilder {
+            rwnd: 1,
+            nack: Seq32::from_u32(0),
+            timestamp: None,
+            timestamp_echo: None,
+            conn_id: None,
+            packet_seq: None,
+        }
+        .build()
+        .unwrap(),
+        frags: seqs
+            .iter()
+            .map(|&seq| {
+                FragBuilder {
+                    seq: Seq32::from_u32(seq),
+                    cmd: FragCommand::Push {
+                        body: Body::Slice(BufSlice::from_bytes(vec![0])),
+                    },
+                }
+                .build()
+                .unwrap()
+            })
+            .collect(),
+    }
+    .build()
+    .unwrap();
+    let mut wtr = OwnedBufWtr::new(1024, 0);
+    packet.append_to(&mut wtr).unwrap();
+    wtr.into_slice()
+}
+
+fn ack(uploader: &mut Uploader, seq: u32, now: &Instant) {
+    uploader.set_state(state(seq + 1), now).unwrap();
+}
+
+fn state(nack: u32) -> SetUploadState {
+    SetUploadState {
+        remote_rwnd_size: 99,
+        remote_nack: Seq32::from_u32(nack),
+        local_next_seq_to_receive: Seq32::from_u32(0),
+        remote_seqs_to_ack: SeqList::new(),
+        acked_local_seqs: SeqList::new(),
+        local_rwnd_size: 1,
+        remote_timestamp: None,
+        timestamp_echo: None,
+        conn_id: None,
+        pings_to_answer: Vec::new(),
+        pong: None,
+        probe_to_answer: None,
+        probe_ack: None,
+        remote_ranges_to_sack: Vec::new(),
+        sacked_local_ranges: Vec::new(),
+        syn_to_answer: None,
+        is_established: true,
+    }
+}
+
+#[test]
+fn zero_size_configs() {
+    let mut builder: Builder = Default::default();
+    builder.to_send_bytes_cap = 0;
+    builder.swnd_size_cap = 0;
+    assert!(builder.validate().is_err());
+
+    let mut builder: UploaderBuilder = Default::default();
+    builder.mtu = 0;
+    let e = builder.build().err().unwrap();
+    assert_eq!(format!("{:?}", e), "MtuTooSmall");
+
+    let mut builder: UploaderBuilder = Default::default();
+    builder.to_send_bytes_cap = 0;
+    let e = builder.build().err().unwrap();
+    assert_eq!(format!("{:?}", e), "ToSendBytesCapZero");
+    let mut builder: UploaderBuilder = Default::default();
+    builder.swnd_size_cap = 0;
+    let e = builder.build().err().unwrap();
+    assert_eq!(format!("{:?}", e), "SwndSizeCapZero");
+
+    let mut builder: UploaderBuilder = Default::default();
+    builder.to_send_bytes_cap = 1;
+    builder.swnd_size_cap = 1;
+    builder.local_recv_buf_len = 0;
+    let mut uploader = builder.build().unwrap();
+    assert!(matches!(
+        uploader.write_bytes(&[0, 1]),
+        Err(SendError::QueueFull(()))
+    ));
+    assert!(uploader.emit(&Instant::now()).is_empty());
+
+    let mut downloader = downloader(0);
+    let state = downloader.write(pushes(&[0])).unwrap();
+    assert_eq!(state.local_rwnd_size, 0);
+    assert!(downloader.emit().is_none());
+}
+
+#[test]
+fn recv_buf_too_large() {
+    let mut builder: UploaderBuilder = Default::default();
+    builder.local_recv_buf_len = u16::MAX as usize + 1;
+    let e = builder.build().err().unwrap();
+    assert_eq!(format!("{:?}", e), "LocalRecvBufTooLarge");
+
+    let mut builder: Builder = Default::default();
+    builder.local_recv_buf_len = u16::MAX as usize + 1;
+    assert!(builder.build().is_err());
+
+    let mut uploader = UploaderBuilder::build(Default::default()).unwrap();
+    let state = SetUploadState {
+        remote_rwnd_size: 0,
+        remote_nack: Seq32::from_u32(0),
+        local_next_seq_to_receive: Seq32::from_u32(0),
+        remote_seqs_to_ack: SeqList::new(),
+        acked_local_seqs: SeqList::new(),
+        local_rwnd_size: u16::MAX as usize + 1,
+        remote_timestamp: None,
+        timestamp_echo: None,
+        conn_id: None,
+        pings_to_answer: Vec::new(),
+        pong: None,
+        probe_to_answer: None,
+        probe_ack: None,
+        remote_ranges_to_sack: Vec::new(),
+        sacked_local_ranges: Vec::new(),
+        syn_to_answer: None,
+        is_established: true,
+    };
+    assert!(matches!(
+        uploader.set_state(state, &Instant::now()),
+        Err(SetStateError::LocalRwndTooLarge { .. })
+    ));
+}
+
+#[test]
+fn extreme_rto_factors() {
+    let mut builder: UploaderBuilder = Default::default();
+    builder.ratio_rto_to_one_rtt = -1.0;
+    assert!(builder.build().is_err());
+    let mut builder: UploaderBuilder = Default::default();
+    builder.ratio_rto_to_one_rtt = f64::NAN;
+    assert!(builder.build().is_err());
+
+    let mut builder: UploaderBuilder = Default::default();
+    builder.ratio_rto_to_one_rtt = f64::MAX;
+    builder.rto_jitter = f64::MAX;
+    let mut uploader = builder.build().unwrap();
+    let now = Instant::now();
+    uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+    assert_eq!(uploader.emit(&now).len(), 1);
+    ack(&mut uploader, 0, &(now + Duration::from_millis(10)));
+    uploader.write_bytes(&[1]).map_err(|_| ()).unwrap();
+    assert_eq!(uploader.emit(&now).len(), 1);
+    let _ = uploader.rto();
+    assert!(uploader.emit(&(now + Duration::from_secs(3600))).is_empty());
+}
+
+#[test]
+fn out_of_window_pushes() {
+    let mut downloader = downloader(4);
+    downloader.write(pushes(&[0, 1])).unwrap();
+    // too early, far too early and too late
+    downloader.write(pushes(&[4, 1 << 30, u32::MAX])).unwrap();
+    downloader.write(pushes(&[0])).unwrap();
+    let stat = downloader.stat();
+    assert_eq!(stat.early_pushes, 2);
+    assert_eq!(stat.late_pushes, 2);
+    assert!(downloader.emit().is_some());
+    assert!(downloader.emit().is_some());
+    assert!(downloader.emit().is_none());
+    assert!(downloader.emit_max(0).is_none());
+}
+
+#[test]
+fn oversized_writes() {
+    let mut builder: UploaderBuilder = Default::default();
+    builder.to_send_bytes_cap = 4;
+    let mut uploader = builder.build().unwrap();
+    let slice = match uploader.write(BufSlice::from_bytes(vec![0; 5])) {
+        Err(SendError::QueueFull(slice)) => slice,
+        _ => panic!(),
+    };
+    let (written, rest) = uploader.write_partial(slice);
+    assert_eq!(written, 4);
+    assert_eq!(rest.unwrap().len(), 1);
+    assert!(uploader
+        .write_vectored(vec![BufSlice::from_bytes(vec![0])])
+        .is_err());
+}
+
+#[test]
+fn malformed_packets() {
+    let mut downloader = downloader(4);
+    let valid = pushes(&[0]).data().to_vec();
+    let inputs = vec![
+        vec![],
+        // truncated header
+        vec![0; 5],
+        // truncated frag
+        valid[..valid.len() - 2].to_vec(),
+        // unknown command
+        vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0xff],
+        // empty push
+        vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        // push longer than the packet
+        vec![0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0],
+    ];
+    let count = inputs.len() as u64;
+    for input in inputs {
+        assert!(downloader.write(BufSlice::from_bytes(input)).is_err());
+    }
+    assert_eq!(downloader.stat().decoding_errors, count);
+    assert_eq!(downloader.stat().packets, 0);
+}
+
+#[test]
+fn conn_id_adopted_mid_flight() {
+    let mut uploader = UploaderBuilder::build(Default::default()).unwrap();
+    let now = Instant::now();
+    uploader.write_bytes(&[0; 1280]).map_err(|_| ()).unwrap();
+    assert_eq!(uploader.emit(&now).len(), 1);
+    // the push in flight fills the MTU without the ID
+    let mut delta = state(0);
+    delta.conn_id = Some(7);
+    uploader.set_state(delta, &now).unwrap();
+    assert_eq!(uploader.conn_id(), None);
+    assert_eq!(uploader.stat().conn_ids_deferred, 1);
+    let later = now + Duration::from_secs(3600);
+    assert!(!uploader.emit(&later).is_empty());
+    // room once it is acked
+    let mut delta = state(1);
+    delta.conn_id = Some(7);
+    uploader.set_state(delta, &later).unwrap();
+    assert_eq!(uploader.conn_id(), Some(7));
+    // cut to the grown header
+    uploader.write_bytes(&[0; 1280]).map_err(|_| ()).unwrap();
+    assert_eq!(uploader.emit(&later).len(), 2);
+
+    // no room at all
+    let mut builder: UploaderBuilder = Default::default();
+    builder.mtu = PACKET_HDR_LEN + PUSH_HDR_LEN + 1;
+    let mut uploader = builder.build().unwrap();
+    let mut delta = state(0);
+    delta.conn_id = Some(7);
+    uploader.set_state(delta, &now).unwrap();
+    assert_eq!(uploader.conn_id(), None);
+    uploader.write_bytes(&[0]).map_err(|_| ()).unwrap();
+    assert_eq!(uploader.emit(&now).len(), 1);
+}
+
+#[test]
+fn timestamp_echoed_mid_flight() {
+    let mut uploader = UploaderBuilder::build(Default::default()).unwrap();
+    let now = Instant::now();
+    uploader.write_bytes(&[0; 1280]).map_err(|_| ()).unwrap();
+    assert_eq!(uploader.emit(&now).len(), 1);
+    // the push in flight fills the MTU without the options
+    let mut delta = state(0);
+    delta.remote_timestamp = Some(5);
+    uploader.set_state(delta, &now).unwrap();
+    assert_eq!(uploader.stat().echoes_skipped, 1);
+    let later = now + Duration::from_secs(3600);
+    let packets = uploader.emit(&later);
+    assert_eq!(packets[0].hdr().timestamp_echo(), None);
+
+    // room once it is acked
+    let mut delta = state(1);
+    delta.remote_timestamp = Some(6);
+    uploader.set_state(delta, &later).unwrap();
+    assert!(uploader.set_mtu(PACKET_HDR_LEN + PUSH_HDR_LEN + 1).is_err());
+    uploader.write_bytes(&[0; 1280]).map_err(|_| ()).unwrap();
+    let packets = uploader.emit(&later);
+    assert_eq!(packets.len(), 2);
+    assert_eq!(packets[0].hdr().timestamp_echo(), Some(6));
+}